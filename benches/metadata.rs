@@ -0,0 +1,104 @@
+//! Benchmarks how long it takes to fetch all the metadata for a busy stream (like a first sync with
+//! `minchange=0` does), both with one `Storage::list_metadata` call and by looking up each change one
+//! by one like `/metadata` used to.
+
+use anyhow::Result;
+use criterion::{criterion_group, criterion_main, Criterion};
+use rugs::{
+    models::{BadgeResult, CreateBadge, UgsUserVote},
+    storage::{SqliteStorage, Storage, UserEventUpdate},
+};
+
+use std::sync::Arc;
+
+const STREAM: &str = "//depot/main";
+const PROJECTS: usize = 4;
+const CHANGES: i64 = 1000;
+const BADGES_PER_CHANGE: usize = 3;
+const USERS_PER_CHANGE: usize = 2;
+
+/// Create an in-memory database with badges & user events for every change in every project
+async fn seed() -> Result<Arc<dyn Storage>> {
+    let pool = sqlx::SqlitePool::connect("sqlite::memory:").await?;
+    sqlx::migrate!("./migrations").run(&pool).await?;
+    let storage: Arc<dyn Storage> = Arc::new(SqliteStorage::new(pool));
+
+    for project in 0..PROJECTS {
+        let project_id = storage
+            .get_or_add_project(STREAM, &format!("project{project}"))
+            .await?;
+
+        for change_number in 1..=CHANGES {
+            for build_type in 0..BADGES_PER_CHANGE {
+                let badge = CreateBadge {
+                    change_number,
+                    build_type: format!("Build{build_type}"),
+                    result: BadgeResult::Success,
+                    url: format!("https://ci.example.com/{project}/{change_number}/{build_type}"),
+                    project: format!("{STREAM}/project{project}"),
+                };
+                storage.add_badge(project_id, &badge, None).await?;
+            }
+
+            for user in 0..USERS_PER_CHANGE {
+                let update = UserEventUpdate {
+                    synced: true,
+                    vote: Some(UgsUserVote::Good),
+                    ..Default::default()
+                };
+                storage
+                    .update_user_event(project_id, change_number, &format!("user{user}"), update)
+                    .await?;
+            }
+        }
+    }
+
+    Ok(storage)
+}
+
+/// Fetch the metadata the way `/metadata` used to, with separate queries for each change
+async fn per_change_metadata(storage: &dyn Storage) -> Result<usize> {
+    let mut rows = 0;
+    for project in 0..PROJECTS {
+        let project = format!("project{project}");
+        for change_number in 1..=CHANGES {
+            for change in storage
+                .list_metadata(
+                    STREAM,
+                    Some(&project),
+                    0,
+                    change_number,
+                    Some(change_number),
+                )
+                .await?
+            {
+                rows += change.badges.len() + change.user_events.len();
+            }
+        }
+    }
+    Ok(rows)
+}
+
+fn metadata(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let storage = runtime.block_on(seed()).unwrap();
+
+    let mut group = c.benchmark_group("metadata");
+    group.sample_size(10);
+    group.bench_function("list_metadata", |b| {
+        b.to_async(&runtime).iter(|| async {
+            storage
+                .list_metadata(STREAM, None, 0, 0, None)
+                .await
+                .unwrap()
+        })
+    });
+    group.bench_function("per_change", |b| {
+        b.to_async(&runtime)
+            .iter(|| async { per_change_metadata(storage.as_ref()).await.unwrap() })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, metadata);
+criterion_main!(benches);
@@ -0,0 +1,48 @@
+CREATE TABLE IF NOT EXISTS issues
+(
+    id              INTEGER PRIMARY KEY NOT NULL,
+    created_at      DATETIME NOT NULL,
+    project         TEXT NOT NULL COLLATE NOCASE,
+    summary         TEXT NOT NULL,
+    owner           TEXT COLLATE NOCASE,
+    nominated_by    TEXT COLLATE NOCASE,
+    acknowledged_at DATETIME,
+    fix_change      INTEGER NOT NULL DEFAULT 0,
+    resolved_at     DATETIME,
+    is_warning      BOOL NOT NULL DEFAULT FALSE,
+    build_url       TEXT
+);
+
+CREATE TABLE IF NOT EXISTS issue_builds
+(
+    id            INTEGER PRIMARY KEY NOT NULL,
+    issue_id      INTEGER NOT NULL REFERENCES issues (id) ON DELETE CASCADE,
+    stream        TEXT NOT NULL,
+    change_number INTEGER NOT NULL,
+    job_name      TEXT NOT NULL,
+    job_url       TEXT NOT NULL,
+    job_step_name TEXT NOT NULL,
+    job_step_url  TEXT NOT NULL,
+    error_url     TEXT,
+    outcome       INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS issue_diagnostics
+(
+    id       INTEGER PRIMARY KEY NOT NULL,
+    issue_id INTEGER NOT NULL REFERENCES issues (id) ON DELETE CASCADE,
+    build_id INTEGER REFERENCES issue_builds (id) ON DELETE SET NULL,
+    message  TEXT NOT NULL,
+    url      TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS issue_watchers
+(
+    issue_id  INTEGER NOT NULL REFERENCES issues (id) ON DELETE CASCADE,
+    user_name TEXT NOT NULL COLLATE NOCASE,
+    PRIMARY KEY (issue_id, user_name)
+);
+
+CREATE INDEX issue_resolved ON issues (resolved_at);
+CREATE INDEX issue_build_issue ON issue_builds (issue_id);
+CREATE INDEX issue_diagnostic_issue ON issue_diagnostics (issue_id);
@@ -0,0 +1,30 @@
+CREATE TABLE IF NOT EXISTS telemetry_timings
+(
+    id          INTEGER PRIMARY KEY NOT NULL,
+    received_at DATETIME NOT NULL,
+    action      TEXT NOT NULL COLLATE NOCASE,
+    result      TEXT NOT NULL COLLATE NOCASE,
+    user_name   TEXT NOT NULL COLLATE NOCASE,
+    project     TEXT NOT NULL COLLATE NOCASE,
+    timestamp   DATETIME NOT NULL,
+    duration    REAL NOT NULL,
+    version     TEXT,
+    ip_address  TEXT
+);
+
+CREATE TABLE IF NOT EXISTS telemetry_errors
+(
+    id          INTEGER PRIMARY KEY NOT NULL,
+    received_at DATETIME NOT NULL,
+    error_type  INTEGER NOT NULL,
+    text        TEXT NOT NULL,
+    user_name   TEXT NOT NULL COLLATE NOCASE,
+    project     TEXT NOT NULL COLLATE NOCASE,
+    timestamp   DATETIME NOT NULL,
+    version     TEXT,
+    ip_address  TEXT
+);
+
+CREATE INDEX telemetry_timing_received ON telemetry_timings (received_at);
+CREATE INDEX telemetry_timing_action ON telemetry_timings (action, timestamp);
+CREATE INDEX telemetry_error_received ON telemetry_errors (received_at);
@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS users
+(
+    user_name     TEXT PRIMARY KEY NOT NULL COLLATE NOCASE,
+    password_hash TEXT NOT NULL,
+    created_at    DATETIME NOT NULL,
+    revoked_at    DATETIME
+);
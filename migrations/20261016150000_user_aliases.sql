@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS user_aliases
+(
+    user_name          TEXT NOT NULL COLLATE NOCASE REFERENCES users (user_name) ON DELETE CASCADE,
+    perforce_user_name TEXT NOT NULL COLLATE NOCASE,
+    PRIMARY KEY (user_name, perforce_user_name)
+);
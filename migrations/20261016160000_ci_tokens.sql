@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS ci_tokens
+(
+    name             TEXT PRIMARY KEY NOT NULL COLLATE NOCASE,
+    token_hash       TEXT NOT NULL UNIQUE,
+    project_patterns TEXT NOT NULL,
+    build_types      TEXT,
+    created_at       DATETIME NOT NULL,
+    revoked_at       DATETIME
+);
+
+ALTER TABLE badges ADD COLUMN posted_by TEXT;
@@ -0,0 +1,11 @@
+-- The last sequence number handed out to a badge or user event. Sequence numbers used to be the
+-- wall-clock time in microseconds, so we continue on from the largest one that's been used.
+CREATE TABLE IF NOT EXISTS sequence_counter
+(
+    id    INTEGER PRIMARY KEY NOT NULL CHECK (id = 0),
+    value INTEGER NOT NULL
+);
+
+INSERT INTO sequence_counter (id, value)
+    SELECT 0, MAX(COALESCE((SELECT MAX(sequence) FROM badges), 0),
+                  COALESCE((SELECT MAX(sequence) FROM user_events), 0));
@@ -0,0 +1,28 @@
+-- Concurrent requests (or projects that only differed by case before they were normalized) could
+-- add the same project twice, so merge any duplicates into the oldest one before making it unique
+UPDATE badges SET project_id = (
+    SELECT MIN(canonical.project_id) FROM projects AS duplicate
+    JOIN projects AS canonical ON canonical.stream = duplicate.stream AND canonical.project = duplicate.project
+    WHERE duplicate.project_id = badges.project_id
+);
+UPDATE user_events SET project_id = (
+    SELECT MIN(canonical.project_id) FROM projects AS duplicate
+    JOIN projects AS canonical ON canonical.stream = duplicate.stream AND canonical.project = duplicate.project
+    WHERE duplicate.project_id = user_events.project_id
+);
+DELETE FROM projects WHERE project_id NOT IN (SELECT MIN(project_id) FROM projects GROUP BY stream, project);
+
+DROP INDEX project_index;
+CREATE UNIQUE INDEX project_index ON projects (stream, project);
+
+-- Likewise, only keep the most recent user event for each user & change
+DELETE FROM user_events WHERE EXISTS (
+    SELECT 1 FROM user_events AS newer
+    WHERE newer.project_id = user_events.project_id
+      AND newer.user_name = user_events.user_name
+      AND newer.change_number = user_events.change_number
+      AND (newer.sequence > user_events.sequence OR (newer.sequence = user_events.sequence AND newer.id > user_events.id))
+);
+
+DROP INDEX user_event_specific_change;
+CREATE UNIQUE INDEX user_event_specific_change ON user_events (project_id, user_name, change_number);
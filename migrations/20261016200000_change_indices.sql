@@ -0,0 +1,3 @@
+-- `/metadata` looks up all the badges & user events for a set of changes at once
+CREATE INDEX badge_project_change ON badges (project_id, change_number);
+CREATE INDEX user_event_project_change ON user_events (project_id, change_number);
@@ -0,0 +1,148 @@
+-- PostgreSQL has the whole schema from `migrations/` in one go. We use `citext` wherever sqlite
+-- uses `COLLATE NOCASE`, so queries need to compare against `$1::citext` to be case insensitive.
+CREATE EXTENSION IF NOT EXISTS citext;
+
+CREATE TABLE IF NOT EXISTS projects
+(
+    project_id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    stream     CITEXT NOT NULL,
+    project    CITEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS badges
+(
+    id            BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    sequence      BIGINT NOT NULL,
+    change_number BIGINT NOT NULL,
+    added_at      TIMESTAMPTZ NOT NULL,
+    build_type    TEXT NOT NULL,
+    result        INTEGER NOT NULL,
+    url           TEXT NOT NULL,
+    project_id    BIGINT NOT NULL REFERENCES projects (project_id),
+    posted_by     TEXT
+);
+
+CREATE TABLE IF NOT EXISTS user_events
+(
+    id            BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    project_id    BIGINT NOT NULL REFERENCES projects (project_id),
+    change_number BIGINT NOT NULL,
+    user_name     TEXT NOT NULL,
+    sequence      BIGINT NOT NULL,
+    updated_at    TIMESTAMPTZ NOT NULL,
+    synced_at     TIMESTAMPTZ,
+    vote          INTEGER,
+    investigating BOOLEAN,
+    starred       BOOLEAN,
+    comment       TEXT
+);
+
+CREATE INDEX project_index ON projects (stream, project);
+CREATE INDEX badge_project_sequence_change ON badges (project_id, sequence, change_number);
+CREATE INDEX user_event_project_sequence_change ON user_events (project_id, sequence, change_number);
+CREATE INDEX user_event_specific_change ON user_events (project_id, user_name, change_number);
+
+CREATE TABLE IF NOT EXISTS issues
+(
+    id              BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    created_at      TIMESTAMPTZ NOT NULL,
+    project         CITEXT NOT NULL,
+    summary         TEXT NOT NULL,
+    owner           CITEXT,
+    nominated_by    CITEXT,
+    acknowledged_at TIMESTAMPTZ,
+    fix_change      BIGINT NOT NULL DEFAULT 0,
+    resolved_at     TIMESTAMPTZ,
+    is_warning      BOOLEAN NOT NULL DEFAULT FALSE,
+    build_url       TEXT
+);
+
+CREATE TABLE IF NOT EXISTS issue_builds
+(
+    id            BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    issue_id      BIGINT NOT NULL REFERENCES issues (id) ON DELETE CASCADE,
+    stream        TEXT NOT NULL,
+    change_number BIGINT NOT NULL,
+    job_name      TEXT NOT NULL,
+    job_url       TEXT NOT NULL,
+    job_step_name TEXT NOT NULL,
+    job_step_url  TEXT NOT NULL,
+    error_url     TEXT,
+    outcome       INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS issue_diagnostics
+(
+    id       BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    issue_id BIGINT NOT NULL REFERENCES issues (id) ON DELETE CASCADE,
+    build_id BIGINT REFERENCES issue_builds (id) ON DELETE SET NULL,
+    message  TEXT NOT NULL,
+    url      TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS issue_watchers
+(
+    issue_id  BIGINT NOT NULL REFERENCES issues (id) ON DELETE CASCADE,
+    user_name CITEXT NOT NULL,
+    PRIMARY KEY (issue_id, user_name)
+);
+
+CREATE INDEX issue_resolved ON issues (resolved_at);
+CREATE INDEX issue_build_issue ON issue_builds (issue_id);
+CREATE INDEX issue_diagnostic_issue ON issue_diagnostics (issue_id);
+
+CREATE TABLE IF NOT EXISTS telemetry_timings
+(
+    id          BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    received_at TIMESTAMPTZ NOT NULL,
+    action      CITEXT NOT NULL,
+    result      CITEXT NOT NULL,
+    user_name   CITEXT NOT NULL,
+    project     CITEXT NOT NULL,
+    timestamp   TIMESTAMPTZ NOT NULL,
+    duration    REAL NOT NULL,
+    version     TEXT,
+    ip_address  TEXT
+);
+
+CREATE TABLE IF NOT EXISTS telemetry_errors
+(
+    id          BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    received_at TIMESTAMPTZ NOT NULL,
+    error_type  INTEGER NOT NULL,
+    text        TEXT NOT NULL,
+    user_name   CITEXT NOT NULL,
+    project     CITEXT NOT NULL,
+    timestamp   TIMESTAMPTZ NOT NULL,
+    version     TEXT,
+    ip_address  TEXT
+);
+
+CREATE INDEX telemetry_timing_received ON telemetry_timings (received_at);
+CREATE INDEX telemetry_timing_action ON telemetry_timings (action, timestamp);
+CREATE INDEX telemetry_error_received ON telemetry_errors (received_at);
+
+CREATE TABLE IF NOT EXISTS users
+(
+    user_name     CITEXT PRIMARY KEY,
+    password_hash TEXT NOT NULL,
+    created_at    TIMESTAMPTZ NOT NULL,
+    revoked_at    TIMESTAMPTZ
+);
+
+CREATE TABLE IF NOT EXISTS user_aliases
+(
+    user_name          CITEXT NOT NULL REFERENCES users (user_name) ON DELETE CASCADE,
+    perforce_user_name CITEXT NOT NULL,
+    PRIMARY KEY (user_name, perforce_user_name)
+);
+
+CREATE TABLE IF NOT EXISTS ci_tokens
+(
+    name             CITEXT PRIMARY KEY,
+    token_hash       TEXT NOT NULL UNIQUE,
+    project_patterns JSONB NOT NULL,
+    build_types      JSONB,
+    created_at       TIMESTAMPTZ NOT NULL,
+    revoked_at       TIMESTAMPTZ
+);
@@ -0,0 +1,26 @@
+-- Concurrent requests could add the same project twice, so merge any duplicates into the oldest one
+-- before making it unique
+WITH canonical AS (
+    SELECT project_id, MIN(project_id) OVER (PARTITION BY stream, project) AS canonical_id FROM projects
+)
+UPDATE badges SET project_id = canonical.canonical_id
+    FROM canonical WHERE badges.project_id = canonical.project_id AND canonical.project_id <> canonical.canonical_id;
+WITH canonical AS (
+    SELECT project_id, MIN(project_id) OVER (PARTITION BY stream, project) AS canonical_id FROM projects
+)
+UPDATE user_events SET project_id = canonical.canonical_id
+    FROM canonical WHERE user_events.project_id = canonical.project_id AND canonical.project_id <> canonical.canonical_id;
+DELETE FROM projects AS duplicate USING projects AS canonical
+    WHERE canonical.stream = duplicate.stream AND canonical.project = duplicate.project AND canonical.project_id < duplicate.project_id;
+
+ALTER TABLE projects ADD CONSTRAINT projects_stream_project_key UNIQUE (stream, project);
+
+-- Likewise, only keep the most recent user event for each user & change
+DELETE FROM user_events USING user_events AS newer
+    WHERE newer.project_id = user_events.project_id
+      AND newer.user_name = user_events.user_name
+      AND newer.change_number = user_events.change_number
+      AND (newer.sequence, newer.id) > (user_events.sequence, user_events.id);
+
+DROP INDEX user_event_specific_change;
+CREATE UNIQUE INDEX user_event_specific_change ON user_events (project_id, user_name, change_number);
@@ -0,0 +1,7 @@
+-- What the server keeps track of about the database itself, e.g. which `[paths]` settings the
+-- projects were last split with
+CREATE TABLE IF NOT EXISTS server_state
+(
+    key   TEXT PRIMARY KEY,
+    value TEXT NOT NULL
+);
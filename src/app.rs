@@ -0,0 +1,285 @@
+//! The HTTP API, with the routes UGS & CI use and the middleware in front of them
+
+use axum::{
+    http::{self, Request, StatusCode},
+    middleware::{self, Next},
+    response::Response,
+    routing::{get, post},
+    Extension, Router,
+};
+use base64::prelude::*;
+use tower::ServiceBuilder;
+use tower_http::{
+    compression::{
+        predicate::{DefaultPredicate, NotForContentType, Predicate},
+        CompressionLayer,
+    },
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    set_header::SetResponseHeaderLayer,
+    trace::TraceLayer,
+};
+use tracing::error;
+
+use std::sync::Arc;
+
+#[cfg(debug_assertions)]
+use crate::middleware::print_request_response;
+use crate::{
+    auth::{constant_time_eq, CiTokenStore, CredentialStore, Principal, UserNamePolicy, UserStore},
+    config::Config,
+    handlers::*,
+    metrics::{track_requests, Metrics},
+    notifications::NotificationHub,
+    otel::request_span,
+    storage::{InstrumentedStorage, Storage},
+};
+
+/// Authenticate the request like `authenticate`, and count any requests it denies as auth failures
+/// for `scope` in `metrics`
+async fn auth<B>(
+    req: Request<B>,
+    next: Next<B>,
+    required_auth: String,
+    credential_store: Option<Arc<dyn CredentialStore>>,
+    metrics: Arc<Metrics>,
+    scope: &'static str,
+) -> Result<Response, StatusCode> {
+    let result = authenticate(req, next, required_auth, credential_store).await;
+    if result.as_ref().err() == Some(&StatusCode::UNAUTHORIZED) {
+        metrics.auth_failure(scope);
+    }
+    result
+}
+
+/// Require an Authorization header that matches `required_auth` (using Basic Auth) or credentials
+/// in `credential_store` (using Basic or Bearer Auth), or deny the request. If `required_auth` is
+/// empty and there are no credentials in the store, allow any request.
+async fn authenticate<B>(
+    mut req: Request<B>,
+    next: Next<B>,
+    required_auth: String,
+    credential_store: Option<Arc<dyn CredentialStore>>,
+) -> Result<Response, StatusCode> {
+    let has_credentials = match &credential_store {
+        Some(credential_store) => credential_store.has_credentials().await.map_err(|e| {
+            error!("Could not check for credentials: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?,
+        None => false,
+    };
+
+    if required_auth.is_empty() && !has_credentials {
+        req.extensions_mut().insert(Principal::Anonymous);
+        return Ok(next.run(req).await);
+    }
+
+    let Some(auth_header) = req.headers().get(http::header::AUTHORIZATION) else {
+        return Err(StatusCode::UNAUTHORIZED);
+    };
+
+    let Ok(auth_header) = auth_header.to_str() else {
+        error!("Bogus Authorization header {:?}, denying", auth_header);
+        return Err(StatusCode::UNAUTHORIZED);
+    };
+
+    let credential_store = credential_store.filter(|_| has_credentials);
+    let principal =
+        if let Some(token) = auth_header.strip_prefix("Bearer ") {
+            match credential_store {
+                Some(credential_store) => credential_store
+                    .authenticate_bearer(token)
+                    .await
+                    .map_err(|e| {
+                        error!("Could not authenticate bearer token: {:?}", e);
+                        StatusCode::INTERNAL_SERVER_ERROR
+                    })?,
+                None => None,
+            }
+        } else {
+            let authorization = auth_header
+                .strip_prefix("Basic ")
+                .and_then(|authorization_b64| BASE64_STANDARD.decode(authorization_b64).ok())
+                .and_then(|bytes| String::from_utf8(bytes).ok());
+
+            let Some(authorization) = authorization else {
+                error!("Bogus Authorization header {:?}, denying", auth_header);
+                return Err(StatusCode::UNAUTHORIZED);
+            };
+
+            let (user_name, password) = authorization
+                .split_once(':')
+                .unwrap_or((authorization.as_str(), ""));
+
+            if !required_auth.is_empty() && constant_time_eq(&authorization, &required_auth) {
+                Some(Principal::Shared(user_name.to_owned()))
+            } else if let Some(credential_store) = credential_store {
+                credential_store
+                    .authenticate_basic(user_name, password)
+                    .await
+                    .map_err(|e| {
+                        error!("Could not authenticate {}: {:?}", user_name, e);
+                        StatusCode::INTERNAL_SERVER_ERROR
+                    })?
+            } else {
+                None
+            }
+        };
+
+    match principal {
+        Some(principal) => {
+            req.extensions_mut().insert(principal);
+            Ok(next.run(req).await)
+        }
+        None => {
+            error!("Invalid token in Authorization header, denying");
+            Err(StatusCode::UNAUTHORIZED)
+        }
+    }
+}
+
+/// Just returns a 200.
+pub async fn health() {}
+
+/// The app serving the API under `config.request_root`, backed by `storage`
+pub fn app(config: Config, storage: Arc<dyn Storage>) -> Router {
+    let metrics = Arc::new(Metrics::default());
+    let storage: Arc<dyn Storage> = Arc::new(InstrumentedStorage::new(storage, metrics.clone()));
+
+    let user_store = Arc::new(UserStore::new(storage.clone()));
+    let ci_token_store: Arc<dyn CredentialStore> = Arc::new(CiTokenStore::new(storage.clone()));
+    let user_name_policy = Arc::new(UserNamePolicy::new(
+        config.enforce_user_names,
+        user_store.clone(),
+    ));
+
+    // Configure routes that require the `user_auth` token (these are expected to come from
+    // the UGS client).
+    let user_routes = Router::new()
+        .route("/latest", get(latest_index))
+        .route("/event", get(event_index).post(event_create))
+        .route("/comment", get(comment_index).post(comment_create))
+        .route("/issues", get(issue_index).post(issue_create))
+        .route("/issues/:id", get(issue_show).put(issue_update))
+        .route(
+            "/issues/:id/builds",
+            get(issue_build_index).post(issue_build_create),
+        )
+        .route(
+            "/issues/:id/diagnostics",
+            get(issue_diagnostic_index).post(issue_diagnostic_create),
+        )
+        .route(
+            "/issues/:id/watchers",
+            get(issue_watcher_index).post(issue_watcher_update),
+        )
+        .route("/metadata", get(metadata_index).post(metadata_submit))
+        .route("/stream", get(metadata_stream))
+        .route("/telemetry", post(telemetry_submit))
+        .route("/error", post(error_submit))
+        .layer(middleware::from_fn({
+            let metrics = metrics.clone();
+            move |req, next| {
+                auth(
+                    req,
+                    next,
+                    config.user_auth.clone(),
+                    Some(user_store.clone() as Arc<dyn CredentialStore>),
+                    metrics.clone(),
+                    "user",
+                )
+            }
+        }))
+        .route_layer(middleware::from_fn(track_requests));
+
+    // Configure routes that require the `ci_auth` token (these are expected to come from your
+    // CI service, e.g. PostBadgeStatus.exe)
+    let ci_routes = Router::new()
+        .route("/build", post(build_create))
+        // Back compat with old PostBadgeStatus.exe which uses the wrong case
+        .route("/Build", post(build_create))
+        .route("/rugs_metrics", get(metrics_index))
+        // Every UGS client can report telemetry, but only admins get to read it back
+        .route("/telemetry", get(telemetry_index))
+        .route("/error", get(error_index))
+        .route("/backup", post(backup_create))
+        .layer(middleware::from_fn({
+            let metrics = metrics.clone();
+            let ci_auth = config.ci_auth.clone();
+            let ci_token_store = ci_token_store.clone();
+            move |req, next| {
+                auth(
+                    req,
+                    next,
+                    ci_auth.clone(),
+                    Some(ci_token_store.clone()),
+                    metrics.clone(),
+                    "ci",
+                )
+            }
+        }))
+        .route_layer(middleware::from_fn(track_requests));
+
+    // Prometheus can scrape `/metrics` using the same credentials as CI
+    let metrics_routes = Router::new()
+        .route("/metrics", get(prometheus_metrics))
+        .layer(middleware::from_fn({
+            let metrics = metrics.clone();
+            move |req, next| {
+                auth(
+                    req,
+                    next,
+                    config.ci_auth.clone(),
+                    Some(ci_token_store.clone()),
+                    metrics.clone(),
+                    "ci",
+                )
+            }
+        }));
+
+    let app = Router::new().nest(
+        &config.request_root,
+        Router::new()
+            .nest("/api", Router::new().merge(user_routes).merge(ci_routes))
+            .merge(metrics_routes)
+            .route("/health", get(health)),
+    );
+
+    // We expose the basic `health` endpoint under both `/health` and `/<request_root>/health` if the
+    // root isn't already `/`
+    let app = if config.request_root != "/" {
+        app.route("/health", get(health))
+    } else {
+        app
+    };
+
+    let notifications = Arc::new(NotificationHub::default());
+    let notification_settings = config.notifications;
+    let project_paths = Arc::new(config.project_paths);
+
+    let service_builder = ServiceBuilder::new()
+        // Give every request an `X-Request-Id` (unless it came with one) before we make its span,
+        // and echo it back in the response
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+        .layer(TraceLayer::new_for_http().make_span_with(request_span))
+        .layer(PropagateRequestIdLayer::x_request_id())
+        // Whether we compress a response depends on `Accept-Encoding`, which caches need to know
+        .layer(SetResponseHeaderLayer::appending(
+            http::header::VARY,
+            http::HeaderValue::from_static("accept-encoding"),
+        ))
+        // Server-sent events have to be sent as they happen, so we can't compress those
+        .layer(CompressionLayer::new().compress_when(
+            DefaultPredicate::new().and(NotForContentType::const_new("text/event-stream")),
+        ))
+        .layer(Extension(storage))
+        .layer(Extension(metrics))
+        .layer(Extension(notifications))
+        .layer(Extension(notification_settings))
+        .layer(Extension(user_name_policy))
+        .layer(Extension(project_paths));
+
+    #[cfg(debug_assertions)]
+    let service_builder = service_builder.layer(middleware::from_fn(print_request_response));
+
+    app.layer(service_builder)
+}
@@ -0,0 +1,484 @@
+use anyhow::{anyhow, Result};
+use argon2::{
+    password_hash::{
+        rand_core::{OsRng, RngCore},
+        PasswordHash, PasswordHasher, PasswordVerifier, SaltString,
+    },
+    Argon2,
+};
+use async_trait::async_trait;
+use base64::prelude::*;
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+use sqlx::types::Json;
+use subtle::ConstantTimeEq;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, OnceLock, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::storage::Storage;
+
+/// How long we trust a successful password verification before we hash the password again.
+/// Verifying a password is intentionally slow, and UGS polls frequently, so this keeps the cost
+/// down. It also bounds how long a revoked user can keep using a running server.
+const VERIFIED_CREDENTIALS_TTL: Duration = Duration::from_secs(60);
+
+/// Who made a request, as determined by the `Authorization` header. This is inserted as a request
+/// extension for authenticated routes.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Principal {
+    /// Authentication isn't configured, so we don't know who this is
+    Anonymous,
+    /// The request used the shared credentials from the config, with the given user name
+    Shared(String),
+    /// The request used the credentials of a user from the user store
+    User(String),
+    /// The request used one of the scoped CI tokens
+    CiToken(CiToken),
+}
+
+impl Principal {
+    /// The name to record as having made this request, if any
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            Principal::Anonymous => None,
+            Principal::Shared(user_name) | Principal::User(user_name) => Some(user_name),
+            Principal::CiToken(token) => Some(&token.name),
+        }
+    }
+}
+
+/// Somewhere we can look up credentials from an `Authorization` header
+#[async_trait]
+pub trait CredentialStore: Send + Sync {
+    /// Whether any credentials have been created (including revoked ones), in which case requests
+    /// need to authenticate with one of them.
+    async fn has_credentials(&self) -> Result<bool>;
+
+    /// Find who the user name & password from a Basic Auth header belong to, if anyone
+    async fn authenticate_basic(
+        &self,
+        user_name: &str,
+        password: &str,
+    ) -> Result<Option<Principal>>;
+
+    /// Find who the token from a Bearer Auth header belongs to, if anyone
+    async fn authenticate_bearer(&self, _token: &str) -> Result<Option<Principal>> {
+        Ok(None)
+    }
+}
+
+/// Whether a store has any credentials, which we check on every request. Credentials are revoked
+/// rather than deleted, so once there are any we don't need to check again, and requests only load
+/// a flag. Until then, we trust the answer for `VERIFIED_CREDENTIALS_TTL` like a verified password,
+/// so credentials added by another process (e.g. the `users` subcommand) are picked up within that
+/// time.
+#[derive(Debug, Default)]
+struct HasCredentialsCache {
+    has_credentials: AtomicBool,
+    /// When we last found that there weren't any credentials
+    checked_at: RwLock<Option<Instant>>,
+}
+
+impl HasCredentialsCache {
+    async fn get_or_check<F>(&self, check: impl FnOnce() -> F) -> Result<bool>
+    where
+        F: Future<Output = Result<bool>>,
+    {
+        if self.has_credentials.load(Ordering::Acquire) {
+            return Ok(true);
+        }
+        if let Some(checked_at) = *self.checked_at.read().unwrap() {
+            if checked_at.elapsed() < VERIFIED_CREDENTIALS_TTL {
+                return Ok(false);
+            }
+        }
+
+        let has_credentials = check().await?;
+        if has_credentials {
+            self.has_credentials.store(true, Ordering::Release);
+        } else {
+            *self.checked_at.write().unwrap() = Some(Instant::now());
+        }
+        Ok(has_credentials)
+    }
+
+    /// Check again next time, since credentials were just added
+    fn forget(&self) {
+        *self.checked_at.write().unwrap() = None;
+    }
+}
+
+/// Compare two secrets without leaking how much of them matched through timing
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.as_bytes().ct_eq(b.as_bytes()).into()
+}
+
+/// Hash `password` with a random salt, returning it in PHC string format
+pub fn hash_password(password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| anyhow!("Could not hash password: {}", e))?;
+    Ok(hash.to_string())
+}
+
+/// A hash to verify passwords against when there's no such user, so that looking up a user that
+/// doesn't exist takes as long as one that does
+fn dummy_password_hash() -> Result<&'static str> {
+    static DUMMY_PASSWORD_HASH: OnceLock<String> = OnceLock::new();
+    if let Some(hash) = DUMMY_PASSWORD_HASH.get() {
+        return Ok(hash);
+    }
+
+    let hash = hash_password("not the password of any user")?;
+    Ok(DUMMY_PASSWORD_HASH.get_or_init(|| hash))
+}
+
+#[derive(Clone, Debug, sqlx::FromRow)]
+pub struct User {
+    pub user_name: String,
+    pub created_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+/// Named user accounts with salted password hashes, stored in the `users` table
+#[derive(Debug)]
+pub struct UserStore {
+    storage: Arc<dyn Storage>,
+    /// Maps a SHA-256 of `user:password` to the user name and when we verified it
+    verified: Mutex<HashMap<[u8; 32], (String, Instant)>>,
+    has_users: HasCredentialsCache,
+}
+
+impl UserStore {
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
+        Self {
+            storage,
+            verified: Mutex::new(HashMap::new()),
+            has_users: HasCredentialsCache::default(),
+        }
+    }
+
+    /// Whether any accounts have been created (including revoked ones)
+    pub async fn has_users(&self) -> Result<bool> {
+        self.has_users
+            .get_or_check(|| self.storage.has_users())
+            .await
+    }
+
+    /// Check that `user_name` exists, hasn't been revoked, and that `password` is theirs. Returns
+    /// the user name as it's stored if so.
+    pub async fn authenticate(&self, user_name: &str, password: &str) -> Result<Option<String>> {
+        let credentials_hash: [u8; 32] = Sha256::new()
+            .chain_update(user_name.to_lowercase())
+            .chain_update(":")
+            .chain_update(password)
+            .finalize()
+            .into();
+
+        {
+            let mut verified = self.verified.lock().await;
+            verified.retain(|_, (_, verified_at)| verified_at.elapsed() < VERIFIED_CREDENTIALS_TTL);
+            if let Some((user_name, _)) = verified.get(&credentials_hash) {
+                return Ok(Some(user_name.clone()));
+            }
+        }
+
+        let user = self.storage.get_password_hash(user_name).await?;
+
+        let Some((user_name, password_hash)) = user else {
+            // Still verify the password, so that you can't tell which users exist by timing this
+            let dummy_hash = PasswordHash::new(dummy_password_hash()?)
+                .map_err(|e| anyhow!("Invalid dummy password hash: {}", e))?;
+            let _ = Argon2::default().verify_password(password.as_bytes(), &dummy_hash);
+            return Ok(None);
+        };
+
+        let password_hash = PasswordHash::new(&password_hash)
+            .map_err(|e| anyhow!("Invalid password hash for user {}: {}", user_name, e))?;
+        if Argon2::default()
+            .verify_password(password.as_bytes(), &password_hash)
+            .is_err()
+        {
+            return Ok(None);
+        }
+
+        self.verified
+            .lock()
+            .await
+            .insert(credentials_hash, (user_name.clone(), Instant::now()));
+        Ok(Some(user_name))
+    }
+
+    /// Create a user with the given password, or reset the password (and revocation) of an
+    /// existing user.
+    pub async fn add_user(&self, user_name: &str, password: &str) -> Result<()> {
+        let password_hash = hash_password(password)?;
+        self.storage.add_user(user_name, &password_hash).await?;
+
+        self.has_users.forget();
+        self.forget_verified(user_name).await;
+        info!("Added user {}", user_name);
+        Ok(())
+    }
+
+    /// Revoke a user's access, returning false if there was no such (unrevoked) user
+    pub async fn revoke_user(&self, user_name: &str) -> Result<bool> {
+        let revoked = self.storage.revoke_user(user_name).await?;
+
+        self.forget_verified(user_name).await;
+        if revoked {
+            info!("Revoked user {}", user_name);
+        }
+        Ok(revoked)
+    }
+
+    pub async fn list_users(&self) -> Result<Vec<User>> {
+        self.storage.list_users().await
+    }
+
+    /// Allow `user_name` to submit data as `perforce_user_name`, for when their login name and
+    /// Perforce user name differ.
+    pub async fn add_alias(&self, user_name: &str, perforce_user_name: &str) -> Result<()> {
+        if !self
+            .storage
+            .add_alias(user_name, perforce_user_name)
+            .await?
+        {
+            return Err(anyhow!("No user named {}", user_name));
+        }
+        info!("Added alias {} for user {}", perforce_user_name, user_name);
+        Ok(())
+    }
+
+    /// Remove an alias added by `add_alias`, returning false if there was no such alias
+    pub async fn remove_alias(&self, user_name: &str, perforce_user_name: &str) -> Result<bool> {
+        self.storage
+            .remove_alias(user_name, perforce_user_name)
+            .await
+    }
+
+    /// List the Perforce user names that `user_name` can submit data as, besides their own
+    pub async fn list_aliases(&self, user_name: &str) -> Result<Vec<String>> {
+        self.storage.list_aliases(user_name).await
+    }
+
+    async fn is_alias(&self, user_name: &str, perforce_user_name: &str) -> Result<bool> {
+        self.storage.is_alias(user_name, perforce_user_name).await
+    }
+
+    async fn forget_verified(&self, user_name: &str) {
+        self.verified
+            .lock()
+            .await
+            .retain(|_, (verified_user_name, _)| {
+                !verified_user_name.eq_ignore_ascii_case(user_name)
+            });
+    }
+}
+
+#[async_trait]
+impl CredentialStore for UserStore {
+    async fn has_credentials(&self) -> Result<bool> {
+        self.has_users().await
+    }
+
+    async fn authenticate_basic(
+        &self,
+        user_name: &str,
+        password: &str,
+    ) -> Result<Option<Principal>> {
+        Ok(self
+            .authenticate(user_name, password)
+            .await?
+            .map(Principal::User))
+    }
+}
+
+fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// A named credential for CI, that can only post badges for some projects (and optionally only
+/// some build types)
+#[derive(Clone, Debug, PartialEq, sqlx::FromRow)]
+pub struct CiToken {
+    pub name: String,
+    /// Globs matched against the `//depot/stream/project` path of a badge, e.g. `//game/*/*`
+    pub project_patterns: Json<Vec<String>>,
+    /// The build types this token can post, or any if `None`
+    pub build_types: Option<Json<Vec<String>>>,
+    pub created_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+impl CiToken {
+    /// Whether this token is allowed to post a badge of `build_type` to `project_path`
+    pub fn allows(&self, project_path: &str, build_type: &str) -> bool {
+        let match_options = glob::MatchOptions {
+            case_sensitive: false,
+            require_literal_separator: true,
+            require_literal_leading_dot: false,
+        };
+
+        let project_allowed = self.project_patterns.iter().any(|pattern| {
+            glob::Pattern::new(pattern)
+                .map(|pattern| pattern.matches_with(project_path, match_options))
+                .unwrap_or_default()
+        });
+        let build_type_allowed = self.build_types.as_ref().is_none_or(|build_types| {
+            build_types
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(build_type))
+        });
+
+        project_allowed && build_type_allowed
+    }
+}
+
+/// Scoped CI tokens, stored in the `ci_tokens` table. Tokens are long & random, so we only store
+/// a SHA-256 of them.
+#[derive(Debug)]
+pub struct CiTokenStore {
+    storage: Arc<dyn Storage>,
+    has_tokens: HasCredentialsCache,
+}
+
+impl CiTokenStore {
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
+        Self {
+            storage,
+            has_tokens: HasCredentialsCache::default(),
+        }
+    }
+
+    /// Create a new token with the given scopes, returning the secret token. The token can not be
+    /// retrieved later.
+    pub async fn add_token(
+        &self,
+        name: &str,
+        project_patterns: Vec<String>,
+        build_types: Option<Vec<String>>,
+    ) -> Result<String> {
+        for pattern in &project_patterns {
+            glob::Pattern::new(pattern)
+                .map_err(|e| anyhow!("Invalid project pattern {}: {}", pattern, e))?;
+        }
+
+        let mut token_bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut token_bytes);
+        let token = BASE64_URL_SAFE_NO_PAD.encode(token_bytes);
+
+        let ci_token = CiToken {
+            name: name.to_owned(),
+            project_patterns: Json(project_patterns),
+            build_types: build_types.map(Json),
+            created_at: Utc::now(),
+            revoked_at: None,
+        };
+        self.storage
+            .add_ci_token(&ci_token, &hash_token(&token))
+            .await?;
+        self.has_tokens.forget();
+
+        info!("Added CI token {}", name);
+        Ok(token)
+    }
+
+    /// Revoke a token, returning false if there was no such (unrevoked) token
+    pub async fn revoke_token(&self, name: &str) -> Result<bool> {
+        let revoked = self.storage.revoke_ci_token(name).await?;
+
+        if revoked {
+            info!("Revoked CI token {}", name);
+        }
+        Ok(revoked)
+    }
+
+    pub async fn list_tokens(&self) -> Result<Vec<CiToken>> {
+        self.storage.list_ci_tokens().await
+    }
+
+    async fn find_token(&self, name: Option<&str>, token: &str) -> Result<Option<CiToken>> {
+        self.storage.find_ci_token(name, &hash_token(token)).await
+    }
+}
+
+#[async_trait]
+impl CredentialStore for CiTokenStore {
+    async fn has_credentials(&self) -> Result<bool> {
+        self.has_tokens
+            .get_or_check(|| self.storage.has_ci_tokens())
+            .await
+    }
+
+    async fn authenticate_basic(
+        &self,
+        user_name: &str,
+        password: &str,
+    ) -> Result<Option<Principal>> {
+        Ok(self
+            .find_token(Some(user_name), password)
+            .await?
+            .map(Principal::CiToken))
+    }
+
+    async fn authenticate_bearer(&self, token: &str) -> Result<Option<Principal>> {
+        Ok(self.find_token(None, token).await?.map(Principal::CiToken))
+    }
+}
+
+/// Decides whether a request may submit data (votes, comments, etc) on behalf of a given UGS
+/// user name. This is available to handlers as an extension.
+#[derive(Clone, Debug)]
+pub struct UserNamePolicy {
+    /// When false, any authenticated request can submit data as any user name
+    enforce: bool,
+    user_store: Arc<UserStore>,
+}
+
+impl UserNamePolicy {
+    pub fn new(enforce: bool, user_store: Arc<UserStore>) -> Self {
+        Self {
+            enforce,
+            user_store,
+        }
+    }
+
+    /// Check whether `principal` is allowed to submit data as `user_name`. When enforcing, only
+    /// named users can submit data, and only as themselves or one of their aliases.
+    pub async fn allows(&self, principal: &Principal, user_name: &str) -> Result<bool> {
+        if !self.enforce {
+            return Ok(true);
+        }
+
+        let allowed = match principal {
+            Principal::User(login_name) => {
+                login_name.eq_ignore_ascii_case(user_name)
+                    || self.user_store.is_alias(login_name, user_name).await?
+            }
+            Principal::Anonymous | Principal::Shared(_) | Principal::CiToken(_) => false,
+        };
+
+        if !allowed {
+            warn!(
+                "Denying request from {:?} to submit data as user {}",
+                principal, user_name
+            );
+        }
+        Ok(allowed)
+    }
+}
@@ -0,0 +1,170 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use sqlx::{sqlite::SqliteConnectOptions, Connection, SqliteConnection};
+use tracing::info;
+
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::storage::Storage;
+
+/// Scheduled backups are named `rugs-backup-<timestamp>.db`, so that they sort by age
+const BACKUP_PREFIX: &str = "rugs-backup-";
+const BACKUP_EXTENSION: &str = ".db";
+
+/// How often to back the database up, and where to
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BackupSettings {
+    /// The directory we write backups to
+    pub directory: PathBuf,
+    /// How often we write a backup
+    pub interval: Duration,
+    /// How many backups we keep, we delete the oldest ones beyond this
+    pub keep: usize,
+}
+
+/// Write a snapshot of the database to `settings.directory`, named after `now`, and delete the
+/// oldest backups there beyond `settings.keep`. Returns the path of the new backup.
+pub async fn scheduled_backup(
+    storage: &dyn Storage,
+    settings: &BackupSettings,
+    now: DateTime<Utc>,
+) -> Result<PathBuf> {
+    tokio::fs::create_dir_all(&settings.directory)
+        .await
+        .with_context(|| {
+            format!(
+                "Could not create backup directory {}",
+                settings.directory.display()
+            )
+        })?;
+
+    let path = settings.directory.join(format!(
+        "{BACKUP_PREFIX}{}{BACKUP_EXTENSION}",
+        now.format("%Y%m%dT%H%M%SZ")
+    ));
+    storage.backup(&path).await?;
+
+    for old_backup in list_backups(&settings.directory)
+        .await?
+        .into_iter()
+        .rev()
+        .skip(settings.keep)
+    {
+        info!("deleting old backup {}", old_backup.display());
+        tokio::fs::remove_file(&old_backup)
+            .await
+            .with_context(|| format!("Could not delete old backup {}", old_backup.display()))?;
+    }
+
+    Ok(path)
+}
+
+/// The scheduled backups in `directory`, from oldest to newest
+pub async fn list_backups(directory: &Path) -> Result<Vec<PathBuf>> {
+    let mut backups = Vec::new();
+    let mut entries = tokio::fs::read_dir(directory)
+        .await
+        .with_context(|| format!("Could not list backups in {}", directory.display()))?;
+    while let Some(entry) = entries.next_entry().await? {
+        let file_name = entry.file_name();
+        let Some(file_name) = file_name.to_str() else {
+            continue;
+        };
+        if file_name.starts_with(BACKUP_PREFIX) && file_name.ends_with(BACKUP_EXTENSION) {
+            backups.push(entry.path());
+        }
+    }
+    backups.sort();
+
+    Ok(backups)
+}
+
+/// Check that the sqlite database at `path` isn't corrupt, and that it's a RUGS database
+pub async fn check_integrity(path: &Path) -> Result<()> {
+    let options = SqliteConnectOptions::new().filename(path).read_only(true);
+    let mut connection = SqliteConnection::connect_with(&options)
+        .await
+        .with_context(|| format!("Could not open {}", path.display()))?;
+
+    let problems = sqlx::query_scalar::<_, String>("PRAGMA integrity_check")
+        .fetch_all(&mut connection)
+        .await
+        .with_context(|| format!("Could not check the integrity of {}", path.display()))?;
+    if problems != ["ok"] {
+        anyhow::bail!("{} is corrupt:\n{}", path.display(), problems.join("\n"));
+    }
+
+    sqlx::query("SELECT version FROM _sqlx_migrations LIMIT 1")
+        .fetch_optional(&mut connection)
+        .await
+        .with_context(|| format!("{} isn't a RUGS database", path.display()))?;
+
+    connection.close().await?;
+    Ok(())
+}
+
+/// `path` with `suffix` appended to its file name
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// Replace the sqlite database at `database` with the backup at `backup`, after checking that the
+/// backup is intact. The database being replaced is kept as `<database>.before-restore`. RUGS
+/// can't be running while we do this, since it wouldn't notice the database being replaced.
+pub async fn restore(database: &Path, backup: &Path) -> Result<()> {
+    // Copy the backup next to the database first, so swapping it in is a rename, and check the
+    // copy so we know that what we swap in is intact
+    let restoring = with_suffix(database, ".restoring");
+    tokio::fs::copy(backup, &restoring).await.with_context(|| {
+        format!(
+            "Could not copy {} to {}",
+            backup.display(),
+            restoring.display()
+        )
+    })?;
+    tokio::fs::OpenOptions::new()
+        .write(true)
+        .open(&restoring)
+        .await?
+        .sync_all()
+        .await?;
+    if let Err(e) = check_integrity(&restoring).await {
+        tokio::fs::remove_file(&restoring).await.ok();
+        return Err(e.context(format!("Not restoring {}", backup.display())));
+    }
+
+    if tokio::fs::try_exists(database).await? {
+        // Keep the write-ahead log with the database it belongs to
+        let before_restore = with_suffix(database, ".before-restore");
+        for suffix in ["", "-wal", "-shm"] {
+            let from = with_suffix(database, suffix);
+            if tokio::fs::try_exists(&from).await? {
+                let to = with_suffix(&before_restore, suffix);
+                tokio::fs::rename(&from, &to).await.with_context(|| {
+                    format!("Could not move {} to {}", from.display(), to.display())
+                })?;
+            }
+        }
+        info!(
+            "moved the previous database to {}",
+            before_restore.display()
+        );
+    }
+
+    tokio::fs::rename(&restoring, database)
+        .await
+        .with_context(|| {
+            format!(
+                "Could not move {} to {}",
+                restoring.display(),
+                database.display()
+            )
+        })?;
+
+    Ok(())
+}
@@ -1,115 +1,625 @@
 use anyhow::{Context, Result};
-use axum::{
-    http::{self, Request, StatusCode},
-    middleware::{self, Next},
-    response::IntoResponse,
-    routing::{get, post},
-    Extension, Router,
+use clap::{Parser, Subcommand};
+use futures::{future::BoxFuture, pin_mut, FutureExt, TryFutureExt};
+use opentelemetry_sdk::trace::TracerProvider;
+use tracing::{error, info, warn, Subscriber};
+use tracing_subscriber::{
+    filter::LevelFilter, fmt::MakeWriter, layer::SubscriberExt, registry::LookupSpan,
+    util::SubscriberInitExt, EnvFilter, Layer,
 };
-use base64::prelude::*;
-use clap::Parser;
-use futures::pin_mut;
-use sqlx::SqlitePool;
-use tokio::sync::RwLock;
-use tower::ServiceBuilder;
-use tower_http::trace::TraceLayer;
-use tracing::{error, info};
-
-use std::{net::SocketAddr, sync::Arc};
-
-use rugs::handlers::*;
-#[cfg(debug_assertions)]
-use rugs::middleware::print_request_response;
-
-// Run `PRAGMA optimize` every 12 hours (https://www.sqlite.org/pragma.html#pragma_optimize)
+
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use rugs::{
+    app::app,
+    auth::{CiTokenStore, UserStore},
+    backup::{check_integrity, restore, scheduled_backup},
+    config::{Config, LogFormat},
+    export::{export, import, ImportSummary},
+    metadata_server::import_metadata_server,
+    otel::otlp_layer,
+    paths::{resplit_if_changed, resplit_projects, ProjectPaths, ProjectResplit},
+    retention::prune_metadata,
+    storage::{BadgeFilter, MigrationStatus, Storage},
+    tls::{redirect_app, CertificateWatcher},
+};
+
+// Run the database's regular maintenance (e.g. `PRAGMA optimize` for sqlite) every 12 hours
 const OPTIMIZE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60 * 12);
+// Prune telemetry that's older than the configured retention every hour
+const TELEMETRY_PRUNE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+// Prune badges & user events that the configured retention doesn't keep every hour
+const METADATA_PRUNE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+// Check whether the TLS certificate has changed on disk every minute
+const CERTIFICATE_RELOAD_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
 
 /// A simple authenticated metadata server for UGS
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
+    /// Path to a TOML config file, whose settings take precedence over environment variables
+    #[clap(long, global = true)]
+    config: Option<PathBuf>,
+
     /// Path to the sqlite database to use for persistence (you can
-    /// use `:memory:` to not persist))
-    #[clap(long, default_value = "metadata.db")]
-    database: String,
+    /// use `:memory:` to not persist)), or a `postgres://` URL to use
+    /// a PostgreSQL database instead. This takes precedence over the
+    /// config file, and defaults to `metadata.db`
+    #[clap(long)]
+    database: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Manage the user accounts that UGS can authenticate as
+    Users {
+        #[command(subcommand)]
+        command: UsersCommand,
+    },
+    /// Manage the scoped tokens that CI can use to post badges
+    CiTokens {
+        #[command(subcommand)]
+        command: CiTokensCommand,
+    },
+    /// Inspect the configuration
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+    /// Inspect or apply the database migrations built into this binary
+    Migrate {
+        #[command(subcommand)]
+        command: MigrateCommand,
+    },
+    /// Write a consistent snapshot of the (sqlite) database to a file, this is safe to do while the
+    /// server is running
+    Backup { path: PathBuf },
+    /// Replace the (sqlite) database with a backup, after checking that the backup is intact. Stop
+    /// the server before doing this.
+    Restore { backup: PathBuf },
+    /// Write all projects, badges & user events to a JSON Lines file, e.g. to move them to another
+    /// server or database backend
+    Export { path: PathBuf },
+    /// Load projects, badges & user events from a file written by `export`. Importing the same
+    /// file twice doesn't change anything.
+    Import { path: PathBuf },
+    /// Load the badges, votes & comments from a dump of Epic's MetadataServer database: a
+    /// directory with a CSV or JSON file per table (e.g. `Projects.csv`, `Badges.csv`,
+    /// `UserVotes.csv` & `Comments.csv`). Importing the same dump twice doesn't change anything.
+    ImportMetadataServer { directory: PathBuf },
+    /// Fix up projects, badges & user events (this is safe to run while the server is running)
+    Admin {
+        /// Print what would change, without changing anything
+        #[clap(long, global = true)]
+        dry_run: bool,
+        #[command(subcommand)]
+        command: AdminCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum UsersCommand {
+    /// Add a user, or reset the password of an existing (or revoked) user
+    Add {
+        user_name: String,
+        /// The password for the user (read from stdin if not specified)
+        #[clap(long)]
+        password: Option<String>,
+    },
+    /// Revoke a user's access
+    Revoke { user_name: String },
+    /// List all users, including revoked ones
+    List,
+    /// Allow a user to submit data as a different Perforce user name
+    Alias {
+        user_name: String,
+        perforce_user_name: String,
+    },
+    /// Remove an alias added with `users alias`
+    Unalias {
+        user_name: String,
+        perforce_user_name: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum CiTokensCommand {
+    /// Create a new token, and print it (it can't be retrieved later)
+    Add {
+        name: String,
+        /// A glob for the `//depot/stream/project` paths this token can post badges to, e.g.
+        /// `//game/*/*` (can be specified multiple times)
+        #[clap(long = "project", required = true)]
+        projects: Vec<String>,
+        /// A build type this token can post badges for (can be specified multiple times, defaults
+        /// to any build type)
+        #[clap(long = "build-type")]
+        build_types: Vec<String>,
+    },
+    /// Revoke a token
+    Revoke { name: String },
+    /// List all tokens, including revoked ones
+    List,
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigCommand {
+    /// Check the configuration, and print the settings we'd use (without secrets)
+    Check,
 }
 
-/// Configuration for the app
-#[derive(Clone, Debug)]
-struct Config {
-    /// The auth token required for user-facing operations (reading badges, leaving comments & feedback)
-    pub user_auth: String,
-    /// The auth token required for CI-facing operations (writing badges)
-    pub ci_auth: String,
-    /// The port we listen to for incoming HTTP connections
-    pub http_port: u16,
-    /// The prefix we expect for any request (e.g. "/ugs" means we look for "/ugs/api/build")
-    pub request_root: String,
+#[derive(Subcommand, Debug)]
+enum AdminCommand {
+    /// Inspect, rename or merge projects
+    Projects {
+        #[command(subcommand)]
+        command: ProjectsCommand,
+    },
+    /// Delete badges
+    Badges {
+        #[command(subcommand)]
+        command: BadgesCommand,
+    },
+    /// Delete user events (votes, comments, etc.)
+    UserEvents {
+        #[command(subcommand)]
+        command: UserEventsCommand,
+    },
 }
 
-impl Config {
-    /// Construct a config from environment variables or sensible defaults
-    fn from_env() -> Self {
-        let user_auth = std::env::var("RUGS_USER_AUTH_FILE")
-            .ok()
-            .and_then(|path| std::fs::read_to_string(path).ok())
-            .map(|value| value.trim_end().to_string())
-            .or_else(|| std::env::var("RUGS_USER_AUTH").ok());
-
-        let ci_auth = std::env::var("RUGS_CI_AUTH_FILE")
-            .ok()
-            .and_then(|path| std::fs::read_to_string(path).ok())
-            .map(|value| value.trim_end().to_string())
-            .or_else(|| std::env::var("RUGS_CI_AUTH").ok());
-
-        let http_port = std::env::var("RUGS_PORT")
-            .ok()
-            .and_then(|port| port.parse::<u16>().ok());
-        let request_root = std::env::var("RUGS_WEB_ROOT").ok();
-
-        Self {
-            user_auth: user_auth.unwrap_or_default(),
-            ci_auth: ci_auth.unwrap_or_default(),
-            http_port: http_port.unwrap_or(3000),
-            request_root: request_root.unwrap_or_else(|| String::from("/")),
+#[derive(Subcommand, Debug)]
+enum ProjectsCommand {
+    /// List all projects, and how many badges & user events they have
+    List,
+    /// Rename a project, e.g. `//depot/main/Game` to `//depot/main/NewGame`
+    Rename { from: String, to: String },
+    /// Move all the badges & user events of a project into another one, and delete it
+    Merge { from: String, into: String },
+    /// Split the existing projects' paths again with the current `[paths]` settings, renaming or
+    /// merging the ones that split differently now (the server and `migrate run`
+    /// do this when the settings change)
+    Resplit,
+}
+
+#[derive(Subcommand, Debug)]
+enum BadgesCommand {
+    /// Delete the badges of a `//depot/stream/project`, optionally only for one change and/or
+    /// build type
+    Delete {
+        project: String,
+        #[clap(long)]
+        change: Option<i64>,
+        #[clap(long = "build-type")]
+        build_type: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum UserEventsCommand {
+    /// Delete all of a user's events, optionally only in one `//depot/stream/project`
+    Clear {
+        user_name: String,
+        #[clap(long)]
+        project: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum MigrateCommand {
+    /// List the migrations, and whether each has been applied to the database
+    Status,
+    /// Apply any pending migrations
+    Run,
+}
+
+/// Run one of the `migrate` subcommands against the database, re-splitting its projects with
+/// `paths` after migrating it if they were split with different settings
+async fn run_migrate_command(
+    storage: &dyn Storage,
+    paths: &ProjectPaths,
+    command: MigrateCommand,
+) -> Result<()> {
+    let status = storage.migration_status().await?;
+    match command {
+        MigrateCommand::Status => {
+            for migration in &status.applied {
+                println!("{} applied  {}", migration.version, migration.description);
+            }
+            for migration in &status.pending {
+                println!("{} pending  {}", migration.version, migration.description);
+            }
+            for version in &status.unknown {
+                println!("{version} unknown  (applied by a newer version of RUGS)");
+            }
+        }
+        MigrateCommand::Run => {
+            ensure_not_newer(&status)?;
+            if status.pending.is_empty() {
+                println!("The database is already up to date");
+            } else {
+                storage.migrate().await?;
+                for migration in &status.pending {
+                    println!("Applied {} {}", migration.version, migration.description);
+                }
+            }
+            let resplits = resplit_if_changed(storage, paths).await?;
+            print_resplits(&resplits);
         }
     }
+    Ok(())
+}
+
+/// Fail if a newer version of RUGS has migrated the database, since its schema may have changed in
+/// ways we don't expect
+fn ensure_not_newer(status: &MigrationStatus) -> Result<()> {
+    if !status.unknown.is_empty() {
+        let versions = status
+            .unknown
+            .iter()
+            .map(i64::to_string)
+            .collect::<Vec<_>>();
+        anyhow::bail!(
+            "The database has migrations that this version of RUGS doesn't know about ({}), so it was migrated by a newer version. Please upgrade RUGS.",
+            versions.join(", ")
+        );
+    }
+    Ok(())
 }
 
-/// Require a Basic Auth header that matches `required_auth`, or deny the request. If `required_auth`
-/// is empty, allow any request.
-async fn auth<B>(req: Request<B>, next: Next<B>, required_auth: String) -> impl IntoResponse {
-    if required_auth.is_empty() {
-        Ok(next.run(req).await)
-    } else if let Some(auth_header) = req.headers().get(http::header::AUTHORIZATION) {
-        let authorization = auth_header
-            .to_str()
-            .ok()
-            .and_then(|header| header.strip_prefix("Basic "))
-            .and_then(|authorization_b64| BASE64_STANDARD.decode(authorization_b64).ok())
-            .and_then(|bytes| String::from_utf8(bytes).ok());
-
-        match authorization {
-            Some(authorization) => {
-                if authorization == required_auth {
-                    Ok(next.run(req).await)
+/// Make sure the database's schema matches this binary before we use it, applying any pending
+/// migrations if `migrate` is set
+async fn prepare_database(storage: &dyn Storage, migrate: bool) -> Result<()> {
+    let status = storage.migration_status().await?;
+    ensure_not_newer(&status)?;
+    if status.pending.is_empty() {
+        return Ok(());
+    }
+    if !migrate {
+        anyhow::bail!(
+            "The database has {} pending migrations, apply them with `migrate run` or set RUGS_MIGRATE_ON_STARTUP (`database.migrate_on_startup` in the config file)",
+            status.pending.len()
+        );
+    }
+    for migration in &status.pending {
+        info!(
+            "applying migration {} {}",
+            migration.version, migration.description
+        );
+    }
+    storage.migrate().await
+}
+
+/// Export the database's projects, badges & user events to a new file at `path`
+async fn run_export(storage: &dyn Storage, path: &Path) -> Result<()> {
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .with_context(|| format!("Could not create {}", path.display()))?;
+    let mut writer = std::io::BufWriter::new(file);
+    let summary = export(storage, &mut writer, chrono::Utc::now()).await?;
+    println!(
+        "Exported {} projects, {} badges and {} user events to {}",
+        summary.projects,
+        summary.badges,
+        summary.user_events,
+        path.display()
+    );
+    Ok(())
+}
+
+/// Import the projects, badges & user events in the export at `path`, reporting any user events
+/// that conflict with existing ones
+async fn run_import(storage: &dyn Storage, path: &Path) -> Result<()> {
+    let file =
+        std::fs::File::open(path).with_context(|| format!("Could not open {}", path.display()))?;
+    let summary = import(storage, std::io::BufReader::new(file))
+        .await
+        .with_context(|| format!("Could not import {}", path.display()))?;
+    print_import_summary(&summary);
+    Ok(())
+}
+
+/// Print which projects were renamed or merged by re-splitting them
+fn print_resplits(resplits: &[ProjectResplit]) {
+    for resplit in resplits {
+        let (from, to) = (&resplit.from, &resplit.to);
+        let action = if resplit.merged { "Merged" } else { "Renamed" };
+        println!(
+            "{action} {}/{} {} stream {} and project {}",
+            from.0,
+            from.1,
+            if resplit.merged { "into" } else { "to" },
+            to.0,
+            to.1
+        );
+    }
+}
+
+/// Print what an import did, and what it couldn't
+fn print_import_summary(summary: &ImportSummary) {
+    for skipped in &summary.skipped {
+        println!("Skipped: {skipped}");
+    }
+    for conflict in &summary.conflicts {
+        println!("Conflict: {conflict}");
+    }
+    println!(
+        "Added {} projects, {} badges ({} already present) and {} user events ({} already present, {} conflicting)",
+        summary.projects_added,
+        summary.badges_added,
+        summary.badges_unchanged,
+        summary.user_events_added,
+        summary.user_events_unchanged,
+        summary.conflicts.len()
+    );
+}
+
+/// Look up the ID of the project at the `//depot/stream/project` path `project_path`
+async fn find_project(
+    storage: &dyn Storage,
+    paths: &ProjectPaths,
+    project_path: &str,
+) -> Result<i64> {
+    let (stream, project) = paths
+        .split(project_path)
+        .with_context(|| format!("{project_path} is not a //depot/stream/project path"))?;
+    storage
+        .get_project(&stream, &project)
+        .await?
+        .with_context(|| format!("No project named {project_path}"))
+}
+
+/// Run one of the `admin` subcommands against the database, without changing anything if
+/// `dry_run` is set
+async fn run_admin_command(
+    storage: &dyn Storage,
+    paths: &ProjectPaths,
+    dry_run: bool,
+    command: AdminCommand,
+) -> Result<()> {
+    match command {
+        AdminCommand::Projects {
+            command: ProjectsCommand::List,
+        } => {
+            for project in storage.list_projects().await? {
+                println!(
+                    "{}/{}: {} badges, {} user events",
+                    project.stream, project.project, project.badges, project.user_events
+                );
+            }
+            return Ok(());
+        }
+        AdminCommand::Projects {
+            command: ProjectsCommand::Rename { from, to },
+        } => {
+            let project_id = find_project(storage, paths, &from).await?;
+            let (stream, project) = paths
+                .split(&to)
+                .with_context(|| format!("{to} is not a //depot/stream/project path"))?;
+            if let Some(existing_id) = storage.get_project(&stream, &project).await? {
+                if existing_id == project_id {
+                    anyhow::bail!("{from} is already named {to}");
+                }
+                anyhow::bail!("There's already a project named {to}, use `admin projects merge` to merge {from} into it");
+            }
+            storage
+                .rename_project(project_id, &stream, &project, dry_run)
+                .await?;
+            println!("Renamed {from} to {stream}/{project}");
+        }
+        AdminCommand::Projects {
+            command: ProjectsCommand::Merge { from, into },
+        } => {
+            let from_id = find_project(storage, paths, &from).await?;
+            let into_id = find_project(storage, paths, &into).await?;
+            if from_id == into_id {
+                anyhow::bail!("{from} and {into} are the same project");
+            }
+            let merge = storage.merge_projects(from_id, into_id, dry_run).await?;
+            println!(
+                "Moved {} badges and {} user events from {from} into {into}, and dropped {} older user events for the same changes",
+                merge.badges, merge.user_events, merge.dropped_user_events
+            );
+        }
+        AdminCommand::Projects {
+            command: ProjectsCommand::Resplit,
+        } => {
+            print_resplits(&resplit_projects(storage, paths, dry_run).await?);
+        }
+        AdminCommand::Badges {
+            command:
+                BadgesCommand::Delete {
+                    project,
+                    change,
+                    build_type,
+                },
+        } => {
+            let filter = BadgeFilter {
+                project_id: find_project(storage, paths, &project).await?,
+                change_number: change,
+                build_type,
+            };
+            let deleted = storage.delete_badges(&filter, dry_run).await?;
+            println!("Deleted {deleted} badges from {project}");
+        }
+        AdminCommand::UserEvents {
+            command: UserEventsCommand::Clear { user_name, project },
+        } => {
+            let project_id = match &project {
+                Some(project) => Some(find_project(storage, paths, project).await?),
+                None => None,
+            };
+            let deleted = storage
+                .delete_user_events(&user_name, project_id, dry_run)
+                .await?;
+            println!("Deleted {deleted} user events by {user_name}");
+        }
+    }
+
+    if dry_run {
+        println!("This was a dry run, so nothing was changed");
+    }
+    Ok(())
+}
+
+/// Run one of the `ci-tokens` subcommands against the database
+async fn run_ci_tokens_command(storage: Arc<dyn Storage>, command: CiTokensCommand) -> Result<()> {
+    let ci_token_store = CiTokenStore::new(storage);
+    match command {
+        CiTokensCommand::Add {
+            name,
+            projects,
+            build_types,
+        } => {
+            let build_types = (!build_types.is_empty()).then_some(build_types);
+            let token = ci_token_store
+                .add_token(&name, projects, build_types)
+                .await?;
+            println!("Created CI token {name}, use it as `Authorization: Bearer {token}` or as the Basic Auth credentials `{name}:{token}`");
+        }
+        CiTokensCommand::Revoke { name } => {
+            if !ci_token_store.revoke_token(&name).await? {
+                anyhow::bail!("No active CI token named {}", name);
+            }
+        }
+        CiTokensCommand::List => {
+            for token in ci_token_store.list_tokens().await? {
+                let build_types = token
+                    .build_types
+                    .map(|build_types| build_types.join(", "))
+                    .unwrap_or_else(|| String::from("any"));
+                let revoked = token
+                    .revoked_at
+                    .map(|revoked_at| format!(" (revoked {revoked_at})"))
+                    .unwrap_or_default();
+                println!(
+                    "{}: projects {}, build types {}{}",
+                    token.name,
+                    token.project_patterns.join(", "),
+                    build_types,
+                    revoked
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Run one of the `users` subcommands against the database
+async fn run_users_command(storage: Arc<dyn Storage>, command: UsersCommand) -> Result<()> {
+    let user_store = UserStore::new(storage);
+    match command {
+        UsersCommand::Add {
+            user_name,
+            password,
+        } => {
+            let password = match password {
+                Some(password) => password,
+                None => {
+                    let mut password = String::new();
+                    std::io::stdin()
+                        .read_line(&mut password)
+                        .context("Could not read password from stdin")?;
+                    password.trim_end_matches(['\r', '\n']).to_owned()
+                }
+            };
+            if password.is_empty() {
+                anyhow::bail!("Refusing to add user {} with an empty password", user_name);
+            }
+            user_store.add_user(&user_name, &password).await?;
+        }
+        UsersCommand::Revoke { user_name } => {
+            if !user_store.revoke_user(&user_name).await? {
+                anyhow::bail!("No active user named {}", user_name);
+            }
+        }
+        UsersCommand::List => {
+            for user in user_store.list_users().await? {
+                let aliases = user_store.list_aliases(&user.user_name).await?;
+                let aliases = if aliases.is_empty() {
+                    String::new()
                 } else {
-                    error!("Invalid token in Authorization header, denying");
-                    Err(StatusCode::UNAUTHORIZED)
+                    format!(" (aliases: {})", aliases.join(", "))
+                };
+                match user.revoked_at {
+                    Some(revoked_at) => {
+                        println!("{}{} (revoked {})", user.user_name, aliases, revoked_at)
+                    }
+                    None => println!("{}{}", user.user_name, aliases),
                 }
             }
-            None => {
-                error!("Bogus Authorization header {:?}, denying", auth_header);
-                Err(StatusCode::UNAUTHORIZED)
+        }
+        UsersCommand::Alias {
+            user_name,
+            perforce_user_name,
+        } => {
+            user_store
+                .add_alias(&user_name, &perforce_user_name)
+                .await?;
+        }
+        UsersCommand::Unalias {
+            user_name,
+            perforce_user_name,
+        } => {
+            if !user_store
+                .remove_alias(&user_name, &perforce_user_name)
+                .await?
+            {
+                anyhow::bail!("{} is not an alias of {}", perforce_user_name, user_name);
             }
         }
-    } else {
-        Err(StatusCode::UNAUTHORIZED)
+    }
+    Ok(())
+}
+
+/// A layer that prints our logs to `writer` in `format`
+fn log_layer<S, W>(format: LogFormat, writer: W) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+    W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer().with_writer(writer);
+    match format {
+        LogFormat::Text => layer.boxed(),
+        LogFormat::Json => layer
+            .json()
+            .with_current_span(false)
+            .with_span_list(true)
+            .boxed(),
     }
 }
 
-/// Just returns a 200.
-pub async fn health() {}
+/// Log to stdout (filtered by `RUST_LOG`), and if `otlp_endpoint` is set, export spans to it. This
+/// returns the provider that needs to be shut down to flush the spans when we exit.
+fn init_tracing(
+    log_format: LogFormat,
+    otlp_endpoint: Option<&str>,
+) -> Result<Option<TracerProvider>> {
+    let (otlp_layer, tracer_provider) = otlp_endpoint.map(otlp_layer).transpose()?.unzip();
+
+    tracing_subscriber::registry()
+        .with(log_layer(log_format, std::io::stdout).with_filter(EnvFilter::from_default_env()))
+        .with(otlp_layer.with_filter(LevelFilter::INFO))
+        .init();
+
+    Ok(tracer_provider)
+}
+
+/// Delete any telemetry or error reports that we received more than `retention_days` ago, and
+/// return how many we deleted.
+async fn prune_telemetry(storage: &dyn Storage, retention_days: u32) -> Result<u64> {
+    let cutoff = chrono::Utc::now() - chrono::Duration::days(retention_days.into());
+
+    storage.prune_telemetry(cutoff).await
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -121,100 +631,212 @@ async fn main() -> Result<()> {
         }
     });
 
-    tracing_subscriber::fmt::init();
-
-    let config = Config::from_env();
     let args = Args::parse();
+    let mut config = Config::load(args.config.as_deref())?;
+    if let Some(database) = args.database {
+        config.database = database;
+    }
 
-    let pool = SqlitePool::connect(&format!("sqlite:{}", args.database))
-        .await
-        .with_context(|| format!("Could not open database at {}", args.database))?;
+    if let Some(Command::Config {
+        command: ConfigCommand::Check,
+    }) = args.command
+    {
+        print!("{}", config.to_redacted_toml()?);
+        return Ok(());
+    }
 
-    let optimize_pool = pool.clone();
-    // Per the sqlite docs, this is recommended to be run on startup (https://www.sqlite.org/pragma.html#pragma_optimize)
-    sqlx::query("PRAGMA optimize=0x10002")
-        .execute(&optimize_pool)
-        .await?;
+    let tracer_provider = init_tracing(config.log_format, config.otlp_endpoint.as_deref())?;
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], config.http_port));
-    info!("listening on {}", addr);
+    if let Some(Command::Restore { backup }) = &args.command {
+        if config.database.starts_with("postgres://")
+            || config.database.starts_with("postgresql://")
+            || config.database == ":memory:"
+        {
+            anyhow::bail!("RUGS can only restore sqlite databases");
+        }
+        restore(Path::new(&config.database), backup).await?;
+        println!("Restored {} to {}", backup.display(), config.database);
+        return Ok(());
+    }
+
+    let storage = rugs::storage::connect(&config.database, config.database_max_connections).await?;
+
+    if let Some(Command::Migrate { command }) = args.command {
+        return run_migrate_command(storage.as_ref(), &config.project_paths, command).await;
+    }
+
+    prepare_database(storage.as_ref(), config.migrate_on_startup).await?;
+
+    if let Some(command) = args.command {
+        return match command {
+            Command::Users { command } => run_users_command(storage, command).await,
+            Command::CiTokens { command } => run_ci_tokens_command(storage, command).await,
+            Command::Admin { dry_run, command } => {
+                run_admin_command(storage.as_ref(), &config.project_paths, dry_run, command).await
+            }
+            Command::Backup { path } => {
+                if path.exists() {
+                    anyhow::bail!("{} already exists", path.display());
+                }
+                storage.backup(&path).await?;
+                check_integrity(&path).await?;
+                println!("Backed the database up to {}", path.display());
+                Ok(())
+            }
+            Command::Export { path } => run_export(storage.as_ref(), &path).await,
+            Command::Import { path } => run_import(storage.as_ref(), &path).await,
+            Command::ImportMetadataServer { directory } => {
+                let summary = import_metadata_server(
+                    storage.as_ref(),
+                    &config.project_paths,
+                    &directory,
+                    chrono::Utc::now(),
+                )
+                .await
+                .with_context(|| format!("Could not import {}", directory.display()))?;
+                print_import_summary(&summary);
+                Ok(())
+            }
+            Command::Config { .. } | Command::Migrate { .. } | Command::Restore { .. } => {
+                unreachable!("handled before preparing the database")
+            }
+        };
+    }
+
+    let resplits = resplit_if_changed(storage.as_ref(), &config.project_paths).await?;
+    if !resplits.is_empty() {
+        info!(
+            "re-split {} projects, since the `[paths]` settings changed",
+            resplits.len()
+        );
+    }
 
-    let server_future = axum::Server::bind(&addr)
-        .serve(app(config, pool).into_make_service())
-        .with_graceful_shutdown(async {
-            exit_rx.await.ok();
+    if config.enforce_user_names && !config.user_auth.is_empty() {
+        warn!("RUGS_ENFORCE_USER_NAMES is enabled, so UGS clients using RUGS_USER_AUTH will not be able to submit any data");
+    }
+
+    if config.telemetry_retention_days > 0 {
+        let prune_storage = storage.clone();
+        let retention_days = config.telemetry_retention_days;
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(TELEMETRY_PRUNE_INTERVAL);
+            loop {
+                interval.tick().await;
+                match prune_telemetry(prune_storage.as_ref(), retention_days).await {
+                    Ok(removed) => info!("pruned {} telemetry entries", removed),
+                    Err(e) => error!("failed to prune telemetry: {:?}", e),
+                }
+            }
+        });
+    }
+
+    if config.metadata_retention.is_enabled() {
+        let prune_storage = storage.clone();
+        let policy = config.metadata_retention.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(METADATA_PRUNE_INTERVAL);
+            loop {
+                interval.tick().await;
+                match prune_metadata(prune_storage.as_ref(), &policy, chrono::Utc::now()).await {
+                    Ok(pruned) => info!(
+                        "pruned {} badges and {} user events",
+                        pruned.badges, pruned.user_events
+                    ),
+                    Err(e) => error!("failed to prune badges & user events: {:?}", e),
+                }
+            }
+        });
+    }
+
+    if let Some(backup_settings) = config.backup.clone() {
+        let backup_storage = storage.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(backup_settings.interval);
+            loop {
+                interval.tick().await;
+                match scheduled_backup(
+                    backup_storage.as_ref(),
+                    &backup_settings,
+                    chrono::Utc::now(),
+                )
+                .await
+                {
+                    Ok(path) => info!("backed the database up to {}", path.display()),
+                    Err(e) => error!("failed to back the database up: {:?}", e),
+                }
+            }
         });
+    }
+
+    let addr = SocketAddr::new(config.address, config.http_port);
+    let http_port = config.http_port;
+    let tls = config.tls.clone();
+    let app = app(config, storage.clone());
+
+    let server_future: BoxFuture<'static, Result<()>> = if let Some(tls) = tls {
+        let (rustls_config, certificate_watcher) =
+            CertificateWatcher::new(&tls.cert_path, &tls.key_path).await?;
+        tokio::spawn(certificate_watcher.run(CERTIFICATE_RELOAD_INTERVAL));
+
+        let handle = axum_server::Handle::new();
+        tokio::spawn({
+            let handle = handle.clone();
+            async move {
+                exit_rx.await.ok();
+                handle.graceful_shutdown(None);
+            }
+        });
+
+        if let Some(redirect_port) = tls.http_redirect_port {
+            let redirect_addr = SocketAddr::new(addr.ip(), redirect_port);
+            info!("redirecting HTTP on {} to HTTPS", redirect_addr);
+
+            let https_port = tls.public_https_port.unwrap_or(http_port);
+            let redirect_server = axum_server::bind(redirect_addr)
+                .handle(handle.clone())
+                .serve(redirect_app(https_port).into_make_service());
+            tokio::spawn(async move {
+                if let Err(e) = redirect_server.await {
+                    error!("HTTP redirect server failed: {:?}", e);
+                }
+            });
+        }
+
+        info!("listening for HTTPS on {}", addr);
+        axum_server::bind_rustls(addr, rustls_config)
+            .handle(handle)
+            .serve(app.into_make_service())
+            .err_into()
+            .boxed()
+    } else {
+        info!("listening on {}", addr);
+        axum::Server::bind(&addr)
+            .serve(app.into_make_service())
+            .with_graceful_shutdown(async {
+                exit_rx.await.ok();
+            })
+            .err_into()
+            .boxed()
+    };
 
     pin_mut!(server_future);
     loop {
         let delay_result = tokio::time::timeout(OPTIMIZE_INTERVAL, &mut server_future).await;
         match delay_result {
             Err(_) => {
-                info!("running regular `PRAGMA optimize`");
-                // Per the sqlite docs, this is recommended to be run regularly for long-running apps (https://www.sqlite.org/pragma.html#pragma_optimize)
-                sqlx::query("PRAGMA optimize")
-                    .execute(&optimize_pool)
-                    .await?;
+                info!("running regular database maintenance");
+                storage.optimize().await?;
             }
             Ok(server_result) => break server_result?,
         }
     }
 
-    Ok(())
-}
-
-fn app(config: Config, pool: SqlitePool) -> Router {
-    // Configure routes that require the `user_auth` token (these are expected to come from
-    // the UGS client).
-    let user_routes = Router::new()
-        .route("/latest", get(latest_index))
-        .route("/event", get(event_index))
-        .route("/comment", get(comment_index))
-        .route("/issues", get(issue_index))
-        .route("/metadata", get(metadata_index).post(metadata_submit))
-        .layer(middleware::from_fn(move |req, next| {
-            auth(req, next, config.user_auth.clone())
-        }));
-
-    // Configure routes that require the `ci_auth` token (these are expected to come from your
-    // CI service, e.g. PostBadgeStatus.exe)
-    let ci_routes = Router::new()
-        .route("/build", post(build_create))
-        // Back compat with old PostBadgeStatus.exe which uses the wrong case
-        .route("/Build", post(build_create))
-        .route("/rugs_metrics", get(metrics_index))
-        .layer(middleware::from_fn(move |req, next| {
-            auth(req, next, config.ci_auth.clone())
-        }));
-
-    let app = Router::new().nest(
-        &config.request_root,
-        Router::new()
-            .nest("/api", Router::new().merge(user_routes).merge(ci_routes))
-            .route("/health", get(health)),
-    );
-
-    // We expose the basic `health` endpoint under both `/health` and `/<request_root>/health` if the
-    // root isn't already `/`
-    let app = if config.request_root != "/" {
-        app.route("/health", get(health))
-    } else {
-        app
-    };
-
-    let sequence_lock = Arc::new(RwLock::new(()));
-    let metrics = Arc::new(Metrics::default());
-
-    let service_builder = ServiceBuilder::new()
-        .layer(TraceLayer::new_for_http())
-        .layer(Extension(sequence_lock))
-        .layer(Extension(pool))
-        .layer(Extension(metrics));
-
-    #[cfg(debug_assertions)]
-    let service_builder = service_builder.layer(middleware::from_fn(print_request_response));
+    if let Some(tracer_provider) = tracer_provider {
+        // This blocks until the last spans have been exported
+        tokio::task::spawn_blocking(move || tracer_provider.shutdown()).await??;
+    }
 
-    app.layer(service_builder)
+    Ok(())
 }
 
 #[cfg(test)]
@@ -222,9 +844,10 @@ mod tests {
     use super::*;
     use axum::{
         body::Body,
-        http::{Request, StatusCode},
+        http::{self, Request, StatusCode},
     };
-    use rugs::models::{CreateBadge, GetMetadataListResponseV2};
+    use base64::prelude::*;
+    use rugs::{models::CreateBadge, storage::SqliteStorage};
     use std::io::Write;
     use tower::{Service, ServiceExt};
 
@@ -240,19 +863,32 @@ mod tests {
         Config {
             user_auth: USER_AUTH.to_string(),
             ci_auth: CI_AUTH.to_string(),
+            address: std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST),
             http_port: 3000,
             request_root: "/".to_string(),
+            telemetry_retention_days: 30,
+            metadata_retention: Default::default(),
+            enforce_user_names: false,
+            tls: None,
+            otlp_endpoint: None,
+            log_format: LogFormat::Text,
+            notifications: Default::default(),
+            database: String::from(":memory:"),
+            database_max_connections: None,
+            migrate_on_startup: false,
+            backup: None,
+            project_paths: Default::default(),
         }
     }
 
-    async fn pool() -> Result<SqlitePool> {
-        let pool = SqlitePool::connect("sqlite::memory:")
+    async fn storage() -> Result<Arc<dyn Storage>> {
+        let pool = sqlx::SqlitePool::connect("sqlite::memory:")
             .await
             .with_context(|| "Could not open in-memory sqlite db")?;
 
         sqlx::migrate!("./migrations").run(&pool).await?;
 
-        Ok(pool)
+        Ok(Arc::new(SqliteStorage::new(pool)))
     }
 
     fn config_secrets_files_internal() {
@@ -267,7 +903,7 @@ mod tests {
         std::env::set_var(USER_AUTH_FILE_KEY, user_auth_tempfile.path().as_os_str());
         std::env::set_var(CI_AUTH_FILE_KEY, ci_auth_tempfile.path().as_os_str());
 
-        let config = Config::from_env();
+        let config = Config::from_env().expect("Could not load the config");
 
         std::env::remove_var(USER_AUTH_FILE_KEY);
         std::env::remove_var(CI_AUTH_FILE_KEY);
@@ -290,7 +926,7 @@ mod tests {
         std::env::set_var(USER_AUTH_KEY, USER_AUTH);
         std::env::set_var(CI_AUTH_KEY, CI_AUTH);
 
-        let config = Config::from_env();
+        let config = Config::from_env()?;
 
         std::env::remove_var(USER_AUTH_KEY);
         std::env::remove_var(CI_AUTH_KEY);
@@ -315,10 +951,52 @@ mod tests {
         Ok(())
     }
 
+    /// Test that we apply the built-in migrations to a new database (only when asked to), and
+    /// refuse to use a database that a newer version has migrated
+    #[tokio::test]
+    async fn migrations() -> Result<()> {
+        let directory = tempfile::tempdir()?;
+        let database = directory.path().join("metadata.db");
+        let database = database.to_str().context("Non-UTF-8 temp dir")?;
+        let storage = rugs::storage::connect(database, None).await?;
+
+        let status = storage.migration_status().await?;
+        assert!(status.applied.is_empty());
+        assert!(!status.pending.is_empty());
+        assert!(prepare_database(storage.as_ref(), false).await.is_err());
+
+        prepare_database(storage.as_ref(), true).await?;
+        let migrated = storage.migration_status().await?;
+        assert_eq!(migrated.applied, status.pending);
+        assert!(migrated.pending.is_empty());
+        // Once the database is up to date, we don't need to be allowed to migrate it
+        prepare_database(storage.as_ref(), false).await?;
+
+        let pool = sqlx::SqlitePool::connect(&format!("sqlite:{database}")).await?;
+        sqlx::query(
+            "INSERT INTO _sqlx_migrations (version, description, success, checksum, execution_time) VALUES (99991231000000, 'from the future', TRUE, X'00', 0)",
+        )
+        .execute(&pool)
+        .await?;
+
+        let status = storage.migration_status().await?;
+        assert_eq!(status.unknown, vec![99991231000000]);
+        assert!(prepare_database(storage.as_ref(), true).await.is_err());
+        assert!(run_migrate_command(
+            storage.as_ref(),
+            &ProjectPaths::default(),
+            MigrateCommand::Run
+        )
+        .await
+        .is_err());
+
+        Ok(())
+    }
+
     /// Test the basic /health API
     #[tokio::test]
     async fn health() -> Result<()> {
-        let mut app = app(config(), pool().await?);
+        let mut app = app(config(), storage().await?);
 
         let response = app
             .ready()
@@ -344,7 +1022,7 @@ mod tests {
             request_root: String::from("/test"),
             ..config()
         };
-        let mut app = app(cfg, pool().await?);
+        let mut app = app(cfg, storage().await?);
 
         let response = app
             .ready()
@@ -394,7 +1072,13 @@ mod tests {
             "/api/event",
             "/api/comment",
             "/api/issues",
+            "/api/issues/1",
+            "/api/issues/1/builds",
+            "/api/issues/1/diagnostics",
+            "/api/issues/1/watchers",
             "/api/metadata",
+            "/api/telemetry",
+            "/api/error",
         ];
 
         // First test without any credentials
@@ -411,7 +1095,7 @@ mod tests {
             .chain(requests_with_bad_auth)
             .collect::<Vec<_>>();
 
-        let mut app = app(config(), pool().await?);
+        let mut app = app(config(), storage().await?);
 
         for request in requests {
             let response = app.ready().await?.call(request?).await?;
@@ -439,7 +1123,7 @@ mod tests {
     /// Test that we allow requests for user routes when the credentials are correct
     #[tokio::test]
     async fn user_auth_works() -> Result<()> {
-        let app = app(config(), pool().await?);
+        let app = app(config(), storage().await?);
 
         let create_request = simple_create_request();
         let body = serde_json::to_vec(&create_request)?;
@@ -466,7 +1150,7 @@ mod tests {
     /// Test that we require auth for the CI routes
     #[tokio::test]
     async fn ci_auth_required() -> Result<()> {
-        let mut app = app(config(), pool().await?);
+        let mut app = app(config(), storage().await?);
 
         let create_request = simple_create_request();
 
@@ -511,7 +1195,7 @@ mod tests {
     /// Test that we allow requests for CI routes when the credentials are correct
     #[tokio::test]
     async fn ci_auth_works() -> Result<()> {
-        let app = app(config(), pool().await?);
+        let app = app(config(), storage().await?);
 
         let create_request = simple_create_request();
         let body = serde_json::to_vec(&create_request)?;
@@ -531,151 +1215,62 @@ mod tests {
         Ok(())
     }
 
-    async fn get_metadata(
-        app: &mut Router,
-        stream: &str,
-        project_name: &str,
-    ) -> Result<GetMetadataListResponseV2> {
-        let url =
-            format!("/api/metadata?stream={stream}&project={project_name}&sequence=0&minchange=0");
-        let response = app
-            .ready()
-            .await?
-            .call(
-                request_builder(&url, "GET", Some(authorization_header(USER_AUTH)))
-                    .body(Body::empty())?,
-            )
-            .await?;
-        let status = response.status();
-        let body = hyper::body::to_bytes(response.into_body()).await?;
-        assert_eq!(status, StatusCode::OK, "body: {:?}", body);
-
-        let response = serde_json::from_slice::<GetMetadataListResponseV2>(&body)?;
-        Ok(response)
-    }
-
-    /// Test that we can submit build badges and then read them back
-    #[tokio::test]
-    async fn metadata_integration() -> Result<()> {
-        const STREAM: &str = "//depot/stream;";
-        const PROJECT_NAME: &str = "proj";
-
-        let mut app = app(config(), pool().await?);
-
-        let metadata = get_metadata(&mut app, STREAM, PROJECT_NAME).await?;
-        assert_eq!(metadata.items.len(), 0);
-        let old_sequence_number = metadata.sequence_number;
-
-        let creates = [
-            CreateBadge {
-                change_number: 1,
-                url: String::from("http://test.com"),
-                build_type: String::from("Editor"),
-                result: rugs::models::BadgeResult::Starting,
-                project: format!("{STREAM}/{PROJECT_NAME}"),
-            },
-            CreateBadge {
-                change_number: 1,
-                url: String::from("http://test.com"),
-                build_type: String::from("Standalone"),
-                result: rugs::models::BadgeResult::Starting,
-                project: format!("{STREAM}/{PROJECT_NAME}"),
-            },
-            CreateBadge {
-                change_number: 1,
-                url: String::from("http://test.com"),
-                build_type: String::from("Editor"),
-                result: rugs::models::BadgeResult::Success,
-                project: format!("{STREAM}/{PROJECT_NAME}"),
-            },
-            CreateBadge {
-                change_number: 2,
-                url: String::from("http://test.com"),
-                build_type: String::from("Editor"),
-                result: rugs::models::BadgeResult::Starting,
-                project: format!("{STREAM}/{PROJECT_NAME}"),
-            },
-        ];
-
-        for create in creates {
-            let body = serde_json::to_vec(&create)?;
-
-            let response = app
-                .ready()
-                .await?
-                .call(
-                    request_builder("/api/build", "POST", Some(authorization_header(CI_AUTH)))
-                        .body(Body::from(body))?,
-                )
-                .await?;
-
-            assert_eq!(response.status(), StatusCode::OK);
-        }
-
-        let response = get_metadata(&mut app, STREAM, PROJECT_NAME).await?;
-        assert!(response.sequence_number > old_sequence_number);
-        assert_eq!(response.items.len(), 2);
-        let cl1_item = response.items.iter().find(|item| item.change == 1);
-        assert!(cl1_item.is_some());
-        assert_eq!(cl1_item.unwrap().badges.len(), 3);
-
-        let cl2_item = response.items.iter().find(|item| item.change == 2);
-        assert!(cl2_item.is_some());
-        assert_eq!(cl2_item.unwrap().badges.len(), 1);
-
-        Ok(())
-    }
-
-    /// Test that we can submit build badges and then read them back
+    /// Test that JSON logs have one object per line, which carries the id of the request it was
+    /// logged for
     #[tokio::test]
-    async fn project_case_insensitivity() -> Result<()> {
-        let mut app = app(config(), pool().await?);
-
-        let creates = [
-            CreateBadge {
-                change_number: 1,
-                url: String::from("http://test.com"),
-                build_type: String::from("Editor"),
-                result: rugs::models::BadgeResult::Starting,
-                project: String::from("//depot/Stream/proj"),
-            },
-            CreateBadge {
-                change_number: 1,
-                url: String::from("http://test.com"),
-                build_type: String::from("Standalone"),
-                result: rugs::models::BadgeResult::Starting,
-                project: String::from("//depot/stream/Proj"),
-            },
-        ];
+    async fn json_logs() -> Result<()> {
+        #[derive(Clone, Default)]
+        struct Buffer(Arc<std::sync::Mutex<Vec<u8>>>);
 
-        for create in creates {
-            let body = serde_json::to_vec(&create)?;
-
-            let response = app
-                .ready()
-                .await?
-                .call(
-                    request_builder("/api/build", "POST", Some(authorization_header(CI_AUTH)))
-                        .body(Body::from(body))?,
-                )
-                .await?;
+        impl Write for Buffer {
+            fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().write(bytes)
+            }
 
-            assert_eq!(response.status(), StatusCode::OK);
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
         }
 
-        let first_response = get_metadata(&mut app, "//depot/Stream", "proj").await?;
-        assert_eq!(first_response.items.len(), 1);
-        let cl1_item = &first_response.items[0];
-        assert_eq!(
-            cl1_item.badges.len(),
-            2,
-            "both badges should be under the same project"
+        let buffer = Buffer::default();
+        let _subscriber_guard = tracing::subscriber::set_default(
+            tracing_subscriber::registry().with(log_layer(LogFormat::Json, {
+                let buffer = buffer.clone();
+                move || buffer.clone()
+            })),
         );
 
-        let response = get_metadata(&mut app, "//depot/stream", "Proj").await?;
+        let mut app = app(config(), storage().await?);
+        // A project path without a project name, which we log an error for
+        let request = request_builder(
+            "/api/latest?project=//depot/stream",
+            "GET",
+            Some(authorization_header(USER_AUTH)),
+        )
+        .header("x-request-id", "json-logs")
+        .body(Body::empty())?;
+        app.ready().await?.call(request).await?;
+
+        let logs = String::from_utf8(buffer.0.lock().unwrap().clone())?;
+        let lines = logs
+            .lines()
+            .map(serde_json::from_str::<serde_json::Value>)
+            .collect::<Result<Vec<_>, _>>()?;
+        let error = lines
+            .iter()
+            .find(|line| line["level"] == "ERROR")
+            .context("The error wasn't logged")?;
         assert_eq!(
-            first_response.items, response.items,
-            "results should be identical even if we use a different case for project"
+            error["fields"]["message"],
+            "Could not find a project name after stream name in //depot/stream"
+        );
+        assert!(
+            error["spans"]
+                .as_array()
+                .context("The error has no spans")?
+                .iter()
+                .any(|span| span["request_id"] == "json-logs"),
+            "The error doesn't have the request id: {error}"
         );
 
         Ok(())
@@ -0,0 +1,811 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use std::{
+    fmt::Display,
+    net::{IpAddr, Ipv4Addr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use crate::{
+    backup::BackupSettings,
+    notifications::NotificationSettings,
+    paths::{DepotLayout, ProjectPaths},
+    retention::{MetadataRetention, ProjectRetention, RetentionPolicy},
+};
+
+/// What `config check` prints instead of secrets
+const REDACTED: &str = "<redacted>";
+
+/// How we print our logs to stdout
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, with the fields of the spans it happened in (e.g. the request's
+    /// `request_id`)
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(format: &str) -> Result<Self> {
+        match format.to_ascii_lowercase().as_str() {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => anyhow::bail!("Unknown log format {format:?}, expected `text` or `json`"),
+        }
+    }
+}
+
+/// Configuration for serving HTTPS directly
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    /// Path to the PEM certificate (chain) to serve
+    pub cert_path: PathBuf,
+    /// Path to the PEM private key for the certificate
+    pub key_path: PathBuf,
+    /// If set, we also listen for plain HTTP on this port and redirect it to HTTPS
+    pub http_redirect_port: Option<u16>,
+    /// The port we redirect to, if it's different from `http_port` (e.g. because of Docker port
+    /// mapping)
+    pub public_https_port: Option<u16>,
+}
+
+/// Configuration for the app
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// The auth token required for user-facing operations (reading badges, leaving comments & feedback)
+    pub user_auth: String,
+    /// The auth token required for CI-facing operations (writing badges)
+    pub ci_auth: String,
+    /// The address we listen on
+    pub address: IpAddr,
+    /// The port we listen to for incoming HTTP connections
+    pub http_port: u16,
+    /// The prefix we expect for any request (e.g. "/ugs" means we look for "/ugs/api/build")
+    pub request_root: String,
+    /// How many days to keep telemetry and error reports from UGS for (0 means forever)
+    pub telemetry_retention_days: u32,
+    /// How long to keep the badges & user events of each project
+    pub metadata_retention: RetentionPolicy,
+    /// Whether UGS can only submit data under the user name it authenticated as (or its aliases)
+    pub enforce_user_names: bool,
+    /// If set, we serve HTTPS on `http_port` instead of plain HTTP
+    pub tls: Option<TlsConfig>,
+    /// If set, we export our spans to the OTLP/HTTP collector at this URL (e.g.
+    /// `http://localhost:4318`)
+    pub otlp_endpoint: Option<String>,
+    /// How we print our logs to stdout
+    pub log_format: LogFormat,
+    /// How long requests wait to be notified of changes
+    pub notifications: NotificationSettings,
+    /// The path to the sqlite database, or a `postgres://` URL
+    pub database: String,
+    /// The most connections we open to the database, if not sqlx's default
+    pub database_max_connections: Option<u32>,
+    /// Whether to apply any pending migrations when the server starts, instead of refusing to
+    /// start
+    pub migrate_on_startup: bool,
+    /// If set, we regularly back the (sqlite) database up to a local directory
+    pub backup: Option<BackupSettings>,
+    /// How we split project paths into their stream & project
+    pub project_paths: ProjectPaths,
+}
+
+impl Config {
+    /// Load the config from the TOML file at `path` (if any), environment variables and defaults,
+    /// in that order of precedence. This fails if any setting is invalid, listing all of them.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        Self::load_with_env(path, |key| std::env::var(key).ok())
+    }
+
+    /// Like `load`, but reading the environment variables from `env`
+    pub fn load_with_env(
+        path: Option<&Path>,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self> {
+        let mut errors = Vec::new();
+
+        let settings = ConfigFile::from_env(&env, &mut errors);
+        let settings = match path {
+            Some(path) => ConfigFile::read(path)?.or(settings),
+            None => settings,
+        };
+        let config = settings.resolve(&mut errors);
+
+        if !errors.is_empty() {
+            anyhow::bail!("Invalid configuration:\n- {}", errors.join("\n- "));
+        }
+        Ok(config)
+    }
+
+    /// Load the config from environment variables and defaults
+    pub fn from_env() -> Result<Self> {
+        Self::load(None)
+    }
+
+    /// The config in the same format as the config file, without any secrets
+    pub fn to_redacted_toml(&self) -> Result<String> {
+        let redact = |secret: &str| {
+            Some(if secret.is_empty() {
+                String::new()
+            } else {
+                REDACTED.to_owned()
+            })
+        };
+        let tls = self.tls.as_ref();
+
+        let settings = ConfigFile {
+            server: ServerSection {
+                address: Some(self.address),
+                port: Some(self.http_port),
+                web_root: Some(self.request_root.clone()),
+                tls: TlsSection {
+                    cert_file: tls.map(|tls| tls.cert_path.clone()),
+                    key_file: tls.map(|tls| tls.key_path.clone()),
+                    http_redirect_port: tls.and_then(|tls| tls.http_redirect_port),
+                    public_https_port: tls.and_then(|tls| tls.public_https_port),
+                },
+            },
+            auth: AuthSection {
+                user: redact(&self.user_auth),
+                user_file: None,
+                ci: redact(&self.ci_auth),
+                ci_file: None,
+                enforce_user_names: Some(self.enforce_user_names),
+            },
+            retention: RetentionSection {
+                telemetry_days: Some(self.telemetry_retention_days),
+                metadata_days: self.metadata_retention.default.days,
+                metadata_changes: self.metadata_retention.default.changes,
+                superseded_badge_days: self.metadata_retention.default.superseded_badge_days,
+                projects: self
+                    .metadata_retention
+                    .projects
+                    .iter()
+                    .map(|project| ProjectRetentionSection {
+                        project: project.pattern.clone(),
+                        metadata_days: project.retention.days,
+                        metadata_changes: project.retention.changes,
+                        superseded_badge_days: project.retention.superseded_badge_days,
+                    })
+                    .collect(),
+            },
+            notifications: NotificationsSection {
+                latest_default_wait_secs: Some(self.notifications.latest_default_wait.as_secs()),
+                latest_max_wait_secs: Some(self.notifications.latest_max_wait.as_secs()),
+                stream_poll_interval_secs: Some(self.notifications.stream_poll_interval.as_secs()),
+            },
+            database: DatabaseSection {
+                url: Some(redact_password(&self.database)),
+                max_connections: self.database_max_connections,
+                migrate_on_startup: Some(self.migrate_on_startup),
+            },
+            backup: BackupSection {
+                directory: self.backup.as_ref().map(|backup| backup.directory.clone()),
+                interval_hours: self
+                    .backup
+                    .as_ref()
+                    .map(|backup| backup.interval.as_secs() / 3600),
+                keep: self.backup.as_ref().map(|backup| backup.keep),
+            },
+            logging: LoggingSection {
+                format: Some(self.log_format),
+                otlp_endpoint: self.otlp_endpoint.as_deref().map(redact_password),
+            },
+            paths: PathsSection {
+                stream_roots: Some(self.project_paths.stream_roots.clone()),
+                depots: self
+                    .project_paths
+                    .depots
+                    .iter()
+                    .map(|layout| DepotSection {
+                        depot: layout.depot.clone(),
+                        stream_depth: layout.stream_depth,
+                    })
+                    .collect(),
+            },
+        };
+
+        Ok(toml::to_string(&settings)?)
+    }
+}
+
+/// `url` with its password (if it has one) redacted, e.g. `postgres://rugs:<redacted>@db/rugs`
+fn redact_password(url: &str) -> String {
+    let Some((scheme, rest)) = url.split_once("://") else {
+        return url.to_owned();
+    };
+    let authority_end = rest.find('/').unwrap_or(rest.len());
+    let Some(at) = rest[..authority_end].rfind('@') else {
+        return url.to_owned();
+    };
+    match rest[..at].split_once(':') {
+        Some((user, _)) => format!("{scheme}://{user}:{REDACTED}{}", &rest[at..]),
+        None => url.to_owned(),
+    }
+}
+
+/// The settings from one source (the config file or environment variables), each of which is
+/// `None` if that source doesn't set it
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    server: ServerSection,
+    auth: AuthSection,
+    retention: RetentionSection,
+    notifications: NotificationsSection,
+    database: DatabaseSection,
+    backup: BackupSection,
+    logging: LoggingSection,
+    paths: PathsSection,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct ServerSection {
+    address: Option<IpAddr>,
+    port: Option<u16>,
+    web_root: Option<String>,
+    #[serde(skip_serializing_if = "TlsSection::is_unset")]
+    tls: TlsSection,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct TlsSection {
+    cert_file: Option<PathBuf>,
+    key_file: Option<PathBuf>,
+    http_redirect_port: Option<u16>,
+    public_https_port: Option<u16>,
+}
+
+impl TlsSection {
+    fn is_unset(&self) -> bool {
+        self.cert_file.is_none()
+            && self.key_file.is_none()
+            && self.http_redirect_port.is_none()
+            && self.public_https_port.is_none()
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct AuthSection {
+    user: Option<String>,
+    user_file: Option<PathBuf>,
+    ci: Option<String>,
+    ci_file: Option<PathBuf>,
+    enforce_user_names: Option<bool>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct RetentionSection {
+    telemetry_days: Option<u32>,
+    metadata_days: Option<u32>,
+    metadata_changes: Option<u32>,
+    superseded_badge_days: Option<u32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    projects: Vec<ProjectRetentionSection>,
+}
+
+/// A `[[retention.projects]]` override for the projects matching the `project` glob
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct ProjectRetentionSection {
+    project: String,
+    metadata_days: Option<u32>,
+    metadata_changes: Option<u32>,
+    superseded_badge_days: Option<u32>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct NotificationsSection {
+    latest_default_wait_secs: Option<u64>,
+    latest_max_wait_secs: Option<u64>,
+    stream_poll_interval_secs: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct DatabaseSection {
+    url: Option<String>,
+    max_connections: Option<u32>,
+    migrate_on_startup: Option<bool>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct BackupSection {
+    directory: Option<PathBuf>,
+    interval_hours: Option<u64>,
+    keep: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct LoggingSection {
+    format: Option<LogFormat>,
+    otlp_endpoint: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct PathsSection {
+    stream_roots: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    depots: Vec<DepotSection>,
+}
+
+/// A `[[paths.depots]]` section for a depot whose streams aren't one level below it
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct DepotSection {
+    depot: String,
+    stream_depth: usize,
+}
+
+/// Reads environment variables, keeping track of any we couldn't parse
+struct EnvVars<'a, F> {
+    env: &'a F,
+    errors: &'a mut Vec<String>,
+}
+
+impl<F: Fn(&str) -> Option<String>> EnvVars<'_, F> {
+    /// The variable's value, if it's set to anything (Docker makes it easy to set empty ones)
+    fn get(&self, key: &str) -> Option<String> {
+        (self.env)(key).filter(|value| !value.is_empty())
+    }
+
+    fn parse<T>(&mut self, key: &str) -> Option<T>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        let value = self.get(key)?;
+        match value.parse() {
+            Ok(value) => Some(value),
+            Err(e) => {
+                self.errors.push(format!("{key}={value:?}: {e}"));
+                None
+            }
+        }
+    }
+}
+
+impl ConfigFile {
+    fn read(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read config file {}", path.display()))?;
+        toml::from_str(&contents).with_context(|| format!("Invalid config file {}", path.display()))
+    }
+
+    /// The settings from our `RUGS_*` environment variables (not all settings have one)
+    fn from_env(env: &impl Fn(&str) -> Option<String>, errors: &mut Vec<String>) -> Self {
+        let mut vars = EnvVars { env, errors };
+
+        Self {
+            server: ServerSection {
+                address: None,
+                port: vars.parse("RUGS_PORT"),
+                web_root: vars.get("RUGS_WEB_ROOT"),
+                tls: TlsSection {
+                    cert_file: vars.get("RUGS_TLS_CERT_FILE").map(PathBuf::from),
+                    key_file: vars.get("RUGS_TLS_KEY_FILE").map(PathBuf::from),
+                    http_redirect_port: vars.parse("RUGS_HTTP_REDIRECT_PORT"),
+                    public_https_port: vars.parse("RUGS_PUBLIC_HTTPS_PORT"),
+                },
+            },
+            auth: AuthSection {
+                user: vars.get("RUGS_USER_AUTH"),
+                user_file: vars.get("RUGS_USER_AUTH_FILE").map(PathBuf::from),
+                ci: vars.get("RUGS_CI_AUTH"),
+                ci_file: vars.get("RUGS_CI_AUTH_FILE").map(PathBuf::from),
+                enforce_user_names: vars.parse("RUGS_ENFORCE_USER_NAMES"),
+            },
+            retention: RetentionSection {
+                telemetry_days: vars.parse("RUGS_TELEMETRY_RETENTION_DAYS"),
+                metadata_days: vars.parse("RUGS_METADATA_RETENTION_DAYS"),
+                metadata_changes: vars.parse("RUGS_METADATA_RETENTION_CHANGES"),
+                superseded_badge_days: vars.parse("RUGS_SUPERSEDED_BADGE_RETENTION_DAYS"),
+                projects: Vec::new(),
+            },
+            notifications: NotificationsSection::default(),
+            database: DatabaseSection {
+                url: None,
+                max_connections: None,
+                migrate_on_startup: vars.parse("RUGS_MIGRATE_ON_STARTUP"),
+            },
+            backup: BackupSection {
+                directory: vars.get("RUGS_BACKUP_DIR").map(PathBuf::from),
+                interval_hours: vars.parse("RUGS_BACKUP_INTERVAL_HOURS"),
+                keep: vars.parse("RUGS_BACKUP_KEEP"),
+            },
+            logging: LoggingSection {
+                format: vars.parse("RUGS_LOG_FORMAT"),
+                otlp_endpoint: vars.get("RUGS_OTLP_ENDPOINT"),
+            },
+            paths: PathsSection {
+                stream_roots: vars.get("RUGS_STREAM_ROOTS").map(|roots| {
+                    roots
+                        .split(',')
+                        .map(|root| root.trim().to_owned())
+                        .filter(|root| !root.is_empty())
+                        .collect()
+                }),
+                depots: Vec::new(),
+            },
+        }
+    }
+
+    /// These settings, falling back to `other`'s for anything these don't set
+    fn or(self, other: Self) -> Self {
+        // A secret and the file to read it from replace each other, so they come from the same
+        // source
+        let secret = |value: Option<String>, file: Option<PathBuf>, fallback| {
+            if value.is_some() || file.is_some() {
+                (value, file)
+            } else {
+                fallback
+            }
+        };
+        let (user, user_file) = secret(
+            self.auth.user,
+            self.auth.user_file,
+            (other.auth.user, other.auth.user_file),
+        );
+        let (ci, ci_file) = secret(
+            self.auth.ci,
+            self.auth.ci_file,
+            (other.auth.ci, other.auth.ci_file),
+        );
+
+        Self {
+            server: ServerSection {
+                address: self.server.address.or(other.server.address),
+                port: self.server.port.or(other.server.port),
+                web_root: self.server.web_root.or(other.server.web_root),
+                tls: TlsSection {
+                    cert_file: self.server.tls.cert_file.or(other.server.tls.cert_file),
+                    key_file: self.server.tls.key_file.or(other.server.tls.key_file),
+                    http_redirect_port: self
+                        .server
+                        .tls
+                        .http_redirect_port
+                        .or(other.server.tls.http_redirect_port),
+                    public_https_port: self
+                        .server
+                        .tls
+                        .public_https_port
+                        .or(other.server.tls.public_https_port),
+                },
+            },
+            auth: AuthSection {
+                user,
+                user_file,
+                ci,
+                ci_file,
+                enforce_user_names: self
+                    .auth
+                    .enforce_user_names
+                    .or(other.auth.enforce_user_names),
+            },
+            retention: RetentionSection {
+                telemetry_days: self
+                    .retention
+                    .telemetry_days
+                    .or(other.retention.telemetry_days),
+                metadata_days: self
+                    .retention
+                    .metadata_days
+                    .or(other.retention.metadata_days),
+                metadata_changes: self
+                    .retention
+                    .metadata_changes
+                    .or(other.retention.metadata_changes),
+                superseded_badge_days: self
+                    .retention
+                    .superseded_badge_days
+                    .or(other.retention.superseded_badge_days),
+                projects: if self.retention.projects.is_empty() {
+                    other.retention.projects
+                } else {
+                    self.retention.projects
+                },
+            },
+            notifications: NotificationsSection {
+                latest_default_wait_secs: self
+                    .notifications
+                    .latest_default_wait_secs
+                    .or(other.notifications.latest_default_wait_secs),
+                latest_max_wait_secs: self
+                    .notifications
+                    .latest_max_wait_secs
+                    .or(other.notifications.latest_max_wait_secs),
+                stream_poll_interval_secs: self
+                    .notifications
+                    .stream_poll_interval_secs
+                    .or(other.notifications.stream_poll_interval_secs),
+            },
+            database: DatabaseSection {
+                url: self.database.url.or(other.database.url),
+                max_connections: self
+                    .database
+                    .max_connections
+                    .or(other.database.max_connections),
+                migrate_on_startup: self
+                    .database
+                    .migrate_on_startup
+                    .or(other.database.migrate_on_startup),
+            },
+            backup: BackupSection {
+                directory: self.backup.directory.or(other.backup.directory),
+                interval_hours: self.backup.interval_hours.or(other.backup.interval_hours),
+                keep: self.backup.keep.or(other.backup.keep),
+            },
+            logging: LoggingSection {
+                format: self.logging.format.or(other.logging.format),
+                otlp_endpoint: self.logging.otlp_endpoint.or(other.logging.otlp_endpoint),
+            },
+            paths: PathsSection {
+                stream_roots: self.paths.stream_roots.or(other.paths.stream_roots),
+                depots: if self.paths.depots.is_empty() {
+                    other.paths.depots
+                } else {
+                    self.paths.depots
+                },
+            },
+        }
+    }
+
+    /// Fill in the defaults for anything that isn't set, adding anything that doesn't make sense to
+    /// `errors`
+    fn resolve(self, errors: &mut Vec<String>) -> Config {
+        let Self {
+            server,
+            auth,
+            retention,
+            notifications,
+            database,
+            backup,
+            logging,
+            paths,
+        } = self;
+
+        let user_auth = resolve_secret(
+            auth.user,
+            auth.user_file,
+            ("auth.user", "RUGS_USER_AUTH"),
+            errors,
+        );
+        let ci_auth = resolve_secret(auth.ci, auth.ci_file, ("auth.ci", "RUGS_CI_AUTH"), errors);
+
+        let http_port = server.port.unwrap_or(3000);
+
+        let request_root = server.web_root.unwrap_or_else(|| String::from("/"));
+        if !request_root.starts_with('/') || (request_root.len() > 1 && request_root.ends_with('/'))
+        {
+            errors.push(format!(
+                "server.web_root (RUGS_WEB_ROOT) should start with `/` and not end with one (e.g. `/ugs`), not {request_root:?}"
+            ));
+        }
+
+        let tls = match (server.tls.cert_file, server.tls.key_file) {
+            (Some(cert_path), Some(key_path)) => {
+                for (path, setting) in [
+                    (&cert_path, "server.tls.cert_file (RUGS_TLS_CERT_FILE)"),
+                    (&key_path, "server.tls.key_file (RUGS_TLS_KEY_FILE)"),
+                ] {
+                    if !path.is_file() {
+                        errors.push(format!("{setting}: {} doesn't exist", path.display()));
+                    }
+                }
+                if server.tls.http_redirect_port == Some(http_port) {
+                    errors.push(format!(
+                        "server.tls.http_redirect_port (RUGS_HTTP_REDIRECT_PORT) can't be the same as server.port (RUGS_PORT), {http_port}"
+                    ));
+                }
+                Some(TlsConfig {
+                    cert_path,
+                    key_path,
+                    http_redirect_port: server.tls.http_redirect_port,
+                    public_https_port: server.tls.public_https_port,
+                })
+            }
+            (None, None) => {
+                if server.tls.http_redirect_port.is_some() || server.tls.public_https_port.is_some()
+                {
+                    errors.push(String::from(
+                        "server.tls.http_redirect_port & public_https_port (RUGS_HTTP_REDIRECT_PORT & RUGS_PUBLIC_HTTPS_PORT) only apply when serving HTTPS, which needs server.tls.cert_file & key_file",
+                    ));
+                }
+                None
+            }
+            _ => {
+                errors.push(String::from(
+                    "server.tls.cert_file & key_file (RUGS_TLS_CERT_FILE & RUGS_TLS_KEY_FILE) have to be set together",
+                ));
+                None
+            }
+        };
+
+        let defaults = NotificationSettings::default();
+        let notifications = NotificationSettings {
+            latest_default_wait: notifications
+                .latest_default_wait_secs
+                .map_or(defaults.latest_default_wait, Duration::from_secs),
+            latest_max_wait: notifications
+                .latest_max_wait_secs
+                .map_or(defaults.latest_max_wait, Duration::from_secs),
+            stream_poll_interval: notifications
+                .stream_poll_interval_secs
+                .map_or(defaults.stream_poll_interval, Duration::from_secs),
+        };
+        if notifications.latest_default_wait > notifications.latest_max_wait {
+            errors.push(format!(
+                "notifications.latest_default_wait_secs ({}) can't be longer than latest_max_wait_secs ({})",
+                notifications.latest_default_wait.as_secs(),
+                notifications.latest_max_wait.as_secs()
+            ));
+        }
+        if notifications.stream_poll_interval.is_zero() {
+            errors.push(String::from(
+                "notifications.stream_poll_interval_secs has to be at least 1",
+            ));
+        }
+
+        let metadata_retention = RetentionPolicy {
+            default: MetadataRetention {
+                days: retention.metadata_days,
+                changes: retention.metadata_changes,
+                superseded_badge_days: retention.superseded_badge_days,
+            },
+            projects: retention
+                .projects
+                .into_iter()
+                .map(|project| ProjectRetention {
+                    pattern: project.project,
+                    retention: MetadataRetention {
+                        days: project.metadata_days,
+                        changes: project.metadata_changes,
+                        superseded_badge_days: project.superseded_badge_days,
+                    },
+                })
+                .collect(),
+        };
+        for project in &metadata_retention.projects {
+            if let Err(e) = glob::Pattern::new(&project.pattern) {
+                errors.push(format!(
+                    "retention.projects: Invalid project pattern {:?}: {e}",
+                    project.pattern
+                ));
+            }
+        }
+
+        let database_url = database.url.unwrap_or_else(|| String::from("metadata.db"));
+        if database.max_connections == Some(0) {
+            errors.push(String::from(
+                "database.max_connections has to be at least 1",
+            ));
+        }
+
+        let backup = backup.directory.map(|directory| BackupSettings {
+            directory,
+            interval: Duration::from_secs(backup.interval_hours.unwrap_or(24) * 3600),
+            keep: backup.keep.unwrap_or(7),
+        });
+        if let Some(backup) = &backup {
+            if backup.interval.is_zero() {
+                errors.push(String::from(
+                    "backup.interval_hours (RUGS_BACKUP_INTERVAL_HOURS) has to be at least 1",
+                ));
+            }
+            if backup.keep == 0 {
+                errors.push(String::from(
+                    "backup.keep (RUGS_BACKUP_KEEP) has to be at least 1",
+                ));
+            }
+            if database_url.starts_with("postgres://") || database_url.starts_with("postgresql://")
+            {
+                errors.push(String::from(
+                    "backup.directory (RUGS_BACKUP_DIR) only works with sqlite databases, use pg_dump to back up PostgreSQL",
+                ));
+            }
+        }
+
+        if let Some(endpoint) = &logging.otlp_endpoint {
+            if !endpoint.starts_with("http://") && !endpoint.starts_with("https://") {
+                errors.push(format!(
+                    "logging.otlp_endpoint (RUGS_OTLP_ENDPOINT) should be an http:// or https:// URL, not {endpoint:?}"
+                ));
+            }
+        }
+
+        let project_paths = ProjectPaths {
+            stream_roots: paths.stream_roots.unwrap_or_default(),
+            depots: paths
+                .depots
+                .into_iter()
+                .map(|depot| DepotLayout {
+                    depot: depot.depot,
+                    stream_depth: depot.stream_depth,
+                })
+                .collect(),
+        };
+        for root in &project_paths.stream_roots {
+            // A stream root has to leave room for the project after it
+            if !root.starts_with("//") || root.trim_end_matches('/').matches('/').count() < 3 {
+                errors.push(format!(
+                    "paths.stream_roots (RUGS_STREAM_ROOTS) should be //depot/stream paths, not {root:?}"
+                ));
+            }
+        }
+        for layout in &project_paths.depots {
+            let depot = layout.depot.trim_end_matches('/');
+            if !depot.starts_with("//") || depot.len() == 2 || depot[2..].contains('/') {
+                errors.push(format!(
+                    "paths.depots: depot should be a //depot path, not {:?}",
+                    layout.depot
+                ));
+            }
+        }
+
+        Config {
+            user_auth,
+            ci_auth,
+            address: server.address.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            http_port,
+            request_root,
+            telemetry_retention_days: retention.telemetry_days.unwrap_or(30),
+            metadata_retention,
+            enforce_user_names: auth.enforce_user_names.unwrap_or_default(),
+            tls,
+            otlp_endpoint: logging.otlp_endpoint,
+            log_format: logging.format.unwrap_or_default(),
+            notifications,
+            database: database_url,
+            database_max_connections: database.max_connections,
+            migrate_on_startup: database.migrate_on_startup.unwrap_or_default(),
+            backup,
+            project_paths,
+        }
+    }
+}
+
+/// The `user:password` secret for the `key` setting (`env_var` in the environment), read from `file`
+/// if that's set, or empty if neither is
+fn resolve_secret(
+    value: Option<String>,
+    file: Option<PathBuf>,
+    (key, env_var): (&str, &str),
+    errors: &mut Vec<String>,
+) -> String {
+    let secret = match file {
+        Some(path) => match std::fs::read_to_string(&path) {
+            Ok(contents) => contents.trim_end().to_owned(),
+            Err(e) => {
+                errors.push(format!(
+                    "{key}_file ({env_var}_FILE): Could not read {}: {e}",
+                    path.display()
+                ));
+                return String::new();
+            }
+        },
+        None => value.unwrap_or_default(),
+    };
+
+    if !secret.is_empty() && !secret.contains(':') {
+        errors.push(format!(
+            "{key} ({env_var}) should look like `user:password`"
+        ));
+    }
+    secret
+}
@@ -0,0 +1,299 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, SubsecRound, Utc};
+use serde::{Deserialize, Serialize};
+
+use std::{
+    collections::HashMap,
+    io::{BufRead, Write},
+};
+
+use crate::{
+    models::{Badge, BadgeResult, UgsUserVote, UserEvent},
+    storage::{ImportOutcome, Storage},
+};
+
+/// The `format` in the header of every export
+pub const EXPORT_FORMAT: &str = "rugs-export";
+/// The newest version of the export format, which we write & can still read everything up to
+pub const EXPORT_VERSION: u32 = 1;
+
+/// One line of an export. Badges & user events name their project rather than referring to its
+/// `project_id`, so that they can be imported into a server that numbers its projects differently.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ExportRecord {
+    /// The first line of every export
+    Header {
+        format: String,
+        version: u32,
+        exported_at: DateTime<Utc>,
+    },
+    Project {
+        stream: String,
+        project: String,
+    },
+    Badge {
+        stream: String,
+        project: String,
+        change_number: i64,
+        build_type: String,
+        result: BadgeResult,
+        url: String,
+        added_at: DateTime<Utc>,
+        posted_by: Option<String>,
+    },
+    UserEvent {
+        stream: String,
+        project: String,
+        change_number: i64,
+        user_name: String,
+        updated_at: DateTime<Utc>,
+        synced_at: Option<DateTime<Utc>>,
+        vote: Option<UgsUserVote>,
+        investigating: Option<bool>,
+        starred: Option<bool>,
+        comment: Option<String>,
+    },
+}
+
+/// How much we exported
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ExportSummary {
+    pub projects: u64,
+    pub badges: u64,
+    pub user_events: u64,
+}
+
+/// How much we imported, and what we couldn't
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ImportSummary {
+    pub projects_added: u64,
+    pub badges_added: u64,
+    pub badges_unchanged: u64,
+    pub user_events_added: u64,
+    pub user_events_unchanged: u64,
+    /// A description of each user event we didn't import because the user already had a different
+    /// one for that change
+    pub conflicts: Vec<String>,
+    /// A description of each record we couldn't make sense of, and so didn't import
+    pub skipped: Vec<String>,
+}
+
+fn write_record(writer: &mut impl Write, record: &ExportRecord) -> Result<()> {
+    serde_json::to_writer(&mut *writer, record)?;
+    writer.write_all(b"\n")?;
+    Ok(())
+}
+
+/// Write every project, badge & user event in `storage` to `writer` as JSON Lines, starting with a
+/// header as of `now`
+pub async fn export(
+    storage: &dyn Storage,
+    writer: &mut impl Write,
+    now: DateTime<Utc>,
+) -> Result<ExportSummary> {
+    // PostgreSQL only keeps microseconds, so we do the same to make exports compare equal whichever
+    // backend they're imported into
+    let timestamp = |timestamp: DateTime<Utc>| timestamp.trunc_subsecs(6);
+
+    write_record(
+        writer,
+        &ExportRecord::Header {
+            format: EXPORT_FORMAT.to_string(),
+            version: EXPORT_VERSION,
+            exported_at: now,
+        },
+    )?;
+
+    let mut summary = ExportSummary::default();
+    for project in storage.list_projects().await? {
+        write_record(
+            writer,
+            &ExportRecord::Project {
+                stream: project.stream.clone(),
+                project: project.project.clone(),
+            },
+        )?;
+        summary.projects += 1;
+
+        let changes = storage
+            .list_metadata(&project.stream, Some(&project.project), 0, i64::MIN, None)
+            .await?;
+        for change in changes {
+            for badge in change.badges {
+                write_record(
+                    writer,
+                    &ExportRecord::Badge {
+                        stream: project.stream.clone(),
+                        project: project.project.clone(),
+                        change_number: badge.change_number,
+                        build_type: badge.build_type,
+                        result: badge.result,
+                        url: badge.url,
+                        added_at: timestamp(badge.added_at),
+                        posted_by: badge.posted_by,
+                    },
+                )?;
+                summary.badges += 1;
+            }
+            for user_event in change.user_events {
+                write_record(
+                    writer,
+                    &ExportRecord::UserEvent {
+                        stream: project.stream.clone(),
+                        project: project.project.clone(),
+                        change_number: user_event.change_number,
+                        user_name: user_event.user_name,
+                        updated_at: timestamp(user_event.updated_at),
+                        synced_at: user_event.synced_at.map(timestamp),
+                        vote: user_event.vote,
+                        investigating: user_event.investigating,
+                        starred: user_event.starred,
+                        comment: user_event.comment,
+                    },
+                )?;
+                summary.user_events += 1;
+            }
+        }
+    }
+    writer.flush()?;
+
+    Ok(summary)
+}
+
+/// The ID of the project `stream`/`project`, adding it if it doesn't exist yet
+async fn project_id(
+    storage: &dyn Storage,
+    project_ids: &mut HashMap<(String, String), i64>,
+    summary: &mut ImportSummary,
+    stream: &str,
+    project: &str,
+) -> Result<i64> {
+    let key = (stream.to_string(), project.to_string());
+    if let Some(&project_id) = project_ids.get(&key) {
+        return Ok(project_id);
+    }
+
+    let project_id = match storage.get_project(stream, project).await? {
+        Some(project_id) => project_id,
+        None => {
+            summary.projects_added += 1;
+            storage.get_or_add_project(stream, project).await?
+        }
+    };
+    project_ids.insert(key, project_id);
+    Ok(project_id)
+}
+
+/// Import an export written by [`export`] from `reader`. Importing the same export again doesn't
+/// change anything, and a user event that conflicts with one the user already has is reported
+/// rather than overwriting it.
+pub async fn import(storage: &dyn Storage, reader: impl BufRead) -> Result<ImportSummary> {
+    let mut summary = ImportSummary::default();
+    let mut project_ids = HashMap::new();
+    let mut has_header = false;
+
+    for (index, line) in reader.lines().enumerate() {
+        let line_number = index + 1;
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: ExportRecord = serde_json::from_str(&line)
+            .with_context(|| format!("Line {line_number} isn't a valid export record"))?;
+
+        match record {
+            ExportRecord::Header {
+                format, version, ..
+            } => {
+                if has_header {
+                    anyhow::bail!("Line {line_number} is a second header");
+                }
+                if format != EXPORT_FORMAT {
+                    anyhow::bail!("This is a {format:?} file, not a RUGS export");
+                }
+                if version > EXPORT_VERSION {
+                    anyhow::bail!(
+                        "This export is version {version}, but we can only import up to version {EXPORT_VERSION}"
+                    );
+                }
+                has_header = true;
+                continue;
+            }
+            _ if !has_header => anyhow::bail!("This export doesn't start with a header"),
+            ExportRecord::Project { stream, project } => {
+                project_id(storage, &mut project_ids, &mut summary, &stream, &project).await?;
+            }
+            ExportRecord::Badge {
+                stream,
+                project,
+                change_number,
+                build_type,
+                result,
+                url,
+                added_at,
+                posted_by,
+            } => {
+                let project_id =
+                    project_id(storage, &mut project_ids, &mut summary, &stream, &project).await?;
+                let badge = Badge {
+                    project_id,
+                    sequence: 0,
+                    change_number,
+                    added_at,
+                    build_type,
+                    result,
+                    url,
+                    posted_by,
+                };
+                match storage.import_badge(project_id, &badge).await? {
+                    ImportOutcome::Added => summary.badges_added += 1,
+                    ImportOutcome::Unchanged | ImportOutcome::Conflict => {
+                        summary.badges_unchanged += 1
+                    }
+                }
+            }
+            ExportRecord::UserEvent {
+                stream,
+                project,
+                change_number,
+                user_name,
+                updated_at,
+                synced_at,
+                vote,
+                investigating,
+                starred,
+                comment,
+            } => {
+                let project_id =
+                    project_id(storage, &mut project_ids, &mut summary, &stream, &project).await?;
+                let user_event = UserEvent {
+                    project_id,
+                    change_number,
+                    user_name,
+                    updated_at,
+                    synced_at,
+                    vote,
+                    investigating,
+                    starred,
+                    comment,
+                    ..Default::default()
+                };
+                match storage.import_user_event(project_id, &user_event).await? {
+                    ImportOutcome::Added => summary.user_events_added += 1,
+                    ImportOutcome::Unchanged => summary.user_events_unchanged += 1,
+                    ImportOutcome::Conflict => summary.conflicts.push(format!(
+                        "line {line_number}: {}'s user event for change {change_number} in {stream}/{project} differs from the existing one, which we kept",
+                        user_event.user_name
+                    )),
+                }
+            }
+        }
+    }
+
+    if !has_header {
+        anyhow::bail!("This export is empty");
+    }
+
+    Ok(summary)
+}
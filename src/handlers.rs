@@ -1,265 +1,544 @@
 use anyhow::anyhow;
-use axum::{extract::Query, http::StatusCode, response::IntoResponse, Extension, Json};
-use itertools::Itertools;
+use axum::{
+    body::StreamBody,
+    extract::{Path, Query},
+    http::{header, HeaderMap, HeaderValue, StatusCode, Uri},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    Extension, Json,
+};
+use futures::StreamExt;
 use serde::{Deserialize, Serialize};
-use sqlx::SqlitePool;
-use tokio::sync::RwLock;
-use tracing::{debug, error, info};
+use sha2::{Digest, Sha256};
+use tokio_util::io::ReaderStream;
+use tracing::{debug, error, instrument, warn, Span};
 
-use std::sync::{
-    atomic::{AtomicU64, Ordering},
-    Arc,
+use std::{
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
 };
 
-use crate::{error::AppError, models::*};
+use crate::{
+    auth::{Principal, UserNamePolicy},
+    error::AppError,
+    metrics::Metrics,
+    models::*,
+    notifications::{NotificationHub, NotificationSettings},
+    paths::{normalize_stream, ProjectPaths},
+    storage::{ChangeMetadata, LatestSequences, Storage, TelemetryFilter, UserEventUpdate},
+};
 
-#[derive(Debug, Default)]
-pub struct Metrics {
-    pub latest_requests: AtomicU64,
-    pub build_create_requests: AtomicU64,
-    pub metadata_index_requests: AtomicU64,
-    pub metadata_submit_requests: AtomicU64,
+/// Attach the project a request is about to the current (handler) span
+fn record_project(stream: &str, project: &str) {
+    let span = Span::current();
+    span.record("stream", stream);
+    span.record("project", project);
 }
 
-fn find_starting_at(haystack: &str, needle: char, starting_index: usize) -> Option<usize> {
-    if let Some(slice) = haystack.get(starting_index..) {
-        slice.find(needle).map(|i| i + starting_index)
-    } else {
-        None
+pub async fn metrics_index(Extension(metrics): Extension<Arc<Metrics>>) -> impl IntoResponse {
+    #[derive(Serialize)]
+    struct MetricsResponse {
+        pub latest_requests: u64,
+        pub build_create_requests: u64,
+        pub metadata_index_requests: u64,
+        pub metadata_submit_requests: u64,
     }
-}
 
-/// Take a //depot/stream/project path and try to split it into `//depot/stream` and `project`
-fn split_project_path(project_path: &str) -> Option<(String, String)> {
-    if !project_path.starts_with("//") {
-        return None;
-    }
+    Json(MetricsResponse {
+        latest_requests: metrics.latest_requests.load(Ordering::Relaxed),
+        build_create_requests: metrics.build_create_requests.load(Ordering::Relaxed),
+        metadata_index_requests: metrics.metadata_index_requests.load(Ordering::Relaxed),
+        metadata_submit_requests: metrics.metadata_submit_requests.load(Ordering::Relaxed),
+    })
+}
 
-    if let Some(stream_name_index) = find_starting_at(project_path, '/', 2) {
-        if let Some(project_index) = find_starting_at(project_path, '/', stream_name_index + 1) {
-            if project_path.len() > project_index + 1 {
-                Some((
-                    normalize_stream(&project_path[0..project_index]),
-                    normalize_project_name(&project_path[project_index + 1..]),
-                ))
-            } else {
-                error!(
-                    "Not enough characters after stream name in {}",
-                    project_path
-                );
-                None
-            }
-        } else {
-            error!(
-                "Could not find a project name after stream name in {}",
-                project_path
-            );
-            None
-        }
-    } else {
-        error!("Could not find a stream name in {}", project_path);
-        None
+/// Handler for POST /api/backup, a consistent snapshot of the (sqlite) database that is safe to
+/// take while we're running. This needs the shared CI credentials, since scoped CI tokens are only
+/// meant to be able to post badges.
+#[instrument(skip_all)]
+pub async fn backup_create(
+    Extension(storage): Extension<Arc<dyn Storage>>,
+    Extension(principal): Extension<Principal>,
+) -> Result<Response, AppError> {
+    if let Principal::CiToken(token) = &principal {
+        warn!(
+            "Denying CI token {} from backing up the database",
+            token.name
+        );
+        return Ok(StatusCode::FORBIDDEN.into_response());
     }
-}
 
-fn normalize_stream(stream: &str) -> String {
-    let stream = stream.strip_suffix('/').unwrap_or(stream);
-    stream.to_lowercase()
-}
+    let backup = tempfile::NamedTempFile::new()?;
+    storage.backup(backup.path()).await?;
+    // We keep reading the snapshot through this handle after the temporary file is deleted
+    let file = tokio::fs::File::from_std(backup.reopen()?);
 
-fn normalize_project_name(project_name: &str) -> String {
-    project_name.to_lowercase()
+    let file_name = format!(
+        "rugs-backup-{}.db",
+        chrono::Utc::now().format("%Y%m%dT%H%M%SZ")
+    );
+    Ok((
+        [
+            (
+                header::CONTENT_TYPE,
+                String::from("application/vnd.sqlite3"),
+            ),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{file_name}\""),
+            ),
+        ],
+        StreamBody::new(ReaderStream::new(file)),
+    )
+        .into_response())
 }
 
-async fn get_project(
-    pool: &SqlitePool,
-    stream: &str,
-    project_name: &str,
-) -> Result<Option<i64>, AppError> {
-    let (stream, project_name) = (stream.to_lowercase(), project_name.to_lowercase());
+/// Handler for GET /metrics, all our metrics in the Prometheus text format
+pub async fn prometheus_metrics(
+    Extension(storage): Extension<Arc<dyn Storage>>,
+    Extension(metrics): Extension<Arc<Metrics>>,
+) -> Result<Response, AppError> {
+    metrics.set_database_size(storage.database_size().await?);
 
-    let project_id = sqlx::query_scalar!(
-        "SELECT project_id FROM projects WHERE stream = ? AND project = ? LIMIT 1",
-        stream,
-        project_name
+    Ok((
+        [(
+            header::CONTENT_TYPE,
+            "application/openmetrics-text; version=1.0.0; charset=utf-8",
+        )],
+        metrics.encode()?,
     )
-    .fetch_optional(pool)
-    .await?;
-
-    Ok(project_id)
+        .into_response())
 }
 
-async fn get_or_add_project(
-    pool: &SqlitePool,
+/// A weak ETag for the response to `uri`, which changes whenever a badge or user event is written
+/// to or deleted from the projects in `stream` (or just the one named `project`). It's weak because
+/// the same ETag is sent for the compressed & uncompressed versions of the response.
+async fn metadata_etag(
+    storage: &dyn Storage,
     stream: &str,
-    project_name: &str,
-) -> Result<i64, AppError> {
-    let (stream, project_name) = (stream.to_lowercase(), project_name.to_lowercase());
+    project: Option<&str>,
+    uri: &Uri,
+) -> Result<HeaderValue, AppError> {
+    let version = storage.metadata_version(stream, project).await?;
+    let uri_hash = Sha256::digest(uri.to_string().as_bytes())
+        .iter()
+        .take(8)
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<String>();
 
-    let project_id = sqlx::query_scalar!(
-        "SELECT project_id FROM projects WHERE stream = ? AND project = ? LIMIT 1",
-        stream,
-        project_name
-    )
-    .fetch_optional(pool)
-    .await?;
+    Ok(HeaderValue::from_str(&format!(
+        "W/\"{}-{}-{uri_hash}\"",
+        version.sequence, version.rows
+    ))?)
+}
 
-    if let Some(project_id) = project_id {
-        Ok(project_id)
-    } else {
-        info!(
-            "Creating new project for stream {}, project name {}",
-            stream, project_name
-        );
+/// Whether `etag` is one of the ones in the request's `If-None-Match`, i.e. whether the client
+/// already has the response
+fn matches_if_none_match(headers: &HeaderMap, etag: &HeaderValue) -> bool {
+    let Some(Ok(if_none_match)) = headers
+        .get(header::IF_NONE_MATCH)
+        .map(|value| value.to_str())
+    else {
+        return false;
+    };
+    let Ok(etag) = etag.to_str() else {
+        return false;
+    };
+    let etag = etag.trim_start_matches("W/");
 
-        // TODO: Thread safety
-        Ok(sqlx::query!(
-            "INSERT INTO projects (stream, project) VALUES (?, ?)",
-            stream,
-            project_name
-        )
-        .execute(pool)
-        .await?
-        .last_insert_rowid())
-    }
+    // `If-None-Match` uses the weak comparison, so ignore any `W/` prefixes
+    if_none_match
+        .split(',')
+        .map(|candidate| candidate.trim())
+        .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == etag)
 }
 
-pub async fn metrics_index(Extension(metrics): Extension<Arc<Metrics>>) -> impl IntoResponse {
-    #[derive(Serialize)]
-    struct MetricsResponse {
-        pub latest_requests: u64,
-        pub build_index_requests: u64,
-        pub build_create_requests: u64,
-        pub metadata_index_requests: u64,
-        pub metadata_submit_requests: u64,
-    }
-
-    Json(MetricsResponse {
-        latest_requests: metrics.latest_requests.load(Ordering::Relaxed),
-        build_index_requests: 0,
-        build_create_requests: metrics.build_create_requests.load(Ordering::Relaxed),
-        metadata_index_requests: metrics.metadata_index_requests.load(Ordering::Relaxed),
-        metadata_submit_requests: metrics.metadata_submit_requests.load(Ordering::Relaxed),
-    })
+fn not_modified(etag: HeaderValue) -> Response {
+    (StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response()
 }
 
 #[derive(Debug, Deserialize)]
 pub struct LatestParams {
     project: String,
+    /// If set, wait for a badge or user event newer than this sequence number (i.e. the largest of
+    /// `LastBuildId` & `LastEventId` the client has seen) before responding
+    since: Option<i64>,
+    /// How many seconds to wait for something newer than `since`
+    wait: Option<u64>,
 }
 
+async fn latest_sequences(
+    storage: &dyn Storage,
+    stream: &str,
+    project_name: &str,
+) -> Result<LatestSequences, AppError> {
+    Ok(match storage.get_project(stream, project_name).await? {
+        Some(project_id) => storage.latest_sequences(project_id).await?,
+        None => Default::default(),
+    })
+}
+
+/// Handler for GET /latest, returns the latest sequence numbers for a project. If `since` is set,
+/// this waits until there's something newer than it (or until it times out) before responding.
+#[instrument(skip_all, fields(stream, project))]
+// Each of these is an extractor, there's nothing to bundle up
+#[allow(clippy::too_many_arguments)]
 pub async fn latest_index(
-    Extension(pool): Extension<SqlitePool>,
+    Extension(storage): Extension<Arc<dyn Storage>>,
+    Extension(paths): Extension<Arc<ProjectPaths>>,
     Extension(metrics): Extension<Arc<Metrics>>,
-    Extension(sequence_lock): Extension<Arc<RwLock<()>>>,
+    Extension(notifications): Extension<Arc<NotificationHub>>,
+    Extension(settings): Extension<NotificationSettings>,
+    uri: Uri,
+    headers: HeaderMap,
     params: Query<LatestParams>,
-) -> Result<impl IntoResponse, AppError> {
+) -> Result<Response, AppError> {
     metrics.latest_requests.fetch_add(1, Ordering::Relaxed);
 
-    let (stream, project_name) = split_project_path(&params.project).ok_or_else(|| {
+    let (stream, project_name) = paths.split(&params.project).ok_or_else(|| {
         anyhow!(
             "Invalid project name format {}, should be Perforce stream path to directory",
             params.project
         )
     })?;
+    record_project(&stream, &project_name);
 
-    let project_id = get_project(&pool, &stream, &project_name).await?;
+    // Long-polling requests wait for something new to respond with instead
+    let etag = if params.since.is_none() {
+        let etag =
+            metadata_etag(storage.as_ref(), &stream, Some(project_name.as_str()), &uri).await?;
+        if matches_if_none_match(&headers, &etag) {
+            return Ok(not_modified(etag));
+        }
+        Some(etag)
+    } else {
+        None
+    };
 
-    let _read_lock = sequence_lock.read().await;
+    // Subscribe before we look at the database, so we can't miss anything written in between
+    let mut receiver = params
+        .since
+        .map(|_| notifications.subscribe(&stream, &project_name));
 
-    let (last_build_id, last_event_id) = if let Some(project_id) = project_id {
-        let badge_sequence = sqlx::query_scalar!(
-            "SELECT sequence FROM badges WHERE project_id = ? ORDER BY sequence DESC LIMIT 1",
-            project_id
-        )
-        .fetch_optional(&pool)
-        .await?;
+    let mut latest = latest_sequences(storage.as_ref(), &stream, &project_name).await?;
 
-        let event_sequence = sqlx::query_scalar!(
-            "SELECT sequence FROM user_events WHERE project_id = ? ORDER BY sequence DESC LIMIT 1",
-            project_id
-        )
-        .fetch_optional(&pool)
-        .await?;
-        (
-            badge_sequence.unwrap_or_default(),
-            event_sequence.unwrap_or_default(),
-        )
-    } else {
-        (0, 0)
-    };
+    if let (Some(since), Some(receiver)) = (params.since, receiver.as_mut()) {
+        if latest.badge.max(latest.event) <= since {
+            let wait = params
+                .wait
+                .map_or(settings.latest_default_wait, Duration::from_secs)
+                .min(settings.latest_max_wait);
+            let newer = receiver.wait_for(|&sequence| sequence > since);
+            // If we time out, we just respond with what we already have
+            if tokio::time::timeout(wait, newer).await.is_ok() {
+                latest = latest_sequences(storage.as_ref(), &stream, &project_name).await?;
+            }
+        }
+    }
 
     let response = LatestResponseV1 {
         version: Some(2),
-        last_build_id,
-        last_comment_id: 0,
-        last_event_id,
+        last_build_id: latest.badge,
+        last_comment_id: latest.comment,
+        last_event_id: latest.event,
     };
-    Ok((StatusCode::OK, Json(response)))
+
+    let mut response = Json(response).into_response();
+    if let Some(etag) = etag {
+        response.headers_mut().insert(header::ETAG, etag);
+    }
+    Ok(response)
 }
 
 /// Handler for POST /api/build, creates a new badge with the given info
+#[instrument(skip_all, fields(stream, project, change))]
 pub async fn build_create(
-    Extension(pool): Extension<SqlitePool>,
+    Extension(storage): Extension<Arc<dyn Storage>>,
+    Extension(paths): Extension<Arc<ProjectPaths>>,
     Extension(metrics): Extension<Arc<Metrics>>,
-    Extension(sequence_lock): Extension<Arc<RwLock<()>>>,
+    Extension(notifications): Extension<Arc<NotificationHub>>,
+    Extension(principal): Extension<Principal>,
     Json(badge): Json<CreateBadge>,
-) -> Result<impl IntoResponse, AppError> {
+) -> Result<Response, AppError> {
     metrics
         .build_create_requests
         .fetch_add(1, Ordering::Relaxed);
 
-    let (stream, project) = split_project_path(&badge.project).ok_or_else(|| {
+    let (stream, project) = paths.split(&badge.project).ok_or_else(|| {
         anyhow!(
             "Invalid project name format {}, should be Perforce stream path to directory",
             badge.project
         )
     })?;
+    record_project(&stream, &project);
+    Span::current().record("change", badge.change_number);
 
     debug!("POST /build request: {:?}", badge);
-    let _write_lock = sequence_lock.write().await;
-
-    let project_id = get_or_add_project(&pool, &stream, &project).await?;
-    let added_at = chrono::Utc::now();
-    let sequence_number = added_at.timestamp_micros();
-    let result = badge.result as u8;
-    let query = sqlx::query!(
-        "INSERT INTO badges (sequence, change_number, added_at, build_type, result, url, project_id) VALUES (?, ?, ?, ?, ?, ?, ?)",
-        sequence_number,
-        badge.change_number,
-        added_at,
-        badge.build_type,
-        result,
-        badge.url,
-        project_id,
+
+    if let Principal::CiToken(token) = &principal {
+        let project_path = format!("{stream}/{project}");
+        if !token.allows(&project_path, &badge.build_type) {
+            warn!(
+                "Denying CI token {} from posting a {} badge to {}",
+                token.name, badge.build_type, project_path
+            );
+            return Ok(StatusCode::FORBIDDEN.into_response());
+        }
+    }
+
+    let project_id = storage.get_or_add_project(&stream, &project).await?;
+    let sequence = storage
+        .add_badge(project_id, &badge, principal.name())
+        .await?;
+    notifications.publish(&stream, &project, sequence);
+    metrics.badge_posted(
+        &format!("{stream}/{project}"),
+        &badge.build_type,
+        &format!("{:?}", badge.result),
     );
-    query.execute(&pool).await?;
 
-    Ok((StatusCode::OK, ""))
+    Ok(StatusCode::OK.into_response())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EventIndexParams {
+    project: String,
+    #[serde(rename = "lasteventid", alias = "lastEventId", default)]
+    last_event_id: i64,
+}
+
+/// Turn the current state of a user event into the list of v1 events that recreates it. The
+/// events all share the sequence number of the user event as their ID.
+fn user_event_to_events(user_event: &UserEvent, project: &str) -> Vec<EventData> {
+    let synced =
+        (user_event.synced_at == Some(user_event.updated_at)).then_some(EventType::Syncing);
+    let vote = user_event.vote.as_ref().map(|vote| match vote {
+        UgsUserVote::None => EventType::Unknown,
+        UgsUserVote::CompileSuccess => EventType::Compiles,
+        UgsUserVote::CompileFailure => EventType::DoesNotCompile,
+        UgsUserVote::Good => EventType::Good,
+        UgsUserVote::Bad => EventType::Bad,
+    });
+    let starred = user_event.starred.map(|starred| {
+        if starred {
+            EventType::Starred
+        } else {
+            EventType::Unstarred
+        }
+    });
+    let investigating = user_event.investigating.map(|investigating| {
+        if investigating {
+            EventType::Investigating
+        } else {
+            EventType::Resolved
+        }
+    });
+
+    [synced, vote, starred, investigating]
+        .into_iter()
+        .flatten()
+        .map(|event_type| EventData {
+            id: user_event.sequence,
+            change: user_event.change_number,
+            user_name: user_event.user_name.clone(),
+            event_type,
+            project: project.to_owned(),
+        })
+        .collect()
 }
 
-/// Handler for GET /event, currently just a placeholder empty response to
-/// prevent error logging in UGS.
-pub async fn event_index() -> impl IntoResponse {
-    let response: [&str; 0] = [];
-    // Unimplemented for now
-    (StatusCode::OK, Json(response))
+/// Handler for GET /event (Used by v1 API clients), returns the events that have happened since
+/// `lasteventid`.
+#[instrument(skip_all, fields(stream, project))]
+pub async fn event_index(
+    Extension(storage): Extension<Arc<dyn Storage>>,
+    Extension(paths): Extension<Arc<ProjectPaths>>,
+    params: Query<EventIndexParams>,
+) -> Result<impl IntoResponse, AppError> {
+    let (stream, project_name) = paths.split(&params.project).ok_or_else(|| {
+        anyhow!(
+            "Invalid project name format {}, should be Perforce stream path to directory",
+            params.project
+        )
+    })?;
+    record_project(&stream, &project_name);
+
+    let mut response = Vec::new();
+    if let Some(project_id) = storage.get_project(&stream, &project_name).await? {
+        let user_events = storage
+            .list_user_events_since(project_id, params.last_event_id, false)
+            .await?;
+
+        response.extend(
+            user_events
+                .iter()
+                .flat_map(|user_event| user_event_to_events(user_event, &params.project)),
+        );
+    }
+
+    Ok(Json(response))
 }
 
-/// Handler for GET /comment, currently just a placeholder empty response to
-/// prevent error logging in UGS.
-pub async fn comment_index() -> impl IntoResponse {
-    let response: [&str; 0] = [];
-    // Unimplemented for now
-    (StatusCode::OK, Json(response))
+/// Handler for POST /event (Used by v1 API clients)
+#[instrument(skip_all, fields(stream, project, change))]
+pub async fn event_create(
+    Extension(storage): Extension<Arc<dyn Storage>>,
+    Extension(paths): Extension<Arc<ProjectPaths>>,
+    Extension(notifications): Extension<Arc<NotificationHub>>,
+    Extension(principal): Extension<Principal>,
+    Extension(user_name_policy): Extension<Arc<UserNamePolicy>>,
+    Json(event): Json<EventData>,
+) -> Result<Response, AppError> {
+    debug!("POST /event request: {:?}", event);
+
+    if !user_name_policy
+        .allows(&principal, &event.user_name)
+        .await?
+    {
+        return Ok(StatusCode::FORBIDDEN.into_response());
+    }
+
+    let (stream, project_name) = paths.split(&event.project).ok_or_else(|| {
+        anyhow!(
+            "Invalid project name format {}, should be Perforce stream path to directory",
+            event.project
+        )
+    })?;
+    record_project(&stream, &project_name);
+    Span::current().record("change", event.change);
+
+    let update = UserEventUpdate::from(event.event_type);
+
+    let project_id = storage.get_or_add_project(&stream, &project_name).await?;
+    let sequence = storage
+        .update_user_event(project_id, event.change, &event.user_name, update)
+        .await?;
+    notifications.publish(&stream, &project_name, sequence);
+
+    Ok(StatusCode::OK.into_response())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CommentIndexParams {
+    project: String,
+    #[serde(rename = "lastcommentid", alias = "lastCommentId", default)]
+    last_comment_id: i64,
+}
+
+/// Handler for GET /comment (Used by v1 API clients), returns the comments that have been
+/// added or changed since `lastcommentid`.
+#[instrument(skip_all, fields(stream, project))]
+pub async fn comment_index(
+    Extension(storage): Extension<Arc<dyn Storage>>,
+    Extension(paths): Extension<Arc<ProjectPaths>>,
+    params: Query<CommentIndexParams>,
+) -> Result<impl IntoResponse, AppError> {
+    let (stream, project_name) = paths.split(&params.project).ok_or_else(|| {
+        anyhow!(
+            "Invalid project name format {}, should be Perforce stream path to directory",
+            params.project
+        )
+    })?;
+    record_project(&stream, &project_name);
+
+    let mut response = Vec::new();
+    if let Some(project_id) = storage.get_project(&stream, &project_name).await? {
+        let user_events = storage
+            .list_user_events_since(project_id, params.last_comment_id, true)
+            .await?;
+
+        response.extend(user_events.into_iter().map(|user_event| CommentData {
+            id: user_event.sequence,
+            change_number: user_event.change_number,
+            user_name: user_event.user_name,
+            text: user_event.comment.unwrap_or_default(),
+            project: params.project.clone(),
+        }));
+    }
+
+    Ok(Json(response))
+}
+
+/// Handler for POST /comment (Used by v1 API clients)
+#[instrument(skip_all, fields(stream, project, change))]
+pub async fn comment_create(
+    Extension(storage): Extension<Arc<dyn Storage>>,
+    Extension(paths): Extension<Arc<ProjectPaths>>,
+    Extension(notifications): Extension<Arc<NotificationHub>>,
+    Extension(principal): Extension<Principal>,
+    Extension(user_name_policy): Extension<Arc<UserNamePolicy>>,
+    Json(comment): Json<CommentData>,
+) -> Result<Response, AppError> {
+    debug!("POST /comment request: {:?}", comment);
+
+    if !user_name_policy
+        .allows(&principal, &comment.user_name)
+        .await?
+    {
+        return Ok(StatusCode::FORBIDDEN.into_response());
+    }
+
+    let (stream, project_name) = paths.split(&comment.project).ok_or_else(|| {
+        anyhow!(
+            "Invalid project name format {}, should be Perforce stream path to directory",
+            comment.project
+        )
+    })?;
+    record_project(&stream, &project_name);
+    Span::current().record("change", comment.change_number);
+
+    let update = UserEventUpdate {
+        comment: Some(comment.text),
+        ..Default::default()
+    };
+
+    let project_id = storage.get_or_add_project(&stream, &project_name).await?;
+    let sequence = storage
+        .update_user_event(
+            project_id,
+            comment.change_number,
+            &comment.user_name,
+            update,
+        )
+        .await?;
+    notifications.publish(&stream, &project_name, sequence);
+
+    Ok(StatusCode::OK.into_response())
 }
 
-/// Handler for GET /issues, currently just a placeholder empty response to
-/// prevent error logging in UGS.
-pub async fn issue_index() -> impl IntoResponse {
-    let response: [&str; 0] = [];
-    // Unimplemented for now
-    (StatusCode::OK, Json(response))
+/// Turn the badges & user events for a change into what UGS expects from `/metadata`
+fn change_metadata_response(stream: &str, change: ChangeMetadata) -> GetMetadataResponseV2 {
+    // We intentionally order these by sequence (from old to new). We don't send the ID, so to manage newness the order here matters.
+    // (We could also only send the most recent badge for each (change_number, build_result) pair, but the client will take care
+    // of figuring out which the most recent is if we order them right.)
+    let badge_responses = change
+        .badges
+        .into_iter()
+        .map(|badge| GetBadgeDataResponseV2 {
+            name: badge.build_type,
+            url: badge.url,
+            state: badge.result,
+        });
+    let user_responses = change
+        .user_events
+        .into_iter()
+        .map(|user_event| GetUserDataResponseV2 {
+            user: user_event.user_name,
+            sync_time: user_event.synced_at.map(|t| t.timestamp_micros() * 10),
+            vote: user_event.vote,
+            comment: user_event.comment,
+            investigating: user_event.investigating,
+            starred: user_event.starred,
+        });
+
+    GetMetadataResponseV2 {
+        project: format!("{}/{}", stream, change.project),
+        change: change.change_number,
+        users: user_responses.collect(),
+        badges: badge_responses.collect(),
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -272,129 +551,154 @@ pub struct MetadataIndexParams {
 }
 
 /// Handler for GET /metadata (Used by v2 API clients)
+#[instrument(skip_all, fields(stream, project))]
 pub async fn metadata_index(
-    Extension(pool): Extension<SqlitePool>,
+    Extension(storage): Extension<Arc<dyn Storage>>,
+    Extension(paths): Extension<Arc<ProjectPaths>>,
     Extension(metrics): Extension<Arc<Metrics>>,
-    Extension(sequence_lock): Extension<Arc<RwLock<()>>>,
+    uri: Uri,
+    headers: HeaderMap,
     params: Query<MetadataIndexParams>,
-) -> Result<impl IntoResponse, AppError> {
+) -> Result<Response, AppError> {
     metrics
         .metadata_index_requests
         .fetch_add(1, Ordering::Relaxed);
 
-    let stream = normalize_stream(&params.stream);
-    let project = params
-        .project
-        .to_owned()
-        .map(|p| normalize_project_name(&p));
-
-    let project_query_string = format!(
-        "SELECT project_id, project FROM projects WHERE stream = ? {}",
-        params
-            .project
-            .is_some()
-            .then_some("AND project = ?")
-            .unwrap_or_default()
-    );
-
-    #[derive(sqlx::FromRow)]
-    struct Project {
-        project_id: i64,
-        project: String,
+    let (stream, project) = match &params.project {
+        Some(project) => {
+            let (stream, project) = paths.resplit(&params.stream, project);
+            (stream, Some(project))
+        }
+        None => (normalize_stream(&params.stream), None),
+    };
+    Span::current().record("stream", stream.as_str());
+    if let Some(project) = &project {
+        Span::current().record("project", project.as_str());
     }
 
-    let mut project_query =
-        sqlx::query_as::<sqlx::Sqlite, Project>(&project_query_string).bind(&stream);
-    if let Some(project) = project {
-        project_query = project_query.bind(project);
+    // We look this up before reading the metadata, so that the ETag is never newer than what we
+    // respond with
+    let etag = metadata_etag(storage.as_ref(), &stream, project.as_deref(), &uri).await?;
+    if matches_if_none_match(&headers, &etag) {
+        return Ok(not_modified(etag));
     }
 
-    let projects = project_query.fetch_all(&pool).await?;
+    let metadata = storage
+        .list_metadata(
+            &stream,
+            project.as_deref(),
+            params.sequence.unwrap_or(0),
+            params.minchange,
+            params.maxchange,
+        )
+        .await?;
 
     let mut response = GetMetadataListResponseV2 {
         sequence_number: 0,
         items: Vec::new(),
     };
 
-    let _read_lock = sequence_lock.read().await;
+    for change in metadata {
+        response.sequence_number = response.sequence_number.max(change.sequence());
+        response
+            .items
+            .push(change_metadata_response(&stream, change));
+    }
+
+    debug!("GET /metadata response: {:?}", response);
 
-    for project in projects {
-        let project_path = format!("{}/{}", stream, project.project);
+    Ok(([(header::ETAG, etag)], Json(response)).into_response())
+}
 
-        let mut filters = vec!["sequence > ?", "change_number >= ?"];
-        if params.maxchange.is_some() {
-            filters.push("change_number <= ?")
-        }
+#[derive(Debug, Deserialize)]
+pub struct MetadataStreamParams {
+    stream: String,
+    project: String,
+    /// Send everything newer than this sequence number first, defaults to only sending what
+    /// changes after connecting
+    sequence: Option<i64>,
+}
 
-        let mut changelists = Vec::<i64>::new();
-        for table in &["badges", "user_events"] {
-            let changelist_query_string = format!(
-                "SELECT DISTINCT change_number FROM {table} WHERE project_id = ? AND {}",
-                filters.join(" AND "),
-            );
+/// Handler for GET /stream, a stream of server-sent events with the same shape as the items from
+/// `/metadata`, sent whenever a badge or user event changes in a project. Each event's ID is its
+/// sequence number, so reconnecting clients resume from `Last-Event-ID` (or `sequence`).
+#[instrument(skip_all, fields(stream, project))]
+pub async fn metadata_stream(
+    Extension(storage): Extension<Arc<dyn Storage>>,
+    Extension(paths): Extension<Arc<ProjectPaths>>,
+    Extension(notifications): Extension<Arc<NotificationHub>>,
+    Extension(settings): Extension<NotificationSettings>,
+    headers: HeaderMap,
+    params: Query<MetadataStreamParams>,
+) -> Result<impl IntoResponse, AppError> {
+    let (stream, project) = paths.resplit(&params.stream, &params.project);
+    record_project(&stream, &project);
 
-            let mut changelist_query =
-                sqlx::query_scalar::<sqlx::Sqlite, i64>(&changelist_query_string)
-                    .bind(project.project_id);
+    // Subscribe before we look at the database, so we can't miss anything written in between
+    let receiver = notifications.subscribe(&stream, &project);
 
-            changelist_query = changelist_query.bind(params.sequence.unwrap_or(0));
-            changelist_query = changelist_query.bind(params.minchange);
-            if let Some(maxchange) = params.maxchange {
-                changelist_query = changelist_query.bind(maxchange);
-            }
-            changelists.extend(changelist_query.fetch_all(&pool).await?);
+    let last_event_id = headers
+        .get("Last-Event-ID")
+        .and_then(|id| id.to_str().ok())
+        .and_then(|id| id.parse::<i64>().ok());
+    let sequence = match last_event_id.or(params.sequence) {
+        Some(sequence) => sequence,
+        None => {
+            let latest = latest_sequences(storage.as_ref(), &stream, &project).await?;
+            latest.badge.max(latest.event)
         }
-        changelists.sort_unstable();
-
-        for changelist in changelists.into_iter().unique() {
-            // We intentionally order these by sequence (from old to new). We don't send the ID, so to manage newness the order here matters.
-            // (We could also only send the most recent badge for each (change_number, build_result) pair, but the client will take care
-            // of figuring out which the most recent is if we order them right.)
-            let badge_query =
-                sqlx::query_as::<sqlx::Sqlite, Badge>("SELECT * FROM badges WHERE project_id = ? AND change_number = ? ORDER BY sequence ASC").bind(project.project_id).bind(changelist);
-            let badges = badge_query.fetch_all(&pool).await?;
-            response.sequence_number = response
-                .sequence_number
-                .max(badges.iter().map(|b| b.sequence).max().unwrap_or_default());
-
-            let user_event_query = sqlx::query_as::<sqlx::Sqlite, UserEvent>("SELECT * FROM user_events WHERE project_id = ? AND change_number = ? ORDER BY sequence ASC").bind(project.project_id).bind(changelist);
-            let user_events = user_event_query.fetch_all(&pool).await?;
-            response.sequence_number = response.sequence_number.max(
-                user_events
-                    .iter()
-                    .map(|u| u.sequence)
-                    .max()
-                    .unwrap_or_default(),
-            );
+    };
 
-            let badge_responses = badges.into_iter().map(|badge| GetBadgeDataResponseV2 {
-                name: badge.build_type,
-                url: badge.url,
-                state: badge.result,
-            });
-            let user_responses = user_events
-                .into_iter()
-                .map(|user_event| GetUserDataResponseV2 {
-                    user: user_event.user_name,
-                    sync_time: user_event.synced_at.map(|t| t.timestamp_micros() * 10),
-                    vote: user_event.vote,
-                    comment: user_event.comment,
-                    investigating: user_event.investigating,
-                    starred: user_event.starred,
-                });
-
-            response.items.push(GetMetadataResponseV2 {
-                project: project_path.to_owned(),
-                change: changelist,
-                users: user_responses.collect(),
-                badges: badge_responses.collect(),
-            });
-        }
-    }
+    let events = futures::stream::unfold(
+        (storage, receiver, sequence),
+        move |(storage, mut receiver, mut sequence)| {
+            let stream = stream.clone();
+            let project = project.clone();
+            async move {
+                loop {
+                    let mut changes = match storage
+                        .list_metadata(&stream, Some(&project), sequence, 0, None)
+                        .await
+                    {
+                        Ok(changes) => changes,
+                        Err(e) => {
+                            // The client will reconnect & resume from the last event it got
+                            error!("Failed to list metadata for {stream}/{project}: {e:?}");
+                            return None;
+                        }
+                    };
 
-    debug!("GET /metadata response: {:?}", response);
+                    if !changes.is_empty() {
+                        changes.sort_by_key(ChangeMetadata::sequence);
+                        sequence = changes.last().map_or(sequence, ChangeMetadata::sequence);
 
-    Ok(Json(response))
+                        let events = changes
+                            .into_iter()
+                            .map(|change| {
+                                Event::default()
+                                    .id(change.sequence().to_string())
+                                    .json_data(change_metadata_response(&stream, change))
+                            })
+                            .collect::<Vec<_>>();
+                        return Some((
+                            futures::stream::iter(events),
+                            (storage, receiver, sequence),
+                        ));
+                    }
+
+                    // Wait to be notified, but check every so often regardless
+                    let notified =
+                        tokio::time::timeout(settings.stream_poll_interval, receiver.changed());
+                    if let Ok(Err(_)) = notified.await {
+                        return None;
+                    }
+                }
+            }
+        },
+    )
+    .flatten();
+
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -413,74 +717,403 @@ pub struct UpdateMetadataRequestV2 {
     comment: Option<String>,
 }
 
+#[instrument(skip_all, fields(stream, project, change))]
 pub async fn metadata_submit(
-    Extension(pool): Extension<SqlitePool>,
+    Extension(storage): Extension<Arc<dyn Storage>>,
+    Extension(paths): Extension<Arc<ProjectPaths>>,
     Extension(metrics): Extension<Arc<Metrics>>,
-    Extension(sequence_lock): Extension<Arc<RwLock<()>>>,
+    Extension(notifications): Extension<Arc<NotificationHub>>,
+    Extension(principal): Extension<Principal>,
+    Extension(user_name_policy): Extension<Arc<UserNamePolicy>>,
     Json(params): Json<UpdateMetadataRequestV2>,
-) -> Result<impl IntoResponse, AppError> {
+) -> Result<Response, AppError> {
     metrics
         .metadata_submit_requests
         .fetch_add(1, Ordering::Relaxed);
 
-    let _write_lock = sequence_lock.write().await;
-    let now = chrono::Utc::now();
-    let sequence_number = now.timestamp_micros();
+    if !user_name_policy
+        .allows(&principal, &params.user_name)
+        .await?
+    {
+        return Ok(StatusCode::FORBIDDEN.into_response());
+    }
+
+    let (stream, project_name) = match &params.project {
+        Some(project) => paths.resplit(&params.stream, project),
+        None => (normalize_stream(&params.stream), String::new()),
+    };
+    record_project(&stream, &project_name);
+    Span::current().record("change", params.change);
+    let project_id = storage.get_or_add_project(&stream, &project_name).await?;
+
+    let update = UserEventUpdate {
+        synced: params.synced.unwrap_or_default(),
+        vote: params.vote,
+        investigating: params.investigating,
+        starred: params.starred,
+        comment: params.comment,
+    };
+    let sequence = storage
+        .update_user_event(project_id, params.change, &params.user_name, update)
+        .await?;
+    notifications.publish(&stream, &project_name, sequence);
+
+    Ok(StatusCode::OK.into_response())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IssueIndexParams {
+    user: Option<String>,
+    #[serde(rename = "includeresolved")]
+    include_resolved: Option<bool>,
+    #[serde(rename = "maxresults")]
+    max_results: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IssueShowParams {
+    user: Option<String>,
+}
+
+fn issue_response(issue: Issue, streams: Vec<String>) -> IssueResponse {
+    IssueResponse {
+        id: issue.id,
+        created_at: issue.created_at,
+        retrieved_at: chrono::Utc::now(),
+        project: issue.project,
+        summary: issue.summary,
+        owner: issue.owner,
+        nominated_by: issue.nominated_by,
+        acknowledged_at: issue.acknowledged_at,
+        fix_change: issue.fix_change,
+        resolved_at: issue.resolved_at,
+        notify: issue.notify,
+        is_warning: issue.is_warning,
+        build_url: issue.build_url,
+        streams,
+    }
+}
+
+/// Handler for GET /issues, lists the open (and optionally resolved) issues
+pub async fn issue_index(
+    Extension(storage): Extension<Arc<dyn Storage>>,
+    params: Query<IssueIndexParams>,
+) -> Result<impl IntoResponse, AppError> {
+    // A negative limit is no limit, which matches what UGS means by `maxresults=-1`
+    let issues = storage
+        .list_issues(
+            params.user.as_deref(),
+            params.include_resolved.unwrap_or_default(),
+            params.max_results.unwrap_or(-1),
+        )
+        .await?;
+
+    let issue_ids = issues.iter().map(|issue| issue.id).collect::<Vec<_>>();
+    let mut streams = storage.get_issue_streams(&issue_ids).await?;
+
+    let response = issues
+        .into_iter()
+        .map(|issue| {
+            let issue_streams = streams.remove(&issue.id).unwrap_or_default();
+            issue_response(issue, issue_streams)
+        })
+        .collect::<Vec<_>>();
+
+    Ok(Json(response))
+}
+
+/// Handler for POST /issues, creates a new issue and returns its ID
+pub async fn issue_create(
+    Extension(storage): Extension<Arc<dyn Storage>>,
+    Json(issue): Json<CreateIssue>,
+) -> Result<impl IntoResponse, AppError> {
+    debug!("POST /issues request: {:?}", issue);
+
+    let id = storage.add_issue(&issue, chrono::Utc::now()).await?;
+
+    Ok(Json(CreatedIdResponse { id }))
+}
+
+/// Handler for GET /issues/:id
+pub async fn issue_show(
+    Extension(storage): Extension<Arc<dyn Storage>>,
+    Path(issue_id): Path<i64>,
+    params: Query<IssueShowParams>,
+) -> Result<Response, AppError> {
+    let Some(issue) = storage.get_issue(issue_id, params.user.as_deref()).await? else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
 
-    let stream = normalize_stream(&params.stream);
-    let project_name = params
-        .project
-        .map(|p| normalize_project_name(&p))
+    let streams = storage
+        .get_issue_streams(&[issue_id])
+        .await?
+        .remove(&issue_id)
         .unwrap_or_default();
-    let project_id = get_or_add_project(&pool, &stream, &project_name).await?;
-    let existing_event_query_string =
-        "SELECT * FROM user_events WHERE project_id = ? AND user_name = ? AND change_number = ?";
-    let existing_event_query =
-        sqlx::query_as::<sqlx::Sqlite, UserEvent>(existing_event_query_string)
-            .bind(project_id)
-            .bind(&params.user_name)
-            .bind(params.change);
-    let user_event = existing_event_query.fetch_optional(&pool).await?;
-
-    let needs_insert = user_event.is_none();
-
-    let mut user_event = user_event.unwrap_or_else(UserEvent::default);
-    if params.synced.unwrap_or_default() {
-        user_event.synced_at = Some(now);
+
+    Ok(Json(issue_response(issue, streams)).into_response())
+}
+
+/// Handler for PUT /issues/:id, applies any fields that are set in the update
+pub async fn issue_update(
+    Extension(storage): Extension<Arc<dyn Storage>>,
+    Path(issue_id): Path<i64>,
+    Json(update): Json<UpdateIssue>,
+) -> Result<Response, AppError> {
+    debug!("PUT /issues/{} request: {:?}", issue_id, update);
+
+    let Some(mut issue) = storage.get_issue(issue_id, None).await? else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+
+    let now = chrono::Utc::now();
+    if let Some(summary) = update.summary {
+        issue.summary = summary;
+    }
+    if let Some(owner) = update.owner {
+        // A new owner needs to acknowledge the issue themselves
+        if issue.owner.as_deref() != Some(owner.as_str()) {
+            issue.acknowledged_at = None;
+        }
+        issue.owner = Some(owner).filter(|owner| !owner.is_empty());
+    }
+    if let Some(nominated_by) = update.nominated_by {
+        issue.nominated_by = Some(nominated_by).filter(|nominated_by| !nominated_by.is_empty());
+    }
+    match update.acknowledged {
+        Some(true) => issue.acknowledged_at = issue.acknowledged_at.or(Some(now)),
+        Some(false) => issue.acknowledged_at = None,
+        None => {}
+    }
+    if let Some(fix_change) = update.fix_change {
+        issue.fix_change = fix_change;
+    }
+    match update.resolved {
+        Some(true) => issue.resolved_at = issue.resolved_at.or(Some(now)),
+        Some(false) => issue.resolved_at = None,
+        None => {}
     }
 
-    user_event.vote = params.vote.or(user_event.vote);
-    user_event.investigating = params.investigating.or(user_event.investigating);
-    user_event.starred = params.starred.or(user_event.starred);
-    user_event.comment = params.comment.or(user_event.comment);
+    storage.update_issue(&issue).await?;
 
-    if needs_insert {
-        sqlx::query!(
-            "INSERT INTO user_events (project_id, change_number, user_name, sequence, updated_at, synced_at, vote, investigating, starred, comment) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
-            project_id,
-            params.change,
-            params.user_name,
-            sequence_number,
-            now,
-            user_event.synced_at,
-            user_event.vote,
-            user_event.investigating,
-            user_event.starred,
-            user_event.comment,
-        ).execute(&pool).await?;
-    } else {
-        sqlx::query!(
-            "UPDATE user_events SET sequence = ?, updated_at = ?, synced_at = ?, vote = ?, investigating = ?, starred = ?, comment = ? WHERE id = ?",
-            sequence_number,
-            now,
-            user_event.synced_at,
-            user_event.vote,
-            user_event.investigating,
-            user_event.starred,
-            user_event.comment,
-            user_event.id,
-        ).execute(&pool).await?;
+    Ok(StatusCode::OK.into_response())
+}
+
+/// Handler for GET /issues/:id/builds
+pub async fn issue_build_index(
+    Extension(storage): Extension<Arc<dyn Storage>>,
+    Path(issue_id): Path<i64>,
+) -> Result<Response, AppError> {
+    if !storage.issue_exists(issue_id).await? {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+
+    let builds = storage.list_issue_builds(issue_id).await?;
+
+    let response = builds
+        .into_iter()
+        .map(IssueBuildData::from)
+        .collect::<Vec<_>>();
+    Ok(Json(response).into_response())
+}
+
+/// Handler for POST /issues/:id/builds, associates a build with an issue and returns the build's ID
+pub async fn issue_build_create(
+    Extension(storage): Extension<Arc<dyn Storage>>,
+    Path(issue_id): Path<i64>,
+    Json(build): Json<IssueBuildData>,
+) -> Result<Response, AppError> {
+    debug!("POST /issues/{}/builds request: {:?}", issue_id, build);
+
+    if !storage.issue_exists(issue_id).await? {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+
+    let build = IssueBuildData {
+        stream: normalize_stream(&build.stream),
+        ..build
+    };
+    let id = storage.add_issue_build(issue_id, &build).await?;
+
+    Ok(Json(CreatedIdResponse { id }).into_response())
+}
+
+/// Handler for GET /issues/:id/diagnostics
+pub async fn issue_diagnostic_index(
+    Extension(storage): Extension<Arc<dyn Storage>>,
+    Path(issue_id): Path<i64>,
+) -> Result<Response, AppError> {
+    if !storage.issue_exists(issue_id).await? {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+
+    let diagnostics = storage.list_issue_diagnostics(issue_id).await?;
+
+    Ok(Json(diagnostics).into_response())
+}
+
+/// Handler for POST /issues/:id/diagnostics
+pub async fn issue_diagnostic_create(
+    Extension(storage): Extension<Arc<dyn Storage>>,
+    Path(issue_id): Path<i64>,
+    Json(diagnostic): Json<IssueDiagnosticData>,
+) -> Result<Response, AppError> {
+    if !storage.issue_exists(issue_id).await? {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+
+    storage.add_issue_diagnostic(issue_id, &diagnostic).await?;
+
+    Ok(StatusCode::OK.into_response())
+}
+
+/// Handler for GET /issues/:id/watchers, lists the names of the users watching the issue
+pub async fn issue_watcher_index(
+    Extension(storage): Extension<Arc<dyn Storage>>,
+    Path(issue_id): Path<i64>,
+) -> Result<Response, AppError> {
+    if !storage.issue_exists(issue_id).await? {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+
+    let watchers = storage.list_issue_watchers(issue_id).await?;
+
+    Ok(Json(watchers).into_response())
+}
+
+/// Handler for POST /issues/:id/watchers, starts or stops watching an issue
+pub async fn issue_watcher_update(
+    Extension(storage): Extension<Arc<dyn Storage>>,
+    Extension(principal): Extension<Principal>,
+    Extension(user_name_policy): Extension<Arc<UserNamePolicy>>,
+    Path(issue_id): Path<i64>,
+    Json(watcher): Json<IssueWatcherData>,
+) -> Result<Response, AppError> {
+    if !user_name_policy
+        .allows(&principal, &watcher.user_name)
+        .await?
+    {
+        return Ok(StatusCode::FORBIDDEN.into_response());
+    }
+
+    if !storage.issue_exists(issue_id).await? {
+        return Ok(StatusCode::NOT_FOUND.into_response());
     }
 
+    storage
+        .set_issue_watcher(issue_id, &watcher.user_name, watcher.watch)
+        .await?;
+
+    Ok(StatusCode::OK.into_response())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TelemetrySubmitParams {
+    #[serde(rename = "Version", alias = "version")]
+    version: Option<String>,
+    #[serde(rename = "IpAddress", alias = "ipaddress", alias = "ipAddress")]
+    ip_address: Option<String>,
+}
+
+/// Handler for POST /telemetry, records how long an action (e.g. a sync or a build) took for a user
+pub async fn telemetry_submit(
+    Extension(storage): Extension<Arc<dyn Storage>>,
+    params: Query<TelemetrySubmitParams>,
+    Json(timing): Json<TelemetryTimingData>,
+) -> Result<impl IntoResponse, AppError> {
+    debug!("POST /telemetry request: {:?}", timing);
+
+    storage
+        .add_telemetry_timing(
+            chrono::Utc::now(),
+            &timing,
+            params.version.as_deref(),
+            params.ip_address.as_deref(),
+        )
+        .await?;
+
+    Ok((StatusCode::OK, ""))
+}
+
+/// Handler for POST /error, records an error or crash reported by a UGS client
+pub async fn error_submit(
+    Extension(storage): Extension<Arc<dyn Storage>>,
+    params: Query<TelemetrySubmitParams>,
+    Json(mut error): Json<TelemetryErrorData>,
+) -> Result<impl IntoResponse, AppError> {
+    debug!("POST /error request: {:?}", error);
+
+    let TelemetrySubmitParams {
+        version,
+        ip_address,
+    } = params.0;
+    error.version = error.version.or(version);
+    error.ip_address = error.ip_address.or(ip_address);
+    storage
+        .add_telemetry_error(chrono::Utc::now(), &error)
+        .await?;
+
     Ok((StatusCode::OK, ""))
 }
+
+/// How many timings or errors GET /telemetry & /error list by default, and at most
+const DEFAULT_TELEMETRY_RECORDS: i64 = 100;
+const MAX_TELEMETRY_RECORDS: i64 = 1000;
+
+#[derive(Debug, Deserialize)]
+pub struct TelemetryIndexParams {
+    action: Option<String>,
+    project: Option<String>,
+    user: Option<String>,
+    since: Option<chrono::DateTime<chrono::Utc>>,
+    records: Option<i64>,
+}
+
+impl TelemetryIndexParams {
+    /// How many records to list, within what we're willing to send in one response
+    fn records(&self) -> i64 {
+        self.records
+            .unwrap_or(DEFAULT_TELEMETRY_RECORDS)
+            .clamp(1, MAX_TELEMETRY_RECORDS)
+    }
+}
+
+impl From<&TelemetryIndexParams> for TelemetryFilter {
+    fn from(params: &TelemetryIndexParams) -> Self {
+        Self {
+            action: params.action.clone(),
+            project: params.project.clone(),
+            user: params.user.clone(),
+            since: params.since,
+        }
+    }
+}
+
+/// Handler for GET /telemetry, summarizes the recorded timings per action and lists the most
+/// recent ones
+pub async fn telemetry_index(
+    Extension(storage): Extension<Arc<dyn Storage>>,
+    params: Query<TelemetryIndexParams>,
+) -> Result<impl IntoResponse, AppError> {
+    let filter = TelemetryFilter::from(&*params);
+
+    let summary = storage.telemetry_summary(&filter).await?;
+    let timings = storage
+        .list_telemetry_timings(&filter, params.records())
+        .await?;
+
+    Ok(Json(TelemetryResponse { summary, timings }))
+}
+
+/// Handler for GET /error, lists the most recent errors reported by UGS clients
+pub async fn error_index(
+    Extension(storage): Extension<Arc<dyn Storage>>,
+    params: Query<TelemetryIndexParams>,
+) -> Result<impl IntoResponse, AppError> {
+    let filter = TelemetryFilter::from(&*params);
+
+    let errors = storage
+        .list_telemetry_errors(&filter, params.records())
+        .await?;
+
+    Ok(Json(errors))
+}
@@ -1,4 +1,17 @@
+pub mod app;
+pub mod auth;
+pub mod backup;
+pub mod config;
 pub mod error;
+pub mod export;
 pub mod handlers;
+pub mod metadata_server;
+pub mod metrics;
 pub mod middleware;
 pub mod models;
+pub mod notifications;
+pub mod otel;
+pub mod paths;
+pub mod retention;
+pub mod storage;
+pub mod tls;
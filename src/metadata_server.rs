@@ -0,0 +1,500 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    export::ImportSummary,
+    models::{Badge, BadgeResult, EventType, UserEvent},
+    paths::ProjectPaths,
+    storage::{ImportOutcome, Storage, UserEventUpdate},
+};
+
+/// The names (and numbers, by index) of `EventType` that Epic's MetadataServer stores as a vote's
+/// `Verdict` or an event's `Type`
+const EVENT_TYPES: [(&str, EventType); 10] = [
+    ("Syncing", EventType::Syncing),
+    ("Compiles", EventType::Compiles),
+    ("DoesNotCompile", EventType::DoesNotCompile),
+    ("Good", EventType::Good),
+    ("Bad", EventType::Bad),
+    ("Unknown", EventType::Unknown),
+    ("Starred", EventType::Starred),
+    ("Unstarred", EventType::Unstarred),
+    ("Investigating", EventType::Investigating),
+    ("Resolved", EventType::Resolved),
+];
+
+/// The names (and numbers, by index) of `BadgeResult` that Epic's MetadataServer stores as a
+/// badge's `Result`
+const BADGE_RESULTS: [(&str, BadgeResult); 5] = [
+    ("Starting", BadgeResult::Starting),
+    ("Failure", BadgeResult::Failure),
+    ("Warning", BadgeResult::Warning),
+    ("Success", BadgeResult::Success),
+    ("Skipped", BadgeResult::Skipped),
+];
+
+/// A row of a table dump, by lowercase column name. NULL values are left out.
+type Row = HashMap<String, String>;
+
+/// A table dump's rows, in the order MetadataServer added them, with the row number each had in
+/// the dump
+struct Table {
+    name: String,
+    rows: Vec<(usize, Row)>,
+}
+
+impl Table {
+    /// Why we couldn't import row `row_number`
+    fn skip(&self, row_number: usize, reason: impl std::fmt::Display) -> String {
+        format!("{} row {row_number}: {reason}", self.name)
+    }
+}
+
+/// Split a CSV file (RFC 4180, as written by e.g. MySQL Workbench or `sqlite3 -csv`) into records
+fn parse_csv(text: &str) -> Result<Vec<Vec<String>>> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if in_quotes {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' => in_quotes = false,
+                _ => field.push(c),
+            }
+            continue;
+        }
+
+        match c {
+            '"' => in_quotes = true,
+            ',' => record.push(std::mem::take(&mut field)),
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\n' => {
+                record.push(std::mem::take(&mut field));
+                records.push(std::mem::take(&mut record));
+            }
+            _ => field.push(c),
+        }
+    }
+    if in_quotes {
+        anyhow::bail!("A quoted field is never closed");
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+
+    // Skip blank lines
+    records.retain(|record| record.len() > 1 || record.first().is_some_and(|f| !f.is_empty()));
+    Ok(records)
+}
+
+/// Read the rows of a CSV dump, whose first record names the columns
+fn read_csv(text: &str) -> Result<Vec<Row>> {
+    let mut records = parse_csv(text)?.into_iter();
+    let Some(columns) = records.next() else {
+        return Ok(Vec::new());
+    };
+    let columns = columns
+        .iter()
+        .map(|column| column.trim().to_lowercase())
+        .collect::<Vec<_>>();
+
+    Ok(records
+        .map(|record| {
+            columns
+                .iter()
+                .cloned()
+                .zip(record)
+                .filter(|(_, value)| value != "\\N" && value != "NULL")
+                .collect()
+        })
+        .collect())
+}
+
+/// Read the rows of a JSON dump, which is either an array of objects or one object per line
+fn read_json(text: &str) -> Result<Vec<Row>> {
+    let objects = if text.trim_start().starts_with('[') {
+        serde_json::from_str::<Vec<serde_json::Map<String, serde_json::Value>>>(text)?
+    } else {
+        text.lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?
+    };
+
+    Ok(objects
+        .into_iter()
+        .map(|object| {
+            object
+                .into_iter()
+                .filter_map(|(column, value)| {
+                    let value = match value {
+                        serde_json::Value::Null => return None,
+                        serde_json::Value::String(value) => value,
+                        value => value.to_string(),
+                    };
+                    Some((column.to_lowercase(), value))
+                })
+                .collect()
+        })
+        .collect())
+}
+
+/// The dumps in `directory`, by lowercase file name
+fn list_dumps(directory: &Path) -> Result<HashMap<String, PathBuf>> {
+    let entries = std::fs::read_dir(directory)
+        .with_context(|| format!("Could not list {}", directory.display()))?;
+    let mut dumps = HashMap::new();
+    for entry in entries {
+        let entry = entry?;
+        if let Some(file_name) = entry.file_name().to_str() {
+            dumps.insert(file_name.to_lowercase(), entry.path());
+        }
+    }
+    Ok(dumps)
+}
+
+/// Read the dump of the first of the tables in `names` that has a `.csv` or `.json` dump in
+/// `dumps`, with its rows ordered by `Id` if it has one
+fn read_table(dumps: &HashMap<String, PathBuf>, names: &[&str]) -> Result<Option<Table>> {
+    for name in names {
+        for (extension, read) in [
+            ("csv", read_csv as fn(&str) -> Result<Vec<Row>>),
+            ("json", read_json),
+        ] {
+            let Some(path) = dumps.get(&format!("{}.{extension}", name.to_lowercase())) else {
+                continue;
+            };
+            let text = std::fs::read_to_string(path)
+                .with_context(|| format!("Could not read {}", path.display()))?;
+            let rows =
+                read(&text).with_context(|| format!("Could not parse {}", path.display()))?;
+
+            let mut rows = rows
+                .into_iter()
+                .enumerate()
+                .map(|(index, row)| (index + 1, row))
+                .collect::<Vec<_>>();
+            rows.sort_by_key(|(_, row)| {
+                row.get("id")
+                    .and_then(|id| id.parse::<i64>().ok())
+                    .unwrap_or(i64::MAX)
+            });
+
+            return Ok(Some(Table {
+                name: name.to_string(),
+                rows,
+            }));
+        }
+    }
+    Ok(None)
+}
+
+/// The value of the first of `columns` that `row` has
+fn column<'a>(row: &'a Row, columns: &[&str]) -> Option<&'a str> {
+    columns
+        .iter()
+        .find_map(|column| row.get(*column))
+        .map(String::as_str)
+}
+
+fn required<'a>(row: &'a Row, columns: &[&str]) -> Result<&'a str, String> {
+    column(row, columns).ok_or_else(|| format!("No {} column", columns[0]))
+}
+
+fn change_number(row: &Row) -> Result<i64, String> {
+    let change = required(row, &["changenumber", "changelist", "change"])?;
+    change
+        .trim()
+        .parse()
+        .map_err(|_| format!("{change:?} isn't a change number"))
+}
+
+/// Look a name (case-insensitively) or number up in a table like `EVENT_TYPES`
+fn lookup<T: Copy>(values: &[(&str, T)], value: &str) -> Option<T> {
+    let value = value.trim();
+    match value.parse::<usize>() {
+        Ok(index) => values.get(index).map(|(_, value)| *value),
+        Err(_) => values
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(value))
+            .map(|(_, value)| *value),
+    }
+}
+
+/// When the row was added, if the dump has that. MySQL dumps these as `2020-01-02 03:04:05` in UTC.
+fn timestamp(row: &Row) -> Result<Option<DateTime<Utc>>, String> {
+    let Some(value) = column(row, &["timestamp", "time", "createdat", "addedat"]) else {
+        return Ok(None);
+    };
+    let value = value.trim();
+    DateTime::parse_from_rfc3339(value)
+        .map(|timestamp| timestamp.with_timezone(&Utc))
+        .or_else(|_| {
+            NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S%.f").map(|t| t.and_utc())
+        })
+        .map(Some)
+        .map_err(|_| format!("{value:?} isn't a timestamp"))
+}
+
+/// The stream & project of a row, split by `paths`. The row either has the project's
+/// `//depot/stream/project` path or the `ProjectId` of a row in the `Projects` table.
+fn project(
+    row: &Row,
+    projects: &HashMap<String, String>,
+    paths: &ProjectPaths,
+) -> Result<(String, String), String> {
+    let path = match column(row, &["projectid"]) {
+        Some(project_id) => projects
+            .get(project_id.trim())
+            .ok_or_else(|| format!("There's no project with ID {project_id}"))?,
+        None => required(row, &["project"])?,
+    };
+    paths
+        .split(path)
+        .ok_or_else(|| format!("{path} is not a //depot/stream/project path"))
+}
+
+/// The badges & user events we read for a project
+#[derive(Default)]
+struct ProjectMetadata {
+    badges: Vec<Badge>,
+    /// By change & lowercase user name, since user names are case-insensitive
+    user_events: BTreeMap<(i64, String), UserEvent>,
+}
+
+/// Fold MetadataServer's votes, events & comments into user events. Rows without a timestamp are
+/// treated as having happened at `imported_at`.
+fn add_user_event_rows(
+    table: &Table,
+    projects: &HashMap<String, String>,
+    paths: &ProjectPaths,
+    imported_at: DateTime<Utc>,
+    metadata: &mut BTreeMap<(String, String), ProjectMetadata>,
+    skipped: &mut Vec<String>,
+) {
+    for (row_number, row) in &table.rows {
+        let parsed = (|| {
+            let project = project(row, projects, paths)?;
+            let change_number = change_number(row)?;
+            let user_name = required(row, &["username", "user"])?.trim();
+            let update = match column(row, &["text", "comment"]) {
+                Some(text) => UserEventUpdate {
+                    comment: Some(text.to_string()),
+                    ..Default::default()
+                },
+                None => {
+                    let verdict = required(row, &["verdict", "type", "eventtype"])?;
+                    let event_type = lookup(&EVENT_TYPES, verdict)
+                        .ok_or_else(|| format!("{verdict:?} isn't an event type"))?;
+                    UserEventUpdate::from(event_type)
+                }
+            };
+            Ok::<_, String>((
+                project,
+                change_number,
+                user_name.to_string(),
+                update,
+                timestamp(row)?.unwrap_or(imported_at),
+            ))
+        })();
+        let (project, change_number, user_name, update, timestamp) = match parsed {
+            Ok(parsed) => parsed,
+            Err(reason) => {
+                skipped.push(table.skip(*row_number, reason));
+                continue;
+            }
+        };
+
+        let user_event = metadata
+            .entry(project)
+            .or_default()
+            .user_events
+            .entry((change_number, user_name.to_lowercase()))
+            .or_insert_with(|| UserEvent {
+                change_number,
+                user_name,
+                ..Default::default()
+            });
+        update.apply_to(user_event, timestamp);
+        user_event.updated_at = user_event.updated_at.max(timestamp);
+    }
+}
+
+/// Whether two user events say the same thing, regardless of when they said it. MetadataServer
+/// doesn't record when most things happened, so we can't compare the timestamps.
+fn same_state(a: &UserEvent, b: &UserEvent) -> bool {
+    a.synced_at.is_some() == b.synced_at.is_some()
+        && a.vote == b.vote
+        && a.investigating == b.investigating
+        && a.starred == b.starred
+        && a.comment == b.comment
+}
+
+/// Import a dump of Epic's MetadataServer database from `directory`, which has a `.csv` or `.json`
+/// file for each table (`Projects`, `Badges` or `Builds`, `UserVotes`, `Events` & `Comments`).
+/// Projects are split by `paths` the same way the API splits them, votes, events & comments are folded into one
+/// user event per user & change, and rows without a timestamp are treated as having happened at
+/// `imported_at`. Importing the same dump again doesn't change anything.
+pub async fn import_metadata_server(
+    storage: &dyn Storage,
+    paths: &ProjectPaths,
+    directory: &Path,
+    imported_at: DateTime<Utc>,
+) -> Result<ImportSummary> {
+    let dumps = list_dumps(directory)?;
+    let mut summary = ImportSummary::default();
+
+    let mut projects = HashMap::new();
+    if let Some(table) = read_table(&dumps, &["Projects"])? {
+        for (row_number, row) in &table.rows {
+            match (column(row, &["id"]), column(row, &["name", "project"])) {
+                (Some(id), Some(name)) => {
+                    projects.insert(id.trim().to_string(), name.to_string());
+                }
+                _ => summary
+                    .skipped
+                    .push(table.skip(*row_number, "Projects need an Id and a Name")),
+            }
+        }
+    }
+
+    let mut metadata = BTreeMap::<(String, String), ProjectMetadata>::new();
+    let badges = read_table(&dumps, &["Badges", "Builds", "CIS"])?;
+    if let Some(table) = &badges {
+        for (index, (row_number, row)) in table.rows.iter().enumerate() {
+            let parsed = (|| {
+                let project = project(row, &projects, paths)?;
+                let result = required(row, &["result", "state"])?;
+                let badge = Badge {
+                    project_id: 0,
+                    sequence: 0,
+                    change_number: change_number(row)?,
+                    // Keep the badges in order even if they were all added at `imported_at`
+                    added_at: timestamp(row)?
+                        .unwrap_or(imported_at + Duration::microseconds(index as i64)),
+                    build_type: required(row, &["buildtype", "name"])?.to_string(),
+                    result: lookup(&BADGE_RESULTS, result)
+                        .ok_or_else(|| format!("{result:?} isn't a badge result"))?,
+                    url: column(row, &["url"]).unwrap_or_default().to_string(),
+                    posted_by: None,
+                };
+                Ok::<_, String>((project, badge))
+            })();
+            match parsed {
+                Ok((project, badge)) => metadata.entry(project).or_default().badges.push(badge),
+                Err(reason) => summary.skipped.push(table.skip(*row_number, reason)),
+            }
+        }
+    }
+
+    let user_event_tables = [
+        read_table(&dumps, &["UserVotes"])?,
+        read_table(&dumps, &["Events"])?,
+        read_table(&dumps, &["Comments"])?,
+    ];
+    if badges.is_none() && user_event_tables.iter().all(Option::is_none) {
+        anyhow::bail!(
+            "{} has no Badges, UserVotes, Events or Comments table dumps",
+            directory.display()
+        );
+    }
+    for table in user_event_tables.iter().flatten() {
+        add_user_event_rows(
+            table,
+            &projects,
+            paths,
+            imported_at,
+            &mut metadata,
+            &mut summary.skipped,
+        );
+    }
+
+    for ((stream, project), project_metadata) in metadata {
+        let project_id = match storage.get_project(&stream, &project).await? {
+            Some(project_id) => project_id,
+            None => {
+                summary.projects_added += 1;
+                storage.get_or_add_project(&stream, &project).await?
+            }
+        };
+
+        // Skip what an earlier import already added, comparing everything but the timestamps
+        let mut existing_badges = HashMap::<_, usize>::new();
+        let mut existing_user_events = HashMap::new();
+        for change in storage
+            .list_metadata(&stream, Some(&project), 0, i64::MIN, None)
+            .await?
+        {
+            for badge in change.badges {
+                *existing_badges
+                    .entry((
+                        badge.change_number,
+                        badge.build_type,
+                        badge.result as i32,
+                        badge.url,
+                    ))
+                    .or_default() += 1;
+            }
+            for user_event in change.user_events {
+                existing_user_events.insert(
+                    (
+                        user_event.change_number,
+                        user_event.user_name.to_lowercase(),
+                    ),
+                    user_event,
+                );
+            }
+        }
+
+        for badge in project_metadata.badges {
+            let key = (
+                badge.change_number,
+                badge.build_type.clone(),
+                badge.result as i32,
+                badge.url.clone(),
+            );
+            if let Some(count) = existing_badges.get_mut(&key).filter(|count| **count > 0) {
+                *count -= 1;
+                summary.badges_unchanged += 1;
+                continue;
+            }
+            match storage.import_badge(project_id, &badge).await? {
+                ImportOutcome::Added => summary.badges_added += 1,
+                ImportOutcome::Unchanged | ImportOutcome::Conflict => summary.badges_unchanged += 1,
+            }
+        }
+
+        for (key, user_event) in project_metadata.user_events {
+            if existing_user_events
+                .get(&key)
+                .is_some_and(|existing| same_state(existing, &user_event))
+            {
+                summary.user_events_unchanged += 1;
+                continue;
+            }
+            match storage.import_user_event(project_id, &user_event).await? {
+                ImportOutcome::Added => summary.user_events_added += 1,
+                ImportOutcome::Unchanged => summary.user_events_unchanged += 1,
+                ImportOutcome::Conflict => summary.conflicts.push(format!(
+                    "{}'s user event for change {} in {stream}/{project} differs from the existing one, which we kept",
+                    user_event.user_name, user_event.change_number
+                )),
+            }
+        }
+    }
+
+    Ok(summary)
+}
@@ -0,0 +1,215 @@
+use axum::{extract::MatchedPath, http::Request, middleware::Next, response::Response, Extension};
+use prometheus_client::{
+    encoding::EncodeLabelSet,
+    metrics::{
+        counter::Counter,
+        family::Family,
+        gauge::Gauge,
+        histogram::{exponential_buckets, Histogram},
+    },
+    registry::Registry,
+};
+
+use std::{
+    sync::{atomic::AtomicU64, Arc},
+    time::{Duration, Instant},
+};
+
+/// Histograms need a constructor to pick their buckets, and this lets us name the family's type
+type HistogramFamily<Labels> = Family<Labels, Histogram, fn() -> Histogram>;
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct RequestLabels {
+    method: String,
+    route: String,
+    status: u16,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct RouteLabels {
+    method: String,
+    route: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct AuthFailureLabels {
+    scope: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct BadgeLabels {
+    project: String,
+    build_type: String,
+    result: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct QueryLabels {
+    operation: String,
+}
+
+/// The metrics we expose in the Prometheus text format at `/metrics`
+#[derive(Debug)]
+pub struct Metrics {
+    registry: Registry,
+
+    // These are only kept for the legacy JSON at `/api/rugs_metrics`
+    pub latest_requests: AtomicU64,
+    pub build_create_requests: AtomicU64,
+    pub metadata_index_requests: AtomicU64,
+    pub metadata_submit_requests: AtomicU64,
+
+    http_requests: Family<RequestLabels, Counter>,
+    http_request_duration: HistogramFamily<RouteLabels>,
+    auth_failures: Family<AuthFailureLabels, Counter>,
+    badges: Family<BadgeLabels, Counter>,
+    database_query_duration: HistogramFamily<QueryLabels>,
+    database_query_errors: Family<QueryLabels, Counter>,
+    database_size: Gauge,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        // 1ms to ~16s
+        let histogram: fn() -> Histogram = || Histogram::new(exponential_buckets(0.001, 2.0, 15));
+
+        let mut metrics = Self {
+            registry: Registry::with_prefix("rugs"),
+            latest_requests: Default::default(),
+            build_create_requests: Default::default(),
+            metadata_index_requests: Default::default(),
+            metadata_submit_requests: Default::default(),
+            http_requests: Default::default(),
+            http_request_duration: Family::new_with_constructor(histogram),
+            auth_failures: Default::default(),
+            badges: Default::default(),
+            database_query_duration: Family::new_with_constructor(histogram),
+            database_query_errors: Default::default(),
+            database_size: Default::default(),
+        };
+
+        let registry = &mut metrics.registry;
+        registry.register(
+            "http_requests",
+            "HTTP requests handled, by route & status code",
+            metrics.http_requests.clone(),
+        );
+        registry.register(
+            "http_request_duration_seconds",
+            "How long it took to handle HTTP requests, by route",
+            metrics.http_request_duration.clone(),
+        );
+        registry.register(
+            "auth_failures",
+            "Requests denied because of missing or invalid credentials",
+            metrics.auth_failures.clone(),
+        );
+        registry.register(
+            "badges",
+            "Badges posted, by project, build type & result",
+            metrics.badges.clone(),
+        );
+        registry.register(
+            "database_query_duration_seconds",
+            "How long database operations took",
+            metrics.database_query_duration.clone(),
+        );
+        registry.register(
+            "database_query_errors",
+            "Database operations that failed",
+            metrics.database_query_errors.clone(),
+        );
+        registry.register(
+            "database_size_bytes",
+            "The size of the database, as of the last scrape",
+            metrics.database_size.clone(),
+        );
+
+        metrics
+    }
+}
+
+impl Metrics {
+    /// Record a request to `route` (the route's pattern, e.g. `/api/issues/:id`, so that we don't
+    /// get a new time series per issue)
+    pub fn observe_request(&self, method: &str, route: &str, status: u16, duration: Duration) {
+        self.http_requests
+            .get_or_create(&RequestLabels {
+                method: method.to_owned(),
+                route: route.to_owned(),
+                status,
+            })
+            .inc();
+        self.http_request_duration
+            .get_or_create(&RouteLabels {
+                method: method.to_owned(),
+                route: route.to_owned(),
+            })
+            .observe(duration.as_secs_f64());
+    }
+
+    /// Record a request that was denied for the routes that `scope` (e.g. "ci") protects
+    pub fn auth_failure(&self, scope: &str) {
+        self.auth_failures
+            .get_or_create(&AuthFailureLabels {
+                scope: scope.to_owned(),
+            })
+            .inc();
+    }
+
+    pub fn badge_posted(&self, project: &str, build_type: &str, result: &str) {
+        self.badges
+            .get_or_create(&BadgeLabels {
+                project: project.to_owned(),
+                build_type: build_type.to_owned(),
+                result: result.to_owned(),
+            })
+            .inc();
+    }
+
+    pub fn observe_query(&self, operation: &str, duration: Duration, succeeded: bool) {
+        let labels = QueryLabels {
+            operation: operation.to_owned(),
+        };
+        self.database_query_duration
+            .get_or_create(&labels)
+            .observe(duration.as_secs_f64());
+        if !succeeded {
+            self.database_query_errors.get_or_create(&labels).inc();
+        }
+    }
+
+    pub fn set_database_size(&self, bytes: u64) {
+        self.database_size.set(bytes.try_into().unwrap_or(i64::MAX));
+    }
+
+    /// All the metrics in the Prometheus text format
+    pub fn encode(&self) -> Result<String, std::fmt::Error> {
+        let mut buffer = String::new();
+        prometheus_client::encoding::text::encode(&mut buffer, &self.registry)?;
+        Ok(buffer)
+    }
+}
+
+/// Record how long each request took and what it returned. This needs to be added with
+/// `route_layer`, since the route pattern is only known once the request has been routed.
+pub async fn track_requests<B>(
+    Extension(metrics): Extension<Arc<Metrics>>,
+    matched_path: MatchedPath,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let method = req.method().clone();
+    let start = Instant::now();
+
+    let res = next.run(req).await;
+
+    metrics.observe_request(
+        method.as_str(),
+        matched_path.as_str(),
+        res.status().as_u16(),
+        start.elapsed(),
+    );
+
+    res
+}
@@ -1,6 +1,6 @@
 use axum::{
     body::{Body, Bytes},
-    http::{Request, StatusCode},
+    http::{header, Request, StatusCode},
     middleware::Next,
     response::{IntoResponse, Response},
 };
@@ -16,11 +16,20 @@ pub async fn print_request_response(
 
     let res = next.run(req).await;
 
+    // Streaming responses never finish, so we can't buffer them
+    let is_event_stream = res
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|content_type| content_type == "text/event-stream");
+    if is_event_stream {
+        return Ok(res);
+    }
+
     let (parts, body) = res.into_parts();
     let bytes = buffer_and_print("response", body).await?;
     let res = Response::from_parts(parts, Body::from(bytes));
 
-    Ok(res)
+    Ok(res.into_response())
 }
 
 async fn buffer_and_print<B>(direction: &str, body: B) -> Result<Bytes, (StatusCode, String)>
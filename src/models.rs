@@ -24,7 +24,7 @@ pub struct LatestResponseV1 {
     ToPrimitive,
     sqlx::Type,
 )]
-#[repr(u8)]
+#[repr(i32)]
 pub enum BadgeResult {
     Starting = 0,
     Failure = 1,
@@ -36,12 +36,21 @@ pub enum BadgeResult {
 #[derive(Clone, Debug, Serialize, Deserialize, sqlx::FromRow)]
 #[serde(rename_all = "PascalCase")]
 pub struct Badge {
+    pub project_id: i64,
     pub sequence: i64,
     pub change_number: i64,
     pub added_at: DateTime<Utc>,
     pub build_type: String,
     pub result: BadgeResult,
     pub url: String,
+    pub posted_by: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, sqlx::FromRow)]
+pub struct Project {
+    pub project_id: i64,
+    pub stream: String,
+    pub project: String,
 }
 
 /// This maps to `BuildData` in MetadataServer, `BadgeData` in UGS
@@ -56,7 +65,7 @@ pub struct CreateBadge {
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize, sqlx::Type)]
-#[repr(u8)]
+#[repr(i32)]
 pub enum UgsUserVote {
     None = 0,
     CompileSuccess = 1,
@@ -65,9 +74,51 @@ pub enum UgsUserVote {
     Bad = 4,
 }
 
+/// This maps to `EventType` in MetadataServer & UGS (used by v1 API clients)
+#[derive(Clone, Copy, PartialEq, Debug, Serialize_repr, Deserialize_repr)]
+#[repr(u8)]
+pub enum EventType {
+    Syncing = 0,
+    Compiles = 1,
+    DoesNotCompile = 2,
+    Good = 3,
+    Bad = 4,
+    Unknown = 5,
+    Starred = 6,
+    Unstarred = 7,
+    Investigating = 8,
+    Resolved = 9,
+}
+
+/// This maps to `EventData` in MetadataServer & UGS (used by v1 API clients)
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct EventData {
+    #[serde(default)]
+    pub id: i64,
+    pub change: i64,
+    pub user_name: String,
+    #[serde(rename = "Type")]
+    pub event_type: EventType,
+    pub project: String,
+}
+
+/// This maps to `CommentData` in MetadataServer & UGS (used by v1 API clients)
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct CommentData {
+    #[serde(default)]
+    pub id: i64,
+    pub change_number: i64,
+    pub user_name: String,
+    pub text: String,
+    pub project: String,
+}
+
 #[derive(Clone, Debug, Default, sqlx::FromRow)]
 pub struct UserEvent {
     pub id: i64,
+    pub project_id: i64,
     pub change_number: i64,
     pub user_name: String,
     pub sequence: i64,
@@ -123,3 +174,251 @@ pub struct GetMetadataListResponseV2 {
     pub sequence_number: i64,
     pub items: Vec<GetMetadataResponseV2>,
 }
+
+#[derive(Clone, Debug, sqlx::FromRow)]
+pub struct Issue {
+    pub id: i64,
+    pub created_at: DateTime<Utc>,
+    pub project: String,
+    pub summary: String,
+    pub owner: Option<String>,
+    pub nominated_by: Option<String>,
+    pub acknowledged_at: Option<DateTime<Utc>>,
+    pub fix_change: i64,
+    pub resolved_at: Option<DateTime<Utc>>,
+    pub is_warning: bool,
+    pub build_url: Option<String>,
+    /// Whether the user we're querying on behalf of is watching this issue
+    pub notify: bool,
+}
+
+/// This maps to `IssueData` in MetadataServer & UGS
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct IssueResponse {
+    pub id: i64,
+    pub created_at: DateTime<Utc>,
+    pub retrieved_at: DateTime<Utc>,
+    pub project: String,
+    pub summary: String,
+    pub owner: Option<String>,
+    pub nominated_by: Option<String>,
+    pub acknowledged_at: Option<DateTime<Utc>>,
+    pub fix_change: i64,
+    pub resolved_at: Option<DateTime<Utc>>,
+    #[serde(rename = "bNotify")]
+    pub notify: bool,
+    #[serde(rename = "bIsWarning")]
+    pub is_warning: bool,
+    pub build_url: Option<String>,
+    pub streams: Vec<String>,
+}
+
+/// This maps to the `IssueData` that UGS posts to create a new issue
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct CreateIssue {
+    pub project: String,
+    pub summary: String,
+    #[serde(default)]
+    pub owner: Option<String>,
+    #[serde(default)]
+    pub nominated_by: Option<String>,
+    #[serde(default, rename = "bIsWarning")]
+    pub is_warning: bool,
+    #[serde(default)]
+    pub build_url: Option<String>,
+}
+
+/// This maps to `IssueUpdateData` in MetadataServer & UGS
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct UpdateIssue {
+    #[serde(default)]
+    pub summary: Option<String>,
+    #[serde(default)]
+    pub owner: Option<String>,
+    #[serde(default)]
+    pub nominated_by: Option<String>,
+    #[serde(default)]
+    pub acknowledged: Option<bool>,
+    #[serde(default)]
+    pub fix_change: Option<i64>,
+    #[serde(default)]
+    pub resolved: Option<bool>,
+}
+
+/// This maps to the response of `POST /api/issues` and friends in MetadataServer
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct CreatedIdResponse {
+    pub id: i64,
+}
+
+/// This maps to `IssueBuildOutcome` in MetadataServer & UGS
+#[derive(
+    Clone,
+    Copy,
+    PartialEq,
+    Debug,
+    Default,
+    Serialize_repr,
+    Deserialize_repr,
+    FromPrimitive,
+    ToPrimitive,
+    sqlx::Type,
+)]
+#[repr(i32)]
+pub enum IssueBuildOutcome {
+    #[default]
+    Unknown = 0,
+    Success = 1,
+    Error = 2,
+    Warning = 3,
+}
+
+#[derive(Clone, Debug, sqlx::FromRow)]
+pub struct IssueBuild {
+    pub id: i64,
+    pub issue_id: i64,
+    pub stream: String,
+    pub change_number: i64,
+    pub job_name: String,
+    pub job_url: String,
+    pub job_step_name: String,
+    pub job_step_url: String,
+    pub error_url: Option<String>,
+    pub outcome: IssueBuildOutcome,
+}
+
+/// This maps to `IssueBuildData` in MetadataServer & UGS
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct IssueBuildData {
+    #[serde(default)]
+    pub id: i64,
+    pub stream: String,
+    pub change: i64,
+    pub job_name: String,
+    pub job_url: String,
+    pub job_step_name: String,
+    pub job_step_url: String,
+    #[serde(default)]
+    pub error_url: Option<String>,
+    #[serde(default)]
+    pub outcome: IssueBuildOutcome,
+}
+
+impl From<IssueBuild> for IssueBuildData {
+    fn from(build: IssueBuild) -> Self {
+        Self {
+            id: build.id,
+            stream: build.stream,
+            change: build.change_number,
+            job_name: build.job_name,
+            job_url: build.job_url,
+            job_step_name: build.job_step_name,
+            job_step_url: build.job_step_url,
+            error_url: build.error_url,
+            outcome: build.outcome,
+        }
+    }
+}
+
+/// This maps to `IssueDiagnosticData` in MetadataServer & UGS
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, sqlx::FromRow)]
+#[serde(rename_all = "PascalCase")]
+pub struct IssueDiagnosticData {
+    #[serde(default)]
+    pub build_id: Option<i64>,
+    pub message: String,
+    pub url: String,
+}
+
+/// This maps to `IssueWatcherData` in MetadataServer & UGS
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct IssueWatcherData {
+    pub user_name: String,
+    #[serde(rename = "bWatch")]
+    pub watch: bool,
+}
+
+/// C# serializes `DateTime` without an offset unless it's explicitly UTC, so this accepts both
+/// RFC 3339 timestamps and naive ones (which we assume are UTC).
+mod ugs_timestamp {
+    use chrono::{DateTime, NaiveDateTime, Utc};
+    use serde::{de::Error, Deserialize, Deserializer};
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<DateTime<Utc>, D::Error> {
+        let timestamp = String::deserialize(deserializer)?;
+        DateTime::parse_from_rfc3339(&timestamp)
+            .map(|timestamp| timestamp.with_timezone(&Utc))
+            .or_else(|_| {
+                NaiveDateTime::parse_from_str(&timestamp, "%Y-%m-%dT%H:%M:%S%.f")
+                    .map(|timestamp| timestamp.and_utc())
+            })
+            .map_err(D::Error::custom)
+    }
+}
+
+/// This maps to `TelemetryTimingData` in MetadataServer & UGS
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, sqlx::FromRow)]
+#[serde(rename_all = "PascalCase")]
+pub struct TelemetryTimingData {
+    pub action: String,
+    pub result: String,
+    pub user_name: String,
+    pub project: String,
+    #[serde(deserialize_with = "ugs_timestamp::deserialize")]
+    pub timestamp: DateTime<Utc>,
+    pub duration: f32,
+}
+
+/// This maps to `TelemetryErrorType` in MetadataServer & UGS
+#[derive(Clone, Copy, PartialEq, Debug, Default, Serialize_repr, Deserialize_repr, sqlx::Type)]
+#[repr(i32)]
+pub enum TelemetryErrorType {
+    #[default]
+    Crash = 0,
+}
+
+/// This maps to `TelemetryErrorData` in MetadataServer & UGS
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, sqlx::FromRow)]
+#[serde(rename_all = "PascalCase")]
+pub struct TelemetryErrorData {
+    #[serde(default)]
+    pub id: i64,
+    #[serde(default, rename = "Type")]
+    pub error_type: TelemetryErrorType,
+    pub text: String,
+    pub user_name: String,
+    pub project: String,
+    #[serde(deserialize_with = "ugs_timestamp::deserialize")]
+    pub timestamp: DateTime<Utc>,
+    #[serde(default)]
+    pub version: Option<String>,
+    #[serde(default)]
+    pub ip_address: Option<String>,
+}
+
+/// Aggregated durations for all telemetry timings with the same action & result
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, sqlx::FromRow)]
+#[serde(rename_all = "PascalCase")]
+pub struct TelemetryTimingSummary {
+    pub action: String,
+    pub result: String,
+    pub count: i64,
+    pub average_duration: f64,
+    pub min_duration: f64,
+    pub max_duration: f64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct TelemetryResponse {
+    pub summary: Vec<TelemetryTimingSummary>,
+    pub timings: Vec<TelemetryTimingData>,
+}
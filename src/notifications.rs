@@ -0,0 +1,66 @@
+use tokio::sync::watch;
+
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+
+/// How long requests wait to be notified of changes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NotificationSettings {
+    /// How long `/latest` waits for something newer than `since` if the request doesn't say
+    pub latest_default_wait: Duration,
+    /// The longest we'll hold a `/latest` request open for
+    pub latest_max_wait: Duration,
+    /// How often `/stream` checks for changes even if it hasn't been notified of any, so we see
+    /// changes made through other servers sharing the database
+    pub stream_poll_interval: Duration,
+}
+
+impl Default for NotificationSettings {
+    fn default() -> Self {
+        Self {
+            latest_default_wait: Duration::from_secs(30),
+            latest_max_wait: Duration::from_secs(60),
+            stream_poll_interval: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Lets requests wait for new badges or user events in a project without polling the database.
+/// Handlers publish the sequence number of anything they write, and anyone waiting on that project
+/// gets woken up.
+///
+/// This only knows about writes made through this server, so if several servers share a
+/// PostgreSQL database, waiters only see writes made through the other servers once they time out.
+#[derive(Debug, Default)]
+pub struct NotificationHub {
+    projects: Mutex<HashMap<(String, String), watch::Sender<i64>>>,
+}
+
+impl NotificationHub {
+    /// Let anyone waiting on the project know that something with `sequence` was written to it.
+    /// `stream` & `project` are expected to already be normalized.
+    pub fn publish(&self, stream: &str, project: &str, sequence: i64) {
+        let projects = self.projects.lock().unwrap();
+        if let Some(sender) = projects.get(&(stream.to_owned(), project.to_owned())) {
+            sender.send_if_modified(|latest| {
+                let is_newer = sequence > *latest;
+                if is_newer {
+                    *latest = sequence;
+                }
+                is_newer
+            });
+        }
+    }
+
+    /// Get notified of the sequence numbers published to the project from now on. The receiver
+    /// starts out with 0 rather than the latest sequence in the database, so callers should check
+    /// the database after subscribing.
+    pub fn subscribe(&self, stream: &str, project: &str) -> watch::Receiver<i64> {
+        let mut projects = self.projects.lock().unwrap();
+        // Forget about any projects that nobody is waiting on anymore
+        projects.retain(|_, sender| sender.receiver_count() > 0);
+        projects
+            .entry((stream.to_owned(), project.to_owned()))
+            .or_insert_with(|| watch::channel(0).0)
+            .subscribe()
+    }
+}
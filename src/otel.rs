@@ -0,0 +1,69 @@
+use anyhow::Result;
+use axum::http::{HeaderMap, Request};
+use opentelemetry::{
+    propagation::Extractor, propagation::TextMapPropagator, trace::TracerProvider as _, KeyValue,
+};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{
+    propagation::TraceContextPropagator,
+    runtime,
+    trace::{Tracer, TracerProvider},
+    Resource,
+};
+use tracing::{Span, Subscriber};
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::registry::LookupSpan;
+
+/// A layer that exports our spans to the OTLP/HTTP collector at `endpoint` (e.g.
+/// `http://localhost:4318`), and the provider behind it, which needs to be shut down before we exit
+/// to flush any spans that haven't been exported yet.
+pub fn otlp_layer<S>(endpoint: &str) -> Result<(OpenTelemetryLayer<S, Tracer>, TracerProvider)>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+        .build()?;
+    let provider = TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_resource(Resource::new([KeyValue::new("service.name", "rugs")]))
+        .build();
+    let tracer = provider.tracer("rugs");
+
+    Ok((tracing_opentelemetry::layer().with_tracer(tracer), provider))
+}
+
+/// Lets the propagator read the headers of our (`http` 0.2) requests
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+/// The span for an incoming request, which continues the trace from the W3C `traceparent` header
+/// if the caller sent one. This needs to run after `SetRequestIdLayer`, so that all the request's
+/// logs carry its `X-Request-Id`.
+pub fn request_span<B>(req: &Request<B>) -> Span {
+    let request_id = req
+        .headers()
+        .get("x-request-id")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let span = tracing::info_span!(
+        "request",
+        method = %req.method(),
+        uri = %req.uri(),
+        version = ?req.version(),
+        request_id,
+    );
+    let parent = TraceContextPropagator::new().extract(&HeaderExtractor(req.headers()));
+    span.set_parent(parent);
+    span
+}
@@ -0,0 +1,240 @@
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+use tracing::{error, info, warn};
+
+use std::collections::HashSet;
+
+use crate::storage::Storage;
+
+/// The `server_state` key of the fingerprint of the `ProjectPaths` the projects were last split with
+const PROJECT_PATHS_STATE: &str = "project_paths";
+
+/// How many levels below a depot its streams are, e.g. 2 for `//depot/branches/release` or 0 for a
+/// classic depot without branches
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DepotLayout {
+    /// The depot, e.g. `//depot`
+    pub depot: String,
+    pub stream_depth: usize,
+}
+
+/// How to split a `//depot/stream/project` path into its stream & project. By default a stream is
+/// one level below its depot, like in a Perforce stream depot.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ProjectPaths {
+    /// Streams that don't follow their depot's layout, e.g. `//depot/branches/release`. When a
+    /// path is in more than one of these, the longest one wins.
+    pub stream_roots: Vec<String>,
+    /// The depots whose streams aren't one level below them
+    pub depots: Vec<DepotLayout>,
+}
+
+impl ProjectPaths {
+    /// Take a //depot/stream/project path and try to split it into `//depot/stream` and `project`
+    pub fn split(&self, project_path: &str) -> Option<(String, String)> {
+        if !project_path.starts_with("//") {
+            return None;
+        }
+        let path = project_path.to_lowercase();
+
+        let stream_root = self
+            .stream_roots
+            .iter()
+            .map(|root| normalize_stream(root))
+            .filter(|root| {
+                path.strip_prefix(root.as_str())
+                    .is_some_and(|rest| rest.starts_with('/'))
+            })
+            .max_by_key(String::len);
+        let project_index = match stream_root {
+            Some(stream_root) => stream_root.len(),
+            None => {
+                let Some(depot_index) = find_starting_at(&path, '/', 2) else {
+                    error!("Could not find a stream name in {}", project_path);
+                    return None;
+                };
+                let stream_depth = self
+                    .depots
+                    .iter()
+                    .find(|layout| normalize_stream(&layout.depot) == path[..depot_index])
+                    .map_or(1, |layout| layout.stream_depth);
+
+                let mut project_index = depot_index;
+                for _ in 0..stream_depth {
+                    let Some(index) = find_starting_at(&path, '/', project_index + 1) else {
+                        error!(
+                            "Could not find a project name after stream name in {}",
+                            project_path
+                        );
+                        return None;
+                    };
+                    project_index = index;
+                }
+                project_index
+            }
+        };
+
+        if path.len() > project_index + 1 {
+            Some((
+                normalize_stream(&path[0..project_index]),
+                normalize_project_name(&path[project_index + 1..]),
+            ))
+        } else {
+            error!(
+                "Not enough characters after stream name in {}",
+                project_path
+            );
+            None
+        }
+    }
+
+    /// A hash of these settings, which changes whenever they could split a path differently
+    pub fn fingerprint(&self) -> String {
+        let mut stream_roots = self
+            .stream_roots
+            .iter()
+            .map(|root| normalize_stream(root))
+            .collect::<Vec<_>>();
+        stream_roots.sort();
+        stream_roots.dedup();
+
+        let mut hasher = Sha256::new();
+        for root in stream_roots {
+            hasher.update(format!("root {root}\n"));
+        }
+        for layout in &self.depots {
+            hasher.update(format!(
+                "depot {} {}\n",
+                normalize_stream(&layout.depot),
+                layout.stream_depth
+            ));
+        }
+        hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+
+    /// Split the `stream` & `project` that a client split itself (e.g. UGS' `/metadata` requests)
+    /// the same way as if it had sent us the whole path
+    pub fn resplit(&self, stream: &str, project: &str) -> (String, String) {
+        let stream = normalize_stream(stream);
+        self.split(&format!("{stream}/{project}"))
+            .unwrap_or_else(|| (stream, normalize_project_name(project)))
+    }
+}
+
+fn find_starting_at(haystack: &str, needle: char, starting_index: usize) -> Option<usize> {
+    if let Some(slice) = haystack.get(starting_index..) {
+        slice.find(needle).map(|i| i + starting_index)
+    } else {
+        None
+    }
+}
+
+pub fn normalize_stream(stream: &str) -> String {
+    let stream = stream.strip_suffix('/').unwrap_or(stream);
+    stream.to_lowercase()
+}
+
+pub fn normalize_project_name(project_name: &str) -> String {
+    project_name.to_lowercase()
+}
+
+/// A project whose path splits differently with the current `ProjectPaths`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProjectResplit {
+    pub from: (String, String),
+    pub to: (String, String),
+    /// Whether `to` already existed, so we merged the project into it rather than renaming it
+    pub merged: bool,
+}
+
+/// Whether the existing projects were split with different settings than `paths`, and need to be
+/// re-split with `resplit_projects`. Databases from before we kept track of this were split with the
+/// default settings, and a database without any projects doesn't need to be re-split.
+pub async fn needs_resplit(storage: &dyn Storage, paths: &ProjectPaths) -> Result<bool> {
+    let fingerprint = paths.fingerprint();
+    match storage.get_state(PROJECT_PATHS_STATE).await? {
+        Some(split_with) => Ok(split_with != fingerprint),
+        None if storage.list_projects().await?.is_empty() => Ok(false),
+        None => Ok(fingerprint != ProjectPaths::default().fingerprint()),
+    }
+}
+
+/// Re-split the existing projects with `paths` if they were split with different settings (see
+/// `needs_resplit`), and record that they're split with `paths` from now on. The server (and
+/// `migrate run`) does this when it starts, so that changing the `[paths]` settings doesn't split a project's history.
+pub async fn resplit_if_changed(
+    storage: &dyn Storage,
+    paths: &ProjectPaths,
+) -> Result<Vec<ProjectResplit>> {
+    if needs_resplit(storage, paths).await? {
+        return resplit_projects(storage, paths, false).await;
+    }
+
+    if storage.get_state(PROJECT_PATHS_STATE).await?.is_none() {
+        storage
+            .set_state(PROJECT_PATHS_STATE, &paths.fingerprint())
+            .await?;
+    }
+    Ok(Vec::new())
+}
+
+/// Split the paths of the existing projects again with `paths`, renaming the ones that split
+/// differently now (or merging them into the project they now split into). This keeps a project's
+/// history when the path splitting settings change.
+pub async fn resplit_projects(
+    storage: &dyn Storage,
+    paths: &ProjectPaths,
+    dry_run: bool,
+) -> Result<Vec<ProjectResplit>> {
+    let mut resplits = Vec::new();
+    // A dry run doesn't rename anything, so keep track of the projects it would have created
+    let mut renamed_to = HashSet::new();
+    for project in storage.list_projects().await? {
+        let path = format!("{}/{}", project.stream, project.project);
+        let Some(to) = paths.split(&path) else {
+            warn!("Not re-splitting {path}, since it's not a valid project path anymore");
+            continue;
+        };
+        let from = (project.stream, project.project);
+        if to == from {
+            continue;
+        }
+
+        let merged = match storage.get_project(&to.0, &to.1).await? {
+            // Someone else re-split it since we listed the projects
+            Some(into) if into == project.project_id => continue,
+            Some(into) => {
+                storage
+                    .merge_projects(project.project_id, into, dry_run)
+                    .await?;
+                true
+            }
+            None if renamed_to.contains(&to) => true,
+            None => {
+                storage
+                    .rename_project(project.project_id, &to.0, &to.1, dry_run)
+                    .await?;
+                renamed_to.insert(to.clone());
+                false
+            }
+        };
+        if !dry_run {
+            info!(
+                "re-split project {path} into stream {} and project {}",
+                to.0, to.1
+            );
+        }
+        resplits.push(ProjectResplit { from, to, merged });
+    }
+
+    if !dry_run {
+        storage
+            .set_state(PROJECT_PATHS_STATE, &paths.fingerprint())
+            .await?;
+    }
+    Ok(resplits)
+}
@@ -0,0 +1,113 @@
+use anyhow::Result;
+use chrono::{DateTime, Duration, Utc};
+
+use crate::storage::{MetadataPruning, PrunedMetadata, Storage};
+
+/// How long to keep a project's badges & user events. A setting that is `None` is inherited from
+/// the default retention, and 0 keeps everything.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MetadataRetention {
+    /// Delete badges & user events that are older than this many days
+    pub days: Option<u32>,
+    /// Only keep the badges & user events of the most recent this many changes
+    pub changes: Option<u32>,
+    /// Delete badges that are older than this many days if there's a newer badge of the same build
+    /// type for the same change (e.g. the `Starting` badge once a build has succeeded)
+    pub superseded_badge_days: Option<u32>,
+}
+
+impl MetadataRetention {
+    /// These settings, falling back to `other`'s for anything these don't set
+    fn or(self, other: Self) -> Self {
+        Self {
+            days: self.days.or(other.days),
+            changes: self.changes.or(other.changes),
+            superseded_badge_days: self.superseded_badge_days.or(other.superseded_badge_days),
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        [self.days, self.changes, self.superseded_badge_days]
+            .into_iter()
+            .any(|setting| setting.unwrap_or_default() > 0)
+    }
+}
+
+/// A retention for the projects whose `//depot/stream/project` path matches a glob
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProjectRetention {
+    pub pattern: String,
+    pub retention: MetadataRetention,
+}
+
+/// How long to keep the badges & user events of each project
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    pub default: MetadataRetention,
+    /// Overrides for specific projects, the first one that matches a project applies to it
+    pub projects: Vec<ProjectRetention>,
+}
+
+impl RetentionPolicy {
+    /// Whether this could prune anything at all
+    pub fn is_enabled(&self) -> bool {
+        self.default.is_enabled()
+            || self
+                .projects
+                .iter()
+                .any(|project| project.retention.is_enabled())
+    }
+
+    /// The retention for the project at `project_path`, with everything it inherits filled in
+    pub fn for_project(&self, project_path: &str) -> MetadataRetention {
+        let match_options = glob::MatchOptions {
+            case_sensitive: false,
+            require_literal_separator: true,
+            require_literal_leading_dot: false,
+        };
+
+        let project_retention = self.projects.iter().find(|project| {
+            glob::Pattern::new(&project.pattern)
+                .map(|pattern| pattern.matches_with(project_path, match_options))
+                .unwrap_or_default()
+        });
+        match project_retention {
+            Some(project) => project.retention.or(self.default),
+            None => self.default,
+        }
+    }
+}
+
+/// Delete the badges & user events that `policy` doesn't keep as of `now`, and return how many we
+/// deleted
+pub async fn prune_metadata(
+    storage: &dyn Storage,
+    policy: &RetentionPolicy,
+    now: DateTime<Utc>,
+) -> Result<PrunedMetadata> {
+    let cutoff = |days: Option<u32>| {
+        days.filter(|&days| days > 0)
+            .map(|days| now - Duration::days(days.into()))
+    };
+
+    let mut pruned = PrunedMetadata::default();
+    for project in storage.list_projects().await? {
+        let retention = policy.for_project(&format!("{}/{}", project.stream, project.project));
+        if !retention.is_enabled() {
+            continue;
+        }
+
+        let project_pruned = storage
+            .prune_metadata(&MetadataPruning {
+                project_id: project.project_id,
+                before: cutoff(retention.days),
+                keep_changes: retention.changes.filter(|&changes| changes > 0),
+                superseded_before: cutoff(retention.superseded_badge_days),
+            })
+            .await?;
+        pruned.badges += project_pruned.badges;
+        pruned.user_events += project_pruned.user_events;
+    }
+
+    Ok(pruned)
+}
@@ -0,0 +1,473 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+
+use tracing::Instrument;
+
+use std::{collections::HashMap, future::Future, path::Path, sync::Arc, time::Instant};
+
+use super::{
+    BadgeFilter, ChangeMetadata, ImportOutcome, LatestSequences, MetadataPruning, MetadataVersion,
+    MigrationStatus, ProjectMerge, ProjectSummary, PrunedMetadata, Storage, TelemetryFilter,
+    UserEventUpdate,
+};
+use crate::{
+    auth::{CiToken, User},
+    metrics::Metrics,
+    models::*,
+};
+
+/// Wraps another storage to record how long each of its operations take in `metrics`, and to give
+/// each of them a span
+#[derive(Debug)]
+pub struct InstrumentedStorage {
+    inner: Arc<dyn Storage>,
+    metrics: Arc<Metrics>,
+}
+
+impl InstrumentedStorage {
+    pub fn new(inner: Arc<dyn Storage>, metrics: Arc<Metrics>) -> Self {
+        Self { inner, metrics }
+    }
+
+    async fn timed<T>(
+        &self,
+        operation: &str,
+        future: impl Future<Output = Result<T>>,
+    ) -> Result<T> {
+        let start = Instant::now();
+        let result = future
+            .instrument(tracing::info_span!("storage", db.operation = operation))
+            .await;
+        self.metrics
+            .observe_query(operation, start.elapsed(), result.is_ok());
+        result
+    }
+}
+
+#[async_trait]
+impl Storage for InstrumentedStorage {
+    async fn get_project(&self, stream: &str, project: &str) -> Result<Option<i64>> {
+        self.timed("get_project", self.inner.get_project(stream, project))
+            .await
+    }
+
+    async fn get_or_add_project(&self, stream: &str, project: &str) -> Result<i64> {
+        self.timed(
+            "get_or_add_project",
+            self.inner.get_or_add_project(stream, project),
+        )
+        .await
+    }
+
+    async fn latest_sequences(&self, project_id: i64) -> Result<LatestSequences> {
+        self.timed("latest_sequences", self.inner.latest_sequences(project_id))
+            .await
+    }
+
+    async fn latest_sequence(&self) -> Result<i64> {
+        self.timed("latest_sequence", self.inner.latest_sequence())
+            .await
+    }
+
+    async fn metadata_version(
+        &self,
+        stream: &str,
+        project: Option<&str>,
+    ) -> Result<MetadataVersion> {
+        self.timed(
+            "metadata_version",
+            self.inner.metadata_version(stream, project),
+        )
+        .await
+    }
+
+    async fn add_badge(
+        &self,
+        project_id: i64,
+        badge: &CreateBadge,
+        posted_by: Option<&str>,
+    ) -> Result<i64> {
+        self.timed(
+            "add_badge",
+            self.inner.add_badge(project_id, badge, posted_by),
+        )
+        .await
+    }
+
+    async fn list_metadata(
+        &self,
+        stream: &str,
+        project: Option<&str>,
+        sequence: i64,
+        min_change: i64,
+        max_change: Option<i64>,
+    ) -> Result<Vec<ChangeMetadata>> {
+        self.timed(
+            "list_metadata",
+            self.inner
+                .list_metadata(stream, project, sequence, min_change, max_change),
+        )
+        .await
+    }
+
+    async fn list_user_events_since(
+        &self,
+        project_id: i64,
+        sequence: i64,
+        comments_only: bool,
+    ) -> Result<Vec<UserEvent>> {
+        self.timed(
+            "list_user_events_since",
+            self.inner
+                .list_user_events_since(project_id, sequence, comments_only),
+        )
+        .await
+    }
+
+    async fn update_user_event(
+        &self,
+        project_id: i64,
+        change_number: i64,
+        user_name: &str,
+        update: UserEventUpdate,
+    ) -> Result<i64> {
+        self.timed(
+            "update_user_event",
+            self.inner
+                .update_user_event(project_id, change_number, user_name, update),
+        )
+        .await
+    }
+
+    async fn list_projects(&self) -> Result<Vec<ProjectSummary>> {
+        self.timed("list_projects", self.inner.list_projects())
+            .await
+    }
+
+    async fn rename_project(
+        &self,
+        project_id: i64,
+        stream: &str,
+        project: &str,
+        dry_run: bool,
+    ) -> Result<()> {
+        self.timed(
+            "rename_project",
+            self.inner
+                .rename_project(project_id, stream, project, dry_run),
+        )
+        .await
+    }
+
+    async fn merge_projects(&self, from: i64, into: i64, dry_run: bool) -> Result<ProjectMerge> {
+        self.timed(
+            "merge_projects",
+            self.inner.merge_projects(from, into, dry_run),
+        )
+        .await
+    }
+
+    async fn delete_badges(&self, filter: &BadgeFilter, dry_run: bool) -> Result<u64> {
+        self.timed("delete_badges", self.inner.delete_badges(filter, dry_run))
+            .await
+    }
+
+    async fn delete_user_events(
+        &self,
+        user_name: &str,
+        project_id: Option<i64>,
+        dry_run: bool,
+    ) -> Result<u64> {
+        self.timed(
+            "delete_user_events",
+            self.inner
+                .delete_user_events(user_name, project_id, dry_run),
+        )
+        .await
+    }
+
+    async fn import_badge(&self, project_id: i64, badge: &Badge) -> Result<ImportOutcome> {
+        self.timed("import_badge", self.inner.import_badge(project_id, badge))
+            .await
+    }
+
+    async fn import_user_event(
+        &self,
+        project_id: i64,
+        user_event: &UserEvent,
+    ) -> Result<ImportOutcome> {
+        self.timed(
+            "import_user_event",
+            self.inner.import_user_event(project_id, user_event),
+        )
+        .await
+    }
+
+    async fn prune_metadata(&self, pruning: &MetadataPruning) -> Result<PrunedMetadata> {
+        self.timed("prune_metadata", self.inner.prune_metadata(pruning))
+            .await
+    }
+
+    async fn list_issues(
+        &self,
+        user: Option<&str>,
+        include_resolved: bool,
+        max_results: i64,
+    ) -> Result<Vec<Issue>> {
+        self.timed(
+            "list_issues",
+            self.inner.list_issues(user, include_resolved, max_results),
+        )
+        .await
+    }
+
+    async fn get_issue(&self, issue_id: i64, user: Option<&str>) -> Result<Option<Issue>> {
+        self.timed("get_issue", self.inner.get_issue(issue_id, user))
+            .await
+    }
+
+    async fn get_issue_streams(&self, issue_ids: &[i64]) -> Result<HashMap<i64, Vec<String>>> {
+        self.timed("get_issue_streams", self.inner.get_issue_streams(issue_ids))
+            .await
+    }
+
+    async fn add_issue(&self, issue: &CreateIssue, created_at: DateTime<Utc>) -> Result<i64> {
+        self.timed("add_issue", self.inner.add_issue(issue, created_at))
+            .await
+    }
+
+    async fn update_issue(&self, issue: &Issue) -> Result<()> {
+        self.timed("update_issue", self.inner.update_issue(issue))
+            .await
+    }
+
+    async fn issue_exists(&self, issue_id: i64) -> Result<bool> {
+        self.timed("issue_exists", self.inner.issue_exists(issue_id))
+            .await
+    }
+
+    async fn list_issue_builds(&self, issue_id: i64) -> Result<Vec<IssueBuild>> {
+        self.timed("list_issue_builds", self.inner.list_issue_builds(issue_id))
+            .await
+    }
+
+    async fn add_issue_build(&self, issue_id: i64, build: &IssueBuildData) -> Result<i64> {
+        self.timed(
+            "add_issue_build",
+            self.inner.add_issue_build(issue_id, build),
+        )
+        .await
+    }
+
+    async fn list_issue_diagnostics(&self, issue_id: i64) -> Result<Vec<IssueDiagnosticData>> {
+        self.timed(
+            "list_issue_diagnostics",
+            self.inner.list_issue_diagnostics(issue_id),
+        )
+        .await
+    }
+
+    async fn add_issue_diagnostic(
+        &self,
+        issue_id: i64,
+        diagnostic: &IssueDiagnosticData,
+    ) -> Result<()> {
+        self.timed(
+            "add_issue_diagnostic",
+            self.inner.add_issue_diagnostic(issue_id, diagnostic),
+        )
+        .await
+    }
+
+    async fn list_issue_watchers(&self, issue_id: i64) -> Result<Vec<String>> {
+        self.timed(
+            "list_issue_watchers",
+            self.inner.list_issue_watchers(issue_id),
+        )
+        .await
+    }
+
+    async fn set_issue_watcher(&self, issue_id: i64, user_name: &str, watch: bool) -> Result<()> {
+        self.timed(
+            "set_issue_watcher",
+            self.inner.set_issue_watcher(issue_id, user_name, watch),
+        )
+        .await
+    }
+
+    async fn add_telemetry_timing(
+        &self,
+        received_at: DateTime<Utc>,
+        timing: &TelemetryTimingData,
+        version: Option<&str>,
+        ip_address: Option<&str>,
+    ) -> Result<()> {
+        self.timed(
+            "add_telemetry_timing",
+            self.inner
+                .add_telemetry_timing(received_at, timing, version, ip_address),
+        )
+        .await
+    }
+
+    async fn add_telemetry_error(
+        &self,
+        received_at: DateTime<Utc>,
+        error: &TelemetryErrorData,
+    ) -> Result<()> {
+        self.timed(
+            "add_telemetry_error",
+            self.inner.add_telemetry_error(received_at, error),
+        )
+        .await
+    }
+
+    async fn telemetry_summary(
+        &self,
+        filter: &TelemetryFilter,
+    ) -> Result<Vec<TelemetryTimingSummary>> {
+        self.timed("telemetry_summary", self.inner.telemetry_summary(filter))
+            .await
+    }
+
+    async fn list_telemetry_timings(
+        &self,
+        filter: &TelemetryFilter,
+        limit: i64,
+    ) -> Result<Vec<TelemetryTimingData>> {
+        self.timed(
+            "list_telemetry_timings",
+            self.inner.list_telemetry_timings(filter, limit),
+        )
+        .await
+    }
+
+    async fn list_telemetry_errors(
+        &self,
+        filter: &TelemetryFilter,
+        limit: i64,
+    ) -> Result<Vec<TelemetryErrorData>> {
+        self.timed(
+            "list_telemetry_errors",
+            self.inner.list_telemetry_errors(filter, limit),
+        )
+        .await
+    }
+
+    async fn prune_telemetry(&self, received_before: DateTime<Utc>) -> Result<u64> {
+        self.timed(
+            "prune_telemetry",
+            self.inner.prune_telemetry(received_before),
+        )
+        .await
+    }
+
+    async fn has_users(&self) -> Result<bool> {
+        self.timed("has_users", self.inner.has_users()).await
+    }
+
+    async fn get_password_hash(&self, user_name: &str) -> Result<Option<(String, String)>> {
+        self.timed("get_password_hash", self.inner.get_password_hash(user_name))
+            .await
+    }
+
+    async fn add_user(&self, user_name: &str, password_hash: &str) -> Result<()> {
+        self.timed("add_user", self.inner.add_user(user_name, password_hash))
+            .await
+    }
+
+    async fn revoke_user(&self, user_name: &str) -> Result<bool> {
+        self.timed("revoke_user", self.inner.revoke_user(user_name))
+            .await
+    }
+
+    async fn list_users(&self) -> Result<Vec<User>> {
+        self.timed("list_users", self.inner.list_users()).await
+    }
+
+    async fn add_alias(&self, user_name: &str, perforce_user_name: &str) -> Result<bool> {
+        self.timed(
+            "add_alias",
+            self.inner.add_alias(user_name, perforce_user_name),
+        )
+        .await
+    }
+
+    async fn remove_alias(&self, user_name: &str, perforce_user_name: &str) -> Result<bool> {
+        self.timed(
+            "remove_alias",
+            self.inner.remove_alias(user_name, perforce_user_name),
+        )
+        .await
+    }
+
+    async fn list_aliases(&self, user_name: &str) -> Result<Vec<String>> {
+        self.timed("list_aliases", self.inner.list_aliases(user_name))
+            .await
+    }
+
+    async fn is_alias(&self, user_name: &str, perforce_user_name: &str) -> Result<bool> {
+        self.timed(
+            "is_alias",
+            self.inner.is_alias(user_name, perforce_user_name),
+        )
+        .await
+    }
+
+    async fn has_ci_tokens(&self) -> Result<bool> {
+        self.timed("has_ci_tokens", self.inner.has_ci_tokens())
+            .await
+    }
+
+    async fn add_ci_token(&self, token: &CiToken, token_hash: &str) -> Result<()> {
+        self.timed("add_ci_token", self.inner.add_ci_token(token, token_hash))
+            .await
+    }
+
+    async fn revoke_ci_token(&self, name: &str) -> Result<bool> {
+        self.timed("revoke_ci_token", self.inner.revoke_ci_token(name))
+            .await
+    }
+
+    async fn list_ci_tokens(&self) -> Result<Vec<CiToken>> {
+        self.timed("list_ci_tokens", self.inner.list_ci_tokens())
+            .await
+    }
+
+    async fn find_ci_token(&self, name: Option<&str>, token_hash: &str) -> Result<Option<CiToken>> {
+        self.timed("find_ci_token", self.inner.find_ci_token(name, token_hash))
+            .await
+    }
+
+    async fn get_state(&self, key: &str) -> Result<Option<String>> {
+        self.timed("get_state", self.inner.get_state(key)).await
+    }
+
+    async fn set_state(&self, key: &str, value: &str) -> Result<()> {
+        self.timed("set_state", self.inner.set_state(key, value))
+            .await
+    }
+
+    async fn database_size(&self) -> Result<u64> {
+        self.timed("database_size", self.inner.database_size())
+            .await
+    }
+
+    async fn optimize(&self) -> Result<()> {
+        self.timed("optimize", self.inner.optimize()).await
+    }
+
+    async fn backup(&self, path: &Path) -> Result<()> {
+        self.timed("backup", self.inner.backup(path)).await
+    }
+
+    async fn migration_status(&self) -> Result<MigrationStatus> {
+        self.timed("migration_status", self.inner.migration_status())
+            .await
+    }
+
+    async fn migrate(&self) -> Result<()> {
+        self.timed("migrate", self.inner.migrate()).await
+    }
+}
@@ -0,0 +1,613 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, SubsecRound, Utc};
+use sqlx::migrate::{Migrate, Migrator};
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::Path,
+    str::FromStr,
+    sync::Arc,
+};
+
+use crate::{
+    auth::{CiToken, User},
+    models::*,
+};
+
+mod instrumented;
+mod postgres;
+mod sqlite;
+
+pub use instrumented::InstrumentedStorage;
+pub use postgres::PostgresStorage;
+pub use sqlite::SqliteStorage;
+
+/// The changes a user wants to make to their state for a given change, any field that is `None`
+/// is left as-is.
+#[derive(Debug, Default)]
+pub struct UserEventUpdate {
+    pub synced: bool,
+    pub vote: Option<UgsUserVote>,
+    pub investigating: Option<bool>,
+    pub starred: Option<bool>,
+    pub comment: Option<String>,
+}
+
+impl UserEventUpdate {
+    /// Apply this update to `user_event`, as of `now`
+    pub fn apply_to(self, user_event: &mut UserEvent, now: DateTime<Utc>) {
+        if self.synced {
+            user_event.synced_at = Some(now);
+        }
+
+        user_event.vote = self.vote.or(user_event.vote.take());
+        user_event.investigating = self.investigating.or(user_event.investigating);
+        user_event.starred = self.starred.or(user_event.starred);
+        user_event.comment = self.comment.or(user_event.comment.take());
+    }
+}
+
+/// The update that a v1 API client's event means
+impl From<EventType> for UserEventUpdate {
+    fn from(event_type: EventType) -> Self {
+        match event_type {
+            EventType::Syncing => UserEventUpdate {
+                synced: true,
+                ..Default::default()
+            },
+            EventType::Compiles => UserEventUpdate {
+                vote: Some(UgsUserVote::CompileSuccess),
+                ..Default::default()
+            },
+            EventType::DoesNotCompile => UserEventUpdate {
+                vote: Some(UgsUserVote::CompileFailure),
+                ..Default::default()
+            },
+            EventType::Good => UserEventUpdate {
+                vote: Some(UgsUserVote::Good),
+                ..Default::default()
+            },
+            EventType::Bad => UserEventUpdate {
+                vote: Some(UgsUserVote::Bad),
+                ..Default::default()
+            },
+            EventType::Unknown => UserEventUpdate {
+                vote: Some(UgsUserVote::None),
+                ..Default::default()
+            },
+            EventType::Starred | EventType::Unstarred => UserEventUpdate {
+                starred: Some(event_type == EventType::Starred),
+                ..Default::default()
+            },
+            EventType::Investigating | EventType::Resolved => UserEventUpdate {
+                investigating: Some(event_type == EventType::Investigating),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+/// The most recent sequence numbers for a project, or 0 if there's nothing of that kind yet
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LatestSequences {
+    pub badge: i64,
+    pub event: i64,
+    pub comment: i64,
+}
+
+/// Identifies what a stream (or one project in it) has in it: this changes whenever a badge or user
+/// event is written to it, or deleted from it, since new rows always get a newer sequence number
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MetadataVersion {
+    /// The newest sequence number of the badges & user events, or 0 if there aren't any
+    pub sequence: i64,
+    /// How many badges & user events there are
+    pub rows: i64,
+}
+
+/// A project, and how many badges & user events it has
+#[derive(Clone, Debug, PartialEq, sqlx::FromRow)]
+pub struct ProjectSummary {
+    pub project_id: i64,
+    pub stream: String,
+    pub project: String,
+    pub badges: i64,
+    pub user_events: i64,
+}
+
+/// What merging one project into another moved
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ProjectMerge {
+    pub badges: u64,
+    pub user_events: u64,
+    /// User events that were dropped because the same user had a newer one for the same change in
+    /// the other project
+    pub dropped_user_events: u64,
+}
+
+/// Which badges to delete, the fields that are `None` match every badge in the project
+#[derive(Clone, Debug)]
+pub struct BadgeFilter {
+    pub project_id: i64,
+    pub change_number: Option<i64>,
+    pub build_type: Option<String>,
+}
+
+/// Which of a project's badges & user events to delete, the fields that are `None` don't delete
+/// anything
+#[derive(Clone, Debug)]
+pub struct MetadataPruning {
+    pub project_id: i64,
+    /// Delete the badges & user events that were last changed before this
+    pub before: Option<DateTime<Utc>>,
+    /// Delete the badges & user events of all but the most recent this many changes
+    pub keep_changes: Option<u32>,
+    /// Delete the badges added before this that have a newer badge of the same build type for the
+    /// same change
+    pub superseded_before: Option<DateTime<Utc>>,
+}
+
+/// How many rows pruning deleted
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PrunedMetadata {
+    pub badges: u64,
+    pub user_events: u64,
+}
+
+/// What happened to a badge or user event we imported
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImportOutcome {
+    Added,
+    /// It had already been imported
+    Unchanged,
+    /// The user already has a different event for the same change, which we kept
+    Conflict,
+}
+
+/// Whether two timestamps are the same to the microsecond, which is all that PostgreSQL & exports
+/// keep. sqlite databases written by older versions have more precise timestamps than that.
+fn same_timestamp(a: DateTime<Utc>, b: DateTime<Utc>) -> bool {
+    a.trunc_subsecs(6) == b.trunc_subsecs(6)
+}
+
+/// Whether two user events for the same user & change say the same thing
+fn same_user_event(a: &UserEvent, b: &UserEvent) -> bool {
+    same_timestamp(a.updated_at, b.updated_at)
+        && match (a.synced_at, b.synced_at) {
+            (Some(a), Some(b)) => same_timestamp(a, b),
+            (a, b) => a == b,
+        }
+        && a.vote == b.vote
+        && a.investigating == b.investigating
+        && a.starred == b.starred
+        && a.comment == b.comment
+}
+
+/// Commit `transaction`, or roll it back if this is a dry run
+async fn finish<DB: sqlx::Database>(
+    transaction: sqlx::Transaction<'_, DB>,
+    dry_run: bool,
+) -> Result<()> {
+    if dry_run {
+        transaction.rollback().await?;
+    } else {
+        transaction.commit().await?;
+    }
+    Ok(())
+}
+
+/// The badges & user events for a change in a project, ordered from oldest to newest
+#[derive(Clone, Debug)]
+pub struct ChangeMetadata {
+    pub project: String,
+    pub change_number: i64,
+    pub badges: Vec<Badge>,
+    pub user_events: Vec<UserEvent>,
+}
+
+impl ChangeMetadata {
+    /// The newest sequence number of any of the badges or user events
+    pub fn sequence(&self) -> i64 {
+        let badges = self.badges.iter().map(|badge| badge.sequence);
+        let user_events = self
+            .user_events
+            .iter()
+            .map(|user_event| user_event.sequence);
+        badges.chain(user_events).max().unwrap_or_default()
+    }
+}
+
+/// Group the badges & user events returned by `list_metadata`'s queries by project & change. Both
+/// are expected to be ordered from oldest to newest, and to only be for `projects`.
+fn group_metadata(
+    projects: &[Project],
+    badges: Vec<Badge>,
+    user_events: Vec<UserEvent>,
+) -> Vec<ChangeMetadata> {
+    let project_names = projects
+        .iter()
+        .map(|project| (project.project_id, project.project.as_str()))
+        .collect::<HashMap<_, _>>();
+    let new_change = |project_id: i64, change_number: i64| ChangeMetadata {
+        project: project_names[&project_id].to_owned(),
+        change_number,
+        badges: Vec::new(),
+        user_events: Vec::new(),
+    };
+
+    let mut changes = BTreeMap::new();
+    for badge in badges {
+        changes
+            .entry((badge.project_id, badge.change_number))
+            .or_insert_with(|| new_change(badge.project_id, badge.change_number))
+            .badges
+            .push(badge);
+    }
+    for user_event in user_events {
+        changes
+            .entry((user_event.project_id, user_event.change_number))
+            .or_insert_with(|| new_change(user_event.project_id, user_event.change_number))
+            .user_events
+            .push(user_event);
+    }
+
+    changes.into_values().collect()
+}
+
+/// A migration that's built into this binary
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SchemaMigration {
+    pub version: i64,
+    pub description: String,
+}
+
+/// How the database's schema compares to the migrations built into this binary
+#[derive(Clone, Debug, Default)]
+pub struct MigrationStatus {
+    /// The migrations that have been applied to the database, from oldest to newest
+    pub applied: Vec<SchemaMigration>,
+    /// The migrations that still need to be applied, from oldest to newest
+    pub pending: Vec<SchemaMigration>,
+    /// The versions of migrations that have been applied to the database, but that this binary
+    /// doesn't know about, i.e. a newer version of RUGS has migrated it
+    pub unknown: Vec<i64>,
+}
+
+/// Compare the migrations that have been applied to the database behind `connection` with the
+/// ones in `migrator`
+async fn migration_status(
+    migrator: &Migrator,
+    connection: &mut impl Migrate,
+) -> Result<MigrationStatus> {
+    connection.ensure_migrations_table().await?;
+    if let Some(version) = connection.dirty_version().await? {
+        anyhow::bail!("Migration {version} failed partway through, and needs to be fixed by hand");
+    }
+    let applied_versions = connection
+        .list_applied_migrations()
+        .await?
+        .into_iter()
+        .map(|migration| migration.version)
+        .collect::<HashSet<_>>();
+
+    let mut status = MigrationStatus::default();
+    for migration in migrator.iter() {
+        let schema_migration = SchemaMigration {
+            version: migration.version,
+            description: migration.description.to_string(),
+        };
+        if applied_versions.contains(&migration.version) {
+            status.applied.push(schema_migration);
+        } else {
+            status.pending.push(schema_migration);
+        }
+    }
+    status.unknown = applied_versions
+        .into_iter()
+        .filter(|&version| !migrator.version_exists(version))
+        .collect();
+    status.unknown.sort_unstable();
+
+    Ok(status)
+}
+
+/// Which telemetry timings or errors to look at, any field that is `None` matches everything
+#[derive(Clone, Debug, Default)]
+pub struct TelemetryFilter {
+    pub action: Option<String>,
+    pub project: Option<String>,
+    pub user: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+}
+
+/// Everything we persist. Handlers get this as an `Extension<Arc<dyn Storage>>`, so we can run
+/// against a local sqlite database, or against a PostgreSQL database shared by several servers.
+///
+/// Project streams & names are expected to already be normalized by the caller. Anything that
+/// hands out a sequence number does so in the same transaction that writes the row, so rows become
+/// visible in sequence order and readers don't need any extra locking.
+#[async_trait]
+pub trait Storage: Send + Sync + std::fmt::Debug {
+    async fn get_project(&self, stream: &str, project: &str) -> Result<Option<i64>>;
+
+    async fn get_or_add_project(&self, stream: &str, project: &str) -> Result<i64>;
+
+    async fn latest_sequences(&self, project_id: i64) -> Result<LatestSequences>;
+
+    /// The last sequence number handed out to a badge or user event in any project, this is cheap
+    /// to look up. Deleting badges or user events also uses up a sequence number, so that this
+    /// changes whenever what clients would see does.
+    async fn latest_sequence(&self) -> Result<i64>;
+
+    /// The version of the badges & user events of the projects in `stream` (or just the one named
+    /// `project`, if it's not `None`), to tell whether they changed without reading them all
+    async fn metadata_version(
+        &self,
+        stream: &str,
+        project: Option<&str>,
+    ) -> Result<MetadataVersion>;
+
+    /// Add a badge, and return the sequence number it was given
+    async fn add_badge(
+        &self,
+        project_id: i64,
+        badge: &CreateBadge,
+        posted_by: Option<&str>,
+    ) -> Result<i64>;
+
+    /// List the badges & user events of every change in the given range that has any newer than
+    /// `sequence`, for all the projects in `stream` (or just the one named `project` if set).
+    ///
+    /// This reads from a single snapshot of the database, so a client that asks for anything
+    /// newer than the largest sequence number in the results won't miss anything.
+    async fn list_metadata(
+        &self,
+        stream: &str,
+        project: Option<&str>,
+        sequence: i64,
+        min_change: i64,
+        max_change: Option<i64>,
+    ) -> Result<Vec<ChangeMetadata>>;
+
+    /// List the user events (or only the ones with comments) that are newer than `sequence`,
+    /// ordered from oldest to newest
+    async fn list_user_events_since(
+        &self,
+        project_id: i64,
+        sequence: i64,
+        comments_only: bool,
+    ) -> Result<Vec<UserEvent>>;
+
+    /// Apply `update` to the user event for `user_name` on the given change, creating it if
+    /// needed, and return the sequence number it was given.
+    async fn update_user_event(
+        &self,
+        project_id: i64,
+        change_number: i64,
+        user_name: &str,
+        update: UserEventUpdate,
+    ) -> Result<i64>;
+
+    /// List all the projects, ordered by stream & name
+    async fn list_projects(&self) -> Result<Vec<ProjectSummary>>;
+
+    /// Rename a project. Its badges & user events are given new sequence numbers (in the same
+    /// order as before), so that clients that were already polling the new name pick them up.
+    ///
+    /// Like the other admin operations, this changes nothing if `dry_run` is set.
+    async fn rename_project(
+        &self,
+        project_id: i64,
+        stream: &str,
+        project: &str,
+        dry_run: bool,
+    ) -> Result<()>;
+
+    /// Move all the badges & user events of project `from` into project `into`, and delete
+    /// `from`. Everything in `into` is given new sequence numbers, ordered by when it was
+    /// written. If a user has an event for the same change in both, we keep the newer one.
+    async fn merge_projects(&self, from: i64, into: i64, dry_run: bool) -> Result<ProjectMerge>;
+
+    /// Delete the badges matching `filter`, and return how many we deleted. If we deleted any, this
+    /// uses up a sequence number (see `latest_sequence`).
+    async fn delete_badges(&self, filter: &BadgeFilter, dry_run: bool) -> Result<u64>;
+
+    /// Delete all of a user's events (matching the user name case insensitively), in `project_id`
+    /// or in every project, and return how many we deleted. Like `delete_badges`, this uses up a
+    /// sequence number if we deleted any.
+    async fn delete_user_events(
+        &self,
+        user_name: &str,
+        project_id: Option<i64>,
+        dry_run: bool,
+    ) -> Result<u64>;
+
+    /// Add a badge from another server (ignoring its `project_id` & `sequence`), unless an identical
+    /// one has already been imported
+    async fn import_badge(&self, project_id: i64, badge: &Badge) -> Result<ImportOutcome>;
+
+    /// Add a user event from another server (ignoring its `id`, `project_id` & `sequence`), unless
+    /// the user already has an event for that change
+    async fn import_user_event(
+        &self,
+        project_id: i64,
+        user_event: &UserEvent,
+    ) -> Result<ImportOutcome>;
+
+    /// Delete the badges & user events of a project that `pruning` doesn't keep. Like
+    /// `delete_badges`, this uses up a sequence number if we deleted any.
+    async fn prune_metadata(&self, pruning: &MetadataPruning) -> Result<PrunedMetadata>;
+
+    /// List the most recent issues, `notify` is set for the ones `user` is watching. A negative
+    /// `max_results` means no limit.
+    async fn list_issues(
+        &self,
+        user: Option<&str>,
+        include_resolved: bool,
+        max_results: i64,
+    ) -> Result<Vec<Issue>>;
+
+    async fn get_issue(&self, issue_id: i64, user: Option<&str>) -> Result<Option<Issue>>;
+
+    /// Look up the streams of all the builds associated with each of `issue_ids`
+    async fn get_issue_streams(&self, issue_ids: &[i64]) -> Result<HashMap<i64, Vec<String>>>;
+
+    async fn add_issue(&self, issue: &CreateIssue, created_at: DateTime<Utc>) -> Result<i64>;
+
+    /// Save the mutable fields of `issue`
+    async fn update_issue(&self, issue: &Issue) -> Result<()>;
+
+    async fn issue_exists(&self, issue_id: i64) -> Result<bool>;
+
+    async fn list_issue_builds(&self, issue_id: i64) -> Result<Vec<IssueBuild>>;
+
+    async fn add_issue_build(&self, issue_id: i64, build: &IssueBuildData) -> Result<i64>;
+
+    async fn list_issue_diagnostics(&self, issue_id: i64) -> Result<Vec<IssueDiagnosticData>>;
+
+    async fn add_issue_diagnostic(
+        &self,
+        issue_id: i64,
+        diagnostic: &IssueDiagnosticData,
+    ) -> Result<()>;
+
+    async fn list_issue_watchers(&self, issue_id: i64) -> Result<Vec<String>>;
+
+    async fn set_issue_watcher(&self, issue_id: i64, user_name: &str, watch: bool) -> Result<()>;
+
+    async fn add_telemetry_timing(
+        &self,
+        received_at: DateTime<Utc>,
+        timing: &TelemetryTimingData,
+        version: Option<&str>,
+        ip_address: Option<&str>,
+    ) -> Result<()>;
+
+    async fn add_telemetry_error(
+        &self,
+        received_at: DateTime<Utc>,
+        error: &TelemetryErrorData,
+    ) -> Result<()>;
+
+    /// Summarize the timings matching `filter` per action & result
+    async fn telemetry_summary(
+        &self,
+        filter: &TelemetryFilter,
+    ) -> Result<Vec<TelemetryTimingSummary>>;
+
+    /// List the most recent timings matching `filter`
+    async fn list_telemetry_timings(
+        &self,
+        filter: &TelemetryFilter,
+        limit: i64,
+    ) -> Result<Vec<TelemetryTimingData>>;
+
+    /// List the most recent errors matching `filter` (which ignores `action`)
+    async fn list_telemetry_errors(
+        &self,
+        filter: &TelemetryFilter,
+        limit: i64,
+    ) -> Result<Vec<TelemetryErrorData>>;
+
+    /// Delete any telemetry or error reports received before `received_before`, and return how
+    /// many we deleted
+    async fn prune_telemetry(&self, received_before: DateTime<Utc>) -> Result<u64>;
+
+    /// Whether any users have been created (including revoked ones)
+    async fn has_users(&self) -> Result<bool>;
+
+    /// Find the stored user name & password hash of an unrevoked user
+    async fn get_password_hash(&self, user_name: &str) -> Result<Option<(String, String)>>;
+
+    /// Create a user with the given password hash, or reset the password hash (and revocation)
+    /// of an existing user
+    async fn add_user(&self, user_name: &str, password_hash: &str) -> Result<()>;
+
+    /// Revoke a user, returning false if there was no such (unrevoked) user
+    async fn revoke_user(&self, user_name: &str) -> Result<bool>;
+
+    async fn list_users(&self) -> Result<Vec<User>>;
+
+    /// Add an alias for `user_name`, returning false if there's no such user
+    async fn add_alias(&self, user_name: &str, perforce_user_name: &str) -> Result<bool>;
+
+    /// Remove an alias, returning false if there was no such alias
+    async fn remove_alias(&self, user_name: &str, perforce_user_name: &str) -> Result<bool>;
+
+    async fn list_aliases(&self, user_name: &str) -> Result<Vec<String>>;
+
+    async fn is_alias(&self, user_name: &str, perforce_user_name: &str) -> Result<bool>;
+
+    /// Whether any CI tokens have been created (including revoked ones)
+    async fn has_ci_tokens(&self) -> Result<bool>;
+
+    async fn add_ci_token(&self, token: &CiToken, token_hash: &str) -> Result<()>;
+
+    /// Revoke a token, returning false if there was no such (unrevoked) token
+    async fn revoke_ci_token(&self, name: &str) -> Result<bool>;
+
+    async fn list_ci_tokens(&self) -> Result<Vec<CiToken>>;
+
+    /// Find the unrevoked token with the given hash (and name, if set)
+    async fn find_ci_token(&self, name: Option<&str>, token_hash: &str) -> Result<Option<CiToken>>;
+
+    /// Look up something the server stored about the database with `set_state`
+    async fn get_state(&self, key: &str) -> Result<Option<String>>;
+
+    async fn set_state(&self, key: &str, value: &str) -> Result<()>;
+
+    /// How much space the database takes up, in bytes
+    async fn database_size(&self) -> Result<u64>;
+
+    /// Run any regular maintenance the database needs, this is called every few hours
+    async fn optimize(&self) -> Result<()>;
+
+    /// Write a consistent snapshot of the database to `path`, which has to be empty or not exist
+    /// yet. This is safe to do while the server is running.
+    async fn backup(&self, path: &Path) -> Result<()>;
+
+    /// Which of the migrations built into this binary have been applied to the database
+    async fn migration_status(&self) -> Result<MigrationStatus>;
+
+    /// Apply any pending migrations. This fails if the database has migrations that this binary
+    /// doesn't know about.
+    async fn migrate(&self) -> Result<()>;
+}
+
+/// Connect to the database at `database`, which is either a `postgres://` URL or the path to a
+/// sqlite database, with at most `max_connections` connections to it (or sqlx's default)
+pub async fn connect(database: &str, max_connections: Option<u32>) -> Result<Arc<dyn Storage>> {
+    let storage: Arc<dyn Storage> =
+        if database.starts_with("postgres://") || database.starts_with("postgresql://") {
+            let mut options = sqlx::postgres::PgPoolOptions::new();
+            if let Some(max_connections) = max_connections {
+                options = options.max_connections(max_connections);
+            }
+            let pool = options
+                .connect(database)
+                .await
+                .context("Could not connect to PostgreSQL database")?;
+            Arc::new(PostgresStorage::new(pool))
+        } else {
+            let mut options = sqlx::sqlite::SqlitePoolOptions::new();
+            if let Some(max_connections) = max_connections {
+                options = options.max_connections(max_connections);
+            }
+            // A new database is empty, but `migration_status` will tell us that
+            let connect_options =
+                sqlx::sqlite::SqliteConnectOptions::from_str(&format!("sqlite:{}", database))?
+                    .create_if_missing(true);
+            let pool = options
+                .connect_with(connect_options)
+                .await
+                .with_context(|| format!("Could not open database at {}", database))?;
+            // Per the sqlite docs, this is recommended to be run on startup (https://www.sqlite.org/pragma.html#pragma_optimize)
+            sqlx::query("PRAGMA optimize=0x10002")
+                .execute(&pool)
+                .await?;
+            Arc::new(SqliteStorage::new(pool))
+        };
+
+    Ok(storage)
+}
@@ -0,0 +1,1156 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use itertools::Itertools;
+use sqlx::{migrate::Migrator, PgPool, Postgres, QueryBuilder};
+use tracing::info;
+
+use std::{collections::HashMap, path::Path};
+
+use super::{
+    finish, group_metadata, migration_status, same_user_event, BadgeFilter, ChangeMetadata,
+    ImportOutcome, LatestSequences, MetadataPruning, MetadataVersion, MigrationStatus,
+    ProjectMerge, ProjectSummary, PrunedMetadata, Storage, TelemetryFilter, UserEventUpdate,
+};
+use crate::{
+    auth::{CiToken, User},
+    models::*,
+};
+
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations_postgres");
+
+/// Storage in a PostgreSQL database (that can be shared by several servers), using the schema in
+/// `migrations_postgres/`
+#[derive(Clone, Debug)]
+pub struct PostgresStorage {
+    pool: PgPool,
+}
+
+impl PostgresStorage {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+}
+
+/// Allocate the next sequence number for a badge or user event. This has to be called inside the
+/// transaction that writes the row, so that the row is visible by the time anyone can see a later
+/// sequence number.
+async fn next_sequence(connection: &mut sqlx::PgConnection) -> Result<i64> {
+    let sequence = sqlx::query_scalar::<Postgres, i64>(
+        "UPDATE sequence_counter SET value = value + 1 WHERE id = 0 RETURNING value",
+    )
+    .fetch_one(connection)
+    .await?;
+
+    Ok(sequence)
+}
+
+/// Give the badges & user events of a project new sequence numbers, in the same order as their
+/// old ones, so that clients that are polling the project see rows that were moved into it. The
+/// order matters, since UGS treats the last badge of each build type as its current state.
+async fn resequence_project(connection: &mut sqlx::PgConnection, project_id: i64) -> Result<()> {
+    for table in ["badges", "user_events"] {
+        let rows = sqlx::query_scalar::<Postgres, i64>(&format!(
+            "SELECT COUNT(*) FROM {table} WHERE project_id = $1"
+        ))
+        .bind(project_id)
+        .fetch_one(&mut *connection)
+        .await?;
+        if rows == 0 {
+            continue;
+        }
+
+        let last = sqlx::query_scalar::<Postgres, i64>(
+            "UPDATE sequence_counter SET value = value + $1 WHERE id = 0 RETURNING value",
+        )
+        .bind(rows)
+        .fetch_one(&mut *connection)
+        .await?;
+        sqlx::query(&format!(
+            "UPDATE {table} SET sequence = renumbered.sequence FROM (
+                SELECT id, $1 + ROW_NUMBER() OVER (ORDER BY sequence, id) AS sequence FROM {table} WHERE project_id = $2
+            ) AS renumbered WHERE {table}.id = renumbered.id"
+        ))
+        .bind(last - rows)
+        .bind(project_id)
+        .execute(&mut *connection)
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Add a `WHERE` clause for the telemetry filters that are set in `filter` to `query`
+fn push_telemetry_filters(
+    query: &mut QueryBuilder<'_, Postgres>,
+    filter: &TelemetryFilter,
+    filter_action: bool,
+) {
+    let action = filter.action.as_ref().filter(|_| filter_action);
+
+    let mut prefix = " WHERE ";
+    for (column, value) in [
+        ("action", action),
+        ("project", filter.project.as_ref()),
+        ("user_name", filter.user.as_ref()),
+    ] {
+        if let Some(value) = value {
+            query
+                .push(prefix)
+                .push(column)
+                .push(" = ")
+                .push_bind(value.clone())
+                .push("::citext");
+            prefix = " AND ";
+        }
+    }
+    if let Some(since) = filter.since {
+        query.push(prefix).push("timestamp >= ").push_bind(since);
+    }
+}
+
+/// List the projects in `stream`, or just the one named `project` if set
+async fn list_projects(
+    connection: &mut sqlx::PgConnection,
+    stream: &str,
+    project: Option<&str>,
+) -> Result<Vec<Project>> {
+    let projects = sqlx::query_as::<Postgres, Project>(
+        "SELECT project_id, stream, project FROM projects WHERE stream = $1::citext AND ($2::citext IS NULL OR project = $2::citext)",
+    )
+    .bind(stream)
+    .bind(project)
+    .fetch_all(&mut *connection)
+    .await?;
+
+    Ok(projects)
+}
+
+/// The changes with badges or user events newer than `$3` in the change range `$4..=$5` (where
+/// `$5` can be NULL), for the projects in stream `$1` (or just the one named `$2`, if it's not
+/// NULL).
+const METADATA_CHANGES: &str = "WITH stream_projects AS (
+        SELECT project_id FROM projects WHERE stream = $1::citext AND ($2::citext IS NULL OR project = $2::citext)
+    ), changes AS (
+        SELECT project_id, change_number FROM badges WHERE project_id IN (SELECT project_id FROM stream_projects) AND sequence > $3 AND change_number >= $4 AND ($5::bigint IS NULL OR change_number <= $5)
+        UNION
+        SELECT project_id, change_number FROM user_events WHERE project_id IN (SELECT project_id FROM stream_projects) AND sequence > $3 AND change_number >= $4 AND ($5::bigint IS NULL OR change_number <= $5)
+    )";
+
+#[async_trait]
+impl Storage for PostgresStorage {
+    async fn get_project(&self, stream: &str, project: &str) -> Result<Option<i64>> {
+        let project_id = sqlx::query_scalar::<Postgres, i64>(
+            "SELECT project_id FROM projects WHERE stream = $1::citext AND project = $2::citext LIMIT 1",
+        )
+        .bind(stream)
+        .bind(project)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(project_id)
+    }
+
+    async fn get_or_add_project(&self, stream: &str, project: &str) -> Result<i64> {
+        if let Some(project_id) = self.get_project(stream, project).await? {
+            return Ok(project_id);
+        }
+
+        info!(
+            "Creating new project for stream {}, project name {}",
+            stream, project
+        );
+
+        // Someone else might've added it since we checked, in which case we use theirs
+        sqlx::query(
+            "INSERT INTO projects (stream, project) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+        )
+        .bind(stream)
+        .bind(project)
+        .execute(&self.pool)
+        .await?;
+
+        self.get_project(stream, project)
+            .await?
+            .context("Project is missing right after adding it")
+    }
+
+    async fn latest_sequences(&self, project_id: i64) -> Result<LatestSequences> {
+        let (badge, event, comment) = sqlx::query_as::<Postgres, (i64, i64, i64)>(
+            "SELECT
+                COALESCE((SELECT MAX(sequence) FROM badges WHERE project_id = $1), 0),
+                COALESCE((SELECT MAX(sequence) FROM user_events WHERE project_id = $1), 0),
+                COALESCE((SELECT MAX(sequence) FROM user_events WHERE project_id = $1 AND comment IS NOT NULL), 0)",
+        )
+        .bind(project_id)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(LatestSequences {
+            badge,
+            event,
+            comment,
+        })
+    }
+
+    async fn latest_sequence(&self) -> Result<i64> {
+        let sequence =
+            sqlx::query_scalar::<Postgres, i64>("SELECT value FROM sequence_counter WHERE id = 0")
+                .fetch_one(&self.pool)
+                .await?;
+
+        Ok(sequence)
+    }
+
+    async fn metadata_version(
+        &self,
+        stream: &str,
+        project: Option<&str>,
+    ) -> Result<MetadataVersion> {
+        let (sequence, rows) = sqlx::query_as::<Postgres, (i64, i64)>(
+            "WITH stream_projects AS (
+                SELECT project_id FROM projects WHERE stream = $1::citext AND ($2::citext IS NULL OR project = $2::citext)
+            )
+            SELECT COALESCE(MAX(sequence), 0), COUNT(*) FROM (
+                SELECT sequence FROM badges WHERE project_id IN (SELECT project_id FROM stream_projects)
+                UNION ALL
+                SELECT sequence FROM user_events WHERE project_id IN (SELECT project_id FROM stream_projects)
+            ) AS metadata",
+        )
+        .bind(stream)
+        .bind(project)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(MetadataVersion { sequence, rows })
+    }
+
+    async fn add_badge(
+        &self,
+        project_id: i64,
+        badge: &CreateBadge,
+        posted_by: Option<&str>,
+    ) -> Result<i64> {
+        let added_at = chrono::Utc::now();
+        let mut transaction = self.pool.begin().await?;
+        let sequence_number = next_sequence(&mut transaction).await?;
+        sqlx::query(
+            "INSERT INTO badges (sequence, change_number, added_at, build_type, result, url, project_id, posted_by) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+        )
+        .bind(sequence_number)
+        .bind(badge.change_number)
+        .bind(added_at)
+        .bind(&badge.build_type)
+        .bind(badge.result)
+        .bind(&badge.url)
+        .bind(project_id)
+        .bind(posted_by)
+        .execute(&mut *transaction)
+        .await?;
+        transaction.commit().await?;
+
+        Ok(sequence_number)
+    }
+
+    async fn list_metadata(
+        &self,
+        stream: &str,
+        project: Option<&str>,
+        sequence: i64,
+        min_change: i64,
+        max_change: Option<i64>,
+    ) -> Result<Vec<ChangeMetadata>> {
+        let mut transaction = self.pool.begin().await?;
+        // The default isolation level gives each statement its own snapshot
+        sqlx::query("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ, READ ONLY")
+            .execute(&mut *transaction)
+            .await?;
+
+        let projects = list_projects(&mut transaction, stream, project).await?;
+
+        let badges = sqlx::query_as::<Postgres, Badge>(&format!(
+            "{METADATA_CHANGES} SELECT badges.* FROM badges JOIN changes USING (project_id, change_number) ORDER BY badges.sequence ASC, badges.id ASC"
+        ))
+        .bind(stream)
+        .bind(project)
+        .bind(sequence)
+        .bind(min_change)
+        .bind(max_change)
+        .fetch_all(&mut *transaction)
+        .await?;
+
+        let user_events = sqlx::query_as::<Postgres, UserEvent>(&format!(
+            "{METADATA_CHANGES} SELECT user_events.* FROM user_events JOIN changes USING (project_id, change_number) ORDER BY user_events.sequence ASC, user_events.id ASC"
+        ))
+        .bind(stream)
+        .bind(project)
+        .bind(sequence)
+        .bind(min_change)
+        .bind(max_change)
+        .fetch_all(&mut *transaction)
+        .await?;
+
+        let metadata = group_metadata(&projects, badges, user_events);
+        transaction.commit().await?;
+
+        Ok(metadata)
+    }
+
+    async fn list_user_events_since(
+        &self,
+        project_id: i64,
+        sequence: i64,
+        comments_only: bool,
+    ) -> Result<Vec<UserEvent>> {
+        let user_events = sqlx::query_as::<Postgres, UserEvent>(
+            "SELECT * FROM user_events WHERE project_id = $1 AND sequence > $2 AND (NOT $3 OR comment IS NOT NULL) ORDER BY sequence ASC, id ASC",
+        )
+        .bind(project_id)
+        .bind(sequence)
+        .bind(comments_only)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(user_events)
+    }
+
+    async fn update_user_event(
+        &self,
+        project_id: i64,
+        change_number: i64,
+        user_name: &str,
+        update: UserEventUpdate,
+    ) -> Result<i64> {
+        let now = chrono::Utc::now();
+        // Allocating the sequence number locks the counter until we commit, so nobody else can
+        // change this user event between us reading & writing it
+        let mut transaction = self.pool.begin().await?;
+        let sequence_number = next_sequence(&mut transaction).await?;
+
+        let user_event = sqlx::query_as::<Postgres, UserEvent>(
+            "SELECT * FROM user_events WHERE project_id = $1 AND user_name = $2 AND change_number = $3",
+        )
+        .bind(project_id)
+        .bind(user_name)
+        .bind(change_number)
+        .fetch_optional(&mut *transaction)
+        .await?;
+
+        let needs_insert = user_event.is_none();
+
+        let mut user_event = user_event.unwrap_or_else(UserEvent::default);
+        update.apply_to(&mut user_event, now);
+
+        if needs_insert {
+            sqlx::query(
+                "INSERT INTO user_events (project_id, change_number, user_name, sequence, updated_at, synced_at, vote, investigating, starred, comment) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)",
+            )
+            .bind(project_id)
+            .bind(change_number)
+            .bind(user_name)
+            .bind(sequence_number)
+            .bind(now)
+            .bind(user_event.synced_at)
+            .bind(user_event.vote)
+            .bind(user_event.investigating)
+            .bind(user_event.starred)
+            .bind(user_event.comment)
+            .execute(&mut *transaction)
+            .await?;
+        } else {
+            sqlx::query(
+                "UPDATE user_events SET sequence = $1, updated_at = $2, synced_at = $3, vote = $4, investigating = $5, starred = $6, comment = $7 WHERE id = $8",
+            )
+            .bind(sequence_number)
+            .bind(now)
+            .bind(user_event.synced_at)
+            .bind(user_event.vote)
+            .bind(user_event.investigating)
+            .bind(user_event.starred)
+            .bind(user_event.comment)
+            .bind(user_event.id)
+            .execute(&mut *transaction)
+            .await?;
+        }
+        transaction.commit().await?;
+
+        Ok(sequence_number)
+    }
+
+    async fn list_projects(&self) -> Result<Vec<ProjectSummary>> {
+        let projects = sqlx::query_as::<Postgres, ProjectSummary>(
+            "SELECT project_id, stream, project, (SELECT COUNT(*) FROM badges WHERE badges.project_id = projects.project_id) AS badges, (SELECT COUNT(*) FROM user_events WHERE user_events.project_id = projects.project_id) AS user_events FROM projects ORDER BY stream, project",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(projects)
+    }
+
+    async fn rename_project(
+        &self,
+        project_id: i64,
+        stream: &str,
+        project: &str,
+        dry_run: bool,
+    ) -> Result<()> {
+        let mut transaction = self.pool.begin().await?;
+        sqlx::query(
+            "UPDATE projects SET stream = $1::citext, project = $2::citext WHERE project_id = $3",
+        )
+        .bind(stream)
+        .bind(project)
+        .bind(project_id)
+        .execute(&mut *transaction)
+        .await?;
+        resequence_project(&mut transaction, project_id).await?;
+        finish(transaction, dry_run).await
+    }
+
+    async fn merge_projects(&self, from: i64, into: i64, dry_run: bool) -> Result<ProjectMerge> {
+        let mut transaction = self.pool.begin().await?;
+        let mut merge = ProjectMerge::default();
+
+        // A user can only have one event per change, so if they have one in both projects, drop
+        // the older one before moving the rest
+        let conflict = "EXISTS (SELECT 1 FROM user_events AS other WHERE other.project_id = $2 AND other.user_name = user_events.user_name AND other.change_number = user_events.change_number";
+        merge.dropped_user_events += sqlx::query(&format!(
+            "DELETE FROM user_events WHERE project_id = $1 AND {conflict} AND (other.sequence, other.id) > (user_events.sequence, user_events.id))"
+        ))
+        .bind(into)
+        .bind(from)
+        .execute(&mut *transaction)
+        .await?
+        .rows_affected();
+        merge.dropped_user_events += sqlx::query(&format!(
+            "DELETE FROM user_events WHERE project_id = $1 AND {conflict})"
+        ))
+        .bind(from)
+        .bind(into)
+        .execute(&mut *transaction)
+        .await?
+        .rows_affected();
+
+        merge.badges = sqlx::query("UPDATE badges SET project_id = $1 WHERE project_id = $2")
+            .bind(into)
+            .bind(from)
+            .execute(&mut *transaction)
+            .await?
+            .rows_affected();
+        merge.user_events =
+            sqlx::query("UPDATE user_events SET project_id = $1 WHERE project_id = $2")
+                .bind(into)
+                .bind(from)
+                .execute(&mut *transaction)
+                .await?
+                .rows_affected();
+        sqlx::query("DELETE FROM projects WHERE project_id = $1")
+            .bind(from)
+            .execute(&mut *transaction)
+            .await?;
+        // Interleave what we moved with what was already there, by when it was written
+        resequence_project(&mut transaction, into).await?;
+
+        finish(transaction, dry_run).await?;
+        Ok(merge)
+    }
+
+    async fn delete_badges(&self, filter: &BadgeFilter, dry_run: bool) -> Result<u64> {
+        let mut transaction = self.pool.begin().await?;
+        let deleted = sqlx::query(
+            "DELETE FROM badges WHERE project_id = $1 AND ($2 IS NULL OR change_number = $3) AND ($4 IS NULL OR build_type = $5)",
+        )
+        .bind(filter.project_id)
+        .bind(filter.change_number)
+        .bind(filter.change_number)
+        .bind(filter.build_type.as_deref())
+        .bind(filter.build_type.as_deref())
+        .execute(&mut *transaction)
+        .await?
+        .rows_affected();
+
+        if deleted > 0 && !dry_run {
+            next_sequence(&mut transaction).await?;
+        }
+
+        finish(transaction, dry_run).await?;
+        Ok(deleted)
+    }
+
+    async fn delete_user_events(
+        &self,
+        user_name: &str,
+        project_id: Option<i64>,
+        dry_run: bool,
+    ) -> Result<u64> {
+        let mut transaction = self.pool.begin().await?;
+        let deleted = sqlx::query(
+            "DELETE FROM user_events WHERE lower(user_name) = lower($1) AND ($2 IS NULL OR project_id = $3)",
+        )
+        .bind(user_name)
+        .bind(project_id)
+        .bind(project_id)
+        .execute(&mut *transaction)
+        .await?
+        .rows_affected();
+
+        if deleted > 0 && !dry_run {
+            next_sequence(&mut transaction).await?;
+        }
+
+        finish(transaction, dry_run).await?;
+        Ok(deleted)
+    }
+
+    async fn import_badge(&self, project_id: i64, badge: &Badge) -> Result<ImportOutcome> {
+        // Allocating the sequence number locks the counter until we commit, so nobody else can
+        // import the same badge in the meantime
+        let mut transaction = self.pool.begin().await?;
+        let sequence_number = next_sequence(&mut transaction).await?;
+
+        let exists = sqlx::query_scalar::<Postgres, bool>(
+            "SELECT EXISTS(SELECT 1 FROM badges WHERE project_id = $1 AND change_number = $2 AND build_type = $3 AND result = $4 AND url = $5 AND added_at = $6)",
+        )
+        .bind(project_id)
+        .bind(badge.change_number)
+        .bind(&badge.build_type)
+        .bind(badge.result)
+        .bind(&badge.url)
+        .bind(badge.added_at)
+        .fetch_one(&mut *transaction)
+        .await?;
+        if exists {
+            transaction.rollback().await?;
+            return Ok(ImportOutcome::Unchanged);
+        }
+
+        sqlx::query(
+            "INSERT INTO badges (sequence, change_number, added_at, build_type, result, url, project_id, posted_by) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+        )
+        .bind(sequence_number)
+        .bind(badge.change_number)
+        .bind(badge.added_at)
+        .bind(&badge.build_type)
+        .bind(badge.result)
+        .bind(&badge.url)
+        .bind(project_id)
+        .bind(&badge.posted_by)
+        .execute(&mut *transaction)
+        .await?;
+        transaction.commit().await?;
+
+        Ok(ImportOutcome::Added)
+    }
+
+    async fn import_user_event(
+        &self,
+        project_id: i64,
+        user_event: &UserEvent,
+    ) -> Result<ImportOutcome> {
+        let mut transaction = self.pool.begin().await?;
+        let sequence_number = next_sequence(&mut transaction).await?;
+
+        let existing = sqlx::query_as::<Postgres, UserEvent>(
+            "SELECT * FROM user_events WHERE project_id = $1 AND user_name = $2 AND change_number = $3",
+        )
+        .bind(project_id)
+        .bind(&user_event.user_name)
+        .bind(user_event.change_number)
+        .fetch_optional(&mut *transaction)
+        .await?;
+        if let Some(existing) = existing {
+            transaction.rollback().await?;
+            return Ok(if same_user_event(&existing, user_event) {
+                ImportOutcome::Unchanged
+            } else {
+                ImportOutcome::Conflict
+            });
+        }
+
+        sqlx::query(
+            "INSERT INTO user_events (project_id, change_number, user_name, sequence, updated_at, synced_at, vote, investigating, starred, comment) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)",
+        )
+        .bind(project_id)
+        .bind(user_event.change_number)
+        .bind(&user_event.user_name)
+        .bind(sequence_number)
+        .bind(user_event.updated_at)
+        .bind(user_event.synced_at)
+        .bind(&user_event.vote)
+        .bind(user_event.investigating)
+        .bind(user_event.starred)
+        .bind(&user_event.comment)
+        .execute(&mut *transaction)
+        .await?;
+        transaction.commit().await?;
+
+        Ok(ImportOutcome::Added)
+    }
+
+    async fn prune_metadata(&self, pruning: &MetadataPruning) -> Result<PrunedMetadata> {
+        let mut transaction = self.pool.begin().await?;
+        let mut pruned = PrunedMetadata::default();
+
+        if let Some(superseded_before) = pruning.superseded_before {
+            pruned.badges += sqlx::query(
+                "DELETE FROM badges WHERE project_id = $1 AND added_at < $2 AND EXISTS (SELECT 1 FROM badges AS newer WHERE newer.project_id = badges.project_id AND newer.change_number = badges.change_number AND newer.build_type = badges.build_type AND (newer.added_at > badges.added_at OR (newer.added_at = badges.added_at AND newer.id > badges.id)))",
+            )
+            .bind(pruning.project_id)
+            .bind(superseded_before)
+            .execute(&mut *transaction)
+            .await?
+            .rows_affected();
+        }
+
+        if let Some(before) = pruning.before {
+            pruned.badges +=
+                sqlx::query("DELETE FROM badges WHERE project_id = $1 AND added_at < $2")
+                    .bind(pruning.project_id)
+                    .bind(before)
+                    .execute(&mut *transaction)
+                    .await?
+                    .rows_affected();
+            pruned.user_events +=
+                sqlx::query("DELETE FROM user_events WHERE project_id = $1 AND updated_at < $2")
+                    .bind(pruning.project_id)
+                    .bind(before)
+                    .execute(&mut *transaction)
+                    .await?
+                    .rows_affected();
+        }
+
+        if let Some(keep_changes) = pruning.keep_changes {
+            // The oldest change we keep, if there are more than `keep_changes` of them
+            let oldest_change = sqlx::query_scalar::<Postgres, i64>(
+                "SELECT change_number FROM (SELECT change_number FROM badges WHERE project_id = $1 UNION SELECT change_number FROM user_events WHERE project_id = $2) AS changes ORDER BY change_number DESC LIMIT 1 OFFSET $3",
+            )
+            .bind(pruning.project_id)
+            .bind(pruning.project_id)
+            .bind(i64::from(keep_changes) - 1)
+            .fetch_optional(&mut *transaction)
+            .await?;
+
+            if let Some(oldest_change) = oldest_change {
+                pruned.badges +=
+                    sqlx::query("DELETE FROM badges WHERE project_id = $1 AND change_number < $2")
+                        .bind(pruning.project_id)
+                        .bind(oldest_change)
+                        .execute(&mut *transaction)
+                        .await?
+                        .rows_affected();
+                pruned.user_events += sqlx::query(
+                    "DELETE FROM user_events WHERE project_id = $1 AND change_number < $2",
+                )
+                .bind(pruning.project_id)
+                .bind(oldest_change)
+                .execute(&mut *transaction)
+                .await?
+                .rows_affected();
+            }
+        }
+
+        if pruned.badges + pruned.user_events > 0 {
+            next_sequence(&mut transaction).await?;
+        }
+
+        transaction.commit().await?;
+        Ok(pruned)
+    }
+
+    async fn list_issues(
+        &self,
+        user: Option<&str>,
+        include_resolved: bool,
+        max_results: i64,
+    ) -> Result<Vec<Issue>> {
+        // A NULL LIMIT is no limit
+        let issues = sqlx::query_as::<Postgres, Issue>(
+            "SELECT issues.*, watchers.user_name IS NOT NULL AS notify FROM issues LEFT JOIN issue_watchers AS watchers ON watchers.issue_id = issues.id AND watchers.user_name = $1::citext WHERE $2 OR issues.resolved_at IS NULL ORDER BY issues.id DESC LIMIT $3",
+        )
+        .bind(user)
+        .bind(include_resolved)
+        .bind((max_results >= 0).then_some(max_results))
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(issues)
+    }
+
+    async fn get_issue(&self, issue_id: i64, user: Option<&str>) -> Result<Option<Issue>> {
+        let issue = sqlx::query_as::<Postgres, Issue>(
+            "SELECT issues.*, watchers.user_name IS NOT NULL AS notify FROM issues LEFT JOIN issue_watchers AS watchers ON watchers.issue_id = issues.id AND watchers.user_name = $1::citext WHERE issues.id = $2",
+        )
+        .bind(user)
+        .bind(issue_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(issue)
+    }
+
+    async fn get_issue_streams(&self, issue_ids: &[i64]) -> Result<HashMap<i64, Vec<String>>> {
+        if issue_ids.is_empty() {
+            return Ok(HashMap::new());
+        }
+
+        let streams = sqlx::query_as::<Postgres, (i64, String)>(
+            "SELECT DISTINCT issue_id, stream FROM issue_builds WHERE issue_id = ANY($1) ORDER BY stream",
+        )
+        .bind(issue_ids)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(streams.into_iter().into_group_map())
+    }
+
+    async fn add_issue(&self, issue: &CreateIssue, created_at: DateTime<Utc>) -> Result<i64> {
+        let id = sqlx::query_scalar::<Postgres, i64>(
+            "INSERT INTO issues (created_at, project, summary, owner, nominated_by, is_warning, build_url) VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING id",
+        )
+        .bind(created_at)
+        .bind(&issue.project)
+        .bind(&issue.summary)
+        .bind(&issue.owner)
+        .bind(&issue.nominated_by)
+        .bind(issue.is_warning)
+        .bind(&issue.build_url)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(id)
+    }
+
+    async fn update_issue(&self, issue: &Issue) -> Result<()> {
+        sqlx::query(
+            "UPDATE issues SET summary = $1, owner = $2, nominated_by = $3, acknowledged_at = $4, fix_change = $5, resolved_at = $6 WHERE id = $7",
+        )
+        .bind(&issue.summary)
+        .bind(&issue.owner)
+        .bind(&issue.nominated_by)
+        .bind(issue.acknowledged_at)
+        .bind(issue.fix_change)
+        .bind(issue.resolved_at)
+        .bind(issue.id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn issue_exists(&self, issue_id: i64) -> Result<bool> {
+        let exists = sqlx::query_scalar::<Postgres, bool>(
+            "SELECT EXISTS(SELECT 1 FROM issues WHERE id = $1)",
+        )
+        .bind(issue_id)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(exists)
+    }
+
+    async fn list_issue_builds(&self, issue_id: i64) -> Result<Vec<IssueBuild>> {
+        let builds = sqlx::query_as::<Postgres, IssueBuild>(
+            "SELECT * FROM issue_builds WHERE issue_id = $1 ORDER BY change_number ASC, id ASC",
+        )
+        .bind(issue_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(builds)
+    }
+
+    async fn add_issue_build(&self, issue_id: i64, build: &IssueBuildData) -> Result<i64> {
+        let id = sqlx::query_scalar::<Postgres, i64>(
+            "INSERT INTO issue_builds (issue_id, stream, change_number, job_name, job_url, job_step_name, job_step_url, error_url, outcome) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9) RETURNING id",
+        )
+        .bind(issue_id)
+        .bind(&build.stream)
+        .bind(build.change)
+        .bind(&build.job_name)
+        .bind(&build.job_url)
+        .bind(&build.job_step_name)
+        .bind(&build.job_step_url)
+        .bind(&build.error_url)
+        .bind(build.outcome)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(id)
+    }
+
+    async fn list_issue_diagnostics(&self, issue_id: i64) -> Result<Vec<IssueDiagnosticData>> {
+        let diagnostics = sqlx::query_as::<Postgres, IssueDiagnosticData>(
+            "SELECT build_id, message, url FROM issue_diagnostics WHERE issue_id = $1 ORDER BY id ASC",
+        )
+        .bind(issue_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(diagnostics)
+    }
+
+    async fn add_issue_diagnostic(
+        &self,
+        issue_id: i64,
+        diagnostic: &IssueDiagnosticData,
+    ) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO issue_diagnostics (issue_id, build_id, message, url) VALUES ($1, $2, $3, $4)",
+        )
+        .bind(issue_id)
+        .bind(diagnostic.build_id)
+        .bind(&diagnostic.message)
+        .bind(&diagnostic.url)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn list_issue_watchers(&self, issue_id: i64) -> Result<Vec<String>> {
+        let watchers = sqlx::query_scalar::<Postgres, String>(
+            "SELECT user_name FROM issue_watchers WHERE issue_id = $1 ORDER BY user_name ASC",
+        )
+        .bind(issue_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(watchers)
+    }
+
+    async fn set_issue_watcher(&self, issue_id: i64, user_name: &str, watch: bool) -> Result<()> {
+        let query = if watch {
+            "INSERT INTO issue_watchers (issue_id, user_name) VALUES ($1, $2) ON CONFLICT DO NOTHING"
+        } else {
+            "DELETE FROM issue_watchers WHERE issue_id = $1 AND user_name = $2::citext"
+        };
+        sqlx::query(query)
+            .bind(issue_id)
+            .bind(user_name)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn add_telemetry_timing(
+        &self,
+        received_at: DateTime<Utc>,
+        timing: &TelemetryTimingData,
+        version: Option<&str>,
+        ip_address: Option<&str>,
+    ) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO telemetry_timings (received_at, action, result, user_name, project, timestamp, duration, version, ip_address) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+        )
+        .bind(received_at)
+        .bind(&timing.action)
+        .bind(&timing.result)
+        .bind(&timing.user_name)
+        .bind(&timing.project)
+        .bind(timing.timestamp)
+        .bind(timing.duration)
+        .bind(version)
+        .bind(ip_address)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn add_telemetry_error(
+        &self,
+        received_at: DateTime<Utc>,
+        error: &TelemetryErrorData,
+    ) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO telemetry_errors (received_at, error_type, text, user_name, project, timestamp, version, ip_address) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+        )
+        .bind(received_at)
+        .bind(error.error_type)
+        .bind(&error.text)
+        .bind(&error.user_name)
+        .bind(&error.project)
+        .bind(error.timestamp)
+        .bind(&error.version)
+        .bind(&error.ip_address)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn telemetry_summary(
+        &self,
+        filter: &TelemetryFilter,
+    ) -> Result<Vec<TelemetryTimingSummary>> {
+        let mut query = QueryBuilder::new(
+            "SELECT action, result, COUNT(*) AS count, AVG(duration)::float8 AS average_duration, MIN(duration)::float8 AS min_duration, MAX(duration)::float8 AS max_duration FROM telemetry_timings",
+        );
+        push_telemetry_filters(&mut query, filter, true);
+        query.push(" GROUP BY action, result ORDER BY action, result");
+
+        let summary = query
+            .build_query_as::<TelemetryTimingSummary>()
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(summary)
+    }
+
+    async fn list_telemetry_timings(
+        &self,
+        filter: &TelemetryFilter,
+        limit: i64,
+    ) -> Result<Vec<TelemetryTimingData>> {
+        let mut query = QueryBuilder::new(
+            "SELECT action, result, user_name, project, timestamp, duration FROM telemetry_timings",
+        );
+        push_telemetry_filters(&mut query, filter, true);
+        query
+            .push(" ORDER BY timestamp DESC LIMIT ")
+            .push_bind(limit);
+
+        let timings = query
+            .build_query_as::<TelemetryTimingData>()
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(timings)
+    }
+
+    async fn list_telemetry_errors(
+        &self,
+        filter: &TelemetryFilter,
+        limit: i64,
+    ) -> Result<Vec<TelemetryErrorData>> {
+        let mut query = QueryBuilder::new(
+            "SELECT id, error_type, text, user_name, project, timestamp, version, ip_address FROM telemetry_errors",
+        );
+        push_telemetry_filters(&mut query, filter, false);
+        query
+            .push(" ORDER BY timestamp DESC LIMIT ")
+            .push_bind(limit);
+
+        let errors = query
+            .build_query_as::<TelemetryErrorData>()
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(errors)
+    }
+
+    async fn prune_telemetry(&self, received_before: DateTime<Utc>) -> Result<u64> {
+        let mut removed = 0;
+        for table in ["telemetry_timings", "telemetry_errors"] {
+            removed += sqlx::query(&format!("DELETE FROM {table} WHERE received_at < $1"))
+                .bind(received_before)
+                .execute(&self.pool)
+                .await?
+                .rows_affected();
+        }
+
+        Ok(removed)
+    }
+
+    async fn has_users(&self) -> Result<bool> {
+        let has_users = sqlx::query_scalar::<Postgres, bool>("SELECT EXISTS(SELECT 1 FROM users)")
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(has_users)
+    }
+
+    async fn get_password_hash(&self, user_name: &str) -> Result<Option<(String, String)>> {
+        let user = sqlx::query_as::<Postgres, (String, String)>(
+            "SELECT user_name, password_hash FROM users WHERE user_name = $1::citext AND revoked_at IS NULL",
+        )
+        .bind(user_name)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(user)
+    }
+
+    async fn add_user(&self, user_name: &str, password_hash: &str) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO users (user_name, password_hash, created_at) VALUES ($1, $2, $3) ON CONFLICT (user_name) DO UPDATE SET password_hash = excluded.password_hash, revoked_at = NULL",
+        )
+        .bind(user_name)
+        .bind(password_hash)
+        .bind(Utc::now())
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn revoke_user(&self, user_name: &str) -> Result<bool> {
+        let revoked = sqlx::query(
+            "UPDATE users SET revoked_at = $1 WHERE user_name = $2::citext AND revoked_at IS NULL",
+        )
+        .bind(Utc::now())
+        .bind(user_name)
+        .execute(&self.pool)
+        .await?
+        .rows_affected()
+            > 0;
+        Ok(revoked)
+    }
+
+    async fn list_users(&self) -> Result<Vec<User>> {
+        let users = sqlx::query_as::<Postgres, User>(
+            "SELECT user_name, created_at, revoked_at FROM users ORDER BY user_name",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(users)
+    }
+
+    async fn add_alias(&self, user_name: &str, perforce_user_name: &str) -> Result<bool> {
+        let inserted = sqlx::query(
+            "INSERT INTO user_aliases (user_name, perforce_user_name) SELECT user_name, $1 FROM users WHERE user_name = $2::citext ON CONFLICT DO NOTHING",
+        )
+        .bind(perforce_user_name)
+        .bind(user_name)
+        .execute(&self.pool)
+        .await?
+        .rows_affected();
+
+        // Nothing is inserted either if the user doesn't exist, or if the alias already did
+        Ok(inserted > 0 || self.is_alias(user_name, perforce_user_name).await?)
+    }
+
+    async fn remove_alias(&self, user_name: &str, perforce_user_name: &str) -> Result<bool> {
+        let removed = sqlx::query(
+            "DELETE FROM user_aliases WHERE user_name = $1::citext AND perforce_user_name = $2::citext",
+        )
+        .bind(user_name)
+        .bind(perforce_user_name)
+        .execute(&self.pool)
+        .await?
+        .rows_affected()
+            > 0;
+        Ok(removed)
+    }
+
+    async fn list_aliases(&self, user_name: &str) -> Result<Vec<String>> {
+        let aliases = sqlx::query_scalar::<Postgres, String>(
+            "SELECT perforce_user_name FROM user_aliases WHERE user_name = $1::citext ORDER BY perforce_user_name",
+        )
+        .bind(user_name)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(aliases)
+    }
+
+    async fn is_alias(&self, user_name: &str, perforce_user_name: &str) -> Result<bool> {
+        let is_alias = sqlx::query_scalar::<Postgres, bool>(
+            "SELECT EXISTS(SELECT 1 FROM user_aliases WHERE user_name = $1::citext AND perforce_user_name = $2::citext)",
+        )
+        .bind(user_name)
+        .bind(perforce_user_name)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(is_alias)
+    }
+
+    async fn has_ci_tokens(&self) -> Result<bool> {
+        let has_tokens =
+            sqlx::query_scalar::<Postgres, bool>("SELECT EXISTS(SELECT 1 FROM ci_tokens)")
+                .fetch_one(&self.pool)
+                .await?;
+        Ok(has_tokens)
+    }
+
+    async fn add_ci_token(&self, token: &CiToken, token_hash: &str) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO ci_tokens (name, token_hash, project_patterns, build_types, created_at) VALUES ($1, $2, $3, $4, $5)",
+        )
+        .bind(&token.name)
+        .bind(token_hash)
+        .bind(&token.project_patterns)
+        .bind(&token.build_types)
+        .bind(token.created_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn revoke_ci_token(&self, name: &str) -> Result<bool> {
+        let revoked = sqlx::query(
+            "UPDATE ci_tokens SET revoked_at = $1 WHERE name = $2::citext AND revoked_at IS NULL",
+        )
+        .bind(Utc::now())
+        .bind(name)
+        .execute(&self.pool)
+        .await?
+        .rows_affected()
+            > 0;
+        Ok(revoked)
+    }
+
+    async fn list_ci_tokens(&self) -> Result<Vec<CiToken>> {
+        let tokens = sqlx::query_as::<Postgres, CiToken>(
+            "SELECT name, project_patterns, build_types, created_at, revoked_at FROM ci_tokens ORDER BY name",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(tokens)
+    }
+
+    async fn find_ci_token(&self, name: Option<&str>, token_hash: &str) -> Result<Option<CiToken>> {
+        let ci_token = sqlx::query_as::<Postgres, CiToken>(
+            "SELECT name, project_patterns, build_types, created_at, revoked_at FROM ci_tokens WHERE token_hash = $1 AND ($2::citext IS NULL OR name = $2::citext) AND revoked_at IS NULL",
+        )
+        .bind(token_hash)
+        .bind(name)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(ci_token)
+    }
+
+    async fn get_state(&self, key: &str) -> Result<Option<String>> {
+        let value =
+            sqlx::query_scalar::<Postgres, String>("SELECT value FROM server_state WHERE key = $1")
+                .bind(key)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(value)
+    }
+
+    async fn set_state(&self, key: &str, value: &str) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO server_state (key, value) VALUES ($1, $2) ON CONFLICT (key) DO UPDATE SET value = excluded.value",
+        )
+        .bind(key)
+        .bind(value)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn database_size(&self) -> Result<u64> {
+        let size =
+            sqlx::query_scalar::<Postgres, i64>("SELECT pg_database_size(current_database())")
+                .fetch_one(&self.pool)
+                .await?;
+
+        Ok(size.try_into()?)
+    }
+
+    async fn optimize(&self) -> Result<()> {
+        // autovacuum takes care of keeping the planner statistics up to date
+        Ok(())
+    }
+
+    async fn backup(&self, _path: &Path) -> Result<()> {
+        anyhow::bail!("RUGS can only back up sqlite databases, use pg_dump to back up PostgreSQL")
+    }
+
+    async fn migration_status(&self) -> Result<MigrationStatus> {
+        let mut connection = self.pool.acquire().await?;
+        migration_status(&MIGRATOR, &mut *connection).await
+    }
+
+    async fn migrate(&self) -> Result<()> {
+        MIGRATOR.run(&self.pool).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,1228 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, SubsecRound, Utc};
+use itertools::Itertools;
+use sqlx::{migrate::Migrator, Sqlite, SqlitePool};
+use tracing::info;
+
+use std::{collections::HashMap, path::Path};
+
+use super::{
+    finish, group_metadata, migration_status, same_timestamp, same_user_event, BadgeFilter,
+    ChangeMetadata, ImportOutcome, LatestSequences, MetadataPruning, MetadataVersion,
+    MigrationStatus, ProjectMerge, ProjectSummary, PrunedMetadata, Storage, TelemetryFilter,
+    UserEventUpdate,
+};
+use crate::{
+    auth::{CiToken, User},
+    models::*,
+};
+
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
+/// Storage in a local sqlite database, using the schema in `migrations/`
+#[derive(Clone, Debug)]
+pub struct SqliteStorage {
+    pool: SqlitePool,
+}
+
+impl SqliteStorage {
+    pub fn new(pool: SqlitePool) -> Self {
+        Self { pool }
+    }
+}
+
+/// Allocate the next sequence number for a badge or user event. This has to be called inside the
+/// transaction that writes the row, so that the row is visible by the time anyone can see a later
+/// sequence number.
+async fn next_sequence(connection: &mut sqlx::SqliteConnection) -> Result<i64> {
+    let sequence = sqlx::query_scalar::<Sqlite, i64>(
+        "UPDATE sequence_counter SET value = value + 1 WHERE id = 0 RETURNING value",
+    )
+    .fetch_one(connection)
+    .await?;
+
+    Ok(sequence)
+}
+
+/// Give the badges & user events of a project new sequence numbers, in the same order as their
+/// old ones, so that clients that are polling the project see rows that were moved into it. The
+/// order matters, since UGS treats the last badge of each build type as its current state.
+async fn resequence_project(
+    connection: &mut sqlx::SqliteConnection,
+    project_id: i64,
+) -> Result<()> {
+    for table in ["badges", "user_events"] {
+        let rows = sqlx::query_scalar::<Sqlite, i64>(&format!(
+            "SELECT COUNT(*) FROM {table} WHERE project_id = ?"
+        ))
+        .bind(project_id)
+        .fetch_one(&mut *connection)
+        .await?;
+        if rows == 0 {
+            continue;
+        }
+
+        let last = sqlx::query_scalar::<Sqlite, i64>(
+            "UPDATE sequence_counter SET value = value + ? WHERE id = 0 RETURNING value",
+        )
+        .bind(rows)
+        .fetch_one(&mut *connection)
+        .await?;
+        sqlx::query(&format!(
+            "UPDATE {table} SET sequence = renumbered.sequence FROM (
+                SELECT id, ?1 + ROW_NUMBER() OVER (ORDER BY sequence, id) AS sequence FROM {table} WHERE project_id = ?2
+            ) AS renumbered WHERE {table}.id = renumbered.id"
+        ))
+        .bind(last - rows)
+        .bind(project_id)
+        .execute(&mut *connection)
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Build the `WHERE` clause for the telemetry filters that are set in `filter`
+fn telemetry_filters(filter: &TelemetryFilter, filter_action: bool) -> String {
+    let filters = [
+        (filter_action && filter.action.is_some()).then_some("action = ?"),
+        filter.project.is_some().then_some("project = ?"),
+        filter.user.is_some().then_some("user_name = ?"),
+        filter.since.is_some().then_some("timestamp >= ?"),
+    ];
+    let filters = filters.into_iter().flatten().collect::<Vec<_>>();
+
+    if filters.is_empty() {
+        String::new()
+    } else {
+        format!("WHERE {}", filters.join(" AND "))
+    }
+}
+
+/// Bind the values for the filters returned by `telemetry_filters` to `query`
+fn bind_telemetry_filters<'q, O>(
+    mut query: sqlx::query::QueryAs<'q, sqlx::Sqlite, O, sqlx::sqlite::SqliteArguments<'q>>,
+    filter: &'q TelemetryFilter,
+    filter_action: bool,
+) -> sqlx::query::QueryAs<'q, sqlx::Sqlite, O, sqlx::sqlite::SqliteArguments<'q>> {
+    if filter_action {
+        if let Some(action) = &filter.action {
+            query = query.bind(action);
+        }
+    }
+    if let Some(project) = &filter.project {
+        query = query.bind(project);
+    }
+    if let Some(user) = &filter.user {
+        query = query.bind(user);
+    }
+    if let Some(since) = filter.since {
+        query = query.bind(since);
+    }
+    query
+}
+
+/// List the projects in `stream`, or just the one named `project` if set
+async fn list_projects(
+    connection: &mut sqlx::SqliteConnection,
+    stream: &str,
+    project: Option<&str>,
+) -> Result<Vec<Project>> {
+    let project_query_string = format!(
+        "SELECT project_id, stream, project FROM projects WHERE stream = ? {}",
+        if project.is_some() {
+            "AND project = ?"
+        } else {
+            ""
+        }
+    );
+
+    let mut project_query =
+        sqlx::query_as::<sqlx::Sqlite, Project>(&project_query_string).bind(stream);
+    if let Some(project) = project {
+        project_query = project_query.bind(project);
+    }
+
+    Ok(project_query.fetch_all(&mut *connection).await?)
+}
+
+/// The changes with badges or user events newer than `?3` in the change range `?4..=?5` (where
+/// `?5` can be NULL), for the projects in stream `?1` (or just the one named `?2`, if it's not
+/// NULL).
+const METADATA_CHANGES: &str = "WITH stream_projects AS (
+        SELECT project_id FROM projects WHERE stream = ?1 AND (?2 IS NULL OR project = ?2)
+    ), changes AS (
+        SELECT project_id, change_number FROM badges WHERE project_id IN stream_projects AND sequence > ?3 AND change_number >= ?4 AND (?5 IS NULL OR change_number <= ?5)
+        UNION
+        SELECT project_id, change_number FROM user_events WHERE project_id IN stream_projects AND sequence > ?3 AND change_number >= ?4 AND (?5 IS NULL OR change_number <= ?5)
+    )";
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn get_project(&self, stream: &str, project: &str) -> Result<Option<i64>> {
+        let project_id = sqlx::query_scalar!(
+            "SELECT project_id FROM projects WHERE stream = ? AND project = ? LIMIT 1",
+            stream,
+            project
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(project_id)
+    }
+
+    async fn get_or_add_project(&self, stream: &str, project: &str) -> Result<i64> {
+        if let Some(project_id) = self.get_project(stream, project).await? {
+            return Ok(project_id);
+        }
+
+        info!(
+            "Creating new project for stream {}, project name {}",
+            stream, project
+        );
+
+        // Someone else might've added it since we checked, in which case we use theirs
+        sqlx::query!(
+            "INSERT INTO projects (stream, project) VALUES (?, ?) ON CONFLICT DO NOTHING",
+            stream,
+            project
+        )
+        .execute(&self.pool)
+        .await?;
+
+        self.get_project(stream, project)
+            .await?
+            .context("Project is missing right after adding it")
+    }
+
+    async fn latest_sequences(&self, project_id: i64) -> Result<LatestSequences> {
+        let badge_sequence = sqlx::query_scalar!(
+            "SELECT sequence FROM badges WHERE project_id = ? ORDER BY sequence DESC LIMIT 1",
+            project_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        let event_sequence = sqlx::query_scalar!(
+            "SELECT sequence FROM user_events WHERE project_id = ? ORDER BY sequence DESC LIMIT 1",
+            project_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        let comment_sequence = sqlx::query_scalar::<sqlx::Sqlite, i64>(
+            "SELECT sequence FROM user_events WHERE project_id = ? AND comment IS NOT NULL ORDER BY sequence DESC LIMIT 1",
+        )
+        .bind(project_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(LatestSequences {
+            badge: badge_sequence.unwrap_or_default(),
+            event: event_sequence.unwrap_or_default(),
+            comment: comment_sequence.unwrap_or_default(),
+        })
+    }
+
+    async fn latest_sequence(&self) -> Result<i64> {
+        let sequence = sqlx::query_scalar::<sqlx::Sqlite, i64>(
+            "SELECT value FROM sequence_counter WHERE id = 0",
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(sequence)
+    }
+
+    async fn metadata_version(
+        &self,
+        stream: &str,
+        project: Option<&str>,
+    ) -> Result<MetadataVersion> {
+        let (sequence, rows) = sqlx::query_as::<sqlx::Sqlite, (i64, i64)>(
+            "WITH stream_projects AS (
+                SELECT project_id FROM projects WHERE stream = ?1 AND (?2 IS NULL OR project = ?2)
+            )
+            SELECT COALESCE(MAX(sequence), 0), COUNT(*) FROM (
+                SELECT sequence FROM badges WHERE project_id IN stream_projects
+                UNION ALL
+                SELECT sequence FROM user_events WHERE project_id IN stream_projects
+            )",
+        )
+        .bind(stream)
+        .bind(project)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(MetadataVersion { sequence, rows })
+    }
+
+    async fn add_badge(
+        &self,
+        project_id: i64,
+        badge: &CreateBadge,
+        posted_by: Option<&str>,
+    ) -> Result<i64> {
+        // Only keep microseconds like PostgreSQL does, so that exports can be imported back into
+        // this database without duplicating anything
+        let added_at = Utc::now().trunc_subsecs(6);
+        let mut transaction = self.pool.begin().await?;
+        let sequence_number = next_sequence(&mut transaction).await?;
+        let result = badge.result as i32;
+        let query = sqlx::query!(
+            "INSERT INTO badges (sequence, change_number, added_at, build_type, result, url, project_id, posted_by) VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+            sequence_number,
+            badge.change_number,
+            added_at,
+            badge.build_type,
+            result,
+            badge.url,
+            project_id,
+            posted_by,
+        );
+        query.execute(&mut *transaction).await?;
+        transaction.commit().await?;
+
+        Ok(sequence_number)
+    }
+
+    async fn list_metadata(
+        &self,
+        stream: &str,
+        project: Option<&str>,
+        sequence: i64,
+        min_change: i64,
+        max_change: Option<i64>,
+    ) -> Result<Vec<ChangeMetadata>> {
+        let mut transaction = self.pool.begin().await?;
+        // A sqlite transaction reads from the same snapshot until it finishes
+
+        let projects = list_projects(&mut transaction, stream, project).await?;
+
+        let badge_query_string = format!(
+            "{METADATA_CHANGES} SELECT badges.* FROM badges JOIN changes USING (project_id, change_number) ORDER BY badges.sequence ASC, badges.id ASC"
+        );
+        let badges = sqlx::query_as::<sqlx::Sqlite, Badge>(&badge_query_string)
+            .bind(stream)
+            .bind(project)
+            .bind(sequence)
+            .bind(min_change)
+            .bind(max_change)
+            .fetch_all(&mut *transaction)
+            .await?;
+
+        let user_event_query_string = format!(
+            "{METADATA_CHANGES} SELECT user_events.* FROM user_events JOIN changes USING (project_id, change_number) ORDER BY user_events.sequence ASC, user_events.id ASC"
+        );
+        let user_events = sqlx::query_as::<sqlx::Sqlite, UserEvent>(&user_event_query_string)
+            .bind(stream)
+            .bind(project)
+            .bind(sequence)
+            .bind(min_change)
+            .bind(max_change)
+            .fetch_all(&mut *transaction)
+            .await?;
+
+        let metadata = group_metadata(&projects, badges, user_events);
+        transaction.commit().await?;
+
+        Ok(metadata)
+    }
+
+    async fn list_user_events_since(
+        &self,
+        project_id: i64,
+        sequence: i64,
+        comments_only: bool,
+    ) -> Result<Vec<UserEvent>> {
+        let user_event_query_string = format!(
+            "SELECT * FROM user_events WHERE project_id = ? AND sequence > ? {} ORDER BY sequence ASC, id ASC",
+            if comments_only {
+                "AND comment IS NOT NULL"
+            } else {
+                ""
+            }
+        );
+
+        let user_events = sqlx::query_as::<sqlx::Sqlite, UserEvent>(&user_event_query_string)
+            .bind(project_id)
+            .bind(sequence)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(user_events)
+    }
+
+    async fn update_user_event(
+        &self,
+        project_id: i64,
+        change_number: i64,
+        user_name: &str,
+        update: UserEventUpdate,
+    ) -> Result<i64> {
+        let now = Utc::now().trunc_subsecs(6);
+        // Allocating the sequence number locks the counter until we commit, so nobody else can
+        // change this user event between us reading & writing it
+        let mut transaction = self.pool.begin().await?;
+        let sequence_number = next_sequence(&mut transaction).await?;
+
+        let existing_event_query_string =
+            "SELECT * FROM user_events WHERE project_id = ? AND user_name = ? AND change_number = ?";
+        let existing_event_query =
+            sqlx::query_as::<sqlx::Sqlite, UserEvent>(existing_event_query_string)
+                .bind(project_id)
+                .bind(user_name)
+                .bind(change_number);
+        let user_event = existing_event_query
+            .fetch_optional(&mut *transaction)
+            .await?;
+
+        let needs_insert = user_event.is_none();
+
+        let mut user_event = user_event.unwrap_or_else(UserEvent::default);
+        update.apply_to(&mut user_event, now);
+
+        if needs_insert {
+            sqlx::query!(
+                "INSERT INTO user_events (project_id, change_number, user_name, sequence, updated_at, synced_at, vote, investigating, starred, comment) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                project_id,
+                change_number,
+                user_name,
+                sequence_number,
+                now,
+                user_event.synced_at,
+                user_event.vote,
+                user_event.investigating,
+                user_event.starred,
+                user_event.comment,
+            ).execute(&mut *transaction).await?;
+        } else {
+            sqlx::query!(
+                "UPDATE user_events SET sequence = ?, updated_at = ?, synced_at = ?, vote = ?, investigating = ?, starred = ?, comment = ? WHERE id = ?",
+                sequence_number,
+                now,
+                user_event.synced_at,
+                user_event.vote,
+                user_event.investigating,
+                user_event.starred,
+                user_event.comment,
+                user_event.id,
+            ).execute(&mut *transaction).await?;
+        }
+        transaction.commit().await?;
+
+        Ok(sequence_number)
+    }
+
+    async fn list_projects(&self) -> Result<Vec<ProjectSummary>> {
+        let projects = sqlx::query_as::<Sqlite, ProjectSummary>(
+            "SELECT project_id, stream, project, (SELECT COUNT(*) FROM badges WHERE badges.project_id = projects.project_id) AS badges, (SELECT COUNT(*) FROM user_events WHERE user_events.project_id = projects.project_id) AS user_events FROM projects ORDER BY stream, project",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(projects)
+    }
+
+    async fn rename_project(
+        &self,
+        project_id: i64,
+        stream: &str,
+        project: &str,
+        dry_run: bool,
+    ) -> Result<()> {
+        let mut transaction = self.pool.begin().await?;
+        sqlx::query("UPDATE projects SET stream = ?, project = ? WHERE project_id = ?")
+            .bind(stream)
+            .bind(project)
+            .bind(project_id)
+            .execute(&mut *transaction)
+            .await?;
+        resequence_project(&mut transaction, project_id).await?;
+        finish(transaction, dry_run).await
+    }
+
+    async fn merge_projects(&self, from: i64, into: i64, dry_run: bool) -> Result<ProjectMerge> {
+        let mut transaction = self.pool.begin().await?;
+        let mut merge = ProjectMerge::default();
+
+        // A user can only have one event per change, so if they have one in both projects, drop
+        // the older one before moving the rest
+        let conflict = "EXISTS (SELECT 1 FROM user_events AS other WHERE other.project_id = ? AND other.user_name = user_events.user_name AND other.change_number = user_events.change_number";
+        merge.dropped_user_events += sqlx::query(&format!(
+            "DELETE FROM user_events WHERE project_id = ? AND {conflict} AND (other.sequence, other.id) > (user_events.sequence, user_events.id))"
+        ))
+        .bind(into)
+        .bind(from)
+        .execute(&mut *transaction)
+        .await?
+        .rows_affected();
+        merge.dropped_user_events += sqlx::query(&format!(
+            "DELETE FROM user_events WHERE project_id = ? AND {conflict})"
+        ))
+        .bind(from)
+        .bind(into)
+        .execute(&mut *transaction)
+        .await?
+        .rows_affected();
+
+        merge.badges = sqlx::query("UPDATE badges SET project_id = ? WHERE project_id = ?")
+            .bind(into)
+            .bind(from)
+            .execute(&mut *transaction)
+            .await?
+            .rows_affected();
+        merge.user_events =
+            sqlx::query("UPDATE user_events SET project_id = ? WHERE project_id = ?")
+                .bind(into)
+                .bind(from)
+                .execute(&mut *transaction)
+                .await?
+                .rows_affected();
+        sqlx::query("DELETE FROM projects WHERE project_id = ?")
+            .bind(from)
+            .execute(&mut *transaction)
+            .await?;
+        // Interleave what we moved with what was already there, by when it was written
+        resequence_project(&mut transaction, into).await?;
+
+        finish(transaction, dry_run).await?;
+        Ok(merge)
+    }
+
+    async fn delete_badges(&self, filter: &BadgeFilter, dry_run: bool) -> Result<u64> {
+        let mut transaction = self.pool.begin().await?;
+        let deleted = sqlx::query(
+            "DELETE FROM badges WHERE project_id = ? AND (? IS NULL OR change_number = ?) AND (? IS NULL OR build_type = ?)",
+        )
+        .bind(filter.project_id)
+        .bind(filter.change_number)
+        .bind(filter.change_number)
+        .bind(filter.build_type.as_deref())
+        .bind(filter.build_type.as_deref())
+        .execute(&mut *transaction)
+        .await?
+        .rows_affected();
+
+        if deleted > 0 && !dry_run {
+            next_sequence(&mut transaction).await?;
+        }
+
+        finish(transaction, dry_run).await?;
+        Ok(deleted)
+    }
+
+    async fn delete_user_events(
+        &self,
+        user_name: &str,
+        project_id: Option<i64>,
+        dry_run: bool,
+    ) -> Result<u64> {
+        let mut transaction = self.pool.begin().await?;
+        let deleted = sqlx::query(
+            "DELETE FROM user_events WHERE user_name = ? COLLATE NOCASE AND (? IS NULL OR project_id = ?)",
+        )
+        .bind(user_name)
+        .bind(project_id)
+        .bind(project_id)
+        .execute(&mut *transaction)
+        .await?
+        .rows_affected();
+
+        if deleted > 0 && !dry_run {
+            next_sequence(&mut transaction).await?;
+        }
+
+        finish(transaction, dry_run).await?;
+        Ok(deleted)
+    }
+
+    async fn import_badge(&self, project_id: i64, badge: &Badge) -> Result<ImportOutcome> {
+        // Allocating the sequence number locks the counter until we commit, so nobody else can
+        // import the same badge in the meantime
+        let mut transaction = self.pool.begin().await?;
+        let sequence_number = next_sequence(&mut transaction).await?;
+
+        // Compare the timestamps outside of sqlite, since it compares them as text
+        let existing_added_at = sqlx::query_scalar::<Sqlite, DateTime<Utc>>(
+            "SELECT added_at FROM badges WHERE project_id = ? AND change_number = ? AND build_type = ? AND result = ? AND url = ?",
+        )
+        .bind(project_id)
+        .bind(badge.change_number)
+        .bind(&badge.build_type)
+        .bind(badge.result)
+        .bind(&badge.url)
+        .fetch_all(&mut *transaction)
+        .await?;
+        if existing_added_at
+            .into_iter()
+            .any(|added_at| same_timestamp(added_at, badge.added_at))
+        {
+            transaction.rollback().await?;
+            return Ok(ImportOutcome::Unchanged);
+        }
+
+        sqlx::query(
+            "INSERT INTO badges (sequence, change_number, added_at, build_type, result, url, project_id, posted_by) VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(sequence_number)
+        .bind(badge.change_number)
+        .bind(badge.added_at)
+        .bind(&badge.build_type)
+        .bind(badge.result)
+        .bind(&badge.url)
+        .bind(project_id)
+        .bind(&badge.posted_by)
+        .execute(&mut *transaction)
+        .await?;
+        transaction.commit().await?;
+
+        Ok(ImportOutcome::Added)
+    }
+
+    async fn import_user_event(
+        &self,
+        project_id: i64,
+        user_event: &UserEvent,
+    ) -> Result<ImportOutcome> {
+        let mut transaction = self.pool.begin().await?;
+        let sequence_number = next_sequence(&mut transaction).await?;
+
+        let existing = sqlx::query_as::<Sqlite, UserEvent>(
+            "SELECT * FROM user_events WHERE project_id = ? AND user_name = ? AND change_number = ?",
+        )
+        .bind(project_id)
+        .bind(&user_event.user_name)
+        .bind(user_event.change_number)
+        .fetch_optional(&mut *transaction)
+        .await?;
+        if let Some(existing) = existing {
+            transaction.rollback().await?;
+            return Ok(if same_user_event(&existing, user_event) {
+                ImportOutcome::Unchanged
+            } else {
+                ImportOutcome::Conflict
+            });
+        }
+
+        sqlx::query(
+            "INSERT INTO user_events (project_id, change_number, user_name, sequence, updated_at, synced_at, vote, investigating, starred, comment) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(project_id)
+        .bind(user_event.change_number)
+        .bind(&user_event.user_name)
+        .bind(sequence_number)
+        .bind(user_event.updated_at)
+        .bind(user_event.synced_at)
+        .bind(&user_event.vote)
+        .bind(user_event.investigating)
+        .bind(user_event.starred)
+        .bind(&user_event.comment)
+        .execute(&mut *transaction)
+        .await?;
+        transaction.commit().await?;
+
+        Ok(ImportOutcome::Added)
+    }
+
+    async fn prune_metadata(&self, pruning: &MetadataPruning) -> Result<PrunedMetadata> {
+        let mut transaction = self.pool.begin().await?;
+        let mut pruned = PrunedMetadata::default();
+
+        if let Some(superseded_before) = pruning.superseded_before {
+            pruned.badges += sqlx::query(
+                "DELETE FROM badges WHERE project_id = ? AND added_at < ? AND EXISTS (SELECT 1 FROM badges AS newer WHERE newer.project_id = badges.project_id AND newer.change_number = badges.change_number AND newer.build_type = badges.build_type AND (newer.added_at > badges.added_at OR (newer.added_at = badges.added_at AND newer.id > badges.id)))",
+            )
+            .bind(pruning.project_id)
+            .bind(superseded_before)
+            .execute(&mut *transaction)
+            .await?
+            .rows_affected();
+        }
+
+        if let Some(before) = pruning.before {
+            pruned.badges +=
+                sqlx::query("DELETE FROM badges WHERE project_id = ? AND added_at < ?")
+                    .bind(pruning.project_id)
+                    .bind(before)
+                    .execute(&mut *transaction)
+                    .await?
+                    .rows_affected();
+            pruned.user_events +=
+                sqlx::query("DELETE FROM user_events WHERE project_id = ? AND updated_at < ?")
+                    .bind(pruning.project_id)
+                    .bind(before)
+                    .execute(&mut *transaction)
+                    .await?
+                    .rows_affected();
+        }
+
+        if let Some(keep_changes) = pruning.keep_changes {
+            // The oldest change we keep, if there are more than `keep_changes` of them
+            let oldest_change = sqlx::query_scalar::<Sqlite, i64>(
+                "SELECT change_number FROM (SELECT change_number FROM badges WHERE project_id = ? UNION SELECT change_number FROM user_events WHERE project_id = ?) ORDER BY change_number DESC LIMIT 1 OFFSET ?",
+            )
+            .bind(pruning.project_id)
+            .bind(pruning.project_id)
+            .bind(i64::from(keep_changes) - 1)
+            .fetch_optional(&mut *transaction)
+            .await?;
+
+            if let Some(oldest_change) = oldest_change {
+                pruned.badges +=
+                    sqlx::query("DELETE FROM badges WHERE project_id = ? AND change_number < ?")
+                        .bind(pruning.project_id)
+                        .bind(oldest_change)
+                        .execute(&mut *transaction)
+                        .await?
+                        .rows_affected();
+                pruned.user_events += sqlx::query(
+                    "DELETE FROM user_events WHERE project_id = ? AND change_number < ?",
+                )
+                .bind(pruning.project_id)
+                .bind(oldest_change)
+                .execute(&mut *transaction)
+                .await?
+                .rows_affected();
+            }
+        }
+
+        if pruned.badges + pruned.user_events > 0 {
+            next_sequence(&mut transaction).await?;
+        }
+
+        transaction.commit().await?;
+        Ok(pruned)
+    }
+
+    async fn list_issues(
+        &self,
+        user: Option<&str>,
+        include_resolved: bool,
+        max_results: i64,
+    ) -> Result<Vec<Issue>> {
+        let issue_query_string = format!(
+            "SELECT issues.*, watchers.user_name IS NOT NULL AS notify FROM issues LEFT JOIN issue_watchers AS watchers ON watchers.issue_id = issues.id AND watchers.user_name = ? {} ORDER BY issues.id DESC LIMIT ?",
+            if include_resolved {
+                ""
+            } else {
+                "WHERE issues.resolved_at IS NULL"
+            }
+        );
+
+        // sqlite treats a negative LIMIT as no limit
+        let issues = sqlx::query_as::<sqlx::Sqlite, Issue>(&issue_query_string)
+            .bind(user)
+            .bind(max_results)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(issues)
+    }
+
+    async fn get_issue(&self, issue_id: i64, user: Option<&str>) -> Result<Option<Issue>> {
+        let issue = sqlx::query_as::<sqlx::Sqlite, Issue>(
+            "SELECT issues.*, watchers.user_name IS NOT NULL AS notify FROM issues LEFT JOIN issue_watchers AS watchers ON watchers.issue_id = issues.id AND watchers.user_name = ? WHERE issues.id = ?",
+        )
+        .bind(user)
+        .bind(issue_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(issue)
+    }
+
+    async fn get_issue_streams(&self, issue_ids: &[i64]) -> Result<HashMap<i64, Vec<String>>> {
+        if issue_ids.is_empty() {
+            return Ok(HashMap::new());
+        }
+
+        let streams_query_string = format!(
+            "SELECT DISTINCT issue_id, stream FROM issue_builds WHERE issue_id IN ({}) ORDER BY stream",
+            itertools::repeat_n("?", issue_ids.len()).join(", ")
+        );
+        let mut streams_query =
+            sqlx::query_as::<sqlx::Sqlite, (i64, String)>(&streams_query_string);
+        for issue_id in issue_ids {
+            streams_query = streams_query.bind(issue_id);
+        }
+
+        Ok(streams_query
+            .fetch_all(&self.pool)
+            .await?
+            .into_iter()
+            .into_group_map())
+    }
+
+    async fn add_issue(&self, issue: &CreateIssue, created_at: DateTime<Utc>) -> Result<i64> {
+        let id = sqlx::query(
+            "INSERT INTO issues (created_at, project, summary, owner, nominated_by, is_warning, build_url) VALUES (?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(created_at)
+        .bind(&issue.project)
+        .bind(&issue.summary)
+        .bind(&issue.owner)
+        .bind(&issue.nominated_by)
+        .bind(issue.is_warning)
+        .bind(&issue.build_url)
+        .execute(&self.pool)
+        .await?
+        .last_insert_rowid();
+
+        Ok(id)
+    }
+
+    async fn update_issue(&self, issue: &Issue) -> Result<()> {
+        sqlx::query(
+            "UPDATE issues SET summary = ?, owner = ?, nominated_by = ?, acknowledged_at = ?, fix_change = ?, resolved_at = ? WHERE id = ?",
+        )
+        .bind(&issue.summary)
+        .bind(&issue.owner)
+        .bind(&issue.nominated_by)
+        .bind(issue.acknowledged_at)
+        .bind(issue.fix_change)
+        .bind(issue.resolved_at)
+        .bind(issue.id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn issue_exists(&self, issue_id: i64) -> Result<bool> {
+        let exists = sqlx::query_scalar::<sqlx::Sqlite, i64>("SELECT id FROM issues WHERE id = ?")
+            .bind(issue_id)
+            .fetch_optional(&self.pool)
+            .await?
+            .is_some();
+        Ok(exists)
+    }
+
+    async fn list_issue_builds(&self, issue_id: i64) -> Result<Vec<IssueBuild>> {
+        let builds = sqlx::query_as::<sqlx::Sqlite, IssueBuild>(
+            "SELECT * FROM issue_builds WHERE issue_id = ? ORDER BY change_number ASC, id ASC",
+        )
+        .bind(issue_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(builds)
+    }
+
+    async fn add_issue_build(&self, issue_id: i64, build: &IssueBuildData) -> Result<i64> {
+        let id = sqlx::query(
+            "INSERT INTO issue_builds (issue_id, stream, change_number, job_name, job_url, job_step_name, job_step_url, error_url, outcome) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(issue_id)
+        .bind(&build.stream)
+        .bind(build.change)
+        .bind(&build.job_name)
+        .bind(&build.job_url)
+        .bind(&build.job_step_name)
+        .bind(&build.job_step_url)
+        .bind(&build.error_url)
+        .bind(build.outcome)
+        .execute(&self.pool)
+        .await?
+        .last_insert_rowid();
+
+        Ok(id)
+    }
+
+    async fn list_issue_diagnostics(&self, issue_id: i64) -> Result<Vec<IssueDiagnosticData>> {
+        let diagnostics = sqlx::query_as::<sqlx::Sqlite, IssueDiagnosticData>(
+            "SELECT build_id, message, url FROM issue_diagnostics WHERE issue_id = ? ORDER BY id ASC",
+        )
+        .bind(issue_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(diagnostics)
+    }
+
+    async fn add_issue_diagnostic(
+        &self,
+        issue_id: i64,
+        diagnostic: &IssueDiagnosticData,
+    ) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO issue_diagnostics (issue_id, build_id, message, url) VALUES (?, ?, ?, ?)",
+        )
+        .bind(issue_id)
+        .bind(diagnostic.build_id)
+        .bind(&diagnostic.message)
+        .bind(&diagnostic.url)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn list_issue_watchers(&self, issue_id: i64) -> Result<Vec<String>> {
+        let watchers = sqlx::query_scalar::<sqlx::Sqlite, String>(
+            "SELECT user_name FROM issue_watchers WHERE issue_id = ? ORDER BY user_name ASC",
+        )
+        .bind(issue_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(watchers)
+    }
+
+    async fn set_issue_watcher(&self, issue_id: i64, user_name: &str, watch: bool) -> Result<()> {
+        let query = if watch {
+            "INSERT OR IGNORE INTO issue_watchers (issue_id, user_name) VALUES (?, ?)"
+        } else {
+            "DELETE FROM issue_watchers WHERE issue_id = ? AND user_name = ?"
+        };
+        sqlx::query(query)
+            .bind(issue_id)
+            .bind(user_name)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn add_telemetry_timing(
+        &self,
+        received_at: DateTime<Utc>,
+        timing: &TelemetryTimingData,
+        version: Option<&str>,
+        ip_address: Option<&str>,
+    ) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO telemetry_timings (received_at, action, result, user_name, project, timestamp, duration, version, ip_address) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(received_at)
+        .bind(&timing.action)
+        .bind(&timing.result)
+        .bind(&timing.user_name)
+        .bind(&timing.project)
+        .bind(timing.timestamp)
+        .bind(timing.duration)
+        .bind(version)
+        .bind(ip_address)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn add_telemetry_error(
+        &self,
+        received_at: DateTime<Utc>,
+        error: &TelemetryErrorData,
+    ) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO telemetry_errors (received_at, error_type, text, user_name, project, timestamp, version, ip_address) VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(received_at)
+        .bind(error.error_type)
+        .bind(&error.text)
+        .bind(&error.user_name)
+        .bind(&error.project)
+        .bind(error.timestamp)
+        .bind(&error.version)
+        .bind(&error.ip_address)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn telemetry_summary(
+        &self,
+        filter: &TelemetryFilter,
+    ) -> Result<Vec<TelemetryTimingSummary>> {
+        let filters = telemetry_filters(filter, true);
+
+        let summary_query_string = format!(
+            "SELECT action, result, COUNT(*) AS count, AVG(duration) AS average_duration, MIN(duration) AS min_duration, MAX(duration) AS max_duration FROM telemetry_timings {filters} GROUP BY action, result ORDER BY action, result"
+        );
+        let summary_query =
+            sqlx::query_as::<sqlx::Sqlite, TelemetryTimingSummary>(&summary_query_string);
+        let summary = bind_telemetry_filters(summary_query, filter, true)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(summary)
+    }
+
+    async fn list_telemetry_timings(
+        &self,
+        filter: &TelemetryFilter,
+        limit: i64,
+    ) -> Result<Vec<TelemetryTimingData>> {
+        let filters = telemetry_filters(filter, true);
+
+        let timing_query_string = format!(
+            "SELECT action, result, user_name, project, timestamp, duration FROM telemetry_timings {filters} ORDER BY timestamp DESC LIMIT ?"
+        );
+        let timing_query =
+            sqlx::query_as::<sqlx::Sqlite, TelemetryTimingData>(&timing_query_string);
+        let timings = bind_telemetry_filters(timing_query, filter, true)
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(timings)
+    }
+
+    async fn list_telemetry_errors(
+        &self,
+        filter: &TelemetryFilter,
+        limit: i64,
+    ) -> Result<Vec<TelemetryErrorData>> {
+        let filters = telemetry_filters(filter, false);
+
+        let error_query_string = format!(
+            "SELECT id, error_type, text, user_name, project, timestamp, version, ip_address FROM telemetry_errors {filters} ORDER BY timestamp DESC LIMIT ?"
+        );
+        let error_query = sqlx::query_as::<sqlx::Sqlite, TelemetryErrorData>(&error_query_string);
+        let errors = bind_telemetry_filters(error_query, filter, false)
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(errors)
+    }
+
+    async fn prune_telemetry(&self, received_before: DateTime<Utc>) -> Result<u64> {
+        let mut removed = 0;
+        for table in ["telemetry_timings", "telemetry_errors"] {
+            removed += sqlx::query(&format!("DELETE FROM {table} WHERE received_at < ?"))
+                .bind(received_before)
+                .execute(&self.pool)
+                .await?
+                .rows_affected();
+        }
+
+        Ok(removed)
+    }
+
+    async fn has_users(&self) -> Result<bool> {
+        let has_users =
+            sqlx::query_scalar::<sqlx::Sqlite, bool>("SELECT EXISTS(SELECT 1 FROM users)")
+                .fetch_one(&self.pool)
+                .await?;
+        Ok(has_users)
+    }
+
+    async fn get_password_hash(&self, user_name: &str) -> Result<Option<(String, String)>> {
+        let user = sqlx::query_as::<sqlx::Sqlite, (String, String)>(
+            "SELECT user_name, password_hash FROM users WHERE user_name = ? AND revoked_at IS NULL",
+        )
+        .bind(user_name)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(user)
+    }
+
+    async fn add_user(&self, user_name: &str, password_hash: &str) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO users (user_name, password_hash, created_at) VALUES (?, ?, ?) ON CONFLICT (user_name) DO UPDATE SET password_hash = excluded.password_hash, revoked_at = NULL",
+        )
+        .bind(user_name)
+        .bind(password_hash)
+        .bind(Utc::now())
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn revoke_user(&self, user_name: &str) -> Result<bool> {
+        let revoked = sqlx::query(
+            "UPDATE users SET revoked_at = ? WHERE user_name = ? AND revoked_at IS NULL",
+        )
+        .bind(Utc::now())
+        .bind(user_name)
+        .execute(&self.pool)
+        .await?
+        .rows_affected()
+            > 0;
+        Ok(revoked)
+    }
+
+    async fn list_users(&self) -> Result<Vec<User>> {
+        let users = sqlx::query_as::<sqlx::Sqlite, User>(
+            "SELECT user_name, created_at, revoked_at FROM users ORDER BY user_name",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(users)
+    }
+
+    async fn add_alias(&self, user_name: &str, perforce_user_name: &str) -> Result<bool> {
+        let inserted = sqlx::query(
+            "INSERT OR IGNORE INTO user_aliases (user_name, perforce_user_name) SELECT user_name, ? FROM users WHERE user_name = ?",
+        )
+        .bind(perforce_user_name)
+        .bind(user_name)
+        .execute(&self.pool)
+        .await?
+        .rows_affected();
+
+        // Nothing is inserted either if the user doesn't exist, or if the alias already did
+        Ok(inserted > 0 || self.is_alias(user_name, perforce_user_name).await?)
+    }
+
+    async fn remove_alias(&self, user_name: &str, perforce_user_name: &str) -> Result<bool> {
+        let removed =
+            sqlx::query("DELETE FROM user_aliases WHERE user_name = ? AND perforce_user_name = ?")
+                .bind(user_name)
+                .bind(perforce_user_name)
+                .execute(&self.pool)
+                .await?
+                .rows_affected()
+                > 0;
+        Ok(removed)
+    }
+
+    async fn list_aliases(&self, user_name: &str) -> Result<Vec<String>> {
+        let aliases = sqlx::query_scalar::<sqlx::Sqlite, String>(
+            "SELECT perforce_user_name FROM user_aliases WHERE user_name = ? ORDER BY perforce_user_name",
+        )
+        .bind(user_name)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(aliases)
+    }
+
+    async fn is_alias(&self, user_name: &str, perforce_user_name: &str) -> Result<bool> {
+        let is_alias = sqlx::query_scalar::<sqlx::Sqlite, bool>(
+            "SELECT EXISTS(SELECT 1 FROM user_aliases WHERE user_name = ? AND perforce_user_name = ?)",
+        )
+        .bind(user_name)
+        .bind(perforce_user_name)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(is_alias)
+    }
+
+    async fn has_ci_tokens(&self) -> Result<bool> {
+        let has_tokens =
+            sqlx::query_scalar::<sqlx::Sqlite, bool>("SELECT EXISTS(SELECT 1 FROM ci_tokens)")
+                .fetch_one(&self.pool)
+                .await?;
+        Ok(has_tokens)
+    }
+
+    async fn add_ci_token(&self, token: &CiToken, token_hash: &str) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO ci_tokens (name, token_hash, project_patterns, build_types, created_at) VALUES (?, ?, ?, ?, ?)",
+        )
+        .bind(&token.name)
+        .bind(token_hash)
+        .bind(&token.project_patterns)
+        .bind(&token.build_types)
+        .bind(token.created_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn revoke_ci_token(&self, name: &str) -> Result<bool> {
+        let revoked = sqlx::query(
+            "UPDATE ci_tokens SET revoked_at = ? WHERE name = ? AND revoked_at IS NULL",
+        )
+        .bind(Utc::now())
+        .bind(name)
+        .execute(&self.pool)
+        .await?
+        .rows_affected()
+            > 0;
+        Ok(revoked)
+    }
+
+    async fn list_ci_tokens(&self) -> Result<Vec<CiToken>> {
+        let tokens = sqlx::query_as::<sqlx::Sqlite, CiToken>(
+            "SELECT name, project_patterns, build_types, created_at, revoked_at FROM ci_tokens ORDER BY name",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(tokens)
+    }
+
+    async fn find_ci_token(&self, name: Option<&str>, token_hash: &str) -> Result<Option<CiToken>> {
+        let ci_token = sqlx::query_as::<sqlx::Sqlite, CiToken>(
+            "SELECT name, project_patterns, build_types, created_at, revoked_at FROM ci_tokens WHERE token_hash = ? AND (? IS NULL OR name = ?) AND revoked_at IS NULL",
+        )
+        .bind(token_hash)
+        .bind(name)
+        .bind(name)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(ci_token)
+    }
+
+    async fn get_state(&self, key: &str) -> Result<Option<String>> {
+        let value = sqlx::query_scalar::<sqlx::Sqlite, String>(
+            "SELECT value FROM server_state WHERE key = ?",
+        )
+        .bind(key)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(value)
+    }
+
+    async fn set_state(&self, key: &str, value: &str) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO server_state (key, value) VALUES (?, ?) ON CONFLICT (key) DO UPDATE SET value = excluded.value",
+        )
+        .bind(key)
+        .bind(value)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn database_size(&self) -> Result<u64> {
+        let size = sqlx::query_scalar::<sqlx::Sqlite, i64>(
+            "SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()",
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(size.try_into()?)
+    }
+
+    async fn optimize(&self) -> Result<()> {
+        // Per the sqlite docs, this is recommended to be run regularly for long-running apps (https://www.sqlite.org/pragma.html#pragma_optimize)
+        sqlx::query("PRAGMA optimize").execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn backup(&self, path: &Path) -> Result<()> {
+        let path = path
+            .to_str()
+            .with_context(|| format!("Backup path {} isn't valid UTF-8", path.display()))?;
+        // This reads from a single snapshot of the database, so it's consistent even if someone
+        // writes to it while we're copying it
+        sqlx::query("VACUUM INTO ?")
+            .bind(path)
+            .execute(&self.pool)
+            .await
+            .with_context(|| format!("Could not back the database up to {path}"))?;
+        // Backing up an in-memory database creates another in-memory database instead
+        if tokio::fs::metadata(path)
+            .await
+            .map_or(0, |metadata| metadata.len())
+            == 0
+        {
+            anyhow::bail!("Could not back the database up to {path}, is it an in-memory database?");
+        }
+        Ok(())
+    }
+
+    async fn migration_status(&self) -> Result<MigrationStatus> {
+        let mut connection = self.pool.acquire().await?;
+        migration_status(&MIGRATOR, &mut *connection).await
+    }
+
+    async fn migrate(&self) -> Result<()> {
+        MIGRATOR.run(&self.pool).await?;
+        Ok(())
+    }
+}
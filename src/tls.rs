@@ -0,0 +1,115 @@
+use anyhow::{bail, Context, Result};
+use axum::{
+    extract::Host,
+    http::Uri,
+    response::{IntoResponse, Redirect},
+    Router,
+};
+use axum_server::tls_rustls::RustlsConfig;
+use tracing::{error, info};
+
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// Keeps track of a PEM certificate & key on disk, so that we can reload them into the
+/// `RustlsConfig` when they change (e.g. when they're renewed). Existing connections keep using
+/// the certificate they were established with, new connections get the new one.
+#[derive(Debug)]
+pub struct CertificateWatcher {
+    config: RustlsConfig,
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    /// The contents of the certificate & key that are currently loaded
+    loaded: (Vec<u8>, Vec<u8>),
+}
+
+/// Read the certificate & key, making sure that the certificate file contains a certificate
+/// (rustls will happily serve an empty certificate chain, e.g. if we read a half-written file).
+async fn read_pem_files(cert_path: &Path, key_path: &Path) -> Result<(Vec<u8>, Vec<u8>)> {
+    let cert = tokio::fs::read(cert_path)
+        .await
+        .with_context(|| format!("Could not read TLS certificate {}", cert_path.display()))?;
+    let key = tokio::fs::read(key_path)
+        .await
+        .with_context(|| format!("Could not read TLS key {}", key_path.display()))?;
+
+    let certificate_count = rustls_pemfile::certs(&mut cert.as_slice())
+        .with_context(|| format!("Invalid TLS certificate {}", cert_path.display()))?
+        .len();
+    if certificate_count == 0 {
+        bail!("No certificates found in {}", cert_path.display());
+    }
+
+    Ok((cert, key))
+}
+
+impl CertificateWatcher {
+    /// Load the certificate & key, returning the config to serve with and a watcher for it
+    pub async fn new(cert_path: &Path, key_path: &Path) -> Result<(RustlsConfig, Self)> {
+        let (cert, key) = read_pem_files(cert_path, key_path).await?;
+        let config = RustlsConfig::from_pem(cert.clone(), key.clone())
+            .await
+            .context("Could not load TLS certificate")?;
+
+        let watcher = Self {
+            config: config.clone(),
+            cert_path: cert_path.to_owned(),
+            key_path: key_path.to_owned(),
+            loaded: (cert, key),
+        };
+        Ok((config, watcher))
+    }
+
+    /// Reload the certificate & key if either of them have changed on disk, returning whether
+    /// we reloaded them. If the new ones are invalid, we keep serving the old ones.
+    pub async fn reload_if_changed(&mut self) -> Result<bool> {
+        let (cert, key) = read_pem_files(&self.cert_path, &self.key_path).await?;
+        if (&cert, &key) == (&self.loaded.0, &self.loaded.1) {
+            return Ok(false);
+        }
+
+        self.config
+            .reload_from_pem(cert.clone(), key.clone())
+            .await
+            .context("Could not load changed TLS certificate")?;
+        self.loaded = (cert, key);
+        Ok(true)
+    }
+
+    /// Check for changes to the certificate every `interval`, forever
+    pub async fn run(mut self, interval: Duration) {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            match self.reload_if_changed().await {
+                Ok(true) => info!("reloaded TLS certificate {}", self.cert_path.display()),
+                Ok(false) => {}
+                Err(e) => error!("failed to reload TLS certificate: {:?}", e),
+            }
+        }
+    }
+}
+
+/// An app that redirects every request to the same path over HTTPS on `https_port`
+pub fn redirect_app(https_port: u16) -> Router {
+    Router::new().fallback(move |Host(host): Host, uri: Uri| async move {
+        // `Host` can include the port the request was made to, which we need to replace
+        let host = match host.rsplit_once(':') {
+            Some((hostname, port)) if port.chars().all(|c| c.is_ascii_digit()) => hostname,
+            _ => host.as_str(),
+        };
+        let path_and_query = uri
+            .path_and_query()
+            .map(|path_and_query| path_and_query.as_str())
+            .unwrap_or("/");
+
+        let location = if https_port == 443 {
+            format!("https://{host}{path_and_query}")
+        } else {
+            format!("https://{host}:{https_port}{path_and_query}")
+        };
+        Redirect::permanent(&location).into_response()
+    })
+}
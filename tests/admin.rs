@@ -0,0 +1,274 @@
+use anyhow::{Context, Result};
+use axum::{body::Body, http::StatusCode};
+use rugs::{
+    app::app,
+    models::{BadgeResult, CreateBadge},
+    storage::{BadgeFilter, ProjectMerge, ProjectSummary, Storage, UserEventUpdate},
+};
+use tower::{Service, ServiceExt};
+
+use std::{path::Path, process::Output, sync::Arc};
+
+mod common;
+use common::{
+    authorization_header, config, for_each_storage, get_metadata, request_builder,
+    simple_create_request, CI_AUTH,
+};
+
+/// Test the storage operations behind the `admin` commands, and that their dry runs don't change
+/// anything
+#[tokio::test]
+async fn admin() -> Result<()> {
+    for_each_storage(admin_with).await
+}
+
+async fn admin_with(storage: Arc<dyn Storage>) -> Result<()> {
+    let old_id = storage.get_or_add_project("//depot/stream", "old").await?;
+    let new_id = storage.get_or_add_project("//depot/stream", "new").await?;
+    for (project_id, build_type) in [(old_id, "Editor"), (old_id, "Game"), (new_id, "Editor")] {
+        let badge = CreateBadge {
+            build_type: String::from(build_type),
+            ..simple_create_request()
+        };
+        storage.add_badge(project_id, &badge, None).await?;
+    }
+    // Alice's newest vote on change 1 is in `old`, and Bob's is in `new`
+    for (project_id, change_number, user_name) in [
+        (new_id, 1, "alice"),
+        (old_id, 1, "alice"),
+        (old_id, 1, "bob"),
+        (new_id, 1, "bob"),
+        (old_id, 2, "bob"),
+    ] {
+        let update = UserEventUpdate {
+            comment: Some(format!("{user_name} in {project_id}")),
+            ..Default::default()
+        };
+        storage
+            .update_user_event(project_id, change_number, user_name, update)
+            .await?;
+    }
+
+    let summary = |projects: Vec<ProjectSummary>| {
+        projects
+            .into_iter()
+            .map(|project| (project.project, project.badges, project.user_events))
+            .collect::<Vec<_>>()
+    };
+    let before = summary(storage.list_projects().await?);
+    assert_eq!(
+        before,
+        vec![(String::from("new"), 1, 2), (String::from("old"), 2, 3)]
+    );
+
+    let merge = storage.merge_projects(old_id, new_id, true).await?;
+    assert_eq!(summary(storage.list_projects().await?), before);
+
+    let sequence = storage.latest_sequence().await?;
+    assert_eq!(storage.merge_projects(old_id, new_id, false).await?, merge);
+    assert_eq!(
+        merge,
+        ProjectMerge {
+            badges: 2,
+            user_events: 2,
+            dropped_user_events: 2,
+        }
+    );
+    assert_eq!(
+        summary(storage.list_projects().await?),
+        vec![(String::from("new"), 3, 3)]
+    );
+    let metadata = storage
+        .list_metadata("//depot/stream", Some("new"), 0, 1, Some(1))
+        .await?;
+    let mut comments = metadata[0]
+        .user_events
+        .iter()
+        .filter_map(|user_event| user_event.comment.clone())
+        .collect::<Vec<_>>();
+    comments.sort();
+    assert_eq!(
+        comments,
+        vec![format!("alice in {old_id}"), format!("bob in {new_id}")]
+    );
+    // Clients that were already polling `new` need to see what we moved into it
+    assert!(storage.latest_sequences(new_id).await?.badge > sequence);
+
+    storage
+        .rename_project(new_id, "//depot/stream", "renamed", true)
+        .await?;
+    assert_eq!(
+        storage.get_project("//depot/stream", "renamed").await?,
+        None
+    );
+    storage
+        .rename_project(new_id, "//depot/stream", "renamed", false)
+        .await?;
+    assert_eq!(
+        storage.get_project("//depot/stream", "renamed").await?,
+        Some(new_id)
+    );
+
+    let filter = BadgeFilter {
+        project_id: new_id,
+        change_number: Some(1),
+        build_type: Some(String::from("Editor")),
+    };
+    let sequence = storage.latest_sequence().await?;
+    let version = storage
+        .metadata_version("//depot/stream", Some("renamed"))
+        .await?;
+    assert_eq!(storage.delete_badges(&filter, true).await?, 2);
+    assert_eq!(storage.latest_sequence().await?, sequence, "dry run");
+    assert_eq!(storage.delete_badges(&filter, false).await?, 2);
+    // Clients polling with an ETag need to notice that the badges are gone
+    assert!(storage.latest_sequence().await? > sequence);
+    assert_ne!(
+        storage
+            .metadata_version("//depot/stream", Some("renamed"))
+            .await?,
+        version
+    );
+    assert_eq!(
+        storage.metadata_version("//depot/stream", None).await?,
+        storage
+            .metadata_version("//depot/stream", Some("renamed"))
+            .await?
+    );
+    let metadata = storage
+        .list_metadata("//depot/stream", Some("renamed"), 0, 1, Some(1))
+        .await?;
+    assert_eq!(metadata[0].badges.len(), 1);
+
+    let sequence = storage.latest_sequence().await?;
+    assert_eq!(storage.delete_user_events("BOB", None, true).await?, 2);
+    assert_eq!(
+        storage
+            .delete_user_events("BOB", Some(old_id), false)
+            .await?,
+        0
+    );
+    assert_eq!(storage.latest_sequence().await?, sequence);
+    assert_eq!(storage.delete_user_events("BOB", None, false).await?, 2);
+    assert!(storage.latest_sequence().await? > sequence);
+    assert_eq!(
+        summary(storage.list_projects().await?),
+        vec![(String::from("renamed"), 1, 1)]
+    );
+
+    Ok(())
+}
+
+/// Test that merging & renaming projects keeps their badges in the order they were posted, since
+/// UGS shows the last badge of each build type for a change
+#[tokio::test]
+async fn admin_badge_order() -> Result<()> {
+    for_each_storage(admin_badge_order_with).await
+}
+
+async fn admin_badge_order_with(storage: Arc<dyn Storage>) -> Result<()> {
+    let mut app = app(config(), storage.clone());
+
+    // The build started while CI was still posting to the old project name
+    for (project, result) in [
+        ("//depot/stream/old", BadgeResult::Starting),
+        ("//depot/stream/new", BadgeResult::Success),
+    ] {
+        let badge = CreateBadge {
+            project: String::from(project),
+            result,
+            ..simple_create_request()
+        };
+        let response = app
+            .ready()
+            .await?
+            .call(
+                request_builder("/api/build", "POST", Some(authorization_header(CI_AUTH)))
+                    .body(Body::from(serde_json::to_vec(&badge)?))?,
+            )
+            .await?;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    let old_id = storage
+        .get_project("//depot/stream", "old")
+        .await?
+        .context("old should exist")?;
+    let new_id = storage
+        .get_project("//depot/stream", "new")
+        .await?
+        .context("new should exist")?;
+    storage.merge_projects(old_id, new_id, false).await?;
+    let metadata = get_metadata(&mut app, "//depot/stream", "new").await?;
+    let results = metadata.items[0]
+        .badges
+        .iter()
+        .map(|badge| badge.state)
+        .collect::<Vec<_>>();
+    assert_eq!(results, vec![BadgeResult::Starting, BadgeResult::Success]);
+
+    storage
+        .rename_project(new_id, "//depot/stream", "renamed", false)
+        .await?;
+    let renamed = get_metadata(&mut app, "//depot/stream", "renamed").await?;
+    assert_eq!(renamed.items[0].badges, metadata.items[0].badges);
+
+    Ok(())
+}
+
+/// Run the server binary's `args` against the sqlite database at `database`
+fn run_rugs(database: &Path, args: &[&str]) -> Result<Output> {
+    Ok(
+        std::process::Command::new(env!("CARGO_BIN_EXE_rugs_metadata_server"))
+            // Don't pick up any settings from whoever is running the tests
+            .env_clear()
+            .arg("--database")
+            .arg(database)
+            .args(args)
+            .output()?,
+    )
+}
+
+/// Test that the `admin` commands find projects by their path, and fail for ones that don't
+/// exist
+#[tokio::test]
+async fn admin_commands() -> Result<()> {
+    let directory = tempfile::tempdir()?;
+    let database = directory.path().join("metadata.db");
+    let output = run_rugs(&database, &["migrate", "run"])?;
+    assert!(output.status.success(), "{output:?}");
+
+    let storage =
+        rugs::storage::connect(database.to_str().context("Non-UTF-8 temp dir")?, None).await?;
+    let project_id = storage.get_or_add_project("//depot/stream", "old").await?;
+
+    let output = run_rugs(
+        &database,
+        &[
+            "admin",
+            "projects",
+            "merge",
+            "//depot/stream/old",
+            "//depot/stream/new",
+        ],
+    )?;
+    assert!(!output.status.success(), "{output:?}");
+
+    let output = run_rugs(
+        &database,
+        &[
+            "admin",
+            "projects",
+            "rename",
+            "//depot/stream/old",
+            "//Depot/Stream/Renamed",
+        ],
+    )?;
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(
+        storage.get_project("//depot/stream", "renamed").await?,
+        Some(project_id)
+    );
+
+    Ok(())
+}
@@ -0,0 +1,349 @@
+use anyhow::Result;
+use axum::{body::Body, http::StatusCode};
+use rugs::{
+    app::app,
+    auth::{CiTokenStore, UserStore},
+    config::Config,
+    models::{CreateBadge, EventData, EventType},
+    storage::Storage,
+};
+use tower::{Service, ServiceExt};
+
+use std::sync::Arc;
+
+mod common;
+use common::{
+    authorization_header, config, for_each_storage, get_metadata, request_builder,
+    simple_create_request, CI_AUTH, USER_AUTH,
+};
+
+/// Test that named users from the user store can authenticate, and that revoked users can't
+#[tokio::test]
+async fn user_store_auth() -> Result<()> {
+    for_each_storage(user_store_auth_with).await
+}
+
+async fn user_store_auth_with(storage: Arc<dyn Storage>) -> Result<()> {
+    let cfg = Config {
+        user_auth: String::new(),
+        ..config()
+    };
+    let mut app = app(cfg.clone(), storage.clone());
+
+    let request = |authorization: Option<&str>| {
+        request_builder(
+            "/api/latest?project=//depot/stream/proj",
+            "GET",
+            authorization.map(authorization_header),
+        )
+        .body(Body::empty())
+    };
+
+    // Without any users or shared credentials, anyone can access the user routes
+    let response = app.ready().await?.call(request(None)?).await?;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let user_store = UserStore::new(storage.clone());
+    user_store.add_user("alice", "alice_password").await?;
+    user_store.add_user("bob", "bob_password").await?;
+    assert!(user_store.revoke_user("bob").await?);
+
+    // A running server only checks whether there are any users every so often, so users added
+    // from another process (like this store) take a while to be required
+    let response = app.ready().await?.call(request(None)?).await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    let mut app = rugs::app::app(cfg, storage);
+
+    for (authorization, expected_status) in [
+        (None, StatusCode::UNAUTHORIZED),
+        (Some("alice:alice_password"), StatusCode::OK),
+        (Some("Alice:alice_password"), StatusCode::OK),
+        (Some("alice:bob_password"), StatusCode::UNAUTHORIZED),
+        (Some("bob:bob_password"), StatusCode::UNAUTHORIZED),
+        (Some("carol:alice_password"), StatusCode::UNAUTHORIZED),
+    ] {
+        let response = app.ready().await?.call(request(authorization)?).await?;
+        assert_eq!(response.status(), expected_status, "{:?}", authorization);
+    }
+
+    // Revoking a user should take effect immediately, even if they were just verified
+    assert!(user_store
+        .authenticate("alice", "alice_password")
+        .await?
+        .is_some());
+    assert!(user_store.revoke_user("alice").await?);
+    assert!(user_store
+        .authenticate("alice", "alice_password")
+        .await?
+        .is_none());
+
+    // Re-adding a revoked user restores their access
+    user_store.add_user("bob", "new_bob_password").await?;
+    let response = app
+        .ready()
+        .await?
+        .call(request(Some("bob:new_bob_password"))?)
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    Ok(())
+}
+
+/// Test that the shared credentials keep working alongside named users
+#[tokio::test]
+async fn user_store_with_shared_auth() -> Result<()> {
+    for_each_storage(user_store_with_shared_auth_with).await
+}
+
+async fn user_store_with_shared_auth_with(storage: Arc<dyn Storage>) -> Result<()> {
+    UserStore::new(storage.clone())
+        .add_user("alice", "alice_password")
+        .await?;
+    let mut app = app(config(), storage);
+
+    for authorization in [USER_AUTH, "alice:alice_password"] {
+        let response = app
+            .ready()
+            .await?
+            .call(
+                request_builder(
+                    "/api/latest?project=//depot/stream/proj",
+                    "GET",
+                    Some(authorization_header(authorization)),
+                )
+                .body(Body::empty())?,
+            )
+            .await?;
+        assert_eq!(response.status(), StatusCode::OK, "{authorization}");
+    }
+
+    Ok(())
+}
+
+/// Test that enforcing user names only lets named users submit data as themselves or their aliases
+#[tokio::test]
+async fn enforce_user_names() -> Result<()> {
+    for_each_storage(enforce_user_names_with).await
+}
+
+async fn enforce_user_names_with(storage: Arc<dyn Storage>) -> Result<()> {
+    let user_store = UserStore::new(storage.clone());
+    user_store.add_user("alice", "alice_password").await?;
+    user_store.add_alias("alice", "alice.smith").await?;
+    user_store.add_user("bob", "bob_password").await?;
+
+    let cfg = Config {
+        enforce_user_names: true,
+        ..config()
+    };
+    let mut app = app(cfg, storage);
+
+    for (authorization, user_name, expected_status) in [
+        ("alice:alice_password", "alice", StatusCode::OK),
+        ("alice:alice_password", "ALICE", StatusCode::OK),
+        ("alice:alice_password", "alice.smith", StatusCode::OK),
+        ("alice:alice_password", "bob", StatusCode::FORBIDDEN),
+        ("bob:bob_password", "alice.smith", StatusCode::FORBIDDEN),
+        (USER_AUTH, "user", StatusCode::FORBIDDEN),
+    ] {
+        let update = serde_json::json!({
+            "Change": 5,
+            "Stream": "//depot/stream",
+            "Project": "proj",
+            "UserName": user_name,
+            "Vote": "Bad",
+        });
+        let response = app
+            .ready()
+            .await?
+            .call(
+                request_builder(
+                    "/api/metadata",
+                    "POST",
+                    Some(authorization_header(authorization)),
+                )
+                .body(Body::from(serde_json::to_vec(&update)?))?,
+            )
+            .await?;
+        assert_eq!(
+            response.status(),
+            expected_status,
+            "{authorization} submitting as {user_name}"
+        );
+
+        let event = EventData {
+            id: 0,
+            change: 5,
+            user_name: String::from(user_name),
+            event_type: EventType::Good,
+            project: String::from("//depot/stream/proj"),
+        };
+        let response = app
+            .ready()
+            .await?
+            .call(
+                request_builder(
+                    "/api/event",
+                    "POST",
+                    Some(authorization_header(authorization)),
+                )
+                .body(Body::from(serde_json::to_vec(&event)?))?,
+            )
+            .await?;
+        assert_eq!(
+            response.status(),
+            expected_status,
+            "{authorization} submitting v1 event as {user_name}"
+        );
+    }
+
+    let metadata = get_metadata(&mut app, "//depot/stream", "proj").await?;
+    let mut users = metadata.items[0]
+        .users
+        .iter()
+        .map(|user| user.user.as_str())
+        .collect::<Vec<_>>();
+    users.sort();
+    assert_eq!(users, vec!["ALICE", "alice", "alice.smith"]);
+
+    Ok(())
+}
+
+/// Test that scoped CI tokens can only post badges for the projects & build types they allow
+#[tokio::test]
+async fn scoped_ci_tokens() -> Result<()> {
+    for_each_storage(scoped_ci_tokens_with).await
+}
+
+async fn scoped_ci_tokens_with(storage: Arc<dyn Storage>) -> Result<()> {
+    let ci_token_store = CiTokenStore::new(storage.clone());
+    let game_token = ci_token_store
+        .add_token("game", vec![String::from("//game/*/Game")], None)
+        .await?;
+    let editor_token = ci_token_store
+        .add_token(
+            "other-editor",
+            vec![String::from("//other/main/*")],
+            Some(vec![String::from("Editor")]),
+        )
+        .await?;
+    let revoked_token = ci_token_store
+        .add_token("revoked", vec![String::from("//**")], None)
+        .await?;
+    assert!(ci_token_store.revoke_token("revoked").await?);
+
+    let mut app = app(config(), storage.clone());
+
+    let bearer = |token: &str| format!("Bearer {token}");
+    for (authorization, project, build_type, expected_status) in [
+        (
+            bearer(&game_token),
+            "//game/main/Game",
+            "Editor",
+            StatusCode::OK,
+        ),
+        (
+            bearer(&game_token),
+            "//game/release/game",
+            "Client",
+            StatusCode::OK,
+        ),
+        (
+            bearer(&game_token),
+            "//game/main/Other",
+            "Editor",
+            StatusCode::FORBIDDEN,
+        ),
+        (
+            bearer(&game_token),
+            "//other/main/Game",
+            "Editor",
+            StatusCode::FORBIDDEN,
+        ),
+        (
+            authorization_header(&format!("game:{game_token}")),
+            "//game/dev/Game",
+            "Editor",
+            StatusCode::OK,
+        ),
+        (
+            authorization_header(&format!("other-editor:{game_token}")),
+            "//game/dev/Game",
+            "Editor",
+            StatusCode::UNAUTHORIZED,
+        ),
+        (
+            bearer(&editor_token),
+            "//other/main/Other",
+            "Editor",
+            StatusCode::OK,
+        ),
+        (
+            bearer(&editor_token),
+            "//other/main/Other",
+            "Client",
+            StatusCode::FORBIDDEN,
+        ),
+        (
+            bearer(&revoked_token),
+            "//game/main/Game",
+            "Editor",
+            StatusCode::UNAUTHORIZED,
+        ),
+        (
+            bearer("bogus"),
+            "//game/main/Game",
+            "Editor",
+            StatusCode::UNAUTHORIZED,
+        ),
+        (
+            authorization_header(CI_AUTH),
+            "//other/main/Other",
+            "Client",
+            StatusCode::OK,
+        ),
+    ] {
+        let create_request = CreateBadge {
+            project: String::from(project),
+            build_type: String::from(build_type),
+            ..simple_create_request()
+        };
+        let response = app
+            .ready()
+            .await?
+            .call(
+                request_builder("/api/build", "POST", Some(authorization.clone()))
+                    .body(Body::from(serde_json::to_vec(&create_request)?))?,
+            )
+            .await?;
+        assert_eq!(
+            response.status(),
+            expected_status,
+            "{authorization} posting {build_type} to {project}"
+        );
+    }
+
+    for (stream, project, expected_posted_by) in [
+        ("//game/main", "game", vec!["game"]),
+        ("//game/release", "game", vec!["game"]),
+        ("//game/dev", "game", vec!["game"]),
+        ("//other/main", "other", vec!["other-editor", "ci"]),
+    ] {
+        let change_number = simple_create_request().change_number;
+        let metadata = storage
+            .list_metadata(stream, Some(project), 0, change_number, Some(change_number))
+            .await?;
+        let posted_by = metadata
+            .iter()
+            .flat_map(|change| &change.badges)
+            .map(|badge| badge.posted_by.as_deref())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            posted_by,
+            expected_posted_by.into_iter().map(Some).collect::<Vec<_>>(),
+            "badges posted to {stream}/{project}"
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,107 @@
+use anyhow::{Context, Result};
+use axum::{body::Body, http::StatusCode};
+use rugs::{
+    app::app,
+    auth::CiTokenStore,
+    backup::{check_integrity, restore, scheduled_backup},
+};
+use tower::{Service, ServiceExt};
+
+mod common;
+use common::{
+    authorization_header, config, request_builder, simple_create_request, storage, CI_AUTH,
+};
+
+/// Test that scheduled backups rotate, and that we only restore intact backups
+#[tokio::test]
+async fn backup_and_restore() -> Result<()> {
+    let directory = tempfile::tempdir()?;
+    let database = directory.path().join("metadata.db");
+    let storage =
+        rugs::storage::connect(database.to_str().context("Non-UTF-8 path")?, None).await?;
+    storage.migrate().await?;
+    let project_id = storage.get_or_add_project("//depot/stream", "proj").await?;
+    storage
+        .add_badge(project_id, &simple_create_request(), None)
+        .await?;
+
+    let settings = rugs::backup::BackupSettings {
+        directory: directory.path().join("backups"),
+        interval: std::time::Duration::from_secs(3600),
+        keep: 2,
+    };
+    let now = chrono::Utc::now();
+    let mut backups = Vec::new();
+    for hours in 0..3 {
+        let backup_time = now + chrono::Duration::hours(hours);
+        backups.push(scheduled_backup(storage.as_ref(), &settings, backup_time).await?);
+    }
+    assert_eq!(
+        rugs::backup::list_backups(&settings.directory).await?,
+        backups[1..]
+    );
+
+    storage
+        .add_badge(project_id, &simple_create_request(), None)
+        .await?;
+    drop(storage);
+
+    let corrupt = directory.path().join("corrupt.db");
+    std::fs::write(&corrupt, b"not a database")?;
+    assert!(restore(&database, &corrupt).await.is_err());
+
+    restore(&database, &backups[2]).await?;
+    let storage =
+        rugs::storage::connect(database.to_str().context("Non-UTF-8 path")?, None).await?;
+    assert_eq!(storage.list_projects().await?[0].badges, 1);
+
+    // The database we replaced is still around, in case the backup was the wrong one
+    check_integrity(&directory.path().join("metadata.db.before-restore")).await?;
+
+    Ok(())
+}
+
+/// Test that CI can download a backup, but not with a scoped token
+#[tokio::test]
+async fn backup_endpoint() -> Result<()> {
+    // In-memory databases can't be backed up
+    assert!(storage()
+        .await?
+        .backup(&std::env::temp_dir().join("rugs-in-memory.db"))
+        .await
+        .is_err());
+
+    let directory = tempfile::tempdir()?;
+    let database = directory.path().join("metadata.db");
+    let storage =
+        rugs::storage::connect(database.to_str().context("Non-UTF-8 path")?, None).await?;
+    storage.migrate().await?;
+    let token = CiTokenStore::new(storage.clone())
+        .add_token("game", vec![String::from("//**")], None)
+        .await?;
+    let mut app = app(config(), storage);
+
+    let response = app
+        .ready()
+        .await?
+        .call(
+            request_builder("/api/backup", "POST", Some(authorization_header(CI_AUTH)))
+                .body(Body::empty())?,
+        )
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    let backup = hyper::body::to_bytes(response.into_body()).await?;
+    assert!(backup.starts_with(b"SQLite format 3\0"));
+
+    let response = app
+        .ready()
+        .await?
+        .call(
+            request_builder("/api/backup", "POST", Some(format!("Bearer {token}")))
+                .body(Body::empty())?,
+        )
+        .await?;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    Ok(())
+}
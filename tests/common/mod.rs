@@ -0,0 +1,211 @@
+//! Helpers shared by the integration tests
+// Each test binary only uses some of these
+#![allow(dead_code)]
+
+use anyhow::{Context, Result};
+use axum::{
+    body::Body,
+    http::{self, Request, StatusCode},
+    Router,
+};
+use base64::prelude::*;
+use rugs::{
+    config::{Config, LogFormat},
+    models::{CreateBadge, GetMetadataListResponseV2},
+    storage::{PostgresStorage, SqliteStorage, Storage},
+};
+use sqlx::Connection;
+use tower::{Service, ServiceExt};
+
+use std::{
+    future::Future,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+pub const CI_AUTH: &str = "ci:ci";
+pub const USER_AUTH: &str = "user:user";
+
+const POSTGRES_URL_KEY: &str = "RUGS_TEST_POSTGRES_URL";
+
+pub fn config() -> Config {
+    Config {
+        user_auth: USER_AUTH.to_string(),
+        ci_auth: CI_AUTH.to_string(),
+        address: std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST),
+        http_port: 3000,
+        request_root: "/".to_string(),
+        telemetry_retention_days: 30,
+        metadata_retention: Default::default(),
+        enforce_user_names: false,
+        tls: None,
+        otlp_endpoint: None,
+        log_format: LogFormat::Text,
+        notifications: Default::default(),
+        database: String::from(":memory:"),
+        database_max_connections: None,
+        migrate_on_startup: false,
+        backup: None,
+        project_paths: Default::default(),
+    }
+}
+
+pub async fn storage() -> Result<Arc<dyn Storage>> {
+    let pool = sqlx::SqlitePool::connect("sqlite::memory:")
+        .await
+        .with_context(|| "Could not open in-memory sqlite db")?;
+
+    sqlx::migrate!("./migrations").run(&pool).await?;
+
+    Ok(Arc::new(SqliteStorage::new(pool)))
+}
+
+/// The storages to run the tests against: an in-memory sqlite database, and a fresh schema in the
+/// PostgreSQL database at `RUGS_TEST_POSTGRES_URL` if that's set.
+pub async fn storages() -> Result<Vec<Arc<dyn Storage>>> {
+    let mut storages = vec![storage().await?];
+
+    if let Ok(url) = std::env::var(POSTGRES_URL_KEY) {
+        // Extensions are shared by the whole database, so create citext up front (rather than
+        // racing to do it in each test's migrations) and outside of the test schemas
+        static CITEXT: tokio::sync::OnceCell<()> = tokio::sync::OnceCell::const_new();
+        CITEXT
+            .get_or_try_init(|| async {
+                let mut connection = sqlx::PgConnection::connect(&url).await?;
+                sqlx::query("CREATE EXTENSION IF NOT EXISTS citext WITH SCHEMA public")
+                    .execute(&mut connection)
+                    .await?;
+                anyhow::Ok(())
+            })
+            .await?;
+
+        static SCHEMA_INDEX: AtomicUsize = AtomicUsize::new(0);
+        let schema = format!(
+            "rugs_test_{}_{}_{}",
+            std::process::id(),
+            chrono::Utc::now().timestamp_micros(),
+            SCHEMA_INDEX.fetch_add(1, Ordering::Relaxed)
+        );
+        let mut connection = sqlx::PgConnection::connect(&url).await?;
+        sqlx::query(&format!("CREATE SCHEMA {schema}"))
+            .execute(&mut connection)
+            .await?;
+
+        let options = sqlx::postgres::PgConnectOptions::from_str(&url)?
+            .options([("search_path", format!("{schema},public"))]);
+        let pool = sqlx::PgPool::connect_with(options).await?;
+        sqlx::migrate!("./migrations_postgres").run(&pool).await?;
+
+        storages.push(Arc::new(PostgresStorage::new(pool)));
+    }
+
+    Ok(storages)
+}
+
+/// Run `test` against each of the `storages()`
+pub async fn for_each_storage<F, T>(test: F) -> Result<()>
+where
+    F: Fn(Arc<dyn Storage>) -> T,
+    T: Future<Output = Result<()>>,
+{
+    for storage in storages().await? {
+        test(storage).await?;
+    }
+    Ok(())
+}
+
+/// Environment variables for `Config::load_with_env`, so tests don't race on the real ones
+pub fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+    let vars = vars
+        .iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect::<std::collections::HashMap<_, _>>();
+    move |key| vars.get(key).cloned()
+}
+
+pub fn simple_create_request() -> CreateBadge {
+    CreateBadge {
+        change_number: 1,
+        url: String::from("http://test.com"),
+        build_type: String::from("Editor"),
+        result: rugs::models::BadgeResult::Starting,
+        project: String::from("//depot/stream/proj"),
+    }
+}
+
+/// Helper to format an `Authorization:` header for HTTP Basic Auth requests
+pub fn authorization_header(token: &str) -> String {
+    format!("Basic {}", BASE64_STANDARD.encode(token))
+}
+
+/// Helper to create HTTP requests
+pub fn request_builder(
+    uri: &str,
+    method: &str,
+    authorization: Option<String>,
+) -> http::request::Builder {
+    let builder = Request::builder()
+        .uri(uri)
+        .method(method)
+        .header(http::header::CONTENT_TYPE, "application/json");
+
+    if let Some(authorization) = authorization {
+        builder.header(http::header::AUTHORIZATION, authorization)
+    } else {
+        builder
+    }
+}
+
+/// Helper to make an authenticated user request, asserting that it succeeds and returning the body
+pub async fn user_request(
+    app: &mut Router,
+    uri: &str,
+    method: &str,
+    body: Body,
+) -> Result<hyper::body::Bytes> {
+    authorized_request(app, USER_AUTH, uri, method, body).await
+}
+
+/// Helper to make an authenticated CI request, asserting that it succeeds and returning the body
+pub async fn ci_request(
+    app: &mut Router,
+    uri: &str,
+    method: &str,
+    body: Body,
+) -> Result<hyper::body::Bytes> {
+    authorized_request(app, CI_AUTH, uri, method, body).await
+}
+
+async fn authorized_request(
+    app: &mut Router,
+    token: &str,
+    uri: &str,
+    method: &str,
+    body: Body,
+) -> Result<hyper::body::Bytes> {
+    let response = app
+        .ready()
+        .await?
+        .call(request_builder(uri, method, Some(authorization_header(token))).body(body)?)
+        .await?;
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    assert_eq!(status, StatusCode::OK, "{method} {uri} body: {:?}", body);
+
+    Ok(body)
+}
+
+pub async fn get_metadata(
+    app: &mut Router,
+    stream: &str,
+    project_name: &str,
+) -> Result<GetMetadataListResponseV2> {
+    let url =
+        format!("/api/metadata?stream={stream}&project={project_name}&sequence=0&minchange=0");
+    let body = user_request(app, &url, "GET", Body::empty()).await?;
+
+    Ok(serde_json::from_slice::<GetMetadataListResponseV2>(&body)?)
+}
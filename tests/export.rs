@@ -0,0 +1,135 @@
+use anyhow::{Context, Result};
+use chrono::DateTime;
+use rugs::{
+    export::{export, import, EXPORT_VERSION},
+    models::{Badge, BadgeResult, UgsUserVote},
+    storage::{Storage, UserEventUpdate},
+};
+
+use std::sync::Arc;
+
+mod common;
+use common::{simple_create_request, storages};
+
+/// Test that exports can be imported into a database that numbers its projects differently, and
+/// that importing again (even into the database it came from) only reports conflicts
+#[tokio::test]
+async fn export_and_import() -> Result<()> {
+    for (source, destination) in storages().await?.into_iter().zip(storages().await?) {
+        export_and_import_with(source, destination).await?;
+    }
+    Ok(())
+}
+
+async fn export_and_import_with(
+    source: Arc<dyn Storage>,
+    destination: Arc<dyn Storage>,
+) -> Result<()> {
+    let project_id = source.get_or_add_project("//depot/stream", "proj").await?;
+    source
+        .add_badge(project_id, &simple_create_request(), Some("ci"))
+        .await?;
+    let update = UserEventUpdate {
+        synced: true,
+        vote: Some(UgsUserVote::Good),
+        comment: Some(String::from("Looks good")),
+        ..Default::default()
+    };
+    source
+        .update_user_event(project_id, 1, "alice", update)
+        .await?;
+    let update = UserEventUpdate {
+        starred: Some(true),
+        ..Default::default()
+    };
+    source
+        .update_user_event(project_id, 1, "bob", update)
+        .await?;
+    // Older versions stored nanoseconds in sqlite, which exports don't keep
+    let legacy_badge = Badge {
+        project_id,
+        sequence: 0,
+        change_number: 2,
+        added_at: DateTime::from_timestamp(1_700_000_000, 123_456_789).context("timestamp")?,
+        build_type: String::from("Editor"),
+        result: BadgeResult::Success,
+        url: String::from("http://test.com"),
+        posted_by: None,
+    };
+    source.import_badge(project_id, &legacy_badge).await?;
+
+    let mut exported = Vec::new();
+    let summary = export(source.as_ref(), &mut exported, chrono::Utc::now()).await?;
+    assert_eq!(
+        (summary.projects, summary.badges, summary.user_events),
+        (1, 2, 2)
+    );
+
+    // Importing an export into the database it came from doesn't change anything
+    let summary = import(source.as_ref(), exported.as_slice()).await?;
+    assert_eq!((summary.badges_added, summary.badges_unchanged), (0, 2));
+    assert_eq!(
+        (summary.user_events_added, summary.user_events_unchanged),
+        (0, 2)
+    );
+    assert!(summary.conflicts.is_empty(), "{:?}", summary.conflicts);
+
+    // The destination already has a different project, so the IDs don't line up
+    destination
+        .get_or_add_project("//depot/other", "proj")
+        .await?;
+    let summary = import(destination.as_ref(), exported.as_slice()).await?;
+    assert_eq!(summary.projects_added, 1);
+    assert_eq!((summary.badges_added, summary.user_events_added), (2, 2));
+    assert!(summary.conflicts.is_empty());
+
+    let project_id = destination
+        .get_project("//depot/stream", "proj")
+        .await?
+        .context("Project wasn't imported")?;
+    let metadata = destination
+        .list_metadata("//depot/stream", Some("proj"), 0, 0, None)
+        .await?;
+    assert_eq!(metadata.len(), 2);
+    assert_eq!(metadata[0].badges[0].project_id, project_id);
+    assert_eq!(metadata[0].badges[0].posted_by.as_deref(), Some("ci"));
+    let alice = &metadata[0].user_events[0];
+    assert_eq!(alice.user_name, "alice");
+    assert_eq!(alice.vote, Some(UgsUserVote::Good));
+    assert_eq!(alice.comment.as_deref(), Some("Looks good"));
+
+    // Importing again doesn't change anything, except that bob has since unstarred the change
+    let update = UserEventUpdate {
+        starred: Some(false),
+        ..Default::default()
+    };
+    destination
+        .update_user_event(project_id, 1, "bob", update)
+        .await?;
+    let summary = import(destination.as_ref(), exported.as_slice()).await?;
+    assert_eq!(summary.projects_added, 0);
+    assert_eq!((summary.badges_added, summary.badges_unchanged), (0, 2));
+    assert_eq!(
+        (summary.user_events_added, summary.user_events_unchanged),
+        (0, 1)
+    );
+    assert_eq!(summary.conflicts.len(), 1);
+    assert!(summary.conflicts[0].contains("bob"));
+    let metadata = destination
+        .list_metadata("//depot/stream", Some("proj"), 0, 0, None)
+        .await?;
+    assert_eq!(metadata[0].badges.len(), 1);
+    assert_eq!(metadata[0].user_events[1].starred, Some(false));
+
+    // Exports from a newer version are rejected rather than half-imported
+    let newer = String::from_utf8(exported)?.replacen(
+        &format!("\"version\":{}", EXPORT_VERSION),
+        &format!("\"version\":{}", EXPORT_VERSION + 1),
+        1,
+    );
+    assert!(import(destination.as_ref(), newer.as_bytes())
+        .await
+        .is_err());
+
+    Ok(())
+}
@@ -0,0 +1,197 @@
+use anyhow::Result;
+use axum::{body::Body, http::StatusCode};
+use rugs::{
+    app::app,
+    models::{
+        CreateIssue, CreatedIdResponse, IssueBuildData, IssueBuildOutcome, IssueDiagnosticData,
+        IssueResponse, IssueWatcherData, UpdateIssue,
+    },
+    storage::Storage,
+};
+use tower::{Service, ServiceExt};
+
+use std::sync::Arc;
+
+mod common;
+use common::{
+    authorization_header, config, for_each_storage, request_builder, user_request, USER_AUTH,
+};
+
+/// Test that we can create issues, attach builds, diagnostics & watchers, and resolve them
+#[tokio::test]
+async fn issues_integration() -> Result<()> {
+    for_each_storage(issues_integration_with).await
+}
+
+async fn issues_integration_with(storage: Arc<dyn Storage>) -> Result<()> {
+    let mut app = app(config(), storage);
+
+    let issues: Vec<IssueResponse> = serde_json::from_slice(
+        &user_request(&mut app, "/api/issues", "GET", Body::empty()).await?,
+    )?;
+    assert!(issues.is_empty());
+
+    let create = CreateIssue {
+        project: String::from("//depot/stream/proj"),
+        summary: String::from("Compile errors in Editor"),
+        owner: None,
+        nominated_by: None,
+        is_warning: false,
+        build_url: None,
+    };
+    let created: CreatedIdResponse = serde_json::from_slice(
+        &user_request(
+            &mut app,
+            "/api/issues",
+            "POST",
+            Body::from(serde_json::to_vec(&create)?),
+        )
+        .await?,
+    )?;
+    let issue_url = format!("/api/issues/{}", created.id);
+
+    let build = IssueBuildData {
+        id: 0,
+        stream: String::from("//depot/Stream"),
+        change: 10,
+        job_name: String::from("Editor"),
+        job_url: String::from("http://test.com/job"),
+        job_step_name: String::from("Compile"),
+        job_step_url: String::from("http://test.com/job/step"),
+        error_url: None,
+        outcome: IssueBuildOutcome::Error,
+    };
+    let build_created: CreatedIdResponse = serde_json::from_slice(
+        &user_request(
+            &mut app,
+            &format!("{issue_url}/builds"),
+            "POST",
+            Body::from(serde_json::to_vec(&build)?),
+        )
+        .await?,
+    )?;
+    let builds: Vec<IssueBuildData> = serde_json::from_slice(
+        &user_request(
+            &mut app,
+            &format!("{issue_url}/builds"),
+            "GET",
+            Body::empty(),
+        )
+        .await?,
+    )?;
+    assert_eq!(builds.len(), 1);
+    assert_eq!(builds[0].id, build_created.id);
+    assert_eq!(builds[0].stream, "//depot/stream");
+    assert_eq!(builds[0].outcome, IssueBuildOutcome::Error);
+
+    let diagnostic = IssueDiagnosticData {
+        build_id: Some(build_created.id),
+        message: String::from("error C2065: undeclared identifier"),
+        url: String::from("http://test.com/job/step/log"),
+    };
+    user_request(
+        &mut app,
+        &format!("{issue_url}/diagnostics"),
+        "POST",
+        Body::from(serde_json::to_vec(&diagnostic)?),
+    )
+    .await?;
+    let diagnostics: Vec<IssueDiagnosticData> = serde_json::from_slice(
+        &user_request(
+            &mut app,
+            &format!("{issue_url}/diagnostics"),
+            "GET",
+            Body::empty(),
+        )
+        .await?,
+    )?;
+    assert_eq!(diagnostics, vec![diagnostic]);
+
+    let watch = IssueWatcherData {
+        user_name: String::from("alice"),
+        watch: true,
+    };
+    user_request(
+        &mut app,
+        &format!("{issue_url}/watchers"),
+        "POST",
+        Body::from(serde_json::to_vec(&watch)?),
+    )
+    .await?;
+    let watchers: Vec<String> = serde_json::from_slice(
+        &user_request(
+            &mut app,
+            &format!("{issue_url}/watchers"),
+            "GET",
+            Body::empty(),
+        )
+        .await?,
+    )?;
+    assert_eq!(watchers, vec![String::from("alice")]);
+
+    let issue: IssueResponse = serde_json::from_slice(
+        &user_request(
+            &mut app,
+            &format!("{issue_url}?user=Alice"),
+            "GET",
+            Body::empty(),
+        )
+        .await?,
+    )?;
+    assert!(
+        issue.notify,
+        "alice should be notified about an issue she's watching"
+    );
+    assert_eq!(issue.streams, vec![String::from("//depot/stream")]);
+
+    let update = UpdateIssue {
+        owner: Some(String::from("bob")),
+        acknowledged: Some(true),
+        resolved: Some(true),
+        fix_change: Some(11),
+        ..Default::default()
+    };
+    user_request(
+        &mut app,
+        &issue_url,
+        "PUT",
+        Body::from(serde_json::to_vec(&update)?),
+    )
+    .await?;
+
+    let issues: Vec<IssueResponse> = serde_json::from_slice(
+        &user_request(&mut app, "/api/issues", "GET", Body::empty()).await?,
+    )?;
+    assert!(issues.is_empty(), "resolved issues should not be listed");
+
+    let issues: Vec<IssueResponse> = serde_json::from_slice(
+        &user_request(
+            &mut app,
+            "/api/issues?includeresolved=true&maxresults=10",
+            "GET",
+            Body::empty(),
+        )
+        .await?,
+    )?;
+    assert_eq!(issues.len(), 1);
+    assert_eq!(issues[0].owner.as_deref(), Some("bob"));
+    assert_eq!(issues[0].fix_change, 11);
+    assert!(issues[0].acknowledged_at.is_some());
+    assert!(issues[0].resolved_at.is_some());
+
+    let response = app
+        .ready()
+        .await?
+        .call(
+            request_builder(
+                "/api/issues/1000",
+                "GET",
+                Some(authorization_header(USER_AUTH)),
+            )
+            .body(Body::empty())?,
+        )
+        .await?;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    Ok(())
+}
@@ -0,0 +1,366 @@
+use anyhow::Result;
+use axum::{body::Body, http::StatusCode};
+use rugs::{
+    app::app,
+    models::{
+        BadgeResult, CommentData, CreateBadge, EventData, EventType, LatestResponseV1, UgsUserVote,
+    },
+    storage::{LatestSequences, Storage, UserEventUpdate},
+};
+use tower::{Service, ServiceExt};
+
+use std::sync::Arc;
+
+mod common;
+use common::{
+    authorization_header, config, for_each_storage, get_metadata, request_builder,
+    simple_create_request, user_request, CI_AUTH,
+};
+
+/// Test that we can submit build badges and then read them back
+#[tokio::test]
+async fn metadata_integration() -> Result<()> {
+    for_each_storage(metadata_integration_with).await
+}
+
+async fn metadata_integration_with(storage: Arc<dyn Storage>) -> Result<()> {
+    const STREAM: &str = "//depot/stream;";
+    const PROJECT_NAME: &str = "proj";
+
+    let mut app = app(config(), storage);
+
+    let metadata = get_metadata(&mut app, STREAM, PROJECT_NAME).await?;
+    assert_eq!(metadata.items.len(), 0);
+    let old_sequence_number = metadata.sequence_number;
+
+    let creates = [
+        CreateBadge {
+            change_number: 1,
+            url: String::from("http://test.com"),
+            build_type: String::from("Editor"),
+            result: BadgeResult::Starting,
+            project: format!("{STREAM}/{PROJECT_NAME}"),
+        },
+        CreateBadge {
+            change_number: 1,
+            url: String::from("http://test.com"),
+            build_type: String::from("Standalone"),
+            result: BadgeResult::Starting,
+            project: format!("{STREAM}/{PROJECT_NAME}"),
+        },
+        CreateBadge {
+            change_number: 1,
+            url: String::from("http://test.com"),
+            build_type: String::from("Editor"),
+            result: BadgeResult::Success,
+            project: format!("{STREAM}/{PROJECT_NAME}"),
+        },
+        CreateBadge {
+            change_number: 2,
+            url: String::from("http://test.com"),
+            build_type: String::from("Editor"),
+            result: BadgeResult::Starting,
+            project: format!("{STREAM}/{PROJECT_NAME}"),
+        },
+    ];
+
+    for create in creates {
+        let body = serde_json::to_vec(&create)?;
+
+        let response = app
+            .ready()
+            .await?
+            .call(
+                request_builder("/api/build", "POST", Some(authorization_header(CI_AUTH)))
+                    .body(Body::from(body))?,
+            )
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    let response = get_metadata(&mut app, STREAM, PROJECT_NAME).await?;
+    assert!(response.sequence_number > old_sequence_number);
+    assert_eq!(response.items.len(), 2);
+    let cl1_item = response.items.iter().find(|item| item.change == 1);
+    assert!(cl1_item.is_some());
+    assert_eq!(cl1_item.unwrap().badges.len(), 3);
+
+    let cl2_item = response.items.iter().find(|item| item.change == 2);
+    assert!(cl2_item.is_some());
+    assert_eq!(cl2_item.unwrap().badges.len(), 1);
+
+    Ok(())
+}
+
+/// Test that we can submit build badges and then read them back
+#[tokio::test]
+async fn project_case_insensitivity() -> Result<()> {
+    for_each_storage(project_case_insensitivity_with).await
+}
+
+async fn project_case_insensitivity_with(storage: Arc<dyn Storage>) -> Result<()> {
+    let mut app = app(config(), storage);
+
+    let creates = [
+        CreateBadge {
+            change_number: 1,
+            url: String::from("http://test.com"),
+            build_type: String::from("Editor"),
+            result: BadgeResult::Starting,
+            project: String::from("//depot/Stream/proj"),
+        },
+        CreateBadge {
+            change_number: 1,
+            url: String::from("http://test.com"),
+            build_type: String::from("Standalone"),
+            result: BadgeResult::Starting,
+            project: String::from("//depot/stream/Proj"),
+        },
+    ];
+
+    for create in creates {
+        let body = serde_json::to_vec(&create)?;
+
+        let response = app
+            .ready()
+            .await?
+            .call(
+                request_builder("/api/build", "POST", Some(authorization_header(CI_AUTH)))
+                    .body(Body::from(body))?,
+            )
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    let first_response = get_metadata(&mut app, "//depot/Stream", "proj").await?;
+    assert_eq!(first_response.items.len(), 1);
+    let cl1_item = &first_response.items[0];
+    assert_eq!(
+        cl1_item.badges.len(),
+        2,
+        "both badges should be under the same project"
+    );
+
+    let response = get_metadata(&mut app, "//depot/stream", "Proj").await?;
+    assert_eq!(
+        first_response.items, response.items,
+        "results should be identical even if we use a different case for project"
+    );
+
+    Ok(())
+}
+
+/// Test that v1 events & comments and v2 metadata are backed by the same data
+#[tokio::test]
+async fn v1_events_and_comments() -> Result<()> {
+    for_each_storage(v1_events_and_comments_with).await
+}
+
+async fn v1_events_and_comments_with(storage: Arc<dyn Storage>) -> Result<()> {
+    const PROJECT: &str = "//depot/stream/proj";
+
+    let mut app = app(config(), storage);
+
+    let event = EventData {
+        id: 0,
+        change: 5,
+        user_name: String::from("alice"),
+        event_type: EventType::Bad,
+        project: String::from(PROJECT),
+    };
+    user_request(
+        &mut app,
+        "/api/event",
+        "POST",
+        Body::from(serde_json::to_vec(&event)?),
+    )
+    .await?;
+
+    let comment = CommentData {
+        id: 0,
+        change_number: 5,
+        user_name: String::from("alice"),
+        text: String::from("Crashes on startup"),
+        project: String::from(PROJECT),
+    };
+    user_request(
+        &mut app,
+        "/api/comment",
+        "POST",
+        Body::from(serde_json::to_vec(&comment)?),
+    )
+    .await?;
+
+    // v2 clients should see the vote & comment submitted through the v1 API
+    let metadata = get_metadata(&mut app, "//depot/stream", "proj").await?;
+    assert_eq!(metadata.items.len(), 1);
+    let users = &metadata.items[0].users;
+    assert_eq!(users.len(), 1);
+    assert_eq!(users[0].vote, Some(UgsUserVote::Bad));
+    assert_eq!(users[0].comment.as_deref(), Some("Crashes on startup"));
+
+    let latest: LatestResponseV1 = serde_json::from_slice(
+        &user_request(
+            &mut app,
+            &format!("/api/latest?project={PROJECT}"),
+            "GET",
+            Body::empty(),
+        )
+        .await?,
+    )?;
+    assert!(latest.last_comment_id > 0);
+    assert_eq!(latest.last_comment_id, latest.last_event_id);
+
+    let comments: Vec<CommentData> = serde_json::from_slice(
+        &user_request(
+            &mut app,
+            &format!("/api/comment?project={PROJECT}&lastcommentid=0"),
+            "GET",
+            Body::empty(),
+        )
+        .await?,
+    )?;
+    assert_eq!(comments.len(), 1);
+    assert_eq!(comments[0].text, "Crashes on startup");
+
+    let comments: Vec<CommentData> = serde_json::from_slice(
+        &user_request(
+            &mut app,
+            &format!(
+                "/api/comment?project={PROJECT}&lastcommentid={}",
+                latest.last_comment_id
+            ),
+            "GET",
+            Body::empty(),
+        )
+        .await?,
+    )?;
+    assert!(comments.is_empty());
+
+    // And v1 clients should see the star submitted through the v2 API
+    let update = serde_json::json!({
+        "Change": 5,
+        "Stream": "//depot/stream",
+        "Project": "proj",
+        "UserName": "bob",
+        "Starred": true,
+    });
+    user_request(
+        &mut app,
+        "/api/metadata",
+        "POST",
+        Body::from(serde_json::to_vec(&update)?),
+    )
+    .await?;
+
+    let events: Vec<EventData> = serde_json::from_slice(
+        &user_request(
+            &mut app,
+            &format!(
+                "/api/event?project={PROJECT}&lasteventid={}",
+                latest.last_event_id
+            ),
+            "GET",
+            Body::empty(),
+        )
+        .await?,
+    )?;
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].user_name, "bob");
+    assert_eq!(events[0].event_type, EventType::Starred);
+
+    Ok(())
+}
+
+/// Test that sequence numbers are handed out by the database in order, rather than being
+/// based on the clock
+#[tokio::test]
+async fn sequence_numbers() -> Result<()> {
+    for_each_storage(sequence_numbers_with).await
+}
+
+async fn sequence_numbers_with(storage: Arc<dyn Storage>) -> Result<()> {
+    let project_id = storage.get_or_add_project("//depot/stream", "proj").await?;
+
+    storage
+        .add_badge(project_id, &simple_create_request(), None)
+        .await?;
+    storage
+        .update_user_event(
+            project_id,
+            1,
+            "alice",
+            UserEventUpdate {
+                synced: true,
+                ..Default::default()
+            },
+        )
+        .await?;
+    storage
+        .update_user_event(
+            project_id,
+            1,
+            "alice",
+            UserEventUpdate {
+                comment: Some(String::from("Looks good")),
+                ..Default::default()
+            },
+        )
+        .await?;
+
+    assert_eq!(
+        storage.latest_sequences(project_id).await?,
+        LatestSequences {
+            badge: 1,
+            event: 3,
+            comment: 3,
+        }
+    );
+
+    Ok(())
+}
+
+/// Test that concurrent writes to a new project all end up in the same project, and that
+/// concurrent updates to a user event are all applied to a single event
+#[tokio::test]
+async fn concurrent_writes() -> Result<()> {
+    for_each_storage(concurrent_writes_with).await
+}
+
+async fn concurrent_writes_with(storage: Arc<dyn Storage>) -> Result<()> {
+    let writes = (0..10).map(|index| {
+        let storage = storage.clone();
+        tokio::spawn(async move {
+            let project_id = storage.get_or_add_project("//depot/stream", "proj").await?;
+            storage
+                .add_badge(project_id, &simple_create_request(), None)
+                .await?;
+            storage
+                .update_user_event(
+                    project_id,
+                    1,
+                    "alice",
+                    UserEventUpdate {
+                        comment: Some(format!("Comment {index}")),
+                        ..Default::default()
+                    },
+                )
+                .await
+        })
+    });
+    for write in futures::future::join_all(writes).await {
+        write??;
+    }
+
+    let metadata = storage
+        .list_metadata("//depot/stream", None, 0, 0, None)
+        .await?;
+    assert_eq!(metadata.len(), 1);
+    assert_eq!(metadata[0].badges.len(), 10);
+    assert_eq!(metadata[0].user_events.len(), 1);
+    assert_eq!(metadata[0].user_events[0].sequence, 20);
+
+    Ok(())
+}
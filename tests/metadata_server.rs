@@ -0,0 +1,97 @@
+use anyhow::Result;
+use rugs::{
+    metadata_server::import_metadata_server,
+    models::{BadgeResult, UgsUserVote},
+};
+
+mod common;
+use common::storages;
+
+/// Test that a MetadataServer dump is mapped onto our projects, badges & user events, and
+/// that importing it again doesn't change anything
+#[tokio::test]
+async fn metadata_server_import() -> Result<()> {
+    let directory = tempfile::tempdir()?;
+    std::fs::write(
+        directory.path().join("Projects.csv"),
+        "Id,Name\n1,//UE5/Main/Samples/Game\n2,//not-a-project\n",
+    )?;
+    std::fs::write(
+        directory.path().join("Badges.csv"),
+        concat!(
+            "Id,ChangeNumber,BuildType,Result,Url,ArchivePath,ProjectId\n",
+            "2,100,Editor,Success,\"https://ci/?a=1,b=2\",\\N,1\n",
+            "1,100,Editor,0,https://ci/1,\\N,1\n",
+            "3,101,Editor,3,https://ci/3,\\N,2\n",
+        ),
+    )?;
+    std::fs::write(
+        directory.path().join("UserVotes.json"),
+        r#"[
+            {"Id": 1, "Changelist": 100, "UserName": "Alice", "Verdict": "Syncing", "Project": "//UE5/Main/Samples/Game"},
+            {"Id": 2, "Changelist": 100, "UserName": "alice", "Verdict": "Good", "Project": "//UE5/Main/Samples/Game"},
+            {"Id": 3, "Changelist": 100, "UserName": "bob", "Verdict": 6, "Project": "//UE5/Main/Samples/Game"},
+            {"Id": 4, "Changelist": 100, "UserName": "bob", "Verdict": "Sideways", "Project": "//UE5/Main/Samples/Game"}
+        ]"#,
+    )?;
+    std::fs::write(
+        directory.path().join("Comments.csv"),
+        "Id,ChangeNumber,UserName,Text,ProjectId\n1,100,bob,\"Broke the \"\"Lyra\"\" map,\nsorry\",1\n",
+    )?;
+
+    for storage in storages().await? {
+        let now = chrono::Utc::now();
+        let summary =
+            import_metadata_server(storage.as_ref(), &Default::default(), directory.path(), now)
+                .await?;
+        assert_eq!(summary.projects_added, 1);
+        assert_eq!((summary.badges_added, summary.user_events_added), (2, 2));
+        assert!(summary.conflicts.is_empty());
+        // The badge in a project we can't split, and bob's unknown verdict
+        assert_eq!(summary.skipped.len(), 2, "{:?}", summary.skipped);
+        assert!(summary.skipped[0].starts_with("Badges row 3:"));
+
+        let metadata = storage
+            .list_metadata("//ue5/main", Some("samples/game"), 0, 0, None)
+            .await?;
+        assert_eq!(metadata.len(), 1);
+        let badges = &metadata[0].badges;
+        assert_eq!(
+            badges
+                .iter()
+                .map(|badge| (badge.result, badge.url.as_str()))
+                .collect::<Vec<_>>(),
+            vec![
+                (BadgeResult::Starting, "https://ci/1"),
+                (BadgeResult::Success, "https://ci/?a=1,b=2"),
+            ]
+        );
+        let user_events = &metadata[0].user_events;
+        assert_eq!(user_events[0].user_name, "Alice");
+        assert_eq!(user_events[0].vote, Some(UgsUserVote::Good));
+        assert!(user_events[0].synced_at.is_some());
+        assert_eq!(user_events[1].starred, Some(true));
+        assert_eq!(
+            user_events[1].comment.as_deref(),
+            Some("Broke the \"Lyra\" map,\nsorry")
+        );
+
+        let later = now + chrono::Duration::hours(1);
+        let summary = import_metadata_server(
+            storage.as_ref(),
+            &Default::default(),
+            directory.path(),
+            later,
+        )
+        .await?;
+        assert_eq!(summary.projects_added, 0);
+        assert_eq!((summary.badges_added, summary.badges_unchanged), (0, 2));
+        assert_eq!(
+            (summary.user_events_added, summary.user_events_unchanged),
+            (0, 2)
+        );
+        assert!(summary.conflicts.is_empty());
+    }
+
+    Ok(())
+}
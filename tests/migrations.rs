@@ -0,0 +1,62 @@
+use anyhow::{Context, Result};
+use rugs::storage::{SqliteStorage, Storage};
+
+mod common;
+use common::{simple_create_request, storages};
+
+/// Test that the built-in migrations apply cleanly to a new database for each backend
+#[tokio::test]
+async fn migrations() -> Result<()> {
+    for storage in storages().await? {
+        let status = storage.migration_status().await?;
+        assert!(!status.applied.is_empty());
+        assert!(status.pending.is_empty(), "{:?}", status.pending);
+        assert!(status.unknown.is_empty(), "{:?}", status.unknown);
+    }
+
+    Ok(())
+}
+
+/// Test that existing databases carry on from the timestamps we used to use as sequence
+/// numbers, so that clients don't miss anything across the upgrade
+#[tokio::test]
+async fn sequence_counter_migration() -> Result<()> {
+    const SEQUENCE_COUNTER_VERSION: i64 = 20261016180000;
+    const OLD_SEQUENCE: i64 = 1_686_500_000_000_000;
+
+    let pool = sqlx::SqlitePool::connect("sqlite::memory:").await?;
+    let migrator = sqlx::migrate!("./migrations");
+    let (old_migrations, new_migrations): (Vec<_>, Vec<_>) = migrator
+        .iter()
+        .partition(|migration| migration.version < SEQUENCE_COUNTER_VERSION);
+
+    for migration in old_migrations {
+        sqlx::raw_sql(&migration.sql).execute(&pool).await?;
+    }
+    sqlx::query("INSERT INTO projects (stream, project) VALUES ('//depot/stream', 'proj')")
+        .execute(&pool)
+        .await?;
+    sqlx::query("INSERT INTO badges (sequence, change_number, added_at, build_type, result, url, project_id) VALUES (?, 1, ?, 'Editor', 0, '', 1)")
+        .bind(OLD_SEQUENCE)
+        .bind(chrono::Utc::now())
+        .execute(&pool)
+        .await?;
+    for migration in new_migrations {
+        sqlx::raw_sql(&migration.sql).execute(&pool).await?;
+    }
+
+    let storage = SqliteStorage::new(pool);
+    let project_id = storage
+        .get_project("//depot/stream", "proj")
+        .await?
+        .context("project should exist")?;
+    storage
+        .add_badge(project_id, &simple_create_request(), None)
+        .await?;
+    assert_eq!(
+        storage.latest_sequences(project_id).await?.badge,
+        OLD_SEQUENCE + 1
+    );
+
+    Ok(())
+}
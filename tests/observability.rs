@@ -0,0 +1,118 @@
+use anyhow::{Context, Result};
+use axum::{
+    body::Body,
+    http::{self, StatusCode},
+};
+use rugs::{app::app, storage::Storage};
+use tower::{Service, ServiceExt};
+
+use std::sync::Arc;
+
+mod common;
+use common::{
+    authorization_header, ci_request, config, for_each_storage, request_builder,
+    simple_create_request, storage, CI_AUTH,
+};
+
+/// Test that `/metrics` reports requests, auth failures, badges & database timings in the
+/// Prometheus text format
+#[tokio::test]
+async fn prometheus_metrics() -> Result<()> {
+    for_each_storage(prometheus_metrics_with).await
+}
+
+async fn prometheus_metrics_with(storage: Arc<dyn Storage>) -> Result<()> {
+    let mut app = app(config(), storage);
+
+    let request = request_builder("/api/build", "POST", Some(authorization_header(CI_AUTH)))
+        .body(Body::from(serde_json::to_vec(&simple_create_request())?))?;
+    let response = app.ready().await?.call(request).await?;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let request = request_builder(
+        "/api/latest?project=//depot/stream/proj",
+        "GET",
+        Some(authorization_header("user:wrong")),
+    )
+    .body(Body::empty())?;
+    let response = app.ready().await?.call(request).await?;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let request = request_builder("/metrics", "GET", None).body(Body::empty())?;
+    let response = app.ready().await?.call(request).await?;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let request = request_builder("/metrics", "GET", Some(authorization_header(CI_AUTH)))
+        .body(Body::empty())?;
+    let response = app.ready().await?.call(request).await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    let body = std::str::from_utf8(&body)?;
+
+    let lines = body.lines().collect::<Vec<_>>();
+    for expected in [
+        r#"rugs_http_requests_total{method="POST",route="/api/build",status="200"} 1"#,
+        r#"rugs_http_requests_total{method="GET",route="/api/latest",status="401"} 1"#,
+        r#"rugs_http_request_duration_seconds_count{method="POST",route="/api/build"} 1"#,
+        r#"rugs_auth_failures_total{scope="user"} 1"#,
+        r#"rugs_auth_failures_total{scope="ci"} 1"#,
+        r#"rugs_badges_total{project="//depot/stream/proj",build_type="Editor",result="Starting"} 1"#,
+        r#"rugs_database_query_duration_seconds_count{operation="add_badge"} 1"#,
+    ] {
+        assert!(
+            lines.contains(&expected),
+            "{expected} is missing from:\n{body}"
+        );
+    }
+    let database_size = lines
+        .iter()
+        .find_map(|line| line.strip_prefix("rugs_database_size_bytes "))
+        .context("database size is missing")?;
+    assert!(database_size.parse::<u64>()? > 0);
+
+    // The deprecated JSON counters only include what we actually count
+    let body = ci_request(&mut app, "/api/rugs_metrics", "GET", Body::empty()).await?;
+    assert_eq!(
+        serde_json::from_slice::<serde_json::Value>(&body)?,
+        serde_json::json!({
+            "latest_requests": 0,
+            "build_create_requests": 1,
+            "metadata_index_requests": 0,
+            "metadata_submit_requests": 0,
+        })
+    );
+
+    Ok(())
+}
+
+/// Test that every response has an `X-Request-Id`, which is either the one from the request or
+/// a new one
+#[tokio::test]
+async fn request_ids() -> Result<()> {
+    let mut app = app(config(), storage().await?);
+
+    let request = request_builder("/health", "GET", None).body(Body::empty())?;
+    let response = app.ready().await?.call(request).await?;
+    let request_id = response
+        .headers()
+        .get("x-request-id")
+        .context("No request id in the response")?;
+    assert_eq!(request_id.len(), 36, "Not a UUID: {request_id:?}");
+
+    let request = request_builder("/health", "GET", None)
+        .header("x-request-id", "from-the-load-balancer")
+        .body(Body::empty())?;
+    let response = app.ready().await?.call(request).await?;
+    assert_eq!(
+        response.headers().get("x-request-id"),
+        Some(&http::HeaderValue::from_static("from-the-load-balancer"))
+    );
+
+    // Requests that we deny still get one, since they're the ones people need to look up
+    let request = request_builder("/api/latest", "GET", None).body(Body::empty())?;
+    let response = app.ready().await?.call(request).await?;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert!(response.headers().contains_key("x-request-id"));
+
+    Ok(())
+}
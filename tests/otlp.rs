@@ -0,0 +1,106 @@
+use anyhow::Result;
+use axum::{body::Body, http::StatusCode, routing::post, Router};
+use rugs::{app::app, models::CreateBadge, otel::otlp_layer};
+use tower::{Service, ServiceExt};
+use tracing_subscriber::layer::SubscriberExt;
+
+mod common;
+use common::{
+    authorization_header, config, request_builder, simple_create_request, storage, CI_AUTH,
+};
+
+/// Test that requests get exported to an OTLP collector as spans (with their project), and that
+/// they continue the trace from the `traceparent` header
+#[tokio::test(flavor = "multi_thread")]
+async fn otlp_export() -> Result<()> {
+    use opentelemetry_proto::tonic::{
+        collector::trace::v1::ExportTraceServiceRequest, common::v1::any_value::Value,
+        trace::v1::Span,
+    };
+    use prost::Message;
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    const PARENT_SPAN_ID: &str = "00f067aa0ba902b7";
+
+    // A stand-in for the collector, which just hands us the exports it receives
+    let (exports_tx, mut exports_rx) = tokio::sync::mpsc::unbounded_channel();
+    let collector = Router::new().route(
+        "/v1/traces",
+        post(move |body: hyper::body::Bytes| {
+            let _ = exports_tx.send(body);
+            async {}
+        }),
+    );
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let collector_addr = listener.local_addr()?;
+    tokio::spawn(axum::Server::from_tcp(listener)?.serve(collector.into_make_service()));
+
+    // This has to be the global subscriber, like it is in `main`: sqlx's sqlite workers can
+    // close our spans on their own threads, and a child closing there releases its parent
+    // through that thread's default subscriber. Other tests' spans will get exported too, so
+    // we only look at our trace.
+    let (layer, tracer_provider) = otlp_layer(&format!("http://{collector_addr}"))?;
+    tracing::subscriber::set_global_default(tracing_subscriber::registry().with(layer))?;
+
+    let mut app = app(config(), storage().await?);
+    let create_request = CreateBadge {
+        project: String::from("//depot/otlp/traced"),
+        ..simple_create_request()
+    };
+    let request = request_builder("/api/build", "POST", Some(authorization_header(CI_AUTH)))
+        .header("traceparent", format!("00-{TRACE_ID}-{PARENT_SPAN_ID}-01"))
+        .body(Body::from(serde_json::to_vec(&create_request)?))?;
+    let response = app.ready().await?.call(request).await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    // The request's span lives as long as its response
+    drop(response);
+
+    let trace_id = u128::from_str_radix(TRACE_ID, 16)?.to_be_bytes();
+    let parent_span_id = u64::from_str_radix(PARENT_SPAN_ID, 16)?.to_be_bytes();
+    let attribute = |span: &Span, key: &str| {
+        span.attributes
+            .iter()
+            .find(|attribute| attribute.key == key)
+            .and_then(|attribute| attribute.value.as_ref()?.value.clone())
+    };
+    let string = |value: &str| Some(Value::StringValue(value.to_owned()));
+
+    let mut spans = Vec::new();
+    // Spans that the database's worker threads touched can be closed a little later, so keep
+    // flushing until we've seen everything
+    for _ in 0..50 {
+        let tracer_provider = tracer_provider.clone();
+        tokio::task::spawn_blocking(move || tracer_provider.force_flush()).await?;
+        while let Ok(export) = exports_rx.try_recv() {
+            let export = ExportTraceServiceRequest::decode(export)?;
+            spans.extend(
+                export
+                    .resource_spans
+                    .into_iter()
+                    .flat_map(|resource_spans| resource_spans.scope_spans)
+                    .flat_map(|scope_spans| scope_spans.spans)
+                    .filter(|span| span.trace_id == trace_id),
+            );
+        }
+
+        let request_span = spans.iter().find(|span| span.name == "request");
+        let handler_span = spans.iter().find(|span| span.name == "build_create");
+        let storage_span = spans.iter().find(|span| {
+            span.name == "storage" && attribute(span, "db.operation") == string("add_badge")
+        });
+        if let (Some(request_span), Some(handler_span), Some(storage_span)) =
+            (request_span, handler_span, storage_span)
+        {
+            assert_eq!(request_span.parent_span_id, parent_span_id);
+            assert_eq!(handler_span.parent_span_id, request_span.span_id);
+            assert_eq!(storage_span.parent_span_id, handler_span.span_id);
+            assert_eq!(attribute(handler_span, "stream"), string("//depot/otlp"));
+            assert_eq!(attribute(handler_span, "project"), string("traced"));
+            assert_eq!(attribute(handler_span, "change"), Some(Value::IntValue(1)));
+            return Ok(());
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+
+    anyhow::bail!("Not all spans were exported: {spans:#?}")
+}
@@ -0,0 +1,252 @@
+use anyhow::Result;
+use axum::{body::Body, http::StatusCode};
+use rugs::{
+    app::app,
+    config::Config,
+    models::{CreateBadge, LatestResponseV1},
+    paths::{needs_resplit, resplit_if_changed, resplit_projects, ProjectPaths},
+    storage::Storage,
+};
+use tower::{Service, ServiceExt};
+
+use std::{io::Write, sync::Arc};
+
+mod common;
+use common::{
+    authorization_header, env, get_metadata, request_builder, simple_create_request, storage,
+    storages, user_request, CI_AUTH,
+};
+
+/// Test that the `[paths]` settings split project paths as configured, and that we can tell when
+/// existing projects need to be re-split because they changed
+#[tokio::test]
+async fn project_paths() -> Result<()> {
+    let mut config_file = tempfile::NamedTempFile::new()?;
+    write!(
+        config_file,
+        r#"
+        [paths]
+        stream_roots = ["//depot/branches", "//Depot/Branches/Release/"]
+
+        [[paths.depots]]
+        depot = "//classic"
+        stream_depth = 0
+
+        [[paths.depots]]
+        depot = "//ue5"
+        stream_depth = 2
+        "#
+    )?;
+    let cfg = Config::load_with_env(Some(config_file.path()), env(&[]))?;
+    let paths = cfg.project_paths.clone();
+
+    // `config check` prints something we can load again
+    let mut printed_file = tempfile::NamedTempFile::new()?;
+    write!(printed_file, "{}", cfg.to_redacted_toml()?)?;
+    let reloaded = Config::load_with_env(Some(printed_file.path()), env(&[]))?;
+    assert_eq!(reloaded.project_paths, paths);
+
+    let from_env = Config::load_with_env(
+        None,
+        env(&[("RUGS_STREAM_ROOTS", "//depot/a/b, //depot/c/d")]),
+    )?;
+    assert_eq!(
+        from_env.project_paths.stream_roots,
+        vec!["//depot/a/b", "//depot/c/d"]
+    );
+    assert!(Config::load_with_env(None, env(&[("RUGS_STREAM_ROOTS", "//depot")])).is_err());
+
+    let split = |path: &str| paths.split(path);
+    let pair = |stream: &str, project: &str| Some((stream.to_string(), project.to_string()));
+    assert_eq!(split("//depot/Main/Game"), pair("//depot/main", "game"));
+    assert_eq!(
+        split("//depot/branches/release/Game"),
+        pair("//depot/branches/release", "game")
+    );
+    assert_eq!(
+        split("//depot/branches/dev/Game"),
+        pair("//depot/branches", "dev/game")
+    );
+    // This is the `release` project in the shorter stream root
+    assert_eq!(
+        split("//depot/branches/release"),
+        pair("//depot/branches", "release")
+    );
+    assert_eq!(
+        split("//classic/Game/Game.uproject"),
+        pair("//classic", "game/game.uproject")
+    );
+    assert_eq!(split("//UE5/Dev/Main/Lyra"), pair("//ue5/dev/main", "lyra"));
+    assert_eq!(split("//ue5/dev/main"), None);
+    assert_eq!(
+        paths.resplit("//depot/branches", "Release/Game"),
+        (
+            String::from("//depot/branches/release"),
+            String::from("game")
+        )
+    );
+
+    for storage in storages().await? {
+        project_paths_with(storage, &paths).await?;
+    }
+
+    // A new database's projects are split with whatever settings it starts out with
+    for storage in storages().await? {
+        assert!(!needs_resplit(storage.as_ref(), &paths).await?);
+        assert!(resplit_if_changed(storage.as_ref(), &paths)
+            .await?
+            .is_empty());
+        storage.get_or_add_project("//depot/main", "game").await?;
+        assert!(!needs_resplit(storage.as_ref(), &paths).await?);
+        assert!(needs_resplit(storage.as_ref(), &ProjectPaths::default()).await?);
+    }
+    Ok(())
+}
+
+async fn project_paths_with(storage: Arc<dyn Storage>, paths: &ProjectPaths) -> Result<()> {
+    // These were added before `//depot/branches/release` was configured as a stream root, and
+    // one of them already has history under the new split
+    let old_release = storage
+        .get_or_add_project("//depot/branches", "release/game")
+        .await?;
+    let new_release = storage
+        .get_or_add_project("//depot/branches/release", "game")
+        .await?;
+    let tools = storage
+        .get_or_add_project("//depot/branches", "release/tools")
+        .await?;
+    // These were split with two different sets of settings, and both split into a new project now
+    let deep_tools = storage
+        .get_or_add_project("//depot/branches", "release/deep/tools")
+        .await?;
+    let deeper_tools = storage
+        .get_or_add_project("//depot/branches/release/deep", "tools")
+        .await?;
+    let main = storage.get_or_add_project("//depot/main", "game").await?;
+    for project_id in [
+        old_release,
+        new_release,
+        tools,
+        deep_tools,
+        deeper_tools,
+        main,
+    ] {
+        storage
+            .add_badge(project_id, &simple_create_request(), None)
+            .await?;
+    }
+
+    // The projects were split with the default settings, which is all we know about databases from
+    // before we kept track of this
+    assert!(needs_resplit(storage.as_ref(), paths).await?);
+    let mut resplits = resplit_projects(storage.as_ref(), paths, true).await?;
+    resplits.sort_by(|a, b| a.from.cmp(&b.from));
+    assert_eq!(
+        resplits
+            .iter()
+            .map(|resplit| (resplit.from.1.as_str(), resplit.merged))
+            .collect::<Vec<_>>(),
+        vec![
+            ("release/deep/tools", false),
+            ("release/game", true),
+            ("release/tools", false),
+            ("tools", true)
+        ]
+    );
+    assert_eq!(storage.list_projects().await?.len(), 6, "dry run");
+
+    // Starting the server re-splits them for real, the same way
+    let mut real_resplits = resplit_if_changed(storage.as_ref(), paths).await?;
+    real_resplits.sort_by(|a, b| a.from.cmp(&b.from));
+    assert_eq!(real_resplits, resplits);
+    assert!(resplit_projects(storage.as_ref(), paths, false)
+        .await?
+        .is_empty());
+    assert!(!needs_resplit(storage.as_ref(), paths).await?);
+    assert!(needs_resplit(storage.as_ref(), &ProjectPaths::default()).await?);
+    assert_eq!(
+        storage
+            .list_projects()
+            .await?
+            .into_iter()
+            .map(|project| (project.stream, project.project, project.badges))
+            .collect::<Vec<_>>(),
+        vec![
+            (
+                String::from("//depot/branches/release"),
+                String::from("deep/tools"),
+                2
+            ),
+            (
+                String::from("//depot/branches/release"),
+                String::from("game"),
+                2
+            ),
+            (
+                String::from("//depot/branches/release"),
+                String::from("tools"),
+                1
+            ),
+            (String::from("//depot/main"), String::from("game"), 1),
+        ]
+    );
+
+    Ok(())
+}
+
+/// Test that CI, UGS' `/latest` & `/metadata` all split project paths the same way with the
+/// `[paths]` settings
+#[tokio::test]
+async fn project_paths_api() -> Result<()> {
+    let mut config_file = tempfile::NamedTempFile::new()?;
+    write!(
+        config_file,
+        r#"
+        [paths]
+        stream_roots = ["//depot/branches", "//Depot/Branches/Release/"]
+        "#
+    )?;
+    let cfg = Config::load_with_env(Some(config_file.path()), env(&[]))?;
+    let storage = storage().await?;
+    let project_id = storage
+        .get_or_add_project("//depot/branches/release", "game")
+        .await?;
+    storage
+        .add_badge(project_id, &simple_create_request(), None)
+        .await?;
+
+    let mut app = app(cfg, storage.clone());
+    let badge = CreateBadge {
+        change_number: 2,
+        project: String::from("//depot/Branches/Release/Game"),
+        ..simple_create_request()
+    };
+    let response = app
+        .ready()
+        .await?
+        .call(
+            request_builder("/api/build", "POST", Some(authorization_header(CI_AUTH)))
+                .body(Body::from(serde_json::to_vec(&badge)?))?,
+        )
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let latest: LatestResponseV1 = serde_json::from_slice(
+        &user_request(
+            &mut app,
+            "/api/latest?project=//depot/branches/release/Game",
+            "GET",
+            Body::empty(),
+        )
+        .await?,
+    )?;
+    let sequences = storage.latest_sequences(project_id).await?;
+    assert_eq!(latest.last_build_id, sequences.badge);
+
+    let metadata = get_metadata(&mut app, "//depot/branches/release", "Game").await?;
+    assert_eq!(metadata.items.len(), 2);
+    let resplit_metadata = get_metadata(&mut app, "//depot/branches", "release/game").await?;
+    assert_eq!(resplit_metadata.items, metadata.items);
+
+    Ok(())
+}
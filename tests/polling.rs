@@ -0,0 +1,356 @@
+use anyhow::{Context, Result};
+use axum::{
+    body::Body,
+    http::{self, StatusCode},
+    Router,
+};
+use rugs::{
+    app::app,
+    models::{CreateBadge, GetMetadataResponseV2, LatestResponseV1},
+    retention::prune_metadata,
+};
+use tower::{Service, ServiceExt};
+
+mod common;
+use common::{
+    authorization_header, config, request_builder, simple_create_request, storage, user_request,
+    CI_AUTH, USER_AUTH,
+};
+
+/// Test that `/latest` with `since` waits for a newer badge or user event, and responds with
+/// what it has when it times out
+#[tokio::test]
+async fn latest_long_poll() -> Result<()> {
+    const PROJECT: &str = "//depot/stream/proj";
+
+    let mut app = app(config(), storage().await?);
+
+    let response = app
+        .ready()
+        .await?
+        .call(
+            request_builder("/api/build", "POST", Some(authorization_header(CI_AUTH)))
+                .body(Body::from(serde_json::to_vec(&simple_create_request())?))?,
+        )
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Nothing new shows up, so this should time out and tell us what we already know
+    let started_at = std::time::Instant::now();
+    let latest: LatestResponseV1 = serde_json::from_slice(
+        &user_request(
+            &mut app,
+            &format!("/api/latest?project={PROJECT}&since=1&wait=1"),
+            "GET",
+            Body::empty(),
+        )
+        .await?,
+    )?;
+    assert!(started_at.elapsed() >= std::time::Duration::from_secs(1));
+    assert_eq!(latest.last_build_id, 1);
+    assert_eq!(latest.last_event_id, 0);
+
+    let waiting = tokio::spawn({
+        let mut app = app.clone();
+        async move {
+            user_request(
+                &mut app,
+                &format!("/api/latest?project={PROJECT}&since=1&wait=30"),
+                "GET",
+                Body::empty(),
+            )
+            .await
+        }
+    });
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    assert!(!waiting.is_finished());
+
+    let started_at = std::time::Instant::now();
+    let update = serde_json::json!({
+        "Change": 1,
+        "Stream": "//depot/stream",
+        "Project": "proj",
+        "UserName": "alice",
+        "Synced": true,
+    });
+    user_request(
+        &mut app,
+        "/api/metadata",
+        "POST",
+        Body::from(serde_json::to_vec(&update)?),
+    )
+    .await?;
+
+    let latest: LatestResponseV1 = serde_json::from_slice(&waiting.await??)?;
+    assert!(started_at.elapsed() < std::time::Duration::from_secs(5));
+    assert_eq!(latest.last_build_id, 1);
+    assert_eq!(latest.last_event_id, 2);
+
+    Ok(())
+}
+
+/// Read the next server-sent event from `body`, and return its ID & data
+async fn next_metadata_event(
+    body: &mut axum::body::BoxBody,
+) -> Result<(i64, GetMetadataResponseV2)> {
+    use hyper::body::HttpBody;
+
+    let chunk = tokio::time::timeout(std::time::Duration::from_secs(5), body.data())
+        .await?
+        .context("event stream ended")??;
+    let event = std::str::from_utf8(&chunk)?;
+
+    let mut id = None;
+    let mut data = None;
+    for line in event.lines() {
+        if let Some(value) = line.strip_prefix("id:") {
+            id = Some(value.trim().parse()?);
+        } else if let Some(value) = line.strip_prefix("data:") {
+            data = Some(serde_json::from_str(value.trim())?);
+        }
+    }
+
+    Ok((
+        id.context("event has no ID")?,
+        data.context("event has no data")?,
+    ))
+}
+
+/// Test that `/stream` sends what changed since the requested sequence number, then anything
+/// that changes after that, and that clients can resume with `Last-Event-ID`
+#[tokio::test]
+async fn metadata_stream() -> Result<()> {
+    let mut app = app(config(), storage().await?);
+
+    let response = app
+        .ready()
+        .await?
+        .call(
+            request_builder("/api/build", "POST", Some(authorization_header(CI_AUTH)))
+                .body(Body::from(serde_json::to_vec(&simple_create_request())?))?,
+        )
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let stream_request = |last_event_id: Option<&str>| {
+        let builder = request_builder(
+            "/api/stream?stream=//depot/stream&project=proj&sequence=0",
+            "GET",
+            Some(authorization_header(USER_AUTH)),
+        );
+        match last_event_id {
+            Some(id) => builder.header("Last-Event-ID", id),
+            None => builder,
+        }
+        .body(Body::empty())
+    };
+
+    let response = app.ready().await?.call(stream_request(None)?).await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    let mut body = response.into_body();
+
+    let (id, change) = next_metadata_event(&mut body).await?;
+    assert_eq!(id, 1);
+    assert_eq!(change.project, "//depot/stream/proj");
+    assert_eq!(change.change, 1);
+    assert_eq!(change.badges.len(), 1);
+    assert_eq!(change.users.len(), 0);
+
+    let update = serde_json::json!({
+        "Change": 1,
+        "Stream": "//depot/stream",
+        "Project": "proj",
+        "UserName": "alice",
+        "Vote": "Good",
+    });
+    user_request(
+        &mut app,
+        "/api/metadata",
+        "POST",
+        Body::from(serde_json::to_vec(&update)?),
+    )
+    .await?;
+
+    let (id, change) = next_metadata_event(&mut body).await?;
+    assert_eq!(id, 2);
+    assert_eq!(change.badges.len(), 1);
+    assert_eq!(change.users.len(), 1);
+    assert_eq!(change.users[0].user, "alice");
+
+    // A reconnecting client only gets what it hasn't seen yet
+    let response = app.ready().await?.call(stream_request(Some("1"))?).await?;
+    let (id, _) = next_metadata_event(&mut response.into_body()).await?;
+    assert_eq!(id, 2);
+
+    Ok(())
+}
+
+/// Test that `/latest` & `/metadata` answer `If-None-Match` with a 304 until something
+/// changes, and that large responses get compressed
+#[tokio::test]
+async fn etags_and_compression() -> Result<()> {
+    let mut app = app(config(), storage().await?);
+
+    let post_badge = |app: &mut Router, project: &str| {
+        let badge = CreateBadge {
+            project: String::from(project),
+            ..simple_create_request()
+        };
+        let request = request_builder("/api/build", "POST", Some(authorization_header(CI_AUTH)))
+            .body(Body::from(serde_json::to_vec(&badge).unwrap()))
+            .unwrap();
+        let call = app.call(request);
+        async move {
+            assert_eq!(call.await?.status(), StatusCode::OK);
+            anyhow::Ok(())
+        }
+    };
+    post_badge(&mut app, "//depot/stream/proj").await?;
+
+    for uri in [
+        "/api/latest?project=//depot/stream/proj",
+        "/api/metadata?stream=//depot/stream&minchange=0",
+        "/api/metadata?stream=//depot/stream&project=proj&minchange=0",
+    ] {
+        let request = |if_none_match: Option<&http::HeaderValue>| {
+            let builder = request_builder(uri, "GET", Some(authorization_header(USER_AUTH)));
+            match if_none_match {
+                Some(etag) => builder.header(http::header::IF_NONE_MATCH, etag),
+                None => builder,
+            }
+            .body(Body::empty())
+        };
+
+        let response = app.ready().await?.call(request(None)?).await?;
+        assert_eq!(response.status(), StatusCode::OK, "{uri}");
+        let etag = response
+            .headers()
+            .get(http::header::ETAG)
+            .context("response should have an ETag")?
+            .clone();
+
+        let response = app.ready().await?.call(request(Some(&etag))?).await?;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED, "{uri}");
+        assert_eq!(response.headers().get(http::header::ETAG), Some(&etag));
+        assert!(hyper::body::to_bytes(response.into_body())
+            .await?
+            .is_empty());
+
+        // Other projects' badges don't change the response
+        post_badge(&mut app, "//depot/other/proj").await?;
+        if uri.contains("project=") {
+            post_badge(&mut app, "//depot/stream/other").await?;
+        }
+        let response = app.ready().await?.call(request(Some(&etag))?).await?;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED, "{uri}");
+
+        post_badge(&mut app, "//depot/stream/proj").await?;
+
+        let response = app.ready().await?.call(request(Some(&etag))?).await?;
+        assert_eq!(response.status(), StatusCode::OK, "{uri}");
+        assert_ne!(response.headers().get(http::header::ETAG), Some(&etag));
+    }
+
+    // The compressed & uncompressed responses share an ETag, so it has to be weak, and caches
+    // need to know that the response depends on `Accept-Encoding`
+    let mut etags = Vec::new();
+    for (accept_encoding, content_encoding) in [("gzip", Some("gzip")), ("identity", None)] {
+        let response = app
+            .ready()
+            .await?
+            .call(
+                request_builder(
+                    "/api/metadata?stream=//depot/stream&minchange=0",
+                    "GET",
+                    Some(authorization_header(USER_AUTH)),
+                )
+                .header(http::header::ACCEPT_ENCODING, accept_encoding)
+                .body(Body::empty())?,
+            )
+            .await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response
+                .headers()
+                .get(http::header::CONTENT_ENCODING)
+                .map(|value| value.to_str())
+                .transpose()?,
+            content_encoding
+        );
+        assert_eq!(
+            response.headers().get(http::header::VARY),
+            Some(&http::HeaderValue::from_static("accept-encoding")),
+            "{accept_encoding}"
+        );
+        let etag = response
+            .headers()
+            .get(http::header::ETAG)
+            .context("response should have an ETag")?
+            .to_str()?
+            .to_owned();
+        assert!(etag.starts_with("W/\""), "{etag}");
+        etags.push(etag);
+    }
+    assert_eq!(etags[0], etags[1]);
+
+    Ok(())
+}
+
+/// Test that pruning badges & user events changes the ETags, since clients that have them
+/// cached would otherwise keep seeing what we deleted
+#[tokio::test]
+async fn etags_after_pruning() -> Result<()> {
+    let storage = storage().await?;
+    let mut app = app(config(), storage.clone());
+    for change_number in [1, 2] {
+        let badge = CreateBadge {
+            change_number,
+            ..simple_create_request()
+        };
+        let request = request_builder("/api/build", "POST", Some(authorization_header(CI_AUTH)))
+            .body(Body::from(serde_json::to_vec(&badge)?))?;
+        assert_eq!(
+            app.ready().await?.call(request).await?.status(),
+            StatusCode::OK
+        );
+    }
+
+    let uris = [
+        "/api/latest?project=//depot/stream/proj",
+        "/api/metadata?stream=//depot/stream&minchange=0",
+    ];
+    let mut etags = Vec::new();
+    for uri in uris {
+        let request = request_builder(uri, "GET", Some(authorization_header(USER_AUTH)))
+            .body(Body::empty())?;
+        let response = app.ready().await?.call(request).await?;
+        etags.push(
+            response
+                .headers()
+                .get(http::header::ETAG)
+                .context("response should have an ETag")?
+                .clone(),
+        );
+    }
+
+    let policy = rugs::retention::RetentionPolicy {
+        default: rugs::retention::MetadataRetention {
+            changes: Some(1),
+            ..Default::default()
+        },
+        projects: Vec::new(),
+    };
+    let pruned = prune_metadata(storage.as_ref(), &policy, chrono::Utc::now()).await?;
+    assert_eq!(pruned.badges, 1);
+
+    for (uri, etag) in uris.into_iter().zip(etags) {
+        let request = request_builder(uri, "GET", Some(authorization_header(USER_AUTH)))
+            .header(http::header::IF_NONE_MATCH, &etag)
+            .body(Body::empty())?;
+        let response = app.ready().await?.call(request).await?;
+        assert_eq!(response.status(), StatusCode::OK, "{uri}");
+        assert_ne!(response.headers().get(http::header::ETAG), Some(&etag));
+    }
+
+    Ok(())
+}
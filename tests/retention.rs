@@ -0,0 +1,112 @@
+use anyhow::Result;
+use rugs::{
+    config::Config,
+    models::{BadgeResult, CreateBadge},
+    retention::{prune_metadata, RetentionPolicy},
+    storage::{Storage, UserEventUpdate},
+};
+
+use std::{io::Write, sync::Arc};
+
+mod common;
+use common::{env, simple_create_request, storages};
+
+/// Test that we prune badges & user events per the retention in the config file, with
+/// per-project overrides inheriting anything they don't set
+#[tokio::test]
+async fn metadata_retention() -> Result<()> {
+    let mut config_file = tempfile::NamedTempFile::new()?;
+    write!(
+        config_file,
+        r#"
+        [retention]
+        metadata_changes = 2
+        superseded_badge_days = 1
+
+        [[retention.projects]]
+        project = "//depot/dev/*"
+        metadata_changes = 0
+
+        [[retention.projects]]
+        project = "//depot/old/*"
+        metadata_days = 1
+        "#
+    )?;
+    let config = Config::load_with_env(Some(config_file.path()), env(&[]))?;
+    let policy = config.metadata_retention.clone();
+    assert_eq!(policy.projects.len(), 2);
+
+    // `config check` prints something we can load again
+    let mut printed_file = tempfile::NamedTempFile::new()?;
+    write!(printed_file, "{}", config.to_redacted_toml()?)?;
+    let reloaded = Config::load_with_env(Some(printed_file.path()), env(&[]))?;
+    assert_eq!(reloaded.metadata_retention, policy);
+
+    for storage in storages().await? {
+        metadata_retention_with(storage, &config.metadata_retention).await?;
+    }
+    Ok(())
+}
+
+async fn metadata_retention_with(
+    storage: Arc<dyn Storage>,
+    policy: &RetentionPolicy,
+) -> Result<()> {
+    let projects = ["main", "dev", "old"];
+    for stream in projects {
+        let project_id = storage
+            .get_or_add_project(&format!("//depot/{stream}"), "game")
+            .await?;
+        for (change_number, build_type, result) in [
+            (1, "Editor", BadgeResult::Starting),
+            (1, "Editor", BadgeResult::Success),
+            (2, "Editor", BadgeResult::Success),
+            (3, "Game", BadgeResult::Success),
+        ] {
+            let badge = CreateBadge {
+                change_number,
+                build_type: String::from(build_type),
+                result,
+                ..simple_create_request()
+            };
+            storage.add_badge(project_id, &badge, None).await?;
+        }
+        for (change_number, user_name) in [(1, "alice"), (3, "bob")] {
+            let update = UserEventUpdate {
+                synced: true,
+                ..Default::default()
+            };
+            storage
+                .update_user_event(project_id, change_number, user_name, update)
+                .await?;
+        }
+    }
+
+    // Nothing is old enough to be pruned by age yet, but `main` & `old` only keep 2 changes
+    let pruned = prune_metadata(storage.as_ref(), policy, chrono::Utc::now()).await?;
+    assert_eq!(pruned.badges, 2 * 2);
+    assert_eq!(pruned.user_events, 2);
+
+    // Two days later, `dev` drops its superseded `Starting` badge, and `old` drops the rest
+    let later = chrono::Utc::now() + chrono::Duration::days(2);
+    let pruned = prune_metadata(storage.as_ref(), policy, later).await?;
+    assert_eq!(pruned.badges, 1 + 2);
+    assert_eq!(pruned.user_events, 1);
+
+    let remaining = storage
+        .list_projects()
+        .await?
+        .into_iter()
+        .map(|project| (project.stream, project.badges, project.user_events))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        remaining,
+        vec![
+            (String::from("//depot/dev"), 3, 2),
+            (String::from("//depot/main"), 2, 1),
+            (String::from("//depot/old"), 0, 0),
+        ]
+    );
+
+    Ok(())
+}
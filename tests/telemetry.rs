@@ -0,0 +1,147 @@
+use anyhow::Result;
+use axum::{body::Body, http::StatusCode};
+use rugs::{
+    app::app,
+    models::{TelemetryErrorData, TelemetryResponse, TelemetryTimingData},
+    storage::Storage,
+};
+
+use tower::{Service, ServiceExt};
+
+use std::sync::Arc;
+
+mod common;
+use common::{
+    authorization_header, ci_request, config, for_each_storage, request_builder, user_request,
+    USER_AUTH,
+};
+
+/// Test that we can submit telemetry & errors and query them back
+#[tokio::test]
+async fn telemetry_integration() -> Result<()> {
+    for_each_storage(telemetry_integration_with).await
+}
+
+async fn telemetry_integration_with(storage: Arc<dyn Storage>) -> Result<()> {
+    let mut app = app(config(), storage.clone());
+
+    for (user_name, duration) in [("alice", 10.0), ("bob", 30.0)] {
+        let timing = serde_json::json!({
+            "Action": "Sync",
+            "Result": "Succeeded",
+            "UserName": user_name,
+            "Project": "//depot/stream/proj",
+            "Timestamp": "2023-06-11T19:04:27.123",
+            "Duration": duration,
+        });
+        user_request(
+            &mut app,
+            "/api/telemetry?Version=1.0&IpAddress=127.0.0.1",
+            "POST",
+            Body::from(serde_json::to_vec(&timing)?),
+        )
+        .await?;
+    }
+
+    let error = serde_json::json!({
+        "Type": 0,
+        "Text": "System.NullReferenceException",
+        "UserName": "alice",
+        "Project": "//depot/stream/proj",
+        "Timestamp": "2023-06-11T19:04:27Z",
+    });
+    user_request(
+        &mut app,
+        "/api/error?Version=1.0",
+        "POST",
+        Body::from(serde_json::to_vec(&error)?),
+    )
+    .await?;
+
+    let telemetry: TelemetryResponse = serde_json::from_slice(
+        &ci_request(&mut app, "/api/telemetry?action=sync", "GET", Body::empty()).await?,
+    )?;
+    assert_eq!(telemetry.timings.len(), 2);
+    assert_eq!(telemetry.summary.len(), 1);
+    assert_eq!(telemetry.summary[0].count, 2);
+    assert_eq!(telemetry.summary[0].average_duration, 20.0);
+    assert_eq!(telemetry.summary[0].max_duration, 30.0);
+
+    let telemetry: TelemetryResponse = serde_json::from_slice(
+        &ci_request(&mut app, "/api/telemetry?user=bob", "GET", Body::empty()).await?,
+    )?;
+    assert_eq!(telemetry.timings.len(), 1);
+    assert_eq!(telemetry.timings[0].duration, 30.0);
+
+    let errors: Vec<TelemetryErrorData> = serde_json::from_slice(
+        &ci_request(&mut app, "/api/error?records=10", "GET", Body::empty()).await?,
+    )?;
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].text, "System.NullReferenceException");
+    assert_eq!(errors[0].version.as_deref(), Some("1.0"));
+
+    // UGS clients can only submit telemetry, not read everyone's back
+    for uri in ["/api/telemetry", "/api/error"] {
+        let response = app
+            .ready()
+            .await?
+            .call(
+                request_builder(uri, "GET", Some(authorization_header(USER_AUTH)))
+                    .body(Body::empty())?,
+            )
+            .await?;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "GET {uri}");
+    }
+
+    // Asking for too few or too many records is clamped to what we're willing to list
+    let errors: Vec<TelemetryErrorData> = serde_json::from_slice(
+        &ci_request(&mut app, "/api/error?records=-1", "GET", Body::empty()).await?,
+    )?;
+    assert_eq!(errors.len(), 1);
+    let telemetry: TelemetryResponse = serde_json::from_slice(
+        &ci_request(&mut app, "/api/telemetry?records=0", "GET", Body::empty()).await?,
+    )?;
+    assert_eq!(telemetry.timings.len(), 1);
+
+    // Nothing is old enough to be pruned yet
+    assert_eq!(
+        storage
+            .prune_telemetry(chrono::Utc::now() - chrono::Duration::days(1))
+            .await?,
+        0
+    );
+
+    let old_timing = TelemetryTimingData {
+        action: String::from("Sync"),
+        result: String::from("Succeeded"),
+        user_name: String::from("carol"),
+        project: String::from("//depot/stream/proj"),
+        timestamp: chrono::Utc::now() - chrono::Duration::days(2),
+        duration: 20.0,
+    };
+    storage
+        .add_telemetry_timing(
+            chrono::Utc::now() - chrono::Duration::days(2),
+            &old_timing,
+            None,
+            None,
+        )
+        .await?;
+    assert_eq!(
+        storage
+            .prune_telemetry(chrono::Utc::now() - chrono::Duration::days(1))
+            .await?,
+        1
+    );
+
+    let telemetry: TelemetryResponse = serde_json::from_slice(
+        &ci_request(&mut app, "/api/telemetry", "GET", Body::empty()).await?,
+    )?;
+    assert_eq!(telemetry.timings.len(), 2);
+    assert!(telemetry
+        .timings
+        .iter()
+        .all(|timing| timing.user_name != "carol"));
+
+    Ok(())
+}
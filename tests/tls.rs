@@ -0,0 +1,79 @@
+use anyhow::Result;
+use axum::{
+    body::Body,
+    http::{self, Request, StatusCode},
+};
+use rugs::tls::{redirect_app, CertificateWatcher};
+use tower::ServiceExt;
+
+use std::sync::Arc;
+
+/// Helper to write a new self-signed certificate & key to `cert_path` & `key_path`
+fn write_self_signed_certificate(
+    cert_path: &std::path::Path,
+    key_path: &std::path::Path,
+) -> Result<()> {
+    let certificate = rcgen::generate_simple_self_signed(vec![String::from("localhost")])?;
+    std::fs::write(cert_path, certificate.serialize_pem()?)?;
+    std::fs::write(key_path, certificate.serialize_private_key_pem())?;
+    Ok(())
+}
+
+/// Test that the certificate is reloaded when it changes on disk, and that we keep the old
+/// one if the new one is broken
+#[tokio::test]
+async fn tls_certificate_reload() -> Result<()> {
+    let directory = tempfile::tempdir()?;
+    let cert_path = directory.path().join("cert.pem");
+    let key_path = directory.path().join("key.pem");
+    write_self_signed_certificate(&cert_path, &key_path)?;
+
+    let (rustls_config, mut watcher) = CertificateWatcher::new(&cert_path, &key_path).await?;
+    let original = rustls_config.get_inner();
+    assert!(!watcher.reload_if_changed().await?);
+    assert!(Arc::ptr_eq(&original, &rustls_config.get_inner()));
+
+    write_self_signed_certificate(&cert_path, &key_path)?;
+    assert!(watcher.reload_if_changed().await?);
+    let renewed = rustls_config.get_inner();
+    assert!(!Arc::ptr_eq(&original, &renewed));
+
+    std::fs::write(&cert_path, "not a certificate")?;
+    assert!(watcher.reload_if_changed().await.is_err());
+    assert!(Arc::ptr_eq(&renewed, &rustls_config.get_inner()));
+
+    Ok(())
+}
+
+/// Test that the HTTP redirect app sends requests to the same path over HTTPS
+#[tokio::test]
+async fn tls_http_redirect() -> Result<()> {
+    for (https_port, host, expected_location) in [
+        (
+            3000,
+            "rugs.local:8080",
+            "https://rugs.local:3000/api/latest?project=//depot/stream/proj",
+        ),
+        (
+            443,
+            "rugs.local",
+            "https://rugs.local/api/latest?project=//depot/stream/proj",
+        ),
+    ] {
+        let response = redirect_app(https_port)
+            .oneshot(
+                Request::builder()
+                    .uri("/api/latest?project=//depot/stream/proj")
+                    .header(http::header::HOST, host)
+                    .body(Body::empty())?,
+            )
+            .await?;
+        assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(
+            response.headers().get(http::header::LOCATION),
+            Some(&http::HeaderValue::from_static(expected_location))
+        );
+    }
+
+    Ok(())
+}